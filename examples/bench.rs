@@ -0,0 +1,180 @@
+//! Codec and handler micro-benchmarks
+//!
+//! Run with `cargo run --release --example bench [filter]`. Criterion is not available (the
+//! harness owns Cargo.toml), so this times each case with a small fixed-duration loop and
+//! reports the mean time per iteration.
+
+use std::hint::black_box;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dns_starter_rust::arena::Arena;
+use dns_starter_rust::blocking::block_on;
+use dns_starter_rust::canonical::name_cmp;
+use dns_starter_rust::dns::{
+    response, DnsLabels, DnsMessage, DnsMessageRef, DnsQuestion, DnsRecord, MessageBuilder, ToBytes,
+};
+use dns_starter_rust::handler::{DefaultHandler, RequestCtx, RequestHandler, Transport};
+use dns_starter_rust::rdata::RData;
+use dns_starter_rust::response_cache::ResponseCache;
+
+const RUN_FOR: Duration = Duration::from_millis(500);
+
+fn bench<T>(filter: &Option<String>, name: &str, mut f: impl FnMut() -> T) {
+    if filter
+        .as_ref()
+        .is_some_and(|filter| !name.contains(filter.as_str()))
+    {
+        return;
+    }
+
+    // warm up caches and the allocator
+    for _ in 0..1_000 {
+        black_box(f());
+    }
+
+    let started = Instant::now();
+    let mut iterations = 0u64;
+    while started.elapsed() < RUN_FOR {
+        for _ in 0..100 {
+            black_box(f());
+        }
+        iterations += 100;
+    }
+    let per_iter = started.elapsed().as_nanos() as f64 / iterations as f64;
+    println!("{name:<40} {per_iter:>10.1} ns/iter");
+}
+
+/// Aggregate lookup throughput of a shared [`ResponseCache`] hit by `threads` threads at once,
+/// with one store for every 100 lookups to exercise writers too
+fn bench_cache(filter: &Option<String>, threads: usize) {
+    let name = format!("cache/{threads} threads");
+    if filter
+        .as_ref()
+        .is_some_and(|filter| !name.contains(filter.as_str()))
+    {
+        return;
+    }
+
+    let cache = ResponseCache::new(10_000, Duration::from_secs(300));
+    let queries: Vec<(Vec<u8>, DnsMessage, Vec<u8>)> = (0..1_000)
+        .map(|i| {
+            let query = DnsMessage::query(i, &format!("host{i}.example.com"), 1);
+            let resp = response(&query);
+            let bytes = resp.to_bytes();
+            (query.to_bytes(), resp, bytes)
+        })
+        .collect();
+    for (query, resp, bytes) in &queries {
+        cache.store(query, resp, bytes);
+    }
+
+    let lookups = AtomicU64::new(0);
+    let started = Instant::now();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                let mut out = Vec::with_capacity(512);
+                let mut done = 0u64;
+                while started.elapsed() < RUN_FOR {
+                    for (i, (query, resp, bytes)) in queries.iter().enumerate() {
+                        if i % 100 == 0 {
+                            cache.store(query, resp, bytes);
+                        }
+                        black_box(cache.lookup(query, &mut out));
+                    }
+                    done += queries.len() as u64;
+                }
+                lookups.fetch_add(done, Ordering::Relaxed);
+            });
+        }
+    });
+    let per_sec = lookups.into_inner() as f64 / started.elapsed().as_secs_f64();
+    println!("{name:<40} {:>10.2} M lookups/s", per_sec / 1e6);
+}
+
+/// Representative messages: a bare query, a typical answer and a large multi-record answer
+fn corpus() -> Vec<(&'static str, DnsMessage)> {
+    let query = DnsMessage::query(0x1234, "www.example.com", 1);
+
+    let name = DnsLabels::from("www.example.com");
+    let small = MessageBuilder::response_to(&query)
+        .add_question(DnsQuestion::new(name.clone(), 1, 1))
+        .add_answer(DnsRecord::with_rdata(
+            name.clone(),
+            300,
+            Ipv4Addr::new(93, 184, 216, 34),
+        ))
+        .build();
+
+    let mut large =
+        MessageBuilder::response_to(&query).add_question(DnsQuestion::new(name.clone(), 28, 1));
+    for i in 0..20u16 {
+        let ip = Ipv6Addr::new(0x2606, 0x2800, 0x220, 1, 0x248, 0x1893, 0x25c8, i);
+        large = large.add_answer(DnsRecord::with_rdata(name.clone(), 300, ip));
+    }
+    large = large.add_answer(DnsRecord::with_rdata(
+        "_sip._tcp.example.com".into(),
+        300,
+        RData::Srv {
+            priority: 10,
+            weight: 60,
+            port: 5060,
+            target: "sip.example.com".into(),
+        },
+    ));
+
+    vec![
+        ("query", query),
+        ("small response", small),
+        ("large response", large.build()),
+    ]
+}
+
+fn main() {
+    let filter = std::env::args().nth(1);
+
+    for (label, msg) in corpus() {
+        let bytes = msg.to_bytes();
+        bench(&filter, &format!("parse owned/{label}"), || {
+            DnsMessage::from_bytes(&bytes).unwrap()
+        });
+        let mut arena = Arena::new(256);
+        bench(&filter, &format!("parse arena/{label}"), || {
+            let msg = DnsMessageRef::from_bytes(&bytes).unwrap();
+            let msg = msg.to_owned_in(&mut arena);
+            let id = msg.id();
+            arena.reclaim(msg);
+            id
+        });
+        bench(&filter, &format!("parse borrowed/{label}"), || {
+            DnsMessageRef::from_bytes(&bytes).unwrap().header().id()
+        });
+
+        let mut buf = Vec::with_capacity(4096);
+        bench(&filter, &format!("serialize/{label}"), || {
+            buf.clear();
+            msg.write_to(&mut buf)
+        });
+    }
+
+    let a = DnsLabels::from("mail.Example.COM");
+    let b = DnsLabels::from("www.example.com");
+    bench(&filter, "name/eq", || a == b);
+    bench(&filter, "name/canonical cmp", || name_cmp(&a, &b));
+
+    for threads in [1, 2, 4, 8] {
+        bench_cache(&filter, threads);
+    }
+
+    let query = DnsMessage::query(7, "codecrafters.io", 1).to_bytes();
+    let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 5353)), Transport::Udp);
+    let mut out = Vec::with_capacity(512);
+    bench(&filter, "handle one query", || {
+        let req = DnsMessage::from_bytes(&query).unwrap();
+        let response = block_on(DefaultHandler.handle(req, ctx.clone()));
+        out.clear();
+        response.write_to(&mut out)
+    });
+}
@@ -0,0 +1,36 @@
+/*
+ * C interface to the DNS message codec.
+ *
+ * Build the shared library with:
+ *   cargo rustc --release --lib --crate-type cdylib
+ */
+#ifndef DNS_H
+#define DNS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Parsed DNS message, owned by the library */
+typedef struct DnsMessage DnsMessage;
+
+/* Parses `len` bytes of wire format. Returns NULL if the message is malformed. */
+DnsMessage *dns_parse(const uint8_t *buf, size_t len);
+
+/* JSON rendering of a message. Release the string with dns_string_free. */
+char *dns_to_json(const DnsMessage *msg);
+
+/* Releases a message returned by dns_parse. NULL is ignored. */
+void dns_free(DnsMessage *msg);
+
+/* Releases a string returned by dns_to_json. NULL is ignored. */
+void dns_string_free(char *json);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DNS_H */
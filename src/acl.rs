@@ -0,0 +1,246 @@
+//! Access control lists of client networks
+//!
+//! An [`Acl`] is a list of networks in CIDR notation (`10.0.0.0/8`, `2001:db8::/32`, or a bare
+//! address). [`AclLayer`] answers REFUSED to the clients outside one, for a scope:
+//!
+//! - [`AclLayer::queries`] covers every query, and belongs at the top of the pipeline;
+//! - [`AclLayer::transfers`] covers zone transfers (AXFR, IXFR);
+//! - [`AclLayer::recursion`] covers the queries that reach it, so it belongs below the layers
+//!   answering from local data and above the cache, which would otherwise hand the answers
+//!   resolved for allowed clients to everyone.
+//!
+//! IPv4 clients of an IPv6 socket (`::ffff:192.0.2.1`) are matched as IPv4.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::dns::{error_response, rcode, rtype, DnsMessage};
+use crate::error::DnsError;
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+
+/// A network: an address and the length of its prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = DnsError;
+
+    /// `addr/prefix`, or an address alone for just that address
+    fn from_str(text: &str) -> Result<Self, DnsError> {
+        let invalid = || DnsError::Config(format!("invalid network {text}"));
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= max),
+            None => Some(max),
+        };
+        Ok(Cidr {
+            addr: addr.to_canonical(),
+            prefix: prefix.ok_or_else(invalid)?,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Networks allowed to do something
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    networks: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, network: Cidr) -> Self {
+        self.networks.push(network);
+        self
+    }
+
+    /// Whether `ip` is in one of the networks
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    pub fn networks(&self) -> &[Cidr] {
+        &self.networks
+    }
+}
+
+impl FromIterator<Cidr> for Acl {
+    fn from_iter<I: IntoIterator<Item = Cidr>>(iter: I) -> Self {
+        Self {
+            networks: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Queries,
+    Transfers,
+    Recursion,
+}
+
+/// Refuses the clients outside an [`Acl`]
+#[derive(Debug, Clone)]
+pub struct AclLayer {
+    acl: Acl,
+    scope: Scope,
+}
+
+impl AclLayer {
+    /// Only clients in `acl` may query
+    pub fn queries(acl: Acl) -> Self {
+        Self {
+            acl,
+            scope: Scope::Queries,
+        }
+    }
+
+    /// Only clients in `acl` may request zone transfers
+    pub fn transfers(acl: Acl) -> Self {
+        Self {
+            acl,
+            scope: Scope::Transfers,
+        }
+    }
+
+    /// Only clients in `acl` get the queries reaching this layer resolved
+    pub fn recursion(acl: Acl) -> Self {
+        Self {
+            acl,
+            scope: Scope::Recursion,
+        }
+    }
+
+    fn refuses(&self, query: &DnsMessage, ctx: &RequestCtx) -> bool {
+        if self.scope == Scope::Transfers {
+            let transfer = query
+                .questions()
+                .any(|question| matches!(question.qtype(), rtype::AXFR | rtype::IXFR));
+            if !transfer {
+                return false;
+            }
+        }
+        !self.acl.contains(ctx.client().ip())
+    }
+}
+
+impl Layer for AclLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            if self.refuses(&query, &ctx) {
+                return error_response(&query, rcode::REFUSED);
+            }
+            next.run(query, ctx).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::cache::CacheLayer;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    #[test]
+    fn test_cidr() {
+        let lan: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(lan.contains([192, 168, 7, 1].into()));
+        assert!(!lan.contains([192, 169, 0, 1].into()));
+        assert!(lan.contains("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!lan.contains("2001:db8::1".parse().unwrap()));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+        let host: Cidr = "::1".parse().unwrap();
+        assert_eq!(host.to_string(), "::1/128");
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains([203, 0, 113, 9].into()));
+
+        for invalid in ["10.0.0.0/33", "10.0.0/8", "example.com", "::/129"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layers() {
+        let lan = Acl::new().allow("10.0.0.0/8".parse().unwrap());
+        let admin = Acl::new().allow("10.0.0.1".parse().unwrap());
+        let pipeline = Pipeline::new(DefaultHandler)
+            .layer(AclLayer::queries(lan))
+            .layer(AclLayer::transfers(admin));
+        let ask = |client: [u8; 4], qtype: u16| {
+            let ctx = RequestCtx::new(SocketAddr::from((client, 5353)), Transport::Tcp);
+            let query = DnsMessage::query(1, "codecrafters.io", qtype);
+            async { pipeline.handle(query, ctx).await.rcode() }
+        };
+
+        assert_eq!(ask([10, 1, 2, 3], rtype::A).await, rcode::NOERROR);
+        assert_eq!(ask([192, 0, 2, 1], rtype::A).await, rcode::REFUSED);
+        assert_eq!(ask([10, 1, 2, 3], rtype::AXFR).await, rcode::REFUSED);
+        assert_ne!(ask([10, 0, 0, 1], rtype::AXFR).await, rcode::REFUSED);
+    }
+
+    #[tokio::test]
+    async fn test_recursion_above_cache() {
+        let local = Acl::new().allow("127.0.0.1".parse().unwrap());
+        let pipeline = Pipeline::new(DefaultHandler)
+            .layer(AclLayer::recursion(local))
+            .layer(CacheLayer::new(10));
+        let ask = |client: [u8; 4]| {
+            let ctx = RequestCtx::new(SocketAddr::from((client, 5353)), Transport::Udp);
+            let query = DnsMessage::query(1, "codecrafters.io", rtype::A);
+            async { pipeline.handle(query, ctx).await.rcode() }
+        };
+
+        assert_eq!(ask([127, 0, 0, 1]).await, rcode::NOERROR);
+        // cached by now, and still refused
+        assert_eq!(ask([192, 0, 2, 1]).await, rcode::REFUSED);
+    }
+}
@@ -0,0 +1,406 @@
+//! ACME DNS-01 challenges published over an HTTP API
+//!
+//! Certificate clients (certbot, lego, acme.sh) prove control of a domain by publishing a TXT
+//! record at `_acme-challenge.<domain>`. [`serve_api`] lets them create those records in
+//! [`Challenges`], which [`AcmeLayer`] serves until they expire. Two APIs are offered:
+//!
+//! - `POST /txt` and `DELETE /txt` with `Authorization: Bearer <token>` and a JSON body
+//!   `{"name": "_acme-challenge.example.com", "txt": "..."}`
+//! - the acme-dns API: `POST /register` creates an account with its own random subdomain under
+//!   the challenge zone, to which `_acme-challenge.<domain>` is then CNAMEd, and `POST /update`
+//!   with `X-Api-User`/`X-Api-Key` headers and `{"subdomain": "...", "txt": "..."}` sets it
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpListener;
+
+use crate::dns::{class, rtype, DnsLabels, DnsMessage, DnsRecord, MessageBuilder};
+use crate::error;
+use crate::handler::RequestCtx;
+use crate::http::{self, Request};
+use crate::info;
+use crate::json::Json;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::rdata::RData;
+
+/// Label every challenge name published through the token API starts with
+const CHALLENGE_LABEL: &[u8] = b"_acme-challenge";
+
+/// TXT values kept per acme-dns subdomain: one for the domain and one for its wildcard
+const VALUES_PER_SUBDOMAIN: usize = 2;
+
+#[derive(Debug)]
+struct Account {
+    password: String,
+    subdomain: String,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    records: HashMap<DnsLabels, Vec<(String, Instant)>>,
+    accounts: HashMap<String, Account>,
+}
+
+/// Published challenge records and acme-dns accounts
+#[derive(Debug)]
+pub struct Challenges {
+    zone: DnsLabels,
+    token: String,
+    lifetime: Duration,
+    state: Mutex<State>,
+}
+
+impl Challenges {
+    /// Challenges for the token API authenticated by `token`, with acme-dns subdomains under
+    /// `zone`
+    pub fn new(zone: DnsLabels, token: impl Into<String>) -> Self {
+        Self {
+            zone,
+            token: token.into(),
+            lifetime: Duration::from_secs(600),
+            state: Mutex::default(),
+        }
+    }
+
+    /// How long records are served after being set, 10 minutes by default
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Live TXT values at `name`
+    pub fn txt(&self, name: &DnsLabels) -> Vec<String> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        state
+            .records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|(_, expires)| *expires > now)
+            .map(|(txt, _)| txt.clone())
+            .collect()
+    }
+
+    /// Adds `txt` at `name`, keeping at most `keep` values there
+    fn set(&self, name: DnsLabels, txt: &str, keep: usize) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.records.retain(|_, values| {
+            values.retain(|(_, expires)| *expires > now);
+            !values.is_empty()
+        });
+        let values = state.records.entry(name).or_default();
+        values.retain(|(value, _)| value != txt);
+        values.push((txt.to_string(), now + self.lifetime));
+        let excess = values.len().saturating_sub(keep);
+        values.drain(..excess);
+    }
+
+    fn remove(&self, name: &DnsLabels, txt: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(values) = state.records.get_mut(name) {
+            values.retain(|(value, _)| value != txt);
+        }
+    }
+
+    /// Status and JSON body answering `request`
+    fn handle(&self, request: &Request) -> (u16, String) {
+        let body = std::str::from_utf8(request.body())
+            .ok()
+            .and_then(Json::parse);
+        match (request.method(), request.path()) {
+            ("POST", "/register") => self.register(),
+            ("POST", "/update") => self.update(request, body.as_ref()),
+            (method @ ("POST" | "DELETE"), "/txt") => {
+                let authorized = request
+                    .header("authorization")
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .is_some_and(|token| constant_time_eq(token, &self.token));
+                if !authorized {
+                    return error(401, "unauthorized");
+                }
+                let Some((name, txt)) = body.as_ref().and_then(|body| {
+                    let name: DnsLabels = body.get("name")?.as_str()?.parse().ok()?;
+                    let txt = body.get("txt")?.as_str()?;
+                    Some((name, txt))
+                }) else {
+                    return error(400, "bad_request");
+                };
+                let is_challenge = name
+                    .labels()
+                    .next()
+                    .is_some_and(|label| label.eq_ignore_ascii_case(CHALLENGE_LABEL));
+                if !is_challenge || !valid_txt(txt) {
+                    return error(400, "bad_request");
+                }
+                if method == "POST" {
+                    self.set(name, txt, usize::MAX);
+                } else {
+                    self.remove(&name, txt);
+                }
+                (200, format!("{{\"txt\":\"{txt}\"}}"))
+            }
+            _ => error(404, "not_found"),
+        }
+    }
+
+    fn register(&self) -> (u16, String) {
+        let username = random_hex(16);
+        let password = random_hex(20);
+        let subdomain = random_hex(16);
+        let fulldomain = format!("{subdomain}.{}", self.zone);
+        let body = format!(
+            "{{\"username\":\"{username}\",\"password\":\"{password}\",\
+             \"fulldomain\":\"{fulldomain}\",\"subdomain\":\"{subdomain}\",\"allowfrom\":[]}}"
+        );
+        let account = Account {
+            password,
+            subdomain,
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .accounts
+            .insert(username, account);
+        (201, body)
+    }
+
+    fn update(&self, request: &Request, body: Option<&Json>) -> (u16, String) {
+        let authorized = {
+            let state = self.state.lock().unwrap();
+            let account = request
+                .header("x-api-user")
+                .and_then(|user| state.accounts.get(user));
+            match (account, request.header("x-api-key")) {
+                (Some(account), Some(key)) if constant_time_eq(key, &account.password) => {
+                    Some(account.subdomain.clone())
+                }
+                _ => None,
+            }
+        };
+        let Some(subdomain) = authorized else {
+            return error(401, "forbidden");
+        };
+        let Some((asked, txt)) = body
+            .and_then(|body| Some((body.get("subdomain")?.as_str()?, body.get("txt")?.as_str()?)))
+        else {
+            return error(400, "bad_request");
+        };
+        if asked != subdomain {
+            return error(401, "forbidden");
+        }
+        if !valid_txt(txt) {
+            return error(400, "bad_txt");
+        }
+        let Ok(name) = format!("{subdomain}.{}", self.zone).parse() else {
+            return error(400, "bad_subdomain");
+        };
+        self.set(name, txt, VALUES_PER_SUBDOMAIN);
+        (200, format!("{{\"txt\":\"{txt}\"}}"))
+    }
+}
+
+fn error(status: u16, message: &str) -> (u16, String) {
+    (status, format!("{{\"error\":\"{message}\"}}"))
+}
+
+/// Challenge tokens are base64url digests, so anything needing escapes is refused
+fn valid_txt(txt: &str) -> bool {
+    !txt.is_empty()
+        && txt.len() <= 255
+        && txt
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b'"' && b != b'\\')
+}
+
+fn random_hex(bytes: usize) -> String {
+    (0..bytes)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Serves the challenge API on `listener` until the task is dropped
+pub async fn serve_api(listener: TcpListener, challenges: Arc<Challenges>) {
+    loop {
+        let (mut stream, addr): (_, SocketAddr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("failed to accept API connection with {err}");
+                continue;
+            }
+        };
+        let challenges = challenges.clone();
+        tokio::spawn(async move {
+            let (status, body) = match http::read_request(&mut stream).await {
+                Ok(request) => {
+                    let (status, body) = challenges.handle(&request);
+                    info!(
+                        "ACME API {} {} from {addr}: {status}",
+                        request.method(),
+                        request.path()
+                    );
+                    (status, body)
+                }
+                Err(_) => error(400, "bad_request"),
+            };
+            let _ = http::write_response(
+                &mut stream,
+                status,
+                "application/json",
+                &[],
+                body.as_bytes(),
+            )
+            .await;
+        });
+    }
+}
+
+/// Answers TXT questions for published challenges
+pub struct AcmeLayer {
+    challenges: Arc<Challenges>,
+    ttl: u32,
+}
+
+impl AcmeLayer {
+    pub fn new(challenges: Arc<Challenges>) -> Self {
+        Self { challenges, ttl: 1 }
+    }
+
+    /// TTL of the records served, 1s by default so retried validations see new values
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn answer(&self, query: &DnsMessage) -> Option<DnsMessage> {
+        let mut questions = query.questions();
+        let question = questions.next()?;
+        if questions.next().is_some() || question.qclass() != class::IN {
+            return None;
+        }
+        let values = self.challenges.txt(question.qname());
+        if values.is_empty() {
+            return None;
+        }
+        let mut response = MessageBuilder::response_to(query)
+            .authoritative(true)
+            .add_question(question.clone());
+        if question.qtype() == rtype::TXT {
+            for txt in values {
+                let name = question.qname().clone();
+                let record = DnsRecord::with_rdata(name, self.ttl, RData::txt(txt));
+                response = response.add_answer(record);
+            }
+        }
+        Some(response.build())
+    }
+}
+
+impl Layer for AcmeLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            match self.answer(&query) {
+                Some(response) => response,
+                None => next.run(query, ctx).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    async fn request(raw: String) -> Request {
+        http::read_request(raw.as_bytes()).await.unwrap()
+    }
+
+    fn post(path: &str, headers: &str, body: &str) -> String {
+        format!(
+            "POST {path} HTTP/1.1\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_token_api() {
+        let challenges = Arc::new(Challenges::new("acme.example.net".into(), "secret"));
+        let pipeline = Pipeline::new(DefaultHandler).layer(AcmeLayer::new(challenges.clone()));
+        let body = r#"{"name": "_acme-challenge.example.com", "txt": "abc-123_x"}"#;
+
+        let denied = request(post("/txt", "Authorization: Bearer wrong\r\n", body)).await;
+        assert_eq!(challenges.handle(&denied).0, 401);
+        let set = request(post("/txt", "Authorization: Bearer secret\r\n", body)).await;
+        assert_eq!(challenges.handle(&set).0, 200);
+
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+        let query = DnsMessage::query(1, "_acme-challenge.example.com", rtype::TXT);
+        let response = pipeline.handle(query, ctx).await;
+        assert!(response.header().authoritative());
+        assert_eq!(response.answers().next().unwrap().data(), b"\x09abc-123_x");
+
+        let remove = request(
+            post("/txt", "Authorization: Bearer secret\r\n", body).replacen("POST", "DELETE", 1),
+        )
+        .await;
+        assert_eq!(challenges.handle(&remove).0, 200);
+        assert!(challenges
+            .txt(&"_acme-challenge.example.com".into())
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_acme_dns_api() {
+        let challenges =
+            Challenges::new("acme.example.net".into(), "secret").lifetime(Duration::from_secs(60));
+        let (status, body) = challenges.handle(&request(post("/register", "", "")).await);
+        assert_eq!(status, 201);
+        let account = Json::parse(&body).unwrap();
+        let field = |name: &str| account.get(name).unwrap().as_str().unwrap().to_string();
+
+        let headers = format!(
+            "X-Api-User: {}\r\nX-Api-Key: {}\r\n",
+            field("username"),
+            field("password")
+        );
+        for txt in ["one", "two", "three"] {
+            let body = format!(
+                r#"{{"subdomain": "{}", "txt": "{txt}"}}"#,
+                field("subdomain")
+            );
+            let update = request(post("/update", &headers, &body)).await;
+            assert_eq!(challenges.handle(&update).0, 200);
+        }
+        // only the two latest values are kept
+        let name = field("fulldomain").parse().unwrap();
+        assert_eq!(challenges.txt(&name), ["two", "three"]);
+
+        let body = format!(r#"{{"subdomain": "{}", "txt": "x"}}"#, field("subdomain"));
+        let forged = request(post(
+            "/update",
+            "X-Api-User: nobody\r\nX-Api-Key: x\r\n",
+            &body,
+        ))
+        .await;
+        assert_eq!(challenges.handle(&forged).0, 401);
+    }
+}
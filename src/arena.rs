@@ -0,0 +1,123 @@
+//! Per-thread recycling of the allocations behind parsed messages
+
+use std::cell::RefCell;
+
+use crate::dns::{DnsMessage, DnsQuestion, DnsRecord};
+
+/// Idle buffers kept per kind before further ones are freed
+const MAX_FREE: usize = 256;
+
+thread_local! {
+    static LOCAL: RefCell<Arena> = RefCell::new(Arena::new(MAX_FREE));
+}
+
+/// Runs `f` with the calling thread's arena
+pub fn with_local<R>(f: impl FnOnce(&mut Arena) -> R) -> R {
+    LOCAL.with(|arena| f(&mut arena.borrow_mut()))
+}
+
+/// Free lists for the name, record data and section vectors of a message.
+///
+/// Parsing with [`crate::dns::DnsMessageRef::to_owned_in`] takes its allocations from here and
+/// [`Arena::reclaim`] hands them back once a message is done with, so a steady stream of queries
+/// stops hitting the allocator.
+#[derive(Debug, Default)]
+pub struct Arena {
+    bytes: Vec<Vec<u8>>,
+    questions: Vec<Vec<DnsQuestion>>,
+    records: Vec<Vec<DnsRecord>>,
+    max_free: usize,
+}
+
+impl Arena {
+    /// Arena keeping at most `max_free` idle buffers of each kind
+    pub fn new(max_free: usize) -> Self {
+        Self {
+            max_free,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the allocations of `msg` to the arena
+    pub fn reclaim(&mut self, msg: DnsMessage) {
+        msg.recycle(self);
+    }
+
+    /// Number of idle byte buffers
+    pub fn free_bytes(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub(crate) fn copy(&mut self, src: &[u8]) -> Vec<u8> {
+        let mut bytes = self.bytes.pop().unwrap_or_default();
+        bytes.extend_from_slice(src);
+        bytes
+    }
+
+    pub(crate) fn questions(&mut self) -> Vec<DnsQuestion> {
+        self.questions.pop().unwrap_or_default()
+    }
+
+    pub(crate) fn records(&mut self) -> Vec<DnsRecord> {
+        self.records.pop().unwrap_or_default()
+    }
+
+    pub(crate) fn put_bytes(&mut self, mut bytes: Vec<u8>) {
+        if self.bytes.len() < self.max_free {
+            bytes.clear();
+            self.bytes.push(bytes);
+        }
+    }
+
+    /// `questions` must already be emptied, its names having gone through [`Arena::put_bytes`]
+    pub(crate) fn put_questions(&mut self, questions: Vec<DnsQuestion>) {
+        debug_assert!(questions.is_empty());
+        if self.questions.len() < self.max_free {
+            self.questions.push(questions);
+        }
+    }
+
+    /// `records` must already be emptied, their buffers having gone through [`Arena::put_bytes`]
+    pub(crate) fn put_records(&mut self, records: Vec<DnsRecord>) {
+        debug_assert!(records.is_empty());
+        if self.records.len() < self.max_free {
+            self.records.push(records);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns::{DnsMessageRef, ToBytes};
+
+    #[test]
+    fn test_reuses_reclaimed_buffers() {
+        let bytes = crate::dns::response(&DnsMessage::query(1, "codecrafters.io", 1)).to_bytes();
+        let mut arena = Arena::new(8);
+
+        let msg = DnsMessageRef::from_bytes(&bytes)
+            .unwrap()
+            .to_owned_in(&mut arena);
+        assert_eq!(msg, DnsMessage::from_bytes(&bytes).unwrap());
+
+        // question name, answer name and answer data
+        arena.reclaim(msg);
+        assert_eq!(arena.free_bytes(), 3);
+
+        let msg = DnsMessageRef::from_bytes(&bytes)
+            .unwrap()
+            .to_owned_in(&mut arena);
+        assert_eq!(arena.free_bytes(), 0);
+        assert_eq!(msg, DnsMessage::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_max_free() {
+        let mut arena = Arena::new(1);
+        arena.put_bytes(vec![1]);
+        arena.put_bytes(vec![2]);
+        assert_eq!(arena.free_bytes(), 1);
+        assert_eq!(arena.copy(b"ab"), b"ab");
+    }
+}
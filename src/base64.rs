@@ -0,0 +1,73 @@
+//! Base64 with the URL and filename safe alphabet and no padding (RFC 4648 section 5), as used
+//! by DNS stamps and DoH `GET` requests, and decoding of the standard alphabet (section 4) that
+//! TSIG secrets are written in
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+const STANDARD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode_url(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Decodes `text`, tolerating trailing `=` padding; `None` if it is not base64url
+pub fn decode_url(text: &str) -> Option<Vec<u8>> {
+    decode_with(text, ALPHABET)
+}
+
+/// Decodes `text` in the standard alphabet, padded or not
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    decode_with(text, STANDARD)
+}
+
+fn decode_with(text: &str, alphabet: &[u8; 64]) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    if text.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = alphabet.iter().position(|a| a == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            out.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for (bytes, text) in [
+            (&b""[..], ""),
+            (b"f", "Zg"),
+            (b"fo", "Zm8"),
+            (b"foo", "Zm9v"),
+            (b"\xfb\xff", "-_8"),
+        ] {
+            assert_eq!(encode_url(bytes), text);
+            assert_eq!(decode_url(text).unwrap(), bytes);
+        }
+        assert_eq!(decode_url("Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_url("Zm9v+"), None);
+        assert_eq!(decode_url("Z"), None);
+        assert_eq!(decode("+/8=").unwrap(), b"\xfb\xff");
+        assert_eq!(decode("-_8"), None);
+    }
+}
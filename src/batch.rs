@@ -0,0 +1,279 @@
+//! Batched UDP receive and send
+//!
+//! On 64-bit Linux a batch is a single `recvmmsg`/`sendmmsg` syscall. Elsewhere the batch is
+//! built by draining the socket with non-blocking calls, which saves wake-ups but not syscalls.
+//! Both functions return `WouldBlock` when nothing could be done, and must be called after the
+//! socket reported readiness (`readable()`/`writable()`).
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+/// Receives up to `bufs.len()` datagrams, filling `bufs` in order.
+///
+/// Returns how many were received along with each one's length and source in `meta`.
+pub fn recv_batch<B: AsMut<[u8]>>(
+    sock: &UdpSocket,
+    bufs: &mut [B],
+    meta: &mut Vec<(usize, SocketAddr)>,
+) -> io::Result<usize> {
+    meta.clear();
+    sock.try_io(Interest::READABLE, || sys::recv_batch(sock, bufs, meta))
+}
+
+/// Sends `packets` in order, returning how many were sent
+pub fn send_batch(sock: &UdpSocket, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+    sock.try_io(Interest::WRITABLE, || sys::send_batch(sock, packets))
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+mod sys {
+    use std::ffi::{c_int, c_uint, c_void};
+    use std::io;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+
+    use tokio::net::UdpSocket;
+
+    const AF_INET: u16 = 2;
+    const AF_INET6: u16 = 10;
+
+    // Kernel layouts of the structures; libc's definitions match them on 64-bit Linux.
+    #[repr(C)]
+    struct IoVec {
+        base: *mut c_void,
+        len: usize,
+    }
+
+    #[repr(C)]
+    struct MsgHdr {
+        name: *mut c_void,
+        name_len: u32,
+        iov: *mut IoVec,
+        iov_len: usize,
+        control: *mut c_void,
+        control_len: usize,
+        flags: c_int,
+    }
+
+    #[repr(C)]
+    struct MMsgHdr {
+        hdr: MsgHdr,
+        len: c_uint,
+    }
+
+    #[repr(C, align(8))]
+    #[derive(Clone, Copy)]
+    struct SockAddrStorage([u8; 128]);
+
+    extern "C" {
+        fn recvmmsg(
+            fd: c_int,
+            msgvec: *mut MMsgHdr,
+            vlen: c_uint,
+            flags: c_int,
+            timeout: *mut c_void,
+        ) -> c_int;
+        fn sendmmsg(fd: c_int, msgvec: *mut MMsgHdr, vlen: c_uint, flags: c_int) -> c_int;
+    }
+
+    fn msg_hdr(name: &mut SockAddrStorage, name_len: u32, iov: &mut IoVec) -> MMsgHdr {
+        MMsgHdr {
+            hdr: MsgHdr {
+                name: name.0.as_mut_ptr().cast(),
+                name_len,
+                iov,
+                iov_len: 1,
+                control: ptr::null_mut(),
+                control_len: 0,
+                flags: 0,
+            },
+            len: 0,
+        }
+    }
+
+    pub fn recv_batch<B: AsMut<[u8]>>(
+        sock: &UdpSocket,
+        bufs: &mut [B],
+        meta: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<usize> {
+        let mut names = vec![SockAddrStorage([0; 128]); bufs.len()];
+        let mut iovs: Vec<IoVec> = bufs
+            .iter_mut()
+            .map(|buf| {
+                let buf = buf.as_mut();
+                IoVec {
+                    base: buf.as_mut_ptr().cast(),
+                    len: buf.len(),
+                }
+            })
+            .collect();
+        let mut msgs: Vec<MMsgHdr> = names
+            .iter_mut()
+            .zip(iovs.iter_mut())
+            .map(|(name, iov)| msg_hdr(name, 128, iov))
+            .collect();
+
+        // SAFETY: every pointer in `msgs` refers to a live buffer of the advertised length
+        let received = unsafe {
+            recvmmsg(
+                sock.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as c_uint,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (msg, name) in msgs.iter().zip(&names).take(received as usize) {
+            let addr = decode_addr(name).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unknown address family")
+            })?;
+            meta.push((msg.len as usize, addr));
+        }
+        Ok(received as usize)
+    }
+
+    pub fn send_batch(sock: &UdpSocket, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let mut names: Vec<(SockAddrStorage, u32)> =
+            packets.iter().map(|(_, addr)| encode_addr(addr)).collect();
+        let mut iovs: Vec<IoVec> = packets
+            .iter()
+            .map(|(bytes, _)| IoVec {
+                base: bytes.as_ptr() as *mut c_void,
+                len: bytes.len(),
+            })
+            .collect();
+        let mut msgs: Vec<MMsgHdr> = names
+            .iter_mut()
+            .zip(iovs.iter_mut())
+            .map(|((name, len), iov)| msg_hdr(name, *len, iov))
+            .collect();
+
+        // SAFETY: every pointer in `msgs` refers to a live buffer of the advertised length,
+        // and sendmmsg does not write through the iovec bases
+        let sent =
+            unsafe { sendmmsg(sock.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as c_uint, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    fn decode_addr(name: &SockAddrStorage) -> Option<SocketAddr> {
+        let raw = &name.0;
+        let port = u16::from_be_bytes([raw[2], raw[3]]);
+        match u16::from_ne_bytes([raw[0], raw[1]]) {
+            AF_INET => {
+                let ip: [u8; 4] = raw[4..8].try_into().ok()?;
+                Some(SocketAddrV4::new(Ipv4Addr::from(ip), port).into())
+            }
+            AF_INET6 => {
+                let flowinfo = u32::from_ne_bytes(raw[4..8].try_into().ok()?);
+                let ip: [u8; 16] = raw[8..24].try_into().ok()?;
+                let scope_id = u32::from_ne_bytes(raw[24..28].try_into().ok()?);
+                Some(SocketAddrV6::new(Ipv6Addr::from(ip), port, flowinfo, scope_id).into())
+            }
+            _ => None,
+        }
+    }
+
+    fn encode_addr(addr: &SocketAddr) -> (SockAddrStorage, u32) {
+        let mut name = SockAddrStorage([0; 128]);
+        let raw = &mut name.0;
+        raw[2..4].copy_from_slice(&addr.port().to_be_bytes());
+        match addr {
+            SocketAddr::V4(addr) => {
+                raw[0..2].copy_from_slice(&AF_INET.to_ne_bytes());
+                raw[4..8].copy_from_slice(&addr.ip().octets());
+                (name, 16)
+            }
+            SocketAddr::V6(addr) => {
+                raw[0..2].copy_from_slice(&AF_INET6.to_ne_bytes());
+                raw[4..8].copy_from_slice(&addr.flowinfo().to_ne_bytes());
+                raw[8..24].copy_from_slice(&addr.ip().octets());
+                raw[24..28].copy_from_slice(&addr.scope_id().to_ne_bytes());
+                (name, 28)
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+mod sys {
+    use std::io;
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+
+    pub fn recv_batch<B: AsMut<[u8]>>(
+        sock: &UdpSocket,
+        bufs: &mut [B],
+        meta: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<usize> {
+        for buf in bufs.iter_mut() {
+            match sock.try_recv_from(buf.as_mut()) {
+                Ok(received) => meta.push(received),
+                Err(err) if meta.is_empty() => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(meta.len())
+    }
+
+    pub fn send_batch(sock: &UdpSocket, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        for (sent, (bytes, addr)) in packets.iter().enumerate() {
+            match sock.try_send_to(bytes, *addr) {
+                Ok(_) => {}
+                Err(err) if sent == 0 => return Err(err),
+                Err(_) => return Ok(sent),
+            }
+        }
+        Ok(packets.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batch_round_trip() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b_addr = b.local_addr().unwrap();
+
+        let packets: [(&[u8], SocketAddr); 3] =
+            [(b"one", b_addr), (b"two", b_addr), (b"three", b_addr)];
+        let mut sent = 0;
+        while sent < packets.len() {
+            a.writable().await.unwrap();
+            if let Ok(n) = send_batch(&a, &packets[sent..]) {
+                sent += n;
+            }
+        }
+
+        let mut bufs = vec![vec![0u8; 64]; 8];
+        let mut meta = Vec::new();
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            b.readable().await.unwrap();
+            if let Ok(n) = recv_batch(&b, &mut bufs, &mut meta) {
+                for (buf, (len, from)) in bufs.iter().zip(&meta).take(n) {
+                    assert_eq!(*from, a.local_addr().unwrap());
+                    received.push(buf[..*len].to_vec());
+                }
+            }
+        }
+        assert_eq!(
+            received,
+            [b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+    }
+}
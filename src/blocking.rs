@@ -0,0 +1,163 @@
+//! Blocking `std::net` UDP and TCP servers, for builds that do not want a tokio runtime
+//!
+//! They drive the same [`RequestHandler`] as the async server with a minimal executor, so the
+//! handler must not rely on tokio itself (timers, sockets) to make progress.
+
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use crate::dns::{header_response, rcode, DnsMessage, ToBytes, MAX_UDP_PAYLOAD};
+use crate::error;
+use crate::handler::{RequestCtx, RequestHandler, Transport};
+use crate::warn;
+
+/// Serves UDP queries on the calling thread, one at a time
+pub fn run_udp<H: RequestHandler>(sock: UdpSocket, handler: H) -> io::Result<()> {
+    // one byte spare so datagrams clipped to the buffer can be recognized
+    let mut buf = [0u8; MAX_UDP_PAYLOAD + 1];
+    let mut out = Vec::with_capacity(512);
+    loop {
+        let (len, addr) = sock.recv_from(&mut buf)?;
+        let response = if len > MAX_UDP_PAYLOAD {
+            warn!("datagram from {addr} exceeds {MAX_UDP_PAYLOAD} bytes");
+            header_response(&buf, rcode::FORMERR)
+        } else {
+            handle(&handler, &buf[..len], addr, Transport::Udp)
+        };
+        let Some(response) = response else {
+            continue;
+        };
+
+        out.clear();
+        response.write_to(&mut out);
+        if let Err(err) = sock.send_to(&out, addr) {
+            error!("failed to write to socket with {err}");
+        }
+    }
+}
+
+/// Serves TCP connections, one thread per connection
+pub fn run_tcp<H: RequestHandler>(listener: TcpListener, handler: H) -> io::Result<()> {
+    let handler = Arc::new(handler);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("failed to accept connection with {err}");
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve_connection(stream, handler.as_ref()) {
+                error!("connection failed with {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection<H: RequestHandler>(mut stream: TcpStream, handler: &H) -> io::Result<()> {
+    let addr = stream.peer_addr()?;
+    let mut out = Vec::with_capacity(512);
+    loop {
+        let mut len = [0u8; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut msg)?;
+
+        let Some(response) = handle(handler, &msg, addr, Transport::Tcp) else {
+            return Ok(());
+        };
+        out.clear();
+        out.extend_from_slice(&(response.wire_len() as u16).to_be_bytes());
+        response.write_to(&mut out);
+        stream.write_all(&out)?;
+    }
+}
+
+fn handle<H: RequestHandler>(
+    handler: &H,
+    bytes: &[u8],
+    addr: SocketAddr,
+    transport: Transport,
+) -> Option<DnsMessage> {
+    let req = match DnsMessage::from_bytes(bytes) {
+        Ok(req) => req,
+        Err(err) => {
+            error!("failed to parse - '{err}'");
+            return header_response(bytes, rcode::FORMERR);
+        }
+    };
+    let max_len = match transport {
+        Transport::Udp => req.max_udp_response(),
+        Transport::Tcp | Transport::Https => u16::MAX as usize,
+    };
+    let response = block_on(handler.handle(req, RequestCtx::new(addr, transport)));
+    Some(response.truncate(max_len))
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` on the current thread, parking between wake-ups
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::DefaultHandler;
+
+    #[test]
+    fn test_blocking_udp_and_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (udp_addr, tcp_addr) = (udp.local_addr().unwrap(), tcp.local_addr().unwrap());
+        thread::spawn(move || run_udp(udp, DefaultHandler));
+        thread::spawn(move || run_tcp(tcp, DefaultHandler));
+
+        let query = DnsMessage::query(11, "codecrafters.io", 1);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&query.to_bytes(), udp_addr).unwrap();
+        let mut buf = [0u8; 512];
+        let (len, _) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(DnsMessage::from_bytes(&buf[..len]).unwrap().id(), 11);
+
+        let mut stream = TcpStream::connect(tcp_addr).unwrap();
+        let bytes = query.to_bytes();
+        stream
+            .write_all(&(bytes.len() as u16).to_be_bytes())
+            .unwrap();
+        stream.write_all(&bytes).unwrap();
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut msg).unwrap();
+        assert_eq!(DnsMessage::from_bytes(&msg).unwrap().id(), 11);
+    }
+}
@@ -0,0 +1,335 @@
+//! Blocking ad, tracking and malware domains
+//!
+//! [`BlocklistLayer`] answers the queries for names on its lists, and for the names below them,
+//! instead of resolving them: with NXDOMAIN, or with a sinkhole address that leads nowhere (or
+//! to a page explaining the block). Lists are read in the formats they are published in, one
+//! entry per line:
+//!
+//! ```text
+//! # plain domains
+//! ads.example.com
+//! # hosts files, the address being ignored
+//! 0.0.0.0 tracker.example.net metrics.example.net
+//! # adblock filters, of which only whole-domain rules are understood
+//! ||malware.example.org^
+//! ```
+//!
+//! Blocked queries are counted, see [`BlocklistLayer::blocked`]. Files are re-read when they
+//! change, see [`BlocklistLayer::watch`].
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use crate::dns::{
+    class, error_response, rcode, rtype, DnsLabels, DnsMessage, DnsRecord, MessageBuilder,
+};
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::{debug, info, warn};
+
+/// Names hosts-format lists carry for the machine itself, never blocked
+const LOCAL_NAMES: [&str; 6] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// Blocked domains, each with everything below it
+#[derive(Debug, Default, Clone)]
+pub struct Blocklist {
+    domains: HashSet<DnsLabels>,
+}
+
+impl Blocklist {
+    /// Parses a list, skipping comments and lines it does not understand
+    pub fn parse(text: &str) -> Blocklist {
+        let mut blocklist = Blocklist::default();
+        blocklist.extend(text);
+        blocklist
+    }
+
+    /// Adds the domains of another list
+    pub fn extend(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.starts_with('!') {
+                continue;
+            }
+            if let Some(rule) = line.strip_prefix("||") {
+                if let Some(Ok(domain)) = rule.strip_suffix('^').map(str::parse) {
+                    self.insert(domain);
+                }
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let names = match fields.split_first() {
+                Some((first, names)) if first.parse::<IpAddr>().is_ok() => names,
+                _ => &fields[..],
+            };
+            for name in names {
+                if LOCAL_NAMES
+                    .iter()
+                    .any(|local| local.eq_ignore_ascii_case(name))
+                {
+                    continue;
+                }
+                if let Ok(domain) = name.parse() {
+                    self.insert(domain);
+                }
+            }
+        }
+    }
+
+    pub fn insert(&mut self, domain: DnsLabels) {
+        if !domain.is_root() {
+            self.domains.insert(domain);
+        }
+    }
+
+    /// Whether `name` is a listed domain or below one
+    pub fn contains(&self, name: &DnsLabels) -> bool {
+        let labels: Vec<&[u8]> = name.labels().collect();
+        (0..labels.len()).any(|skip| {
+            DnsLabels::new(&labels[skip..]).is_ok_and(|domain| self.domains.contains(&domain))
+        })
+    }
+
+    /// Listed domains, in no particular order
+    pub fn domains(&self) -> impl Iterator<Item = &DnsLabels> {
+        self.domains.iter()
+    }
+
+    /// Number of listed domains
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+}
+
+/// Answers queries for blocked names locally; clones share the lists and the count
+#[derive(Clone)]
+pub struct BlocklistLayer {
+    paths: Vec<PathBuf>,
+    sinkhole: Vec<IpAddr>,
+    ttl: u32,
+    table: Arc<RwLock<Arc<Blocklist>>>,
+    blocked: Arc<AtomicU64>,
+}
+
+impl BlocklistLayer {
+    /// Layer blocking the domains listed in `paths`, read now; unreadable files are logged and
+    /// skipped. Blocked names get NXDOMAIN unless a [`sinkhole`](Self::sinkhole) is set.
+    pub fn new(paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        let paths: Vec<PathBuf> = paths.into_iter().map(Into::into).collect();
+        let blocklist = load(&paths);
+        Self {
+            paths,
+            sinkhole: Vec::new(),
+            ttl: 60,
+            table: Arc::new(RwLock::new(Arc::new(blocklist))),
+            blocked: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Answers A and AAAA questions for blocked names with the addresses of `sinkhole` of the
+    /// family asked, and other questions with no records
+    pub fn sinkhole(mut self, sinkhole: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.sinkhole = sinkhole.into_iter().collect();
+        self
+    }
+
+    /// TTL of the sinkhole records, 60 seconds by default
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Re-reads the lists whenever one of their modification times changes, checking every
+    /// `interval` on a background task that ends with the layer. Must be called inside a tokio
+    /// runtime.
+    pub fn watch(self, interval: Duration) -> Self {
+        let table = Arc::downgrade(&self.table);
+        tokio::spawn(watch(self.paths.clone(), table, interval));
+        self
+    }
+
+    /// Addresses answered for blocked names; none when they get NXDOMAIN
+    pub fn sinkholes(&self) -> &[IpAddr] {
+        &self.sinkhole
+    }
+
+    pub fn blocklist(&self) -> Arc<Blocklist> {
+        self.table.read().unwrap().clone()
+    }
+
+    /// Queries blocked since the start
+    pub fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    /// Answer to `query`, if it asks about a blocked name
+    fn answer(&self, query: &DnsMessage) -> Option<DnsMessage> {
+        let question = query.questions().next()?;
+        let name = question.qname();
+        if question.qclass() != class::IN || !self.blocklist().contains(name) {
+            return None;
+        }
+        debug!("blocked {name}");
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+        if self.sinkhole.is_empty() {
+            return Some(error_response(query, rcode::NXDOMAIN));
+        }
+
+        let want_v4 = match question.qtype() {
+            rtype::A => Some(true),
+            rtype::AAAA => Some(false),
+            _ => None,
+        };
+        let response = self
+            .sinkhole
+            .iter()
+            .filter(|addr| want_v4 == Some(addr.is_ipv4()))
+            .map(|addr| DnsRecord::with_rdata(name.clone(), self.ttl, *addr))
+            .fold(
+                MessageBuilder::response_to(query).add_question(question.clone()),
+                MessageBuilder::add_answer,
+            );
+        Some(response.build())
+    }
+}
+
+impl Layer for BlocklistLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            match self.answer(&query) {
+                Some(response) => response,
+                None => next.run(query, ctx).await,
+            }
+        })
+    }
+}
+
+fn load(paths: &[PathBuf]) -> Blocklist {
+    let mut blocklist = Blocklist::default();
+    for path in paths {
+        match fs::read_to_string(path) {
+            Ok(text) => blocklist.extend(&text),
+            Err(err) => warn!("failed to read {} with {err}", path.display()),
+        }
+    }
+    blocklist
+}
+
+fn modified(paths: &[PathBuf]) -> Vec<io::Result<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path)?.modified())
+        .collect()
+}
+
+async fn watch(paths: Vec<PathBuf>, table: Weak<RwLock<Arc<Blocklist>>>, interval: Duration) {
+    let mut seen = modified(&paths);
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(table) = table.upgrade() else {
+            return;
+        };
+        let current = modified(&paths);
+        let changed = current
+            .iter()
+            .zip(&seen)
+            .any(|(now, before)| now.as_ref().ok() != before.as_ref().ok());
+        if changed {
+            let blocklist = load(&paths);
+            info!("reloaded blocklists, {} domains", blocklist.len());
+            *table.write().unwrap() = Arc::new(blocklist);
+            seen = current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    const LIST: &str = "\
+# a comment line
+ads.example.com
+0.0.0.0 Tracker.example.net   # trailing comment
+127.0.0.1 localhost
+||malware.example.org^
+! adblock comment
+||example.com/banner.png
+";
+
+    #[test]
+    fn test_parse() {
+        let blocklist = Blocklist::parse(LIST);
+        assert_eq!(blocklist.len(), 3);
+        assert!(blocklist.contains(&"ads.example.com".into()));
+        assert!(blocklist.contains(&"cdn.ADS.example.com".into()));
+        assert!(blocklist.contains(&"tracker.example.net".into()));
+        assert!(blocklist.contains(&"x.malware.example.org".into()));
+        assert!(!blocklist.contains(&"example.com".into()));
+        assert!(!blocklist.contains(&"bads.example.com".into()));
+        assert!(!blocklist.contains(&"localhost".into()));
+    }
+
+    async fn ask(pipeline: &Pipeline, name: &str, qtype: u16) -> DnsMessage {
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+        pipeline
+            .handle(DnsMessage::query(1, name, qtype), ctx)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let path = std::env::temp_dir().join(format!("blocklist-test-{}", std::process::id()));
+        fs::write(&path, LIST).unwrap();
+        let nxdomain = BlocklistLayer::new([&path]);
+        let sinkhole = BlocklistLayer::new([&path])
+            .sinkhole(["0.0.0.0".parse().unwrap(), "::".parse().unwrap()]);
+        fs::remove_file(&path).unwrap();
+
+        let pipeline = Pipeline::new(DefaultHandler).layer(nxdomain.clone());
+        let response = ask(&pipeline, "www.ads.example.com", rtype::A).await;
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+        let response = ask(&pipeline, "codecrafters.io", rtype::A).await;
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(nxdomain.blocked(), 1);
+
+        let pipeline = Pipeline::new(DefaultHandler).layer(sinkhole);
+        let response = ask(&pipeline, "tracker.example.net", rtype::AAAA).await;
+        let answers: Vec<_> = response.answers().collect();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            IpAddr::try_from(answers[0]).unwrap(),
+            "::".parse::<IpAddr>().unwrap()
+        );
+        let response = ask(&pipeline, "tracker.example.net", rtype::MX).await;
+        assert_eq!(response.rcode(), rcode::NOERROR);
+        assert_eq!(response.answers().len(), 0);
+    }
+}
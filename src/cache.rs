@@ -0,0 +1,355 @@
+//! Answer cache honoring record TTLs
+//!
+//! [`CacheLayer`] keeps the answers the rest of the pipeline (an upstream, the recursor, ...)
+//! produced, keyed by question, and answers repeated questions itself until the smallest TTL
+//! among the records runs out. TTLs in cached answers count down, so clients never hold a
+//! record longer than its owner intended.
+//!
+//! Negative answers (NXDOMAIN, and NOERROR without records of the type asked, "NODATA") are
+//! cached too (RFC 2308), so repeated lookups of missing names do not reach the upstream every
+//! time. They are kept as long as the SOA record of their authority section allows, up to
+//! [`CacheLayer::max_negative_ttl`]; those without an SOA are not cached.
+//!
+//! Unlike [`crate::response_cache`], which replays serialized responses in front of the whole
+//! pipeline, this layer sits where the answers come in and works on parsed records, so the
+//! layers before it still see every query. It spreads its entries over the same number of
+//! independently locked [`SHARDS`], and a full shard evicts its oldest entry, so neither
+//! lookups nor stores scan the cache.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dns::{rcode, DnsMessage, DnsQuestion, DnsRecord, MessageBuilder};
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::rdata::negative_ttl;
+use crate::response_cache::SHARDS;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    rcode: u8,
+    authoritative: bool,
+    recursion_available: bool,
+    records: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Entries of one shard, and their questions in the order they were stored
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<DnsQuestion, Entry>,
+    order: VecDeque<DnsQuestion>,
+}
+
+/// Layer answering from the answers it saw earlier, while their TTLs last
+#[derive(Debug)]
+pub struct CacheLayer {
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+    shard_capacity: usize,
+    max_ttl: Duration,
+    max_negative_ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheLayer {
+    /// Cache of at most `capacity` questions, rounded up to a multiple of [`SHARDS`]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            shard_capacity: capacity.div_ceil(SHARDS),
+            max_ttl: Duration::from_secs(86_400),
+            max_negative_ttl: Duration::from_secs(3 * 3600),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Longest an answer is kept whatever its TTLs say, one day by default
+    pub fn max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// Longest NXDOMAIN and NODATA answers are kept whatever their SOA says, 3 hours by
+    /// default as RFC 2308 suggests
+    pub fn max_negative_ttl(mut self, max_negative_ttl: Duration) -> Self {
+        self.max_negative_ttl = max_negative_ttl;
+        self
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        let lens = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len());
+        lens.sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, question: &DnsQuestion) -> &Mutex<Shard> {
+        let hash = self.hasher.hash_one(question) as usize;
+        &self.shards[hash % SHARDS]
+    }
+
+    /// Response to `query` from a live entry, its TTLs reduced by the time spent in the cache
+    fn lookup(
+        &self,
+        query: &DnsMessage,
+        question: &DnsQuestion,
+        now: Instant,
+    ) -> Option<DnsMessage> {
+        let entry = {
+            let shard = self.shard(question).lock().unwrap();
+            shard
+                .entries
+                .get(question)
+                .filter(|entry| entry.expires > now)?
+                .clone()
+        };
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let response = MessageBuilder::response_to(query)
+            .authoritative(entry.authoritative)
+            .recursion_available(entry.recursion_available)
+            .rcode(entry.rcode)
+            .add_question(question.clone());
+        let age = |record: DnsRecord| {
+            let ttl = record.ttl().saturating_sub(elapsed);
+            record.with_ttl(ttl)
+        };
+        let response = entry
+            .records
+            .into_iter()
+            .fold(response, |response, record| {
+                response.add_answer(age(record))
+            });
+        let response = entry
+            .authorities
+            .into_iter()
+            .fold(response, |response, record| {
+                response.add_authority(age(record))
+            });
+        Some(response.build())
+    }
+
+    fn store(&self, question: &DnsQuestion, response: &DnsMessage, now: Instant) {
+        let negative = match response.rcode() {
+            rcode::NXDOMAIN => true,
+            rcode::NOERROR => !response
+                .answers()
+                .any(|record| record.record_type() == question.qtype()),
+            _ => return,
+        };
+        // a CNAME chain before the missing name is cached along with it
+        let ttl = response.answers().map(DnsRecord::ttl).min();
+        let ttl = if negative {
+            let Some(soa) = response.authorities().find_map(negative_ttl) else {
+                return;
+            };
+            let ttl = ttl.map_or(soa, |ttl| ttl.min(soa));
+            Duration::from_secs(ttl.into()).min(self.max_negative_ttl)
+        } else {
+            match ttl {
+                Some(ttl) => Duration::from_secs(ttl.into()),
+                None => return,
+            }
+        };
+        let ttl = ttl.min(self.max_ttl);
+        if ttl.is_zero() {
+            return;
+        }
+        let entry = Entry {
+            rcode: response.rcode(),
+            authoritative: response.header().authoritative(),
+            recursion_available: response.header().recursion_available(),
+            records: response.answers().cloned().collect(),
+            authorities: if negative {
+                response.authorities().cloned().collect()
+            } else {
+                Vec::new()
+            },
+            stored: now,
+            expires: now + ttl,
+        };
+
+        if self.shard_capacity == 0 {
+            return;
+        }
+        let mut shard = self.shard(question).lock().unwrap();
+        let shard = &mut *shard;
+        if let Some(stored) = shard.entries.get_mut(question) {
+            *stored = entry;
+            return;
+        }
+        if shard.entries.len() >= self.shard_capacity {
+            if let Some(oldest) = shard.order.pop_front() {
+                shard.entries.remove(&oldest);
+            }
+        }
+        shard.order.push_back(question.clone());
+        shard.entries.insert(question.clone(), entry);
+    }
+}
+
+impl Layer for CacheLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let mut questions = query.questions();
+            let (Some(question), None) = (questions.next(), questions.next()) else {
+                return next.run(query, ctx).await;
+            };
+            let question = question.clone();
+            let now = ctx.received();
+            if let Some(response) = self.lookup(&query, &question, now) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return response;
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            let response = next.run(query, ctx).await;
+            self.store(&question, &response, now);
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::dns::{response, rtype};
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+    use crate::rdata::RData;
+
+    #[tokio::test]
+    async fn test_ttl_expiry() {
+        let pipeline = Pipeline::new(DefaultHandler).layer(CacheLayer::new(10));
+        let start = Instant::now();
+        let at = |secs| {
+            RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp)
+                .received_at(start + Duration::from_secs(secs))
+        };
+        let query = |id| DnsMessage::query(id, "Example.com", rtype::A);
+
+        let first = pipeline.handle(query(1), at(0)).await;
+        assert_eq!(first.answers().next().unwrap().ttl(), 60);
+
+        // DefaultHandler answers with TTL 60: served from the cache, counted down
+        let cached = pipeline.handle(query(2), at(45)).await;
+        assert_eq!(cached.id(), 2);
+        assert_eq!(cached.answers().next().unwrap().ttl(), 15);
+        assert_eq!(
+            cached.questions().next().unwrap().qname().to_string(),
+            "Example.com"
+        );
+
+        // expired, so resolved again from a full TTL
+        let fresh = pipeline.handle(query(3), at(61)).await;
+        assert_eq!(fresh.answers().next().unwrap().ttl(), 60);
+    }
+
+    #[test]
+    fn test_negative() {
+        let cache = CacheLayer::new(10).max_negative_ttl(Duration::from_secs(600));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let soa = |minimum| {
+            let rdata = RData::Soa {
+                mname: "ns1.example.com".into(),
+                rname: "hostmaster.example.com".into(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum,
+            };
+            DnsRecord::with_rdata("example.com".into(), 3600, rdata)
+        };
+        let query = DnsMessage::query(1, "example.com", rtype::AAAA);
+        let question = query.questions().next().unwrap().clone();
+        let negative = |rcode, minimum| {
+            MessageBuilder::response_to(&query)
+                .add_question(question.clone())
+                .add_authority(soa(minimum))
+                .rcode(rcode)
+                .build()
+        };
+
+        // NODATA kept for the SOA MINIMUM, the SOA counting down with it
+        cache.store(&question, &negative(rcode::NOERROR, 30), at(0));
+        let hit = cache.lookup(&query, &question, at(29)).unwrap();
+        assert_eq!((hit.rcode(), hit.answers().len()), (rcode::NOERROR, 0));
+        assert_eq!(hit.authorities().next().unwrap().ttl(), 3600 - 29);
+        assert!(cache.lookup(&query, &question, at(30)).is_none());
+
+        // NXDOMAIN capped by max_negative_ttl
+        cache.store(&question, &negative(rcode::NXDOMAIN, 86_400), at(0));
+        let hit = cache.lookup(&query, &question, at(599)).unwrap();
+        assert_eq!(hit.rcode(), rcode::NXDOMAIN);
+        assert!(cache.lookup(&query, &question, at(600)).is_none());
+
+        // without an SOA there is no telling how long the name stays missing
+        let bare = CacheLayer::new(10);
+        bare.store(&question, &response(&query), at(0));
+        assert!(bare.is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let cache = CacheLayer::new(SHARDS);
+        let response = response(&DnsMessage::query(1, "a.example", rtype::A));
+        let now = Instant::now();
+        for i in 0..1000 {
+            let question = DnsQuestion::new(format!("host{i}.example").as_str().into(), 1, 1);
+            cache.store(&question, &response, now);
+        }
+        assert_eq!(cache.len(), SHARDS);
+
+        // the newest entries stay
+        let query = DnsMessage::query(2, "host999.example", rtype::A);
+        let question = query.questions().next().unwrap().clone();
+        assert!(cache.lookup(&query, &question, now).is_some());
+    }
+
+    #[test]
+    fn test_keeps_authoritative() {
+        let cache = CacheLayer::new(10);
+        let query = DnsMessage::query(1, "www.example.com", rtype::A);
+        let question = query.questions().next().unwrap().clone();
+        let answer =
+            DnsRecord::with_rdata(question.qname().clone(), 300, Ipv4Addr::new(192, 0, 2, 1));
+        let authoritative = MessageBuilder::response_to(&query)
+            .authoritative(true)
+            .add_question(question.clone())
+            .add_answer(answer)
+            .build();
+        let now = Instant::now();
+        cache.store(&question, &authoritative, now);
+        let hit = cache.lookup(&query, &question, now).unwrap();
+        assert!(hit.header().authoritative());
+    }
+}
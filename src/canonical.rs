@@ -0,0 +1,136 @@
+//! Canonical name ordering and canonical record form (RFC 4034 section 6)
+//!
+//! Needed wherever records must be compared or hashed deterministically: DNSSEC signing and
+//! validation, and stable zone dumps.
+
+use std::cmp::Ordering;
+
+use crate::dns::{DnsLabels, DnsRecord, ToBytes};
+use crate::rdata::RData;
+
+/// Orders names label by label starting from the root, comparing lowercased label bytes
+pub fn name_cmp(a: &DnsLabels, b: &DnsLabels) -> Ordering {
+    for (a, b) in a.labels().rev().zip(b.labels().rev()) {
+        let a = a.iter().map(u8::to_ascii_lowercase);
+        let b = b.iter().map(u8::to_ascii_lowercase);
+        match a.cmp(b) {
+            Ordering::Equal => continue,
+            other => return other,
+        }
+    }
+    a.label_count().cmp(&b.label_count())
+}
+
+/// Name with every ASCII letter lowercased
+pub fn canonical_name(name: &DnsLabels) -> DnsLabels {
+    DnsLabels::new(name.labels().map(<[u8]>::to_ascii_lowercase))
+        .expect("lowercasing keeps label lengths")
+}
+
+/// Record with its owner name, and any names embedded in its RDATA, lowercased
+pub fn canonical_record(record: &DnsRecord) -> DnsRecord {
+    let name = canonical_name(record.name());
+    let rdata = match record.rdata() {
+        Ok(RData::Cname(target)) => Some(RData::Cname(canonical_name(&target))),
+        Ok(RData::Ns(host)) => Some(RData::Ns(canonical_name(&host))),
+        Ok(RData::Ptr(target)) => Some(RData::Ptr(canonical_name(&target))),
+        Ok(RData::Mx {
+            preference,
+            exchange,
+        }) => Some(RData::Mx {
+            preference,
+            exchange: canonical_name(&exchange),
+        }),
+        Ok(RData::Soa {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        }) => Some(RData::Soa {
+            mname: canonical_name(&mname),
+            rname: canonical_name(&rname),
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        }),
+        Ok(RData::Srv {
+            priority,
+            weight,
+            port,
+            target,
+        }) => Some(RData::Srv {
+            priority,
+            weight,
+            port,
+            target: canonical_name(&target),
+        }),
+        _ => None,
+    };
+    let data = match rdata {
+        Some(rdata) => rdata.to_bytes(),
+        None => record.data().to_vec(),
+    };
+    DnsRecord::new(
+        name,
+        record.record_type(),
+        record.class(),
+        record.ttl(),
+        data,
+    )
+}
+
+/// Canonical form of an RRset: records in canonical form, sorted by RDATA, duplicates removed
+pub fn canonical_rrset(records: &[DnsRecord]) -> Vec<DnsRecord> {
+    let mut rrset: Vec<DnsRecord> = records.iter().map(canonical_record).collect();
+    rrset.sort_by(|a, b| a.data().cmp(b.data()));
+    rrset.dedup_by(|a, b| a.data() == b.data());
+    rrset
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_rfc4034_name_order() {
+        // RFC 4034 6.1, without the entries that need escaped octets
+        let ordered = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        let mut names: Vec<DnsLabels> = ordered.iter().rev().map(|n| (*n).into()).collect();
+        names.sort_by(name_cmp);
+
+        let sorted: Vec<String> = names.iter().map(ToString::to_string).collect();
+        assert_eq!(sorted, ordered);
+    }
+
+    #[test]
+    fn test_canonical_rrset() {
+        let record = |ip| DnsRecord::with_rdata("WWW.Example.com".into(), 60, ip);
+        let rrset = canonical_rrset(&[
+            record(Ipv4Addr::new(10, 0, 0, 2)),
+            record(Ipv4Addr::new(10, 0, 0, 1)),
+            record(Ipv4Addr::new(10, 0, 0, 2)),
+        ]);
+
+        assert_eq!(rrset.len(), 2);
+        assert_eq!(rrset[0].data(), [10, 0, 0, 1]);
+        assert_eq!(
+            rrset[0].name().to_bytes(),
+            DnsLabels::from("www.example.com").to_bytes()
+        );
+    }
+}
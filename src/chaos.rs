@@ -0,0 +1,133 @@
+//! CHAOS-class introspection queries, as answered by BIND and Unbound
+//!
+//! `dig CH TXT version.bind` (or `version.server`), `hostname.bind` and `id.server` (RFC 4892)
+//! tell operators which software and which instance of a fleet answered. Each value is
+//! configured separately; a name without a value, and any other CHAOS question, is REFUSED.
+
+use crate::dns::{
+    class, error_response, rcode, rtype, DnsLabels, DnsMessage, DnsRecord, MessageBuilder, ToBytes,
+};
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::rdata::RData;
+
+/// Answers CHAOS TXT questions and passes every other class on
+#[derive(Debug, Clone, Default)]
+pub struct ChaosLayer {
+    version: Option<String>,
+    hostname: Option<String>,
+    id: Option<String>,
+}
+
+impl ChaosLayer {
+    /// Layer refusing every CHAOS question until values are set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer to `version.bind` and `version.server`
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Answer to `hostname.bind`
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Answer to `id.server`, usually the same identity as NSID
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    fn value(&self, name: &DnsLabels) -> Option<&str> {
+        let value = match name.to_string().to_ascii_lowercase().as_str() {
+            "version.bind" | "version.server" => &self.version,
+            "hostname.bind" => &self.hostname,
+            "id.server" => &self.id,
+            _ => return None,
+        };
+        value.as_deref()
+    }
+
+    fn answer(&self, query: &DnsMessage) -> DnsMessage {
+        let mut questions = query.questions();
+        let (Some(question), None) = (questions.next(), questions.next()) else {
+            return error_response(query, rcode::REFUSED);
+        };
+        let Some(value) = self.value(question.qname()) else {
+            return error_response(query, rcode::REFUSED);
+        };
+        let mut response = MessageBuilder::response_to(query)
+            .authoritative(true)
+            .add_question(question.clone());
+        if question.qtype() == rtype::TXT {
+            let name = question.qname().clone();
+            let data = RData::txt(value).to_bytes();
+            let record = DnsRecord::new(name, rtype::TXT, class::CH, 0, data);
+            response = response.add_answer(record);
+        }
+        response.build()
+    }
+}
+
+impl Layer for ChaosLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            if query
+                .questions()
+                .any(|question| question.qclass() == class::CH)
+            {
+                return self.answer(&query);
+            }
+            next.run(query, ctx).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::dns::DnsQuestion;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    fn chaos_query(name: &str) -> DnsMessage {
+        let question = DnsQuestion::new(DnsLabels::from(name), rtype::TXT, class::CH);
+        MessageBuilder::new().id(1).add_question(question).build()
+    }
+
+    #[tokio::test]
+    async fn test_chaos() {
+        let layer = ChaosLayer::new().version("dns 1.0").id("ams1");
+        let pipeline = Pipeline::new(DefaultHandler).layer(layer);
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+
+        let resp = pipeline
+            .handle(chaos_query("VERSION.BIND"), ctx.clone())
+            .await;
+        assert_eq!(resp.rcode(), rcode::NOERROR);
+        let answer = resp.answers().next().unwrap();
+        assert_eq!(answer.class(), class::CH);
+        assert_eq!(answer.data(), b"\x07dns 1.0");
+
+        let resp = pipeline.handle(chaos_query("id.server"), ctx.clone()).await;
+        assert_eq!(resp.answers().next().unwrap().data(), b"\x04ams1");
+
+        for name in ["hostname.bind", "authors.bind"] {
+            let resp = pipeline.handle(chaos_query(name), ctx.clone()).await;
+            assert_eq!(resp.rcode(), rcode::REFUSED);
+            assert_eq!(resp.answers().len(), 0);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Command-line options
+//!
+//! [`Cli::parse`] reads the options of the server binary, by hand since the crate keeps its
+//! dependencies few. Options given on the command line override the settings of the
+//! configuration file, see [`Cli::config`], so the binary runs anywhere without a file and a
+//! file can be tried out with one setting changed.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::config::{Config, LogFormat, LogLevel};
+use crate::error::DnsError;
+use crate::privacy::Client;
+
+pub const USAGE: &str = "\
+usage: dns-starter-rust [options]
+       dns-starter-rust [options] replay <capture.pcap> [server address]
+       dns-starter-rust forget <client> --log-dir <dir> [--log-prefix <prefix>]
+
+options:
+  --config <path>       TOML configuration file, see the config module
+  --addr <ip>           address to listen on, instead of server.listen
+  --port <port>         port to listen on
+  --resolver <ip[:port]> upstream to forward to, may be repeated
+  --recursive           resolve from the root servers instead of forwarding
+  --zone-file <path>    zone file to serve, may be repeated
+  --log-level <level>   error, warn, info or debug
+  --log-format <format> text or json
+  --query-log <dir>     write a line per query to queries.log in <dir>
+  --self-test           query the server once started, then exit
+  -h, --help            print this help
+";
+
+/// What the binary is asked to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Run the server, querying it once started if `self_test`
+    Serve {
+        self_test: bool,
+    },
+    /// Replay a capture of the traffic of `server` and diff the responses
+    Replay {
+        capture: PathBuf,
+        server: Option<SocketAddr>,
+    },
+    /// Remove everything recorded about `client` from the logs named `log_prefix*` in `log_dir`
+    Forget {
+        client: Client,
+        log_dir: PathBuf,
+        log_prefix: String,
+    },
+    Help,
+}
+
+/// Parsed command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cli {
+    config: Option<PathBuf>,
+    addr: Option<IpAddr>,
+    port: Option<u16>,
+    resolvers: Vec<SocketAddr>,
+    recursive: bool,
+    zone_files: Vec<PathBuf>,
+    log_level: Option<LogLevel>,
+    log_format: Option<LogFormat>,
+    query_log: Option<PathBuf>,
+    command: Command,
+}
+
+fn usage(message: impl Into<String>) -> DnsError {
+    DnsError::Usage(message.into())
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, DnsError> {
+    value
+        .parse()
+        .map_err(|_| usage(format!("invalid value {value:?} for {flag}")))
+}
+
+/// `ip` or `ip:port`
+fn resolver(value: &str) -> Result<SocketAddr, DnsError> {
+    match value.parse() {
+        Ok(addr) => Ok(addr),
+        Err(_) => Ok(SocketAddr::new(parse("--resolver", value)?, 53)),
+    }
+}
+
+/// A client as the logs record it: an address, or `#<hash>` when anonymized by hashing
+fn client(value: &str) -> Result<Client, DnsError> {
+    match value.strip_prefix('#') {
+        Some(hash) => u64::from_str_radix(hash, 16)
+            .map(Client::Hashed)
+            .map_err(|_| usage(format!("invalid client hash {value}"))),
+        None => parse("forget", value).map(Client::Addr),
+    }
+}
+
+impl Cli {
+    /// Parses `args`, the arguments without the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, DnsError> {
+        let mut cli = Cli {
+            config: None,
+            addr: None,
+            port: None,
+            resolvers: Vec::new(),
+            recursive: false,
+            zone_files: Vec::new(),
+            log_level: None,
+            log_format: None,
+            query_log: None,
+            command: Command::Serve { self_test: false },
+        };
+        let (mut self_test, mut log_dir, mut log_prefix) = (false, None, None);
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| usage(format!("{arg} needs a value")))
+            };
+            match arg.as_str() {
+                "--config" => cli.config = Some(value()?.into()),
+                "--addr" => cli.addr = Some(parse(&arg, &value()?)?),
+                "--port" => cli.port = Some(parse(&arg, &value()?)?),
+                "--resolver" => cli.resolvers.push(resolver(&value()?)?),
+                "--recursive" => cli.recursive = true,
+                "--zone-file" => cli.zone_files.push(value()?.into()),
+                "--log-level" => cli.log_level = Some(parse(&arg, &value()?)?),
+                "--log-format" => cli.log_format = Some(parse(&arg, &value()?)?),
+                "--query-log" => cli.query_log = Some(value()?.into()),
+                "--log-dir" => log_dir = Some(PathBuf::from(value()?)),
+                "--log-prefix" => log_prefix = Some(value()?),
+                "--self-test" => self_test = true,
+                "-h" | "--help" => cli.command = Command::Help,
+                flag if flag.starts_with('-') => {
+                    return Err(usage(format!("unknown option {flag}")))
+                }
+                _ => positional.push(arg),
+            }
+        }
+        if cli.recursive && !cli.resolvers.is_empty() {
+            return Err(usage("--resolver and --recursive exclude each other"));
+        }
+        if cli.command == Command::Help {
+            return Ok(cli);
+        }
+
+        cli.command = match positional.split_first() {
+            None => Command::Serve { self_test },
+            Some((command, rest)) => match (command.as_str(), rest) {
+                ("replay", [capture]) => Command::Replay {
+                    capture: capture.into(),
+                    server: None,
+                },
+                ("replay", [capture, server]) => Command::Replay {
+                    capture: capture.into(),
+                    server: Some(parse("replay", server)?),
+                },
+                ("forget", [who]) => Command::Forget {
+                    client: client(who)?,
+                    log_dir: log_dir.ok_or_else(|| usage("forget needs --log-dir"))?,
+                    log_prefix: log_prefix.unwrap_or_else(|| "queries.log".to_string()),
+                },
+                (command, _) => return Err(usage(format!("unexpected arguments to {command}"))),
+            },
+        };
+        Ok(cli)
+    }
+
+    pub fn command(&self) -> &Command {
+        &self.command
+    }
+
+    /// Settings of the `--config` file, or the defaults, overridden by the options given
+    pub fn config(&self) -> Result<Config, DnsError> {
+        let mut config = match &self.config {
+            Some(path) => Config::read(path)?,
+            None => Config::default(),
+        };
+        match (self.addr, self.port) {
+            (Some(addr), port) => {
+                let port = port.unwrap_or(config.listen()[0].port());
+                config = config.with_listen(vec![SocketAddr::new(addr, port)]);
+            }
+            (None, Some(port)) => {
+                let listen = config
+                    .listen()
+                    .iter()
+                    .map(|addr| SocketAddr::new(addr.ip(), port))
+                    .collect();
+                config = config.with_listen(listen);
+            }
+            (None, None) => {}
+        }
+        if !self.resolvers.is_empty() {
+            config = config
+                .with_resolvers(self.resolvers.clone())
+                .with_recursive(false);
+        }
+        if self.recursive {
+            config = config.with_resolvers(Vec::new()).with_recursive(true);
+        }
+        if !self.zone_files.is_empty() {
+            config = config.with_zone_files(self.zone_files.clone());
+        }
+        if let Some(level) = self.log_level {
+            config = config.with_log_level(level);
+        }
+        if let Some(format) = self.log_format {
+            config = config.with_log_format(format);
+        }
+        if let Some(dir) = &self.query_log {
+            config = config.with_query_log(dir.clone());
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cli(args: &str) -> Result<Cli, DnsError> {
+        Cli::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_overrides() {
+        let config = cli("--port 53 --resolver 9.9.9.9 --resolver 1.1.1.1:5353 --log-level warn --log-format json")
+            .unwrap()
+            .config()
+            .unwrap();
+        assert_eq!(config.listen(), ["127.0.0.1:53".parse().unwrap()]);
+        assert_eq!(
+            config.resolvers(),
+            [
+                "9.9.9.9:53".parse().unwrap(),
+                "1.1.1.1:5353".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.log_level(), LogLevel::Warn);
+        assert_eq!(config.log_format(), LogFormat::Json);
+
+        let path = std::env::temp_dir().join(format!("cli-test-{}.toml", std::process::id()));
+        std::fs::write(&path, "[upstream]\nresolvers = [\"8.8.8.8\"]\n").unwrap();
+        let args = format!("--config {} --addr ::1 --recursive", path.display());
+        let config = cli(&args).unwrap().config().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.listen(), ["[::1]:2053".parse().unwrap()]);
+        assert!(config.recursive() && config.resolvers().is_empty());
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            cli("--self-test").unwrap().command(),
+            &Command::Serve { self_test: true }
+        );
+        assert_eq!(
+            cli("replay capture.pcap 127.0.0.1:2053").unwrap().command(),
+            &Command::Replay {
+                capture: "capture.pcap".into(),
+                server: Some("127.0.0.1:2053".parse().unwrap()),
+            }
+        );
+        assert_eq!(
+            cli("forget #00000000000000ff --log-dir /var/log/dns")
+                .unwrap()
+                .command(),
+            &Command::Forget {
+                client: Client::Hashed(255),
+                log_dir: "/var/log/dns".into(),
+                log_prefix: "queries.log".to_string(),
+            }
+        );
+        assert_eq!(cli("--help").unwrap().command(), &Command::Help);
+
+        for bad in [
+            "--port",
+            "--port 70000",
+            "--log-level loud",
+            "--verbose",
+            "forget 10.0.0.1",
+            "--recursive --resolver 1.1.1.1",
+        ] {
+            assert!(matches!(cli(bad), Err(DnsError::Usage(_))), "{bad}");
+        }
+    }
+}
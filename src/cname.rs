@@ -0,0 +1,191 @@
+//! CNAME chasing for answers that stop at an alias
+//!
+//! Local sources and some upstreams answer a question about an alias with the CNAME record
+//! alone. [`CnameLayer`] then asks the rest of the pipeline about the target, again and again
+//! along the chain, and appends what it learns to the same answer section, so clients get the
+//! CNAMEs followed by the records they asked for in one response.
+
+use crate::dns::{rcode, rtype, DnsLabels, DnsMessage, DnsQuestion, DnsRecord, MessageBuilder};
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::rdata::RData;
+use crate::recursive::MAX_CNAMES;
+use crate::warn;
+
+/// Layer completing answers that end with a CNAME by querying the rest of the pipeline for its
+/// target
+#[derive(Debug, Clone)]
+pub struct CnameLayer {
+    max_depth: usize,
+}
+
+impl Default for CnameLayer {
+    fn default() -> Self {
+        Self {
+            max_depth: MAX_CNAMES,
+        }
+    }
+}
+
+impl CnameLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// CNAMEs followed before answering SERVFAIL, [`MAX_CNAMES`] by default
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+/// Target of the CNAME owned by `name` among `answers`
+fn cname_target(answers: &[DnsRecord], name: &DnsLabels) -> Option<DnsLabels> {
+    answers
+        .iter()
+        .filter(|record| record.name() == name && record.record_type() == rtype::CNAME)
+        .find_map(|record| match record.rdata() {
+            Ok(RData::Cname(target)) => Some(target),
+            _ => None,
+        })
+}
+
+impl Layer for CnameLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let mut questions = query.questions();
+            let (Some(question), None) = (questions.next(), questions.next()) else {
+                return next.run(query, ctx).await;
+            };
+            let question = question.clone();
+            let response = next.run(query.clone(), ctx.clone()).await;
+            if response.rcode() != rcode::NOERROR || question.qtype() == rtype::CNAME {
+                return response;
+            }
+
+            let (qtype, qclass) = (question.qtype(), question.qclass());
+            let mut answers: Vec<DnsRecord> = response.answers().cloned().collect();
+            let mut result = rcode::NOERROR;
+            let mut current = question.qname().clone();
+            let mut depth = 0;
+            loop {
+                while let Some(target) = cname_target(&answers, &current) {
+                    depth += 1;
+                    if depth > self.max_depth {
+                        warn!("more than {} CNAMEs from {current}", self.max_depth);
+                        return MessageBuilder::response_to(&query)
+                            .add_question(question)
+                            .rcode(rcode::SERVFAIL)
+                            .build();
+                    }
+                    current = target;
+                }
+                let answered = answers
+                    .iter()
+                    .any(|record| record.name() == &current && record.record_type() == qtype);
+                if answered || depth == 0 {
+                    break;
+                }
+
+                let follow = MessageBuilder::new()
+                    .id(query.id())
+                    .recursion_desired(query.header().recursion_desired())
+                    .checking_disabled(query.header().checking_disabled())
+                    .add_question(DnsQuestion::new(current.clone(), qtype, qclass))
+                    .build();
+                let followed = next.run(follow, ctx.clone()).await;
+                result = followed.rcode();
+                let before = answers.len();
+                answers.extend(followed.answers().cloned());
+                if result != rcode::NOERROR || answers.len() == before {
+                    break;
+                }
+            }
+            if depth == 0 {
+                return response;
+            }
+
+            // the rcode is that of the last name of the chain (RFC 6604)
+            let header = response.header();
+            answers
+                .into_iter()
+                .fold(
+                    MessageBuilder::response_to(&query)
+                        .authoritative(header.authoritative())
+                        .recursion_available(header.recursion_available())
+                        .add_question(question),
+                    MessageBuilder::add_answer,
+                )
+                .rcode(result)
+                .build()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::dns::{class, error_response};
+    use crate::handler::{RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    /// Local data with a chain www -> web -> host, and a loop between a and b
+    struct Aliases;
+
+    impl RequestHandler for Aliases {
+        async fn handle(&self, query: DnsMessage, _ctx: RequestCtx) -> DnsMessage {
+            let question = query.questions().next().unwrap().clone();
+            let cname = |target: &str| {
+                DnsRecord::with_rdata(question.qname().clone(), 300, RData::Cname(target.into()))
+            };
+            let record = match question.qname().to_string().as_str() {
+                "www.example.com" => cname("web.example.com"),
+                "web.example.com" => cname("host.example.net"),
+                "host.example.net" => {
+                    DnsRecord::with_rdata(question.qname().clone(), 60, Ipv4Addr::new(192, 0, 2, 1))
+                }
+                "a.example.com" => cname("b.example.com"),
+                "b.example.com" => cname("a.example.com"),
+                _ => return error_response(&query, rcode::NXDOMAIN),
+            };
+            MessageBuilder::response_to(&query)
+                .add_question(question)
+                .add_answer(record)
+                .build()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chase() {
+        let pipeline = Pipeline::new(Aliases).layer(CnameLayer::new());
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+
+        let query = DnsMessage::query(1, "www.example.com", rtype::A);
+        let resp = pipeline.handle(query, ctx.clone()).await;
+        assert_eq!(resp.rcode(), rcode::NOERROR);
+        let chain: Vec<(String, u16)> = resp
+            .answers()
+            .map(|record| (record.name().to_string(), record.record_type()))
+            .collect();
+        assert_eq!(
+            chain,
+            [
+                ("www.example.com".to_string(), rtype::CNAME),
+                ("web.example.com".to_string(), rtype::CNAME),
+                ("host.example.net".to_string(), rtype::A),
+            ]
+        );
+        assert_eq!(resp.answers().last().unwrap().class(), class::IN);
+
+        let query = DnsMessage::query(2, "a.example.com", rtype::A);
+        let resp = pipeline.handle(query, ctx).await;
+        assert_eq!(resp.rcode(), rcode::SERVFAIL);
+    }
+}
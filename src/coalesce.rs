@@ -0,0 +1,194 @@
+//! Deduplication of identical queries in flight
+//!
+//! When a burst of clients asks the same question at once, only the first query goes down the
+//! pipeline; the others wait for its response and get a copy carrying their own id. This keeps
+//! a popular name that just expired from the cache from fanning out into one upstream query per
+//! client.
+//!
+//! Queries are matched on their question, opcode and RD, AD and CD bits, not on the client: only
+//! add this layer in front of handlers whose answers do not depend on the client.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::dns::{DnsMessage, DnsQuestion};
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct Key {
+    question: DnsQuestion,
+    opcode: u8,
+    recursion_desired: bool,
+    authentic_data: bool,
+    checking_disabled: bool,
+}
+
+impl Key {
+    /// Only single-question queries are coalesced
+    fn of(query: &DnsMessage) -> Option<Key> {
+        let mut questions = query.questions();
+        let question = questions.next()?;
+        if questions.next().is_some() {
+            return None;
+        }
+        let header = query.header();
+        Some(Key {
+            question: question.clone(),
+            opcode: header.opcode(),
+            recursion_desired: header.recursion_desired(),
+            authentic_data: header.authentic_data(),
+            checking_disabled: header.checking_disabled(),
+        })
+    }
+}
+
+type Waiters = HashMap<Key, Vec<oneshot::Sender<DnsMessage>>>;
+
+/// Layer answering identical concurrent queries with a single call to the rest of the pipeline
+#[derive(Debug, Default)]
+pub struct CoalesceLayer {
+    in_flight: Arc<Mutex<Waiters>>,
+    coalesced: AtomicU64,
+}
+
+impl CoalesceLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queries answered with the response to an identical one
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+/// Removes the entry of the query being resolved, even if its future is dropped midway; waiters
+/// then see their sender dropped and resolve on their own
+struct Leader {
+    in_flight: Arc<Mutex<Waiters>>,
+    key: Key,
+    finished: bool,
+}
+
+impl Leader {
+    fn finish(mut self, response: &DnsMessage) {
+        self.finished = true;
+        let waiters = self.in_flight.lock().unwrap().remove(&self.key);
+        for waiter in waiters.into_iter().flatten() {
+            let _ = waiter.send(response.clone());
+        }
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        // once finished, the entry may already belong to a newer query
+        if !self.finished {
+            self.in_flight.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+impl Layer for CoalesceLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let Some(key) = Key::of(&query) else {
+                return next.run(query, ctx).await;
+            };
+
+            let waiting = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get_mut(&key) {
+                    Some(waiters) => {
+                        let (tx, rx) = oneshot::channel();
+                        waiters.push(tx);
+                        Some(rx)
+                    }
+                    None => {
+                        in_flight.insert(key.clone(), Vec::new());
+                        None
+                    }
+                }
+            };
+
+            match waiting {
+                Some(rx) => match rx.await {
+                    Ok(response) => {
+                        self.coalesced.fetch_add(1, Ordering::Relaxed);
+                        response.retarget(&query)
+                    }
+                    // the query we waited on was cancelled
+                    Err(_) => next.run(query, ctx).await,
+                },
+                None => {
+                    let leader = Leader {
+                        in_flight: self.in_flight.clone(),
+                        key,
+                        finished: false,
+                    };
+                    let response = next.run(query, ctx).await;
+                    leader.finish(&response);
+                    response
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::dns::{response, MessageBuilder};
+    use crate::handler::{RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    /// Answers after a delay, counting how often it ran
+    struct Counting(Arc<AtomicU64>);
+
+    impl RequestHandler for Counting {
+        async fn handle(&self, query: DnsMessage, _ctx: RequestCtx) -> DnsMessage {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            response(&query)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_identical_queries_resolve_once() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let pipeline = Arc::new(Pipeline::new(Counting(calls.clone())).layer(CoalesceLayer::new()));
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+
+        let queries = (0..5).map(|id| {
+            let (pipeline, ctx) = (pipeline.clone(), ctx.clone());
+            tokio::spawn(async move {
+                let query = DnsMessage::query(id, "codecrafters.io", 1);
+                pipeline.handle(query, ctx).await
+            })
+        });
+        let queries: Vec<_> = queries.collect();
+        for (id, query) in queries.into_iter().enumerate() {
+            assert_eq!(query.await.unwrap().id(), id as u16);
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // a different question is resolved on its own
+        let other = MessageBuilder::new()
+            .add_question(DnsQuestion::new("other.example".into(), 1, 1))
+            .build();
+        pipeline.handle(other, ctx).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
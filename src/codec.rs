@@ -0,0 +1,131 @@
+//! Length-prefixed framing for DNS over stream transports (RFC 1035 4.2.2)
+//!
+//! `tokio-util` is not a dependency, so [`DnsCodec`] mirrors its `Decoder`/`Encoder` shape and
+//! [`Framed`] provides the receive/send loop on top of any tokio stream.
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::dns::{DnsMessage, ToBytes};
+use crate::error::DnsError;
+
+/// Two byte big-endian length prefix followed by the message
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DnsCodec;
+
+impl DnsCodec {
+    /// Decodes one frame from the front of `src`, leaving a partial frame in place
+    pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<DnsMessage>, DnsError> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([src[0], src[1]]) as usize;
+        if src.len() < 2 + len {
+            src.reserve(2 + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(2);
+        let frame = src.split_to(len);
+        DnsMessage::from_bytes(&frame).map(Some)
+    }
+
+    pub fn encode(&mut self, msg: &DnsMessage, dst: &mut BytesMut) -> Result<(), DnsError> {
+        let len = msg.wire_len();
+        if len > u16::MAX as usize {
+            return Err(DnsError::Malformed(format!(
+                "message of {len} bytes does not fit a stream frame"
+            )));
+        }
+
+        dst.reserve(2 + len);
+        dst.put_u16(len as u16);
+        msg.write_to(dst);
+        Ok(())
+    }
+}
+
+/// Reads and writes whole messages over a stream such as a `TcpStream`
+pub struct Framed<T> {
+    io: T,
+    codec: DnsCodec,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Framed<T> {
+    pub fn new(io: T) -> Self {
+        Self {
+            io,
+            codec: DnsCodec,
+            read_buf: BytesMut::with_capacity(512),
+            write_buf: BytesMut::with_capacity(512),
+        }
+    }
+
+    /// Next message on the stream, or `None` once the peer closed it cleanly
+    pub async fn recv(&mut self) -> Option<Result<DnsMessage, DnsError>> {
+        loop {
+            match self.codec.decode(&mut self.read_buf) {
+                Ok(Some(msg)) => return Some(Ok(msg)),
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+
+            match self.io.read_buf(&mut self.read_buf).await {
+                Ok(0) if self.read_buf.is_empty() => return None,
+                Ok(0) => return Some(Err(DnsError::Truncated)),
+                Ok(_) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, msg: &DnsMessage) -> Result<(), DnsError> {
+        self.write_buf.clear();
+        self.codec.encode(msg, &mut self.write_buf)?;
+        self.io.write_all(&self.write_buf).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_partial_frames() {
+        let query = DnsMessage::query(5, "example.com", 1);
+        let mut encoded = BytesMut::new();
+        DnsCodec.encode(&query, &mut encoded).unwrap();
+
+        let mut src = BytesMut::from(&encoded[..10]);
+        assert!(DnsCodec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&encoded[10..]);
+        assert_eq!(DnsCodec.decode(&mut src).unwrap(), Some(query));
+        assert!(src.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_framed_round_trip() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = Framed::new(client);
+        let mut server = Framed::new(server);
+
+        let query = DnsMessage::query(6, "example.com", 1);
+        client.send(&query).await.unwrap();
+        drop(client);
+
+        assert_eq!(server.recv().await.unwrap().unwrap(), query);
+        assert!(server.recv().await.is_none());
+    }
+}
@@ -0,0 +1,1510 @@
+//! Server configuration file, in TOML
+//!
+//! [`Config::read`] loads the settings that are otherwise built in:
+//!
+//! ```toml
+//! [server]
+//! listen = ["127.0.0.1:2053", "[::1]:2053"]
+//! doh_listen = ["127.0.0.1:8053"]         # DNS over HTTPS, behind a proxy terminating TLS
+//! identity = "ams1"                       # name of this node in the stats reports
+//!
+//! [upstream]
+//! resolvers = ["1.1.1.1", "8.8.8.8:53"]   # tried in order, port 53 unless given; or the
+//!                                         # sdns:// stamps of plain DNS resolvers
+//! # recursive = true                     # or resolve from the root servers
+//!
+//! [zones]
+//! files = ["zones/example.com.zone"]      # relative to the configuration file
+//!
+//! [cache]
+//! size = 10000
+//!
+//! [response_cache]                        # serialized UDP responses replayed before the
+//! size = 10000                            # pipeline, so hits skip logs; off by default, and
+//! max_age_secs = 30                       # not allowed along with [acl] or DGA rate limits
+//!
+//! [hosts]
+//! files = ["/etc/hosts", "lan.hosts"]     # /etc/hosts by default, [] for none
+//! ttl = 60                                # 0 by default, so edits show up at once
+//!
+//! [leases]                                # DHCP clients served as <hostname>.<domain>
+//! dnsmasq = ["/var/lib/misc/dnsmasq.leases"]
+//! kea = ["/var/lib/kea/kea-leases4.csv"]
+//! domain = "lan"                          # the default
+//!
+//! [blocklist]
+//! files = ["ads.txt", "malware.hosts"]   # domains blocked with everything below them
+//! sinkhole = ["0.0.0.0", "::"]            # answered for them, NXDOMAIN by default
+//!
+//! [mdns]
+//! enabled = true                          # .local names asked on the LAN, off by default
+//!
+//! [consul]
+//! agent = "127.0.0.1:8500"                # services of its catalog served, off by default
+//! suffix = "service.consul"               # under this name
+//!
+//! [chaos]                                 # CH TXT answers, REFUSED when not set
+//! version = "dns-starter 0.1"             # version.bind and version.server
+//! hostname = "ns1.example.com"            # hostname.bind
+//! id = "ns1"                              # id.server
+//!
+//! [acme]
+//! listen = "127.0.0.1:8080"               # DNS-01 challenge API, off by default
+//! zone = "acme.example.com"               # where acme-dns accounts get their subdomains
+//! token = "s3cret"                        # bearer token of the /txt API
+//!
+//! [dga]                                   # names that look generated by malware
+//! action = "block"                        # "log", "rate_limit" or "block"; off by default
+//! per_minute = 10                         # suspicious lookups per client for "rate_limit"
+//! threshold_percent = 60                  # score above which names are acted on
+//!
+//! [split]
+//! routes = "routes.txt"                   # `domain upstream` lines, re-read when changed;
+//!                                         # or command = "vpn-routes", its output
+//!
+//! [log]
+//! level = "info"                          # error, warn, info or debug
+//! format = "json"                         # or "text"
+//!
+//! [query_log]
+//! dir = "/var/log/dns"                    # a line per query in queries.log there
+//! max_bytes = 100_000_000                 # rotated past this size
+//! rotate_secs = 86400                     # or age
+//! keep_secs = 604800                      # rotated files older than this are purged
+//!
+//! [dnstap]
+//! socket = "/var/run/dnstap.sock"         # or file = "dns.fstrm"
+//! identity = "ns1"
+//!
+//! [rate_limit]
+//! qps = 50                                # queries per second and client
+//! burst = 100                             # at once, qps by default
+//! action = "refuse"                       # or "drop", the default
+//!
+//! [rrl]
+//! responses_per_second = 5                # identical responses per client network
+//! window = 15                             # seconds of excess remembered
+//! slip = 2                                # every 2nd limited response truncated, not dropped
+//!
+//! [acl]                                   # networks allowed, everyone by default
+//! query = ["127.0.0.0/8", "10.0.0.0/8", "::1"]
+//! recursion = ["127.0.0.1"]
+//! transfer = []
+//!
+//! [tsig]
+//! keys = ["hmac-sha256:xfr.example:c2VjcmV0"]  # [algorithm:]name:base64 secret
+//!
+//! [stats]
+//! report_secs = 300                       # top clients and listener counters logged this
+//!                                         # often, never by default
+//! top_clients = 10
+//!
+//! [privacy]                               # how each output records clients: "off" (the
+//! log = "truncate"                        # default), "truncate" or "hash"
+//! query_log = "hash"
+//! dnstap = "truncate"
+//! stats = "off"
+//! v4_prefix = 24                          # networks "truncate" keeps
+//! v6_prefix = 56
+//! ```
+//!
+//! Every setting is optional. Only the part of TOML such a file needs is understood: tables,
+//! strings, integers, booleans and arrays of them, and comments. Unknown settings and values of
+//! the wrong type are errors naming their line, so that typos do not go unnoticed.
+
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::acl::Acl;
+use crate::dga::DgaAction;
+use crate::dns::DnsLabels;
+use crate::error::DnsError;
+use crate::hosts::SYSTEM_HOSTS;
+use crate::leases::LeaseFormat;
+use crate::privacy::Anonymizer;
+use crate::ratelimit::RateLimitPolicy;
+use crate::stamp::{Protocol, Stamp};
+use crate::tsig::Key;
+
+/// Address the server listens on unless configured otherwise, the one the tester expects
+pub const DEFAULT_ADDR: &str = "127.0.0.1:2053";
+
+/// How much the server logs; each level includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    /// Every query and response too
+    #[default]
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = DnsError;
+
+    fn from_str(level: &str) -> Result<Self, DnsError> {
+        match level.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(DnsError::Config(format!(
+                "unknown log level {level}, expected error, warn, info or debug"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        })
+    }
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// `LEVEL: message` followed by the fields of the query, for people
+    #[default]
+    Text,
+    /// A JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = DnsError;
+
+    fn from_str(format: &str) -> Result<Self, DnsError> {
+        match format.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(DnsError::Config(format!(
+                "unknown log format {format}, expected text or json"
+            ))),
+        }
+    }
+}
+
+/// How an output records client addresses, see [`Anonymizer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Privacy {
+    #[default]
+    Off,
+    Truncate,
+    Hash,
+}
+
+/// Settings of the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    listen: Vec<SocketAddr>,
+    identity: Option<String>,
+    resolvers: Vec<SocketAddr>,
+    recursive: bool,
+    zone_files: Vec<PathBuf>,
+    cache_size: usize,
+    response_cache_size: Option<usize>,
+    response_cache_max_age: Duration,
+    log_level: LogLevel,
+    log_format: LogFormat,
+    query_log: Option<PathBuf>,
+    query_log_max_bytes: u64,
+    query_log_rotate: Duration,
+    query_log_keep: Option<Duration>,
+    dnstap_socket: Option<PathBuf>,
+    dnstap_file: Option<PathBuf>,
+    dnstap_identity: Option<String>,
+    rate_limit_qps: Option<u32>,
+    rate_limit_burst: Option<u32>,
+    rate_limit_policy: RateLimitPolicy,
+    rrl_rate: Option<u32>,
+    rrl_window: Duration,
+    rrl_slip: u32,
+    acl_query: Option<Acl>,
+    acl_recursion: Option<Acl>,
+    acl_transfer: Option<Acl>,
+    tsig_keys: Vec<Key>,
+    doh_listen: Vec<SocketAddr>,
+    hosts_files: Vec<PathBuf>,
+    hosts_ttl: u32,
+    leases: Vec<(PathBuf, LeaseFormat)>,
+    lease_domain: DnsLabels,
+    blocklists: Vec<PathBuf>,
+    sinkhole: Vec<IpAddr>,
+    dga_action: Option<DgaAction>,
+    dga_per_minute: u32,
+    dga_threshold: f64,
+    split_routes: Option<PathBuf>,
+    mdns: bool,
+    consul_agent: Option<String>,
+    consul_suffix: DnsLabels,
+    chaos_version: Option<String>,
+    chaos_hostname: Option<String>,
+    chaos_id: Option<String>,
+    acme_listen: Option<SocketAddr>,
+    acme_zone: Option<DnsLabels>,
+    acme_token: Option<String>,
+    split_command: Option<String>,
+    stats_report: Option<Duration>,
+    stats_top_clients: usize,
+    privacy_log: Privacy,
+    privacy_query_log: Privacy,
+    privacy_dnstap: Privacy,
+    privacy_stats: Privacy,
+    privacy_v4_prefix: u8,
+    privacy_v6_prefix: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![DEFAULT_ADDR.parse().unwrap()],
+            identity: None,
+            resolvers: Vec::new(),
+            recursive: false,
+            zone_files: Vec::new(),
+            cache_size: 10_000,
+            response_cache_size: None,
+            response_cache_max_age: Duration::from_secs(30),
+            log_level: LogLevel::default(),
+            log_format: LogFormat::default(),
+            query_log: None,
+            query_log_max_bytes: 100_000_000,
+            query_log_rotate: Duration::from_secs(24 * 60 * 60),
+            query_log_keep: None,
+            dnstap_socket: None,
+            dnstap_file: None,
+            dnstap_identity: None,
+            rate_limit_qps: None,
+            rate_limit_burst: None,
+            rate_limit_policy: RateLimitPolicy::Drop,
+            rrl_rate: None,
+            rrl_window: Duration::from_secs(15),
+            rrl_slip: 2,
+            acl_query: None,
+            acl_recursion: None,
+            acl_transfer: None,
+            tsig_keys: Vec::new(),
+            doh_listen: Vec::new(),
+            hosts_files: vec![PathBuf::from(SYSTEM_HOSTS)],
+            hosts_ttl: 0,
+            leases: Vec::new(),
+            lease_domain: "lan".into(),
+            blocklists: Vec::new(),
+            sinkhole: Vec::new(),
+            dga_action: None,
+            dga_per_minute: 10,
+            dga_threshold: 0.6,
+            split_routes: None,
+            mdns: false,
+            consul_agent: None,
+            consul_suffix: "service.consul".into(),
+            chaos_version: None,
+            chaos_hostname: None,
+            chaos_id: None,
+            acme_listen: None,
+            acme_zone: None,
+            acme_token: None,
+            split_command: None,
+            stats_report: None,
+            stats_top_clients: 10,
+            privacy_log: Privacy::Off,
+            privacy_query_log: Privacy::Off,
+            privacy_dnstap: Privacy::Off,
+            privacy_stats: Privacy::Off,
+            privacy_v4_prefix: 24,
+            privacy_v6_prefix: 56,
+        }
+    }
+}
+
+impl Config {
+    /// Settings of the TOML document `text`, the defaults for those it leaves out
+    pub fn parse(text: &str) -> Result<Config, DnsError> {
+        let mut config = Config::default();
+        let mut seen: Vec<String> = Vec::new();
+        for (line, key, value) in Parser::new(text).document()? {
+            let fail = |message: String| DnsError::Config(format!("line {line}: {message}"));
+            if seen.contains(&key) {
+                return Err(fail(format!("{key} is set twice")));
+            }
+            let wrong_type = |expected: &str| fail(format!("{key} must be {expected}"));
+            match key.as_str() {
+                "server.listen" => {
+                    config.listen = addrs(&value, None)
+                        .ok_or_else(|| wrong_type("an array of addresses with ports"))?;
+                }
+                "server.doh_listen" => {
+                    config.doh_listen = addrs(&value, None)
+                        .ok_or_else(|| wrong_type("an array of addresses with ports"))?;
+                }
+                "server.identity" => {
+                    let Value::String(identity) = value else {
+                        return Err(wrong_type("a string"));
+                    };
+                    config.identity = Some(identity);
+                }
+                "upstream.resolvers" => {
+                    config.resolvers = strings(&value)
+                        .ok_or_else(|| wrong_type("an array of addresses or stamps"))?
+                        .into_iter()
+                        .map(|text| resolver(text).map_err(fail))
+                        .collect::<Result<_, DnsError>>()?;
+                }
+                "upstream.recursive" => {
+                    let Value::Bool(recursive) = value else {
+                        return Err(wrong_type("true or false"));
+                    };
+                    config.recursive = recursive;
+                }
+                "zones.files" => {
+                    config.zone_files = strings(&value)
+                        .ok_or_else(|| wrong_type("an array of paths"))?
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect();
+                }
+                "hosts.files" => {
+                    config.hosts_files = strings(&value)
+                        .ok_or_else(|| wrong_type("an array of paths"))?
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect();
+                }
+                "hosts.ttl" => {
+                    let ttl = match value {
+                        Value::Integer(ttl) => u32::try_from(ttl).ok(),
+                        _ => None,
+                    };
+                    config.hosts_ttl = ttl.ok_or_else(|| wrong_type("a non-negative integer"))?;
+                }
+                "leases.dnsmasq" | "leases.kea" => {
+                    let format = match key.as_str() {
+                        "leases.dnsmasq" => LeaseFormat::Dnsmasq,
+                        _ => LeaseFormat::KeaCsv,
+                    };
+                    let files = strings(&value).ok_or_else(|| wrong_type("an array of paths"))?;
+                    let files = files.into_iter().map(|file| (PathBuf::from(file), format));
+                    config.leases.extend(files);
+                }
+                "leases.domain" => {
+                    let domain = match value {
+                        Value::String(domain) => domain.parse().ok(),
+                        _ => None,
+                    };
+                    config.lease_domain = domain.ok_or_else(|| wrong_type("a domain name"))?;
+                }
+                "blocklist.files" => {
+                    config.blocklists = strings(&value)
+                        .ok_or_else(|| wrong_type("an array of paths"))?
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect();
+                }
+                "blocklist.sinkhole" => {
+                    config.sinkhole = strings(&value)
+                        .and_then(|addrs| addrs.into_iter().map(|addr| addr.parse().ok()).collect())
+                        .ok_or_else(|| wrong_type("an array of IP addresses"))?;
+                }
+                "dga.action" => {
+                    // the allowance is filled in once dga.per_minute is known
+                    config.dga_action = Some(match value {
+                        Value::String(action) if action == "log" => DgaAction::Log,
+                        Value::String(action) if action == "rate_limit" => {
+                            DgaAction::RateLimit { per_minute: 0 }
+                        }
+                        Value::String(action) if action == "block" => DgaAction::Block,
+                        _ => return Err(wrong_type("\"log\", \"rate_limit\" or \"block\"")),
+                    });
+                }
+                "dga.per_minute" => {
+                    let number = match value {
+                        Value::Integer(number) => u32::try_from(number).ok(),
+                        _ => None,
+                    };
+                    config.dga_per_minute =
+                        number.ok_or_else(|| wrong_type("a non-negative integer"))?;
+                }
+                "dga.threshold_percent" => {
+                    let percent = match value {
+                        Value::Integer(percent) => u8::try_from(percent).ok().filter(|&n| n <= 100),
+                        _ => None,
+                    };
+                    let percent = percent.ok_or_else(|| wrong_type("a percentage"))?;
+                    config.dga_threshold = f64::from(percent) / 100.0;
+                }
+                "split.routes" => {
+                    let Value::String(path) = value else {
+                        return Err(wrong_type("a path"));
+                    };
+                    config.split_routes = Some(PathBuf::from(path));
+                }
+                "split.command" => {
+                    let Value::String(command) = value else {
+                        return Err(wrong_type("a string"));
+                    };
+                    config.split_command = Some(command);
+                }
+                "mdns.enabled" => {
+                    let Value::Bool(enabled) = value else {
+                        return Err(wrong_type("true or false"));
+                    };
+                    config.mdns = enabled;
+                }
+                "consul.agent" => {
+                    let Value::String(agent) = value else {
+                        return Err(wrong_type("a host:port string"));
+                    };
+                    config.consul_agent = Some(agent);
+                }
+                "consul.suffix" => {
+                    let suffix = match value {
+                        Value::String(suffix) => suffix.parse().ok(),
+                        _ => None,
+                    };
+                    config.consul_suffix = suffix.ok_or_else(|| wrong_type("a domain name"))?;
+                }
+                "chaos.version" | "chaos.hostname" | "chaos.id" => {
+                    let Value::String(text) = value else {
+                        return Err(wrong_type("a string"));
+                    };
+                    match key.as_str() {
+                        "chaos.version" => config.chaos_version = Some(text),
+                        "chaos.hostname" => config.chaos_hostname = Some(text),
+                        _ => config.chaos_id = Some(text),
+                    }
+                }
+                "acme.listen" => {
+                    let addr = match value {
+                        Value::String(addr) => addr.parse().ok(),
+                        _ => None,
+                    };
+                    config.acme_listen =
+                        Some(addr.ok_or_else(|| wrong_type("an address with port"))?);
+                }
+                "acme.zone" => {
+                    let zone = match value {
+                        Value::String(zone) => zone.parse().ok(),
+                        _ => None,
+                    };
+                    config.acme_zone = Some(zone.ok_or_else(|| wrong_type("a domain name"))?);
+                }
+                "acme.token" => {
+                    let Value::String(token) = value else {
+                        return Err(wrong_type("a string"));
+                    };
+                    config.acme_token = Some(token);
+                }
+                "cache.size" => {
+                    let size = match value {
+                        Value::Integer(size) => usize::try_from(size).ok(),
+                        _ => None,
+                    };
+                    config.cache_size = size.ok_or_else(|| wrong_type("a non-negative integer"))?;
+                }
+                "response_cache.size" | "response_cache.max_age_secs" => {
+                    let number = match value {
+                        Value::Integer(number) => usize::try_from(number).ok(),
+                        _ => None,
+                    };
+                    let number = number.ok_or_else(|| wrong_type("a non-negative integer"))?;
+                    match key.as_str() {
+                        "response_cache.size" => config.response_cache_size = Some(number),
+                        _ => config.response_cache_max_age = Duration::from_secs(number as u64),
+                    }
+                }
+                "query_log.dir" => {
+                    let Value::String(dir) = value else {
+                        return Err(wrong_type("a path"));
+                    };
+                    config.query_log = Some(PathBuf::from(dir));
+                }
+                "query_log.max_bytes" | "query_log.rotate_secs" | "query_log.keep_secs" => {
+                    let number = match value {
+                        Value::Integer(number) => u64::try_from(number).ok(),
+                        _ => None,
+                    };
+                    let number = number.ok_or_else(|| wrong_type("a non-negative integer"))?;
+                    match key.as_str() {
+                        "query_log.max_bytes" => config.query_log_max_bytes = number,
+                        "query_log.rotate_secs" => {
+                            config.query_log_rotate = Duration::from_secs(number);
+                        }
+                        _ => config.query_log_keep = Some(Duration::from_secs(number)),
+                    }
+                }
+                "log.level" => {
+                    let Value::String(level) = value else {
+                        return Err(wrong_type("a string"));
+                    };
+                    config.log_level = level.parse().map_err(|err: DnsError| match err {
+                        DnsError::Config(message) => fail(message),
+                        other => other,
+                    })?;
+                }
+                "log.format" => {
+                    let Value::String(format) = value else {
+                        return Err(wrong_type("a string"));
+                    };
+                    config.log_format = format.parse().map_err(|err: DnsError| match err {
+                        DnsError::Config(message) => fail(message),
+                        other => other,
+                    })?;
+                }
+                "dnstap.socket" | "dnstap.file" => {
+                    let Value::String(path) = value else {
+                        return Err(wrong_type("a path"));
+                    };
+                    match key.as_str() {
+                        "dnstap.socket" => config.dnstap_socket = Some(PathBuf::from(path)),
+                        _ => config.dnstap_file = Some(PathBuf::from(path)),
+                    }
+                }
+                "dnstap.identity" => {
+                    let Value::String(identity) = value else {
+                        return Err(wrong_type("a string"));
+                    };
+                    config.dnstap_identity = Some(identity);
+                }
+                "rate_limit.qps" | "rate_limit.burst" => {
+                    let number = match value {
+                        Value::Integer(number) => u32::try_from(number).ok().filter(|&n| n > 0),
+                        _ => None,
+                    };
+                    let number = number.ok_or_else(|| wrong_type("a positive integer"))?;
+                    match key.as_str() {
+                        "rate_limit.qps" => config.rate_limit_qps = Some(number),
+                        _ => config.rate_limit_burst = Some(number),
+                    }
+                }
+                "rrl.responses_per_second" | "rrl.window" | "rrl.slip" => {
+                    let number = match value {
+                        Value::Integer(number) => u32::try_from(number).ok(),
+                        _ => None,
+                    };
+                    let number = number.ok_or_else(|| wrong_type("a non-negative integer"))?;
+                    match key.as_str() {
+                        "rrl.responses_per_second" => config.rrl_rate = Some(number),
+                        "rrl.window" => config.rrl_window = Duration::from_secs(number.into()),
+                        _ => config.rrl_slip = number,
+                    }
+                }
+                "acl.query" | "acl.recursion" | "acl.transfer" => {
+                    let networks =
+                        strings(&value).ok_or_else(|| wrong_type("an array of networks"))?;
+                    let acl = networks
+                        .into_iter()
+                        .map(str::parse)
+                        .collect::<Result<Acl, DnsError>>()
+                        .map_err(|err| match err {
+                            DnsError::Config(message) => fail(message),
+                            other => other,
+                        })?;
+                    match key.as_str() {
+                        "acl.query" => config.acl_query = Some(acl),
+                        "acl.recursion" => config.acl_recursion = Some(acl),
+                        _ => config.acl_transfer = Some(acl),
+                    }
+                }
+                "tsig.keys" => {
+                    let keys = strings(&value).ok_or_else(|| wrong_type("an array of keys"))?;
+                    config.tsig_keys = keys
+                        .into_iter()
+                        .map(str::parse)
+                        .collect::<Result<_, DnsError>>()
+                        .map_err(|err| match err {
+                            DnsError::Config(message) => fail(message),
+                            other => other,
+                        })?;
+                }
+                "stats.report_secs" => {
+                    let secs = match value {
+                        Value::Integer(secs) => u64::try_from(secs).ok().filter(|&n| n > 0),
+                        _ => None,
+                    };
+                    let secs = secs.ok_or_else(|| wrong_type("a positive integer"))?;
+                    config.stats_report = Some(Duration::from_secs(secs));
+                }
+                "stats.top_clients" => {
+                    let top = match value {
+                        Value::Integer(top) => usize::try_from(top).ok(),
+                        _ => None,
+                    };
+                    config.stats_top_clients =
+                        top.ok_or_else(|| wrong_type("a non-negative integer"))?;
+                }
+                "privacy.log" | "privacy.query_log" | "privacy.dnstap" | "privacy.stats" => {
+                    let privacy = match value {
+                        Value::String(mode) if mode == "off" => Privacy::Off,
+                        Value::String(mode) if mode == "truncate" => Privacy::Truncate,
+                        Value::String(mode) if mode == "hash" => Privacy::Hash,
+                        _ => return Err(wrong_type("\"off\", \"truncate\" or \"hash\"")),
+                    };
+                    match key.as_str() {
+                        "privacy.log" => config.privacy_log = privacy,
+                        "privacy.query_log" => config.privacy_query_log = privacy,
+                        "privacy.dnstap" => config.privacy_dnstap = privacy,
+                        _ => config.privacy_stats = privacy,
+                    }
+                }
+                "privacy.v4_prefix" | "privacy.v6_prefix" => {
+                    let max = if key == "privacy.v4_prefix" { 32 } else { 128 };
+                    let prefix = match value {
+                        Value::Integer(prefix) => u8::try_from(prefix).ok().filter(|&n| n <= max),
+                        _ => None,
+                    };
+                    let prefix = prefix
+                        .ok_or_else(|| wrong_type(&format!("a prefix length up to {max}")))?;
+                    match key.as_str() {
+                        "privacy.v4_prefix" => config.privacy_v4_prefix = prefix,
+                        _ => config.privacy_v6_prefix = prefix,
+                    }
+                }
+                "rate_limit.action" => {
+                    config.rate_limit_policy = match value {
+                        Value::String(action) if action == "drop" => RateLimitPolicy::Drop,
+                        Value::String(action) if action == "refuse" => RateLimitPolicy::Refused,
+                        _ => return Err(wrong_type("\"drop\" or \"refuse\"")),
+                    };
+                }
+                _ => return Err(fail(format!("unknown setting {key}"))),
+            }
+            seen.push(key);
+        }
+
+        if config.listen.is_empty() {
+            return Err(DnsError::Config(
+                "server.listen needs at least one address".to_string(),
+            ));
+        }
+        if config.recursive && !config.resolvers.is_empty() {
+            return Err(DnsError::Config(
+                "upstream.resolvers and upstream.recursive = true exclude each other".to_string(),
+            ));
+        }
+        if config.dnstap_socket.is_some() && config.dnstap_file.is_some() {
+            return Err(DnsError::Config(
+                "dnstap.socket and dnstap.file exclude each other".to_string(),
+            ));
+        }
+        if config.acme_listen.is_some()
+            && (config.acme_zone.is_none() || config.acme_token.is_none())
+        {
+            return Err(DnsError::Config(
+                "acme.listen needs acme.zone and acme.token".to_string(),
+            ));
+        }
+        let acl = config.acl_query.is_some() || config.acl_recursion.is_some();
+        if config.response_cache_size.is_some() && acl {
+            return Err(DnsError::Config(
+                "response_cache answers before the ACLs are checked, remove one of them"
+                    .to_string(),
+            ));
+        }
+        let dga_limit = matches!(config.dga_action, Some(DgaAction::RateLimit { .. }));
+        if config.response_cache_size.is_some() && dga_limit {
+            return Err(DnsError::Config(
+                "response_cache answers before DGA rate limits are counted, remove one of them"
+                    .to_string(),
+            ));
+        }
+        if let Some(DgaAction::RateLimit { per_minute }) = &mut config.dga_action {
+            *per_minute = config.dga_per_minute;
+        }
+        if config.split_routes.is_some() && config.split_command.is_some() {
+            return Err(DnsError::Config(
+                "split.routes and split.command exclude each other".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Settings of the file at `path`; relative paths are taken from its directory
+    pub fn read(path: impl AsRef<Path>) -> Result<Config, DnsError> {
+        let path = path.as_ref();
+        let in_file = |message| DnsError::Config(format!("{}: {message}", path.display()));
+        let text = fs::read_to_string(path).map_err(|err| in_file(err.to_string()))?;
+        let mut config = Config::parse(&text).map_err(|err| match err {
+            DnsError::Config(message) => in_file(message),
+            other => other,
+        })?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let files = config.zone_files.iter_mut().chain(&mut config.hosts_files);
+        let files = files.chain(config.leases.iter_mut().map(|(file, _)| file));
+        for file in files.chain(&mut config.blocklists) {
+            *file = dir.join(&file);
+        }
+        let paths = [
+            &mut config.query_log,
+            &mut config.dnstap_socket,
+            &mut config.dnstap_file,
+            &mut config.split_routes,
+        ];
+        for file in paths.into_iter().flatten() {
+            *file = dir.join(&file);
+        }
+        Ok(config)
+    }
+
+    /// Addresses to serve UDP and TCP on, [`DEFAULT_ADDR`] by default
+    pub fn listen(&self) -> &[SocketAddr] {
+        &self.listen
+    }
+
+    /// Addresses to serve DNS over HTTPS on, none by default
+    pub fn doh_listen(&self) -> &[SocketAddr] {
+        &self.doh_listen
+    }
+
+    /// Name of this node among the instances of the service
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// Upstreams to forward to, in order of preference; none to answer locally
+    pub fn resolvers(&self) -> &[SocketAddr] {
+        &self.resolvers
+    }
+
+    /// Whether to resolve from the root servers instead of forwarding
+    pub fn recursive(&self) -> bool {
+        self.recursive
+    }
+
+    pub fn zone_files(&self) -> &[PathBuf] {
+        &self.zone_files
+    }
+
+    /// Questions whose answers are cached, 10000 by default
+    pub fn cache_size(&self) -> usize {
+        self.cache_size
+    }
+
+    /// Responses replayed before the pipeline, if any, see [`crate::response_cache`]
+    pub fn response_cache_size(&self) -> Option<usize> {
+        self.response_cache_size
+    }
+
+    /// Longest a replayed response is kept, 30 seconds by default
+    pub fn response_cache_max_age(&self) -> Duration {
+        self.response_cache_max_age
+    }
+
+    pub fn log_level(&self) -> LogLevel {
+        self.log_level
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format
+    }
+
+    /// Directory of the query log, if queries are logged
+    pub fn query_log(&self) -> Option<&Path> {
+        self.query_log.as_deref()
+    }
+
+    /// Size past which the query log is rotated, 100 MB by default
+    pub fn query_log_max_bytes(&self) -> u64 {
+        self.query_log_max_bytes
+    }
+
+    /// Age past which the query log is rotated, a day by default
+    pub fn query_log_rotate(&self) -> Duration {
+        self.query_log_rotate
+    }
+
+    /// How long rotated query logs are kept, forever by default
+    pub fn query_log_keep(&self) -> Option<Duration> {
+        self.query_log_keep
+    }
+
+    /// Unix socket of the dnstap collector, if messages are sent to one
+    pub fn dnstap_socket(&self) -> Option<&Path> {
+        self.dnstap_socket.as_deref()
+    }
+
+    /// File messages are written to in dnstap format, if any
+    pub fn dnstap_file(&self) -> Option<&Path> {
+        self.dnstap_file.as_deref()
+    }
+
+    /// Name of the server in dnstap messages
+    pub fn dnstap_identity(&self) -> Option<&str> {
+        self.dnstap_identity.as_deref()
+    }
+
+    /// Queries per second each client may send, unlimited by default
+    pub fn rate_limit_qps(&self) -> Option<u32> {
+        self.rate_limit_qps
+    }
+
+    /// Queries a client may send at once, the rate by default
+    pub fn rate_limit_burst(&self) -> Option<u32> {
+        self.rate_limit_burst
+    }
+
+    /// What happens to queries over the rate limit, dropped by default
+    pub fn rate_limit_policy(&self) -> RateLimitPolicy {
+        self.rate_limit_policy
+    }
+
+    /// Identical responses per second to a client network, unlimited by default
+    pub fn rrl_rate(&self) -> Option<u32> {
+        self.rrl_rate
+    }
+
+    /// Excess response rate limiting remembers, 15 seconds by default
+    pub fn rrl_window(&self) -> Duration {
+        self.rrl_window
+    }
+
+    /// Every how many limited responses one is truncated rather than dropped, 2 by default
+    pub fn rrl_slip(&self) -> u32 {
+        self.rrl_slip
+    }
+
+    /// Hosts files answered from, [`SYSTEM_HOSTS`] by default
+    pub fn hosts_files(&self) -> &[PathBuf] {
+        &self.hosts_files
+    }
+
+    /// TTL of the answers from hosts files, 0 by default
+    pub fn hosts_ttl(&self) -> u32 {
+        self.hosts_ttl
+    }
+
+    /// DHCP lease files and their layouts
+    pub fn leases(&self) -> &[(PathBuf, LeaseFormat)] {
+        &self.leases
+    }
+
+    /// Domain DHCP clients are served under, `lan` by default
+    pub fn lease_domain(&self) -> &DnsLabels {
+        &self.lease_domain
+    }
+
+    /// Lists of domains to block
+    pub fn blocklists(&self) -> &[PathBuf] {
+        &self.blocklists
+    }
+
+    /// Addresses answered for blocked names; none to answer NXDOMAIN
+    pub fn sinkhole(&self) -> &[IpAddr] {
+        &self.sinkhole
+    }
+
+    /// What happens to lookups of names that look generated, nothing by default
+    pub fn dga_action(&self) -> Option<DgaAction> {
+        self.dga_action
+    }
+
+    /// Score above which names count as generated, 0.6 by default
+    pub fn dga_threshold(&self) -> f64 {
+        self.dga_threshold
+    }
+
+    /// File of split DNS routes, see [`crate::split`]
+    pub fn split_routes(&self) -> Option<&Path> {
+        self.split_routes.as_deref()
+    }
+
+    /// Command printing split DNS routes, run with `sh -c`
+    pub fn split_command(&self) -> Option<&str> {
+        self.split_command.as_deref()
+    }
+
+    /// Whether `.local` names are resolved over multicast DNS, see [`crate::mdns`]
+    pub fn mdns(&self) -> bool {
+        self.mdns
+    }
+
+    /// HTTP address of the Consul agent whose services are served, if any
+    pub fn consul_agent(&self) -> Option<&str> {
+        self.consul_agent.as_deref()
+    }
+
+    /// Name Consul services are served under, `service.consul` by default
+    pub fn consul_suffix(&self) -> &DnsLabels {
+        &self.consul_suffix
+    }
+
+    /// Answer to CHAOS `version.bind`, refused by default
+    pub fn chaos_version(&self) -> Option<&str> {
+        self.chaos_version.as_deref()
+    }
+
+    /// Answer to CHAOS `hostname.bind`, refused by default
+    pub fn chaos_hostname(&self) -> Option<&str> {
+        self.chaos_hostname.as_deref()
+    }
+
+    /// Answer to CHAOS `id.server`, refused by default
+    pub fn chaos_id(&self) -> Option<&str> {
+        self.chaos_id.as_deref()
+    }
+
+    /// Address of the ACME challenge API, if it is served
+    pub fn acme_listen(&self) -> Option<SocketAddr> {
+        self.acme_listen
+    }
+
+    /// Zone acme-dns accounts get their subdomains under, set along with [`Self::acme_listen`]
+    pub fn acme_zone(&self) -> Option<&DnsLabels> {
+        self.acme_zone.as_ref()
+    }
+
+    /// Bearer token of the ACME `/txt` API, set along with [`Self::acme_listen`]
+    pub fn acme_token(&self) -> Option<&str> {
+        self.acme_token.as_deref()
+    }
+
+    /// Networks that may query, everyone by default
+    pub fn acl_query(&self) -> Option<&Acl> {
+        self.acl_query.as_ref()
+    }
+
+    /// Networks that get queries resolved upstream, everyone by default
+    pub fn acl_recursion(&self) -> Option<&Acl> {
+        self.acl_recursion.as_ref()
+    }
+
+    /// Networks that may request zone transfers, everyone by default
+    pub fn acl_transfer(&self) -> Option<&Acl> {
+        self.acl_transfer.as_ref()
+    }
+
+    /// Keys queries may be signed with; signed zone transfers and updates only when any
+    pub fn tsig_keys(&self) -> &[Key] {
+        &self.tsig_keys
+    }
+
+    /// How often the busiest clients are logged, never by default
+    pub fn stats_report(&self) -> Option<Duration> {
+        self.stats_report
+    }
+
+    /// Number of clients each report lists, 10 by default
+    pub fn stats_top_clients(&self) -> usize {
+        self.stats_top_clients
+    }
+
+    /// How the server log records clients
+    pub fn log_anonymizer(&self) -> Anonymizer {
+        self.anonymizer(self.privacy_log)
+    }
+
+    /// How the query log records clients
+    pub fn query_log_anonymizer(&self) -> Anonymizer {
+        self.anonymizer(self.privacy_query_log)
+    }
+
+    /// How dnstap messages record clients
+    pub fn dnstap_anonymizer(&self) -> Anonymizer {
+        self.anonymizer(self.privacy_dnstap)
+    }
+
+    /// How the client stats record clients
+    pub fn stats_anonymizer(&self) -> Anonymizer {
+        self.anonymizer(self.privacy_stats)
+    }
+
+    fn anonymizer(&self, privacy: Privacy) -> Anonymizer {
+        match privacy {
+            Privacy::Off => Anonymizer::off(),
+            Privacy::Truncate => {
+                Anonymizer::truncate(self.privacy_v4_prefix, self.privacy_v6_prefix)
+            }
+            Privacy::Hash => Anonymizer::hash(),
+        }
+    }
+
+    pub fn with_listen(mut self, listen: Vec<SocketAddr>) -> Self {
+        self.listen = listen;
+        self
+    }
+
+    pub fn with_resolvers(mut self, resolvers: Vec<SocketAddr>) -> Self {
+        self.resolvers = resolvers;
+        self
+    }
+
+    pub fn with_recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    pub fn with_zone_files(mut self, zone_files: Vec<PathBuf>) -> Self {
+        self.zone_files = zone_files;
+        self
+    }
+
+    pub fn with_log_level(mut self, log_level: LogLevel) -> Self {
+        self.log_level = log_level;
+        self
+    }
+
+    pub fn with_log_format(mut self, log_format: LogFormat) -> Self {
+        self.log_format = log_format;
+        self
+    }
+
+    pub fn with_query_log(mut self, dir: PathBuf) -> Self {
+        self.query_log = Some(dir);
+        self
+    }
+}
+
+fn strings(value: &Value) -> Option<Vec<&str>> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::String(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Socket addresses in `value`; bare IP addresses get `default_port` if there is one
+fn addrs(value: &Value, default_port: Option<u16>) -> Option<Vec<SocketAddr>> {
+    strings(value)?
+        .into_iter()
+        .map(|text| match (text.parse(), default_port) {
+            (Ok(addr), _) => Some(addr),
+            (Err(_), Some(port)) => Some(SocketAddr::new(text.parse::<IpAddr>().ok()?, port)),
+            (Err(_), None) => None,
+        })
+        .collect()
+}
+
+/// Address of an upstream resolver: `ip`, `ip:port`, or the `sdns://` stamp of a plain DNS
+/// resolver
+fn resolver(text: &str) -> Result<SocketAddr, String> {
+    if !text.starts_with("sdns://") {
+        let addr = text.parse().ok();
+        let addr = addr.or_else(|| Some(SocketAddr::new(text.parse().ok()?, 53)));
+        return addr.ok_or_else(|| format!("invalid resolver address {text}"));
+    }
+    let stamp: Stamp = text
+        .parse()
+        .map_err(|err| format!("invalid stamp {text}: {err}"))?;
+    if stamp.protocol() != Protocol::Plain {
+        return Err(format!(
+            "stamp {text} is for {:?}, only plain DNS upstreams are supported",
+            stamp.protocol()
+        ));
+    }
+    stamp
+        .addr()
+        .ok_or_else(|| format!("stamp {text} has no address"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            input: text.as_bytes(),
+            at: 0,
+        }
+    }
+
+    fn line(&self) -> usize {
+        1 + self.input[..self.at]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+    }
+
+    fn error(&self, message: impl fmt::Display) -> DnsError {
+        DnsError::Config(format!("line {}: {message}", self.line()))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.at).copied()
+    }
+
+    /// Skips spaces and tabs, and newlines and comments too if `lines`
+    fn skip(&mut self, lines: bool) {
+        loop {
+            match self.peek() {
+                Some(b' ' | b'\t' | b'\r') => self.at += 1,
+                Some(b'\n') if lines => self.at += 1,
+                Some(b'#') => {
+                    while self.peek().is_some_and(|b| b != b'\n') {
+                        self.at += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// `table.key`, value and line of every setting
+    fn document(mut self) -> Result<Vec<(usize, String, Value)>, DnsError> {
+        let mut settings = Vec::new();
+        let mut table = String::new();
+        loop {
+            self.skip(true);
+            let Some(byte) = self.peek() else {
+                return Ok(settings);
+            };
+            if byte == b'[' {
+                self.at += 1;
+                self.skip(false);
+                table = self.key()?;
+                self.skip(false);
+                if self.peek() != Some(b']') {
+                    return Err(self.error("expected ] after the table name"));
+                }
+                self.at += 1;
+            } else {
+                let line = self.line();
+                let key = self.key()?;
+                self.skip(false);
+                if self.peek() != Some(b'=') {
+                    return Err(self.error(format!("expected = after {key}")));
+                }
+                self.at += 1;
+                self.skip(false);
+                let value = self.value()?;
+                let key = if table.is_empty() {
+                    key
+                } else {
+                    format!("{table}.{key}")
+                };
+                settings.push((line, key, value));
+            }
+            self.skip(false);
+            if !matches!(self.peek(), None | Some(b'\n')) {
+                return Err(self.error("unexpected text at the end of the line"));
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String, DnsError> {
+        let start = self.at;
+        while self
+            .peek()
+            .is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        {
+            self.at += 1;
+        }
+        if start == self.at {
+            return Err(self.error("expected a name"));
+        }
+        Ok(String::from_utf8_lossy(&self.input[start..self.at]).into_owned())
+    }
+
+    fn value(&mut self) -> Result<Value, DnsError> {
+        match self.peek() {
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'\'') => {
+                self.at += 1;
+                let start = self.at;
+                while self.peek().is_some_and(|b| b != b'\'' && b != b'\n') {
+                    self.at += 1;
+                }
+                if self.peek() != Some(b'\'') {
+                    return Err(self.error("unterminated string"));
+                }
+                self.at += 1;
+                let text = &self.input[start..self.at - 1];
+                Ok(Value::String(String::from_utf8_lossy(text).into_owned()))
+            }
+            Some(b'[') => {
+                self.at += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip(true);
+                    if self.peek() == Some(b']') {
+                        self.at += 1;
+                        return Ok(Value::Array(items));
+                    }
+                    items.push(self.value()?);
+                    self.skip(true);
+                    match self.peek() {
+                        Some(b',') => self.at += 1,
+                        Some(b']') => {}
+                        _ => return Err(self.error("expected , or ] in array")),
+                    }
+                }
+            }
+            _ => {
+                let start = self.at;
+                while self
+                    .peek()
+                    .is_some_and(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'+'))
+                {
+                    self.at += 1;
+                }
+                let word = String::from_utf8_lossy(&self.input[start..self.at]);
+                match word.as_ref() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    word => word
+                        .replace('_', "")
+                        .parse()
+                        .map(Value::Integer)
+                        .map_err(|_| self.error(format!("invalid value {word:?}"))),
+                }
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, DnsError> {
+        self.at += 1;
+        let mut out = Vec::new();
+        loop {
+            let byte = match self.peek() {
+                None | Some(b'\n') => return Err(self.error("unterminated string")),
+                Some(byte) => byte,
+            };
+            self.at += 1;
+            match byte {
+                b'"' => {
+                    return String::from_utf8(out).map_err(|_| self.error("invalid UTF-8"));
+                }
+                b'\\' => {
+                    let escaped = match self.peek() {
+                        Some(b'"') => b'"',
+                        Some(b'\\') => b'\\',
+                        Some(b'n') => b'\n',
+                        Some(b't') => b'\t',
+                        _ => return Err(self.error("unsupported escape in string")),
+                    };
+                    self.at += 1;
+                    out.push(escaped);
+                }
+                byte => out.push(byte),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+# forwarding resolver for the lab
+[server]
+listen = ["0.0.0.0:53", "[::]:53"]
+doh_listen = ["127.0.0.1:8053"]
+identity = "lab1"
+
+[upstream]
+resolvers = [
+    "1.1.1.1",          # port 53
+    '9.9.9.9:5353',
+    "sdns://AAEAAAAAAAAABzkuOS45Ljk",  # 9.9.9.9, DNSSEC
+]
+
+[zones]
+files = ["lab.internal.zone"]
+
+[cache]
+size = 50_000
+
+[hosts]
+files = ["lab.hosts"]
+ttl = 30
+
+[leases]
+kea = ["kea-leases4.csv"]
+domain = "lab.internal"
+
+[blocklist]
+files = ["ads.txt"]
+sinkhole = ["0.0.0.0"]
+
+[mdns]
+enabled = true
+
+[consul]
+agent = "127.0.0.1:8500"
+
+[chaos]
+hostname = "lab-ns1"
+id = "ns1"
+
+[acme]
+listen = "127.0.0.1:8080"
+zone = "acme.lab.internal"
+token = "s3cret"
+
+[dga]
+action = "rate_limit"
+per_minute = 5
+threshold_percent = 70
+
+[split]
+routes = "/etc/dns/routes.txt"
+
+[log]
+level = "INFO"
+format = "json"
+
+[query_log]
+dir = "/var/log/dns"
+keep_secs = 604_800
+
+[acl]
+query = ["10.0.0.0/8", "::1"]
+transfer = []
+
+[tsig]
+keys = ["xfr.example:c2VjcmV0"]
+
+[stats]
+report_secs = 60
+
+[privacy]
+query_log = "truncate"
+dnstap = "hash"
+v4_prefix = 16
+
+[rate_limit]
+qps = 20
+action = "refuse"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen(),
+            ["0.0.0.0:53".parse().unwrap(), "[::]:53".parse().unwrap()]
+        );
+        assert_eq!(
+            config.resolvers(),
+            [
+                "1.1.1.1:53".parse().unwrap(),
+                "9.9.9.9:5353".parse().unwrap(),
+                "9.9.9.9:53".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.doh_listen(), ["127.0.0.1:8053".parse().unwrap()]);
+        assert_eq!(config.identity(), Some("lab1"));
+        assert!(!config.recursive());
+        assert_eq!(config.zone_files(), [PathBuf::from("lab.internal.zone")]);
+        assert_eq!(config.cache_size(), 50_000);
+        assert_eq!(config.response_cache_size(), None);
+        assert_eq!(config.response_cache_max_age(), Duration::from_secs(30));
+        assert_eq!(config.hosts_files(), [PathBuf::from("lab.hosts")]);
+        assert_eq!(config.hosts_ttl(), 30);
+        assert_eq!(
+            config.leases(),
+            [(PathBuf::from("kea-leases4.csv"), LeaseFormat::KeaCsv)]
+        );
+        assert_eq!(config.lease_domain(), &"lab.internal".into());
+        assert_eq!(config.blocklists(), [PathBuf::from("ads.txt")]);
+        assert_eq!(config.sinkhole(), [IpAddr::from([0, 0, 0, 0])]);
+        assert_eq!(
+            config.split_routes(),
+            Some(Path::new("/etc/dns/routes.txt"))
+        );
+        assert_eq!(config.split_command(), None);
+        assert_eq!(
+            config.dga_action(),
+            Some(DgaAction::RateLimit { per_minute: 5 })
+        );
+        assert_eq!(config.dga_threshold(), 0.7);
+        assert!(config.mdns());
+        assert_eq!(config.consul_agent(), Some("127.0.0.1:8500"));
+        assert_eq!(config.consul_suffix(), &"service.consul".into());
+        assert_eq!(config.chaos_version(), None);
+        assert_eq!(config.chaos_hostname(), Some("lab-ns1"));
+        assert_eq!(config.chaos_id(), Some("ns1"));
+        assert_eq!(
+            config.acme_listen(),
+            Some("127.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(config.acme_zone(), Some(&"acme.lab.internal".into()));
+        assert_eq!(config.acme_token(), Some("s3cret"));
+        assert_eq!(config.log_level(), LogLevel::Info);
+        assert_eq!(config.log_format(), LogFormat::Json);
+        assert_eq!(config.query_log(), Some(Path::new("/var/log/dns")));
+        assert_eq!(config.query_log_max_bytes(), 100_000_000);
+        assert_eq!(config.rate_limit_qps(), Some(20));
+        let query = config.acl_query().unwrap();
+        assert!(query.contains([10, 9, 8, 7].into()) && !query.contains([192, 0, 2, 1].into()));
+        assert_eq!(config.acl_recursion(), None);
+        assert_eq!(config.acl_transfer(), Some(&Acl::new()));
+        assert_eq!(config.tsig_keys()[0].name(), &"xfr.example".into());
+        assert_eq!(config.stats_report(), Some(Duration::from_secs(60)));
+        assert_eq!(config.stats_top_clients(), 10);
+        let client: IpAddr = [192, 0, 2, 77].into();
+        assert_eq!(
+            config.log_anonymizer().client(client).to_string(),
+            "192.0.2.77"
+        );
+        let truncated = config.query_log_anonymizer().client(client);
+        assert_eq!(truncated.to_string(), "192.0.0.0");
+        assert!(config
+            .dnstap_anonymizer()
+            .client(client)
+            .to_string()
+            .starts_with('#'));
+        assert_eq!(config.rate_limit_policy(), RateLimitPolicy::Refused);
+        assert_eq!(
+            config.query_log_keep(),
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text: &str| Config::parse(text).unwrap_err().to_string();
+
+        assert!(
+            error("[cache]\nsize = 1\nttl = 60\n").contains("line 3: unknown setting cache.ttl")
+        );
+        assert!(error("[server]\nlisten = \"127.0.0.1:53\"\n")
+            .contains("line 2: server.listen must be"));
+        assert!(error("[server]\nlisten = [\"127.0.0.1\"]\n").contains("with ports"));
+        assert!(error("[log]\nlevel = \"loud\"\n").contains("unknown log level loud"));
+        assert!(
+            error("[upstream]\nresolvers = [\"1.1.1.1\"]\nrecursive = true\n").contains("exclude")
+        );
+        let doh = "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5";
+        assert!(error(&format!("[upstream]\nresolvers = [\"{doh}\"]\n")).contains("plain DNS"));
+        assert!(error("[acl]\nquery = [\"10.0.0.0/40\"]\n").contains("line 2: invalid network"));
+        assert!(error("[dnstap]\nsocket = \"a\"\nfile = \"b\"\n").contains("exclude"));
+        assert!(error("[acme]\nlisten = \"127.0.0.1:8080\"\n").contains("acme.zone"));
+        assert!(error("[response_cache]\nsize = 100\n[acl]\nquery = []\n").contains("ACLs"));
+        assert!(
+            error("[response_cache]\nsize = 100\n[dga]\naction = \"rate_limit\"\n")
+                .contains("DGA rate limits")
+        );
+        assert!(error("[split]\nroutes = \"a\"\ncommand = \"b\"\n").contains("exclude"));
+        assert!(error("[zones]\nfiles = [\"a.zone\"\n").contains("expected , or ]"));
+        assert!(error("[cache]\nsize = 1 2\n").contains("line 2: unexpected text"));
+    }
+}
@@ -0,0 +1,350 @@
+//! Service discovery zone backed by a Consul agent
+//!
+//! [`ConsulLayer`] serves the healthy instances of every service in the Consul catalog under a
+//! configured suffix, refreshed in the background, see [`ConsulLayer::watch`]. For the suffix
+//! `service.consul`:
+//!
+//! - `web.service.consul` A/AAAA: the addresses of the passing instances of `web`
+//! - `web.service.consul` or `_web._tcp.service.consul` SRV: their ports, targeting
+//!   `<node>.web.service.consul`
+//! - `<node>.web.service.consul` A/AAAA: the address of the instance on that node
+//!
+//! Records carry a short TTL so clients follow instances coming and going.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::dns::{class, rcode, rtype, DnsLabels, DnsMessage, DnsRecord, MessageBuilder};
+use crate::handler::RequestCtx;
+use crate::http;
+use crate::json::Json;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::rdata::RData;
+use crate::warn;
+
+/// A passing instance of a service
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Instance {
+    node: String,
+    addr: IpAddr,
+    port: u16,
+}
+
+impl Instance {
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// Instances by lowercase service name
+pub type Services = HashMap<String, Vec<Instance>>;
+
+type Table = RwLock<Option<Arc<Services>>>;
+
+/// Answers questions under a suffix from the Consul catalog
+pub struct ConsulLayer {
+    agent: String,
+    suffix: DnsLabels,
+    ttl: u32,
+    table: Arc<Table>,
+}
+
+impl ConsulLayer {
+    /// Layer serving `suffix` from the HTTP API of the agent at `agent` (`host:port`, usually
+    /// `127.0.0.1:8500`). Nothing is fetched until [`ConsulLayer::refresh`] or
+    /// [`ConsulLayer::watch`]; until then questions under the suffix get SERVFAIL.
+    pub fn new(agent: impl Into<String>, suffix: DnsLabels) -> Self {
+        Self {
+            agent: agent.into(),
+            suffix,
+            ttl: 5,
+            table: Arc::default(),
+        }
+    }
+
+    /// TTL of the records served, 5s by default
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Fetches the catalog now and then every `interval` on a background task that ends with
+    /// the layer. A failed fetch is logged and the previous instances are kept. Must be called
+    /// inside a tokio runtime.
+    pub fn watch(self, interval: Duration) -> Self {
+        let table = Arc::downgrade(&self.table);
+        tokio::spawn(watch(self.agent.clone(), table, interval));
+        self
+    }
+
+    /// Replaces the served instances with the current catalog
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let services = fetch(&self.agent).await?;
+        *self.table.write().unwrap() = Some(Arc::new(services));
+        Ok(())
+    }
+
+    pub fn services(&self) -> Option<Arc<Services>> {
+        self.table.read().unwrap().clone()
+    }
+
+    /// Labels of `name` before the suffix, if it is under it
+    fn relative<'n>(&self, name: &'n DnsLabels) -> Option<Vec<&'n [u8]>> {
+        let mut labels: Vec<&[u8]> = name.labels().collect();
+        let suffix: Vec<&[u8]> = self.suffix.labels().collect();
+        let split = labels.len().checked_sub(suffix.len())?;
+        let under = labels[split..]
+            .iter()
+            .zip(&suffix)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b));
+        if !under || split == 0 {
+            return None;
+        }
+        labels.truncate(split);
+        Some(labels)
+    }
+
+    fn answer(&self, query: &DnsMessage) -> Option<DnsMessage> {
+        let mut questions = query.questions();
+        let question = questions.next()?;
+        if questions.next().is_some() || question.qclass() != class::IN {
+            return None;
+        }
+        let name = question.qname();
+        let relative = self.relative(name)?;
+        let response = MessageBuilder::response_to(query).add_question(question.clone());
+        let Some(services) = self.services() else {
+            return Some(response.rcode(rcode::SERVFAIL).build());
+        };
+        let response = response.authoritative(true);
+
+        let label = |label: &[u8]| String::from_utf8_lossy(label).to_ascii_lowercase();
+        let (service, node, srv_only) = match relative.as_slice() {
+            [service] => (label(service), None, false),
+            [service, proto]
+                if service.starts_with(b"_") && proto.eq_ignore_ascii_case(b"_tcp") =>
+            {
+                (label(&service[1..]), None, true)
+            }
+            [node, service] => (label(service), Some(label(node)), false),
+            _ => return Some(response.rcode(rcode::NXDOMAIN).build()),
+        };
+        let instances: Vec<&Instance> = services
+            .get(&service)
+            .into_iter()
+            .flatten()
+            .filter(|instance| match &node {
+                Some(node) => instance.node == *node,
+                None => true,
+            })
+            .collect();
+        if instances.is_empty() {
+            return Some(response.rcode(rcode::NXDOMAIN).build());
+        }
+
+        let answers: Vec<DnsRecord> = match question.qtype() {
+            rtype::A | rtype::AAAA if !srv_only => {
+                let want_v4 = question.qtype() == rtype::A;
+                instances
+                    .iter()
+                    .filter(|instance| instance.addr.is_ipv4() == want_v4)
+                    .map(|instance| DnsRecord::with_rdata(name.clone(), self.ttl, instance.addr))
+                    .collect()
+            }
+            rtype::SRV if node.is_none() => instances
+                .iter()
+                .filter_map(|instance| {
+                    let target = format!("{}.{service}.{}", instance.node, self.suffix);
+                    let srv = RData::Srv {
+                        priority: 1,
+                        weight: 1,
+                        port: instance.port,
+                        target: target.parse().ok()?,
+                    };
+                    Some(DnsRecord::with_rdata(name.clone(), self.ttl, srv))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(
+            answers
+                .into_iter()
+                .fold(response, MessageBuilder::add_answer)
+                .build(),
+        )
+    }
+}
+
+impl Layer for ConsulLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            match self.answer(&query) {
+                Some(response) => response,
+                None => next.run(query, ctx).await,
+            }
+        })
+    }
+}
+
+async fn get_json(agent: &str, path: &str) -> anyhow::Result<Json> {
+    let body = http::get(agent, path).await?;
+    let text = std::str::from_utf8(&body).context("body not UTF-8")?;
+    Json::parse(text).with_context(|| format!("invalid JSON from {path}"))
+}
+
+/// Passing instances of every service whose name can be a DNS label
+async fn fetch(agent: &str) -> anyhow::Result<Services> {
+    let catalog = get_json(agent, "/v1/catalog/services").await?;
+    let names = catalog.as_object().context("catalog is not an object")?;
+
+    let mut services = Services::new();
+    for (name, _) in names {
+        let valid = !name.is_empty()
+            && name.len() <= 63
+            && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        if !valid {
+            continue;
+        }
+        let health = get_json(agent, &format!("/v1/health/service/{name}?passing=true")).await?;
+        let instances = health
+            .as_array()
+            .context("health entries are not an array")?
+            .iter()
+            .filter_map(instance)
+            .collect();
+        services.insert(name.to_ascii_lowercase(), instances);
+    }
+    Ok(services)
+}
+
+/// Instance of a `/v1/health/service` entry; the service address falls back to the node's
+fn instance(entry: &Json) -> Option<Instance> {
+    let node = entry.get("Node")?;
+    let service = entry.get("Service")?;
+    let addr = match service.get("Address").and_then(Json::as_str) {
+        Some(addr) if !addr.is_empty() => addr,
+        _ => node.get("Address")?.as_str()?,
+    };
+    Some(Instance {
+        node: node.get("Node")?.as_str()?.to_ascii_lowercase(),
+        addr: addr.parse().ok()?,
+        port: service.get("Port")?.as_f64()? as u16,
+    })
+}
+
+async fn watch(agent: String, table: Weak<Table>, interval: Duration) {
+    loop {
+        let result = fetch(&agent).await;
+        let Some(table) = table.upgrade() else {
+            return;
+        };
+        match result {
+            Ok(services) => *table.write().unwrap() = Some(Arc::new(services)),
+            Err(err) => warn!("failed to refresh services from {agent} with {err:#}"),
+        }
+        drop(table);
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    /// Agent answering the two endpoints the layer uses
+    async fn fake_agent() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]);
+                let body = if request.starts_with("GET /v1/catalog/services ") {
+                    r#"{"consul": [], "web": ["v1"]}"#
+                } else if request.starts_with("GET /v1/health/service/web?") {
+                    r#"[{"Node": {"Node": "node1", "Address": "10.0.0.1"},
+                        "Service": {"Address": "", "Port": 8080}},
+                       {"Node": {"Node": "node2", "Address": "10.0.0.2"},
+                        "Service": {"Address": "10.0.1.2", "Port": 8081}}]"#
+                } else {
+                    "[]"
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        addr.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let layer = ConsulLayer::new(fake_agent().await, "service.consul".into());
+        layer.refresh().await.unwrap();
+        let pipeline = Pipeline::new(DefaultHandler).layer(layer);
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+        let ask =
+            |name: &str, qtype| pipeline.handle(DnsMessage::query(1, name, qtype), ctx.clone());
+
+        let response = ask("web.service.consul", rtype::A).await;
+        let addrs: Vec<IpAddr> = response
+            .answers()
+            .map(|answer| IpAddr::try_from(answer).unwrap())
+            .collect();
+        assert_eq!(
+            addrs,
+            [IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 1, 2])]
+        );
+        assert_eq!(response.answers().next().unwrap().ttl(), 5);
+
+        let response = ask("_web._tcp.service.consul", rtype::SRV).await;
+        let srv = response.answers().nth(1).unwrap().rdata().unwrap();
+        assert_eq!(
+            srv,
+            RData::Srv {
+                priority: 1,
+                weight: 1,
+                port: 8081,
+                target: "node2.web.service.consul".into()
+            }
+        );
+
+        let response = ask("NODE2.web.service.consul", rtype::A).await;
+        assert_eq!(response.answers().count(), 1);
+
+        let response = ask("db.service.consul", rtype::A).await;
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+
+        // outside the suffix
+        let response = ask("codecrafters.io", rtype::A).await;
+        assert!(!response.header().authoritative());
+    }
+}
@@ -0,0 +1,238 @@
+//! Heuristic detection of algorithmically generated domain names
+//!
+//! Malware finds its command servers by looking up names made by a domain generation algorithm
+//! (DGA), which look like `xjw3k9qzv7mpl2h.com` rather than words. [`score`] rates how random
+//! the most distinctive label of a name looks and [`DgaLayer`] acts on names above a threshold:
+//! logging them, rate limiting them per client, or blocking them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dns::{error_response, rcode, DnsLabels, DnsMessage};
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::warn;
+
+/// The most frequent letter pairs of English text, which make up most pairs of real names
+const COMMON_BIGRAMS: &str = "th he in er an re on at en nd ti es or te of ed is it al ar st to \
+    nt ng se ha as ou io le ve co me de hi ri ro ic ne ea ra ce li ch ll be ma si om ur ca el ta \
+    la ns di fo ho pe ec pr no ct us ac ot il tr ly nc et ut ss so rs un lo wa ge ie wh ee wi em \
+    ad ol rt po we na ul ni ts mo ow pa im mi ai sh ir su id os iv ia am fi ci vi pl ig tu ev ld \
+    ry mp fe bl ab gh ty op wo sa ay ex ke fr oo av ag if ap gr od bo sp rd do uc bu ei ov by rm \
+    ep tt oc fa ef cu rn sc gi da yo cr cl du ga qu ue ff ba ey ls va um pp ua up lu go ht ru ug \
+    ds lt pi rc rr eg au ck ew mu br bi pt ak pu ui rg ib tl ny ki rk ys ob mm fu ph og ms ye ud \
+    mb ip ub oi rl gu dr hr cc tw ft wn nu af hu nn eo vo rv nf xp gn sm fl iz ok nl my gl aw ju \
+    oa sy sl ps jo lf nk kn gs dy hy ze ks xt bs ik dd cy rp sk oy ws lv dl eu wr ka";
+
+/// Labels shorter than this are too short to judge and score 0
+const MIN_LABEL_LEN: usize = 8;
+
+/// Randomness of `name` between 0 (reads like words) and 1 (looks generated)
+///
+/// The longest label other than the top-level one is judged on three signs: the Shannon entropy
+/// of its characters, the share of its letter pairs that are rare in English, and how digits are
+/// mixed into letters. Long labels count fully, short ones are scaled down.
+pub fn score(name: &DnsLabels) -> f64 {
+    let labels: Vec<&[u8]> = name.labels().collect();
+    let candidates = match labels.len() {
+        0 => return 0.0,
+        1 => &labels[..],
+        n => &labels[..n - 1],
+    };
+    let label = candidates
+        .iter()
+        .max_by_key(|label| label.len())
+        .expect("at least one label");
+    let label = label.to_ascii_lowercase();
+    if label.len() < MIN_LABEL_LEN {
+        return 0.0;
+    }
+
+    let mut counts = [0usize; 256];
+    for &b in &label {
+        counts[b as usize] += 1;
+    }
+    let len = label.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    // the entropy of a label with all characters distinct, capped by the 37 character alphabet
+    let max_entropy = len.min(37.0).log2();
+    let entropy = entropy / max_entropy;
+
+    // a letter next to a digit is as unlikely in a word as a rare pair of letters
+    let rare = label
+        .windows(2)
+        .filter(|pair| match pair {
+            [a, b] if a.is_ascii_digit() && b.is_ascii_digit() => false,
+            [a, b] if a.is_ascii_lowercase() && b.is_ascii_lowercase() => !COMMON_BIGRAMS
+                .split_whitespace()
+                .any(|common| common.as_bytes() == *pair),
+            _ => true,
+        })
+        .count() as f64
+        / (len - 1.0);
+
+    // digits between letters, as in `a1b2`, rather than a trailing number as in `web01`
+    let switches = label
+        .windows(2)
+        .filter(|pair| pair[0].is_ascii_digit() != pair[1].is_ascii_digit())
+        .count();
+    let mixing = (switches as f64 / (len / 2.0)).min(1.0);
+
+    let randomness = 0.35 * entropy + 0.45 * rare + 0.2 * mixing;
+    let length = (len / 16.0).min(1.0);
+    randomness * (0.6 + 0.4 * length)
+}
+
+/// What to do with a name scoring above the threshold
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DgaAction {
+    /// Log the lookup and answer it normally
+    Log,
+    /// Let each client look up `per_minute` suspicious names, then refuse the rest of the minute
+    RateLimit { per_minute: u32 },
+    /// Answer NXDOMAIN, as sinkholing resolvers do
+    Block,
+}
+
+/// Policy stage scoring the names of queries with [`score`]
+pub struct DgaLayer {
+    action: DgaAction,
+    threshold: f64,
+    /// Suspicious lookups per client in the current window, which starts at the instant
+    windows: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl DgaLayer {
+    pub fn new(action: DgaAction) -> Self {
+        Self {
+            action,
+            threshold: 0.6,
+            windows: Mutex::default(),
+        }
+    }
+
+    /// Score above which names are acted on, 0.6 by default. Lower values catch more generated
+    /// names at the cost of flagging more real ones.
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Whether `client` is still within its allowance of suspicious lookups
+    fn allow(&self, client: IpAddr, now: Instant, per_minute: u32) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() > 10_000 {
+            windows.retain(|_, (start, _)| now - *start < Duration::from_secs(60));
+        }
+        let (start, count) = windows.entry(client).or_insert((now, 0));
+        if now - *start >= Duration::from_secs(60) {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= per_minute
+    }
+}
+
+impl Layer for DgaLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let suspicious = query
+                .questions()
+                .map(|question| (question.qname(), score(question.qname())))
+                .find(|(_, score)| *score > self.threshold);
+            let Some((name, score)) = suspicious else {
+                return next.run(query, ctx).await;
+            };
+            let client = ctx.client().ip();
+            warn!("possible DGA lookup of {name} (score {score:.2}) from {client}");
+            match self.action {
+                DgaAction::Log => next.run(query, ctx).await,
+                DgaAction::RateLimit { per_minute }
+                    if self.allow(client, ctx.received(), per_minute) =>
+                {
+                    next.run(query, ctx).await
+                }
+                DgaAction::RateLimit { .. } => error_response(&query, rcode::REFUSED),
+                DgaAction::Block => error_response(&query, rcode::NXDOMAIN),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::dns::rtype;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    const REAL: &[&str] = &[
+        "www.google.com",
+        "stackoverflow.com",
+        "en.wikipedia.org",
+        "codecrafters.io",
+        "login.microsoftonline.com",
+        "web01.internal.example",
+        "cdn.jsdelivr.net",
+        "api.openweathermap.org",
+    ];
+
+    const GENERATED: &[&str] = &[
+        "xjw3k9qzv7mpl2h.com",
+        "qmfzkcvbnrtwpxl.net",
+        "a8f3k2l9x7c1v5b.org",
+        "kdjfhgqpwozmxnv.info",
+        "pqzxwvkjmbnrtyl.ru",
+    ];
+
+    #[test]
+    fn test_score() {
+        for name in REAL {
+            let score = score(&(*name).into());
+            assert!(score <= 0.6, "{name} scored {score}");
+        }
+        for name in GENERATED {
+            let score = score(&(*name).into());
+            assert!(score > 0.6, "{name} scored {score}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_actions() {
+        let ctx = RequestCtx::new(SocketAddr::from(([10, 0, 0, 1], 5353)), Transport::Udp);
+        let query = || DnsMessage::query(1, GENERATED[0], rtype::A);
+
+        let block = Pipeline::new(DefaultHandler).layer(DgaLayer::new(DgaAction::Block));
+        let response = block.handle(query(), ctx.clone()).await;
+        assert_eq!(response.rcode(), rcode::NXDOMAIN);
+        let response = block
+            .handle(DnsMessage::query(2, REAL[0], rtype::A), ctx.clone())
+            .await;
+        assert_eq!(response.rcode(), rcode::NOERROR);
+
+        let limit = DgaLayer::new(DgaAction::RateLimit { per_minute: 2 });
+        let limit = Pipeline::new(DefaultHandler).layer(limit);
+        let mut rcodes = Vec::new();
+        for _ in 0..3 {
+            rcodes.push(limit.handle(query(), ctx.clone()).await.rcode());
+        }
+        assert_eq!(rcodes, [rcode::NOERROR, rcode::NOERROR, rcode::REFUSED]);
+    }
+}
@@ -1,22 +1,156 @@
+//! DNS message types, parser and serializer (RFC 1035 wire format)
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::io::{Result as IOResult, Write};
+use std::net::Ipv4Addr;
+use std::slice;
+use std::str::FromStr;
 
+use bytes::BufMut;
 use nom::bits::complete::take as take_bits;
 use nom::bytes::complete::take as take_bytes;
-use nom::combinator::map_res;
-use nom::error::Error;
 use nom::multi::count;
 use nom::number::complete::be_u32;
 use nom::sequence::tuple;
 use nom::Err as NomErr;
 use nom::{
     number::complete::{be_u16, be_u8},
-    IResult, InputTake,
+    IResult,
 };
 
+use crate::arena::Arena;
+use crate::canonical;
+use crate::error::DnsError;
+use crate::rdata::{self, RData};
+
+type ParseResult<'a, T> = IResult<&'a [u8], T, DnsError>;
+
+/// Largest UDP payload accepted, the EDNS maximum in common use
+pub const MAX_UDP_PAYLOAD: usize = 4096;
+/// Largest UDP response to a client that did not advertise a size (RFC 1035 4.2.1)
+pub const MAX_UDP_RESPONSE: usize = 512;
+
+/// Longest label allowed, in bytes
+pub const MAX_LABEL_LEN: usize = 63;
+/// Longest name allowed, in wire form bytes including the length octets and the root label
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Record TYPE values
+pub mod rtype {
+    pub const A: u16 = 1;
+    pub const NS: u16 = 2;
+    pub const CNAME: u16 = 5;
+    pub const SOA: u16 = 6;
+    pub const PTR: u16 = 12;
+    pub const MX: u16 = 15;
+    pub const TXT: u16 = 16;
+    pub const AAAA: u16 = 28;
+    pub const SRV: u16 = 33;
+    /// EDNS pseudo-record, see [`super::Edns`]
+    pub const OPT: u16 = 41;
+    /// Transaction signature (RFC 8945), see [`crate::tsig`]
+    pub const TSIG: u16 = 250;
+    /// Incremental zone transfer (RFC 1995), a QTYPE only
+    pub const IXFR: u16 = 251;
+    /// Zone transfer, a QTYPE only
+    pub const AXFR: u16 = 252;
+    /// Certification authority authorization (RFC 8659)
+    pub const CAA: u16 = 257;
+
+    /// Mnemonic of the type `code`, such as `AAAA`, if it is one of the above
+    pub fn name(code: u16) -> Option<&'static str> {
+        Some(match code {
+            A => "A",
+            NS => "NS",
+            CNAME => "CNAME",
+            SOA => "SOA",
+            PTR => "PTR",
+            MX => "MX",
+            TXT => "TXT",
+            AAAA => "AAAA",
+            SRV => "SRV",
+            OPT => "OPT",
+            TSIG => "TSIG",
+            IXFR => "IXFR",
+            AXFR => "AXFR",
+            CAA => "CAA",
+            _ => return None,
+        })
+    }
+}
+
+/// Record CLASS values
+pub mod class {
+    pub const IN: u16 = 1;
+    /// CHAOS, used for server introspection such as `version.bind`
+    pub const CH: u16 = 3;
+    /// Any class, in questions and meta-records such as TSIG
+    pub const ANY: u16 = 255;
+}
+
+/// EDNS option codes
+pub mod edns_option {
+    /// Idle timeout of a TCP connection, in units of 100 milliseconds (RFC 7828)
+    pub const TCP_KEEPALIVE: u16 = 11;
+}
+
+/// Header OPCODE values
+pub mod opcode {
+    pub const QUERY: u8 = 0;
+    /// Inverse query, obsoleted by RFC 3425
+    pub const IQUERY: u8 = 1;
+    pub const STATUS: u8 = 2;
+    pub const NOTIFY: u8 = 4;
+    pub const UPDATE: u8 = 5;
+}
+
+/// Response codes
+pub mod rcode {
+    pub const NOERROR: u8 = 0;
+    pub const FORMERR: u8 = 1;
+    pub const SERVFAIL: u8 = 2;
+    pub const NXDOMAIN: u8 = 3;
+    pub const NOTIMP: u8 = 4;
+    pub const REFUSED: u8 = 5;
+    /// Not authorized, e.g. a TSIG signature that does not verify (RFC 8945)
+    pub const NOTAUTH: u8 = 9;
+
+    /// Mnemonic of the response code `code`, such as `NXDOMAIN`, if it is one of the above
+    pub fn name(code: u8) -> Option<&'static str> {
+        Some(match code {
+            NOERROR => "NOERROR",
+            FORMERR => "FORMERR",
+            SERVFAIL => "SERVFAIL",
+            NXDOMAIN => "NXDOMAIN",
+            NOTIMP => "NOTIMP",
+            REFUSED => "REFUSED",
+            NOTAUTH => "NOTAUTH",
+            _ => return None,
+        })
+    }
+}
+
+/// Encodes a value into its wire format
 pub trait ToBytes {
-    fn to_bytes(&self) -> Vec<u8>;
+    /// Appends the wire format to `buf` and returns the number of bytes written.
+    ///
+    /// Writing into a `&mut [u8]` panics if the slice is too small.
+    fn write_to(&self, buf: &mut impl BufMut) -> usize;
+
+    /// Number of bytes [`ToBytes::write_to`] will write, computed without serializing
+    fn wire_len(&self) -> usize;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes);
+        bytes
+    }
 }
 
+/// Writes the wire format of a value into any [`Write`]
 pub trait Writeable {
     fn write(&self, writer: impl Write) -> IOResult<usize>;
 }
@@ -27,65 +161,284 @@ impl<T: ToBytes> Writeable for T {
     }
 }
 
+/// Message section a resource record belongs to
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+/// Resource record, as carried in the answer, authority and additional sections
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub struct DnsAnswer {
+pub struct DnsRecord {
     name: DnsLabels,
-    answer_type: u16,
+    record_type: u16,
     class: u16,
     ttl: u32,
     data: Vec<u8>,
 }
 
-impl ToBytes for DnsAnswer {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend(&self.name.to_bytes());
-        bytes.extend(&self.answer_type.to_be_bytes());
-        bytes.extend(&self.class.to_be_bytes());
-        bytes.extend(&self.ttl.to_be_bytes());
-        bytes.extend((self.data.len() as u16).to_be_bytes());
-        for v in &self.data {
-            bytes.extend(v.to_be_bytes());
+/// Records of the answer section; kept for callers written before authority/additional support
+pub type DnsAnswer = DnsRecord;
+
+impl DnsRecord {
+    pub fn new(name: DnsLabels, record_type: u16, class: u16, ttl: u32, data: Vec<u8>) -> Self {
+        Self {
+            name,
+            record_type,
+            class,
+            ttl,
+            data,
         }
+    }
 
-        bytes
+    /// Internet class record with typed data
+    pub fn with_rdata(name: DnsLabels, ttl: u32, rdata: impl Into<RData>) -> Self {
+        let rdata = rdata.into();
+        Self::new(name, rdata.record_type(), class::IN, ttl, rdata.to_bytes())
+    }
+
+    pub fn name(&self) -> &DnsLabels {
+        &self.name
+    }
+
+    pub fn record_type(&self) -> u16 {
+        self.record_type
+    }
+
+    pub fn class(&self) -> u16 {
+        self.class
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// This record with its TTL replaced, e.g. counted down by a cache
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Raw RDATA bytes
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// RDATA decoded according to the record type
+    pub fn rdata(&self) -> Result<RData, DnsError> {
+        RData::from_wire(self.record_type, &self.data)
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub struct DnsLabels(Vec<String>);
+impl ToBytes for DnsRecord {
+    fn write_to(&self, buf: &mut impl BufMut) -> usize {
+        let len = self.name.write_to(buf);
+        buf.put_u16(self.record_type);
+        buf.put_u16(self.class);
+        buf.put_u32(self.ttl);
+        buf.put_u16(self.data.len() as u16);
+        buf.put_slice(&self.data);
 
-impl ToBytes for DnsLabels {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        for label in self.0.iter() {
-            bytes.extend((label.len() as u8).to_be_bytes());
-            bytes.extend(label.as_bytes());
+        len + 10 + self.data.len()
+    }
+
+    fn wire_len(&self) -> usize {
+        self.name.wire_len() + 10 + self.data.len()
+    }
+}
+
+/// Domain name, stored as its uncompressed wire form (length-prefixed labels ending with the
+/// root label).
+///
+/// Labels are raw bytes: anything is legal on the wire, and only [`fmt::Display`] converts them,
+/// lossily, to text. Equality, hashing and ordering ignore ASCII case, so `WWW.Example.com` and
+/// `www.example.com` are the same map key.
+#[derive(Debug, Clone)]
+pub struct DnsLabels(Vec<u8>);
+
+impl PartialEq for DnsLabels {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for DnsLabels {}
+
+impl Hash for DnsLabels {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for byte in &self.0 {
+            state.write_u8(byte.to_ascii_lowercase());
         }
-        bytes.extend(0_u8.to_be_bytes());
-        bytes
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+impl Ord for DnsLabels {
+    /// RFC 4034 canonical order
+    fn cmp(&self, other: &Self) -> Ordering {
+        canonical::name_cmp(self, other)
+    }
+}
+
+impl PartialOrd for DnsLabels {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl DnsLabels {
+    /// Name made of `labels`, failing if a label is empty or longer than [`MAX_LABEL_LEN`] or
+    /// the name is longer than [`MAX_NAME_LEN`]
+    pub fn new<L: AsRef<[u8]>>(labels: impl IntoIterator<Item = L>) -> Result<Self, DnsError> {
+        let mut wire = Vec::new();
+        for label in labels {
+            let label = label.as_ref();
+            if label.is_empty() || label.len() > MAX_LABEL_LEN {
+                return Err(DnsError::BadLabelLength(label.len()));
+            }
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label);
+        }
+        wire.push(0);
+        if wire.len() > MAX_NAME_LEN {
+            return Err(DnsError::NameTooLong(wire.len()));
+        }
+        Ok(DnsLabels(wire))
+    }
+
+    /// Labels from the leftmost one, without the root label
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        wire_labels(&self.0).collect::<Vec<_>>().into_iter()
+    }
+
+    pub fn label_count(&self) -> usize {
+        wire_labels(&self.0).count()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0 == [0]
+    }
+
+    /// Whether this name is `zone` or below it, ignoring ASCII case
+    pub fn is_subdomain_of(&self, zone: &DnsLabels) -> bool {
+        let mut name = self.labels().rev();
+        zone.labels().rev().all(|label| {
+            name.next()
+                .is_some_and(|own| own.eq_ignore_ascii_case(label))
+        })
+    }
+}
+
+/// Splits an uncompressed wire form name into its labels
+fn wire_labels(wire: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = wire;
+    std::iter::from_fn(move || {
+        let (&length, tail) = rest.split_first()?;
+        if length == 0 {
+            return None;
+        }
+        let (label, tail) = tail.split_at(length as usize);
+        rest = tail;
+        Some(label)
+    })
+}
+
+impl fmt::Display for DnsLabels {
+    /// Dotted form without the trailing root dot, or `.` for the root itself
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+        for (i, label) in wire_labels(&self.0).enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(&String::from_utf8_lossy(label))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DnsLabels {
+    type Err = DnsError;
+
+    /// Splits a dotted name such as `www.example.com.` into its labels
+    fn from_str(name: &str) -> Result<Self, DnsError> {
+        DnsLabels::new(name.split('.').filter(|label| !label.is_empty()))
+    }
+}
+
+impl From<&str> for DnsLabels {
+    /// Same as [`str::parse`], for names known to be valid.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a label or the whole name is too long.
+    fn from(name: &str) -> Self {
+        match name.parse() {
+            Ok(name) => name,
+            Err(err) => panic!("invalid name {name:?}: {err}"),
+        }
+    }
+}
+
+impl ToBytes for DnsLabels {
+    fn write_to(&self, buf: &mut impl BufMut) -> usize {
+        buf.put_slice(&self.0);
+        self.0.len()
+    }
+
+    fn wire_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Entry of the question section
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct DnsQuestion {
     qname: DnsLabels,
     qtype: u16,
     qclass: u16,
 }
 
+impl DnsQuestion {
+    pub fn new(qname: DnsLabels, qtype: u16, qclass: u16) -> Self {
+        Self {
+            qname,
+            qtype,
+            qclass,
+        }
+    }
+
+    pub fn qname(&self) -> &DnsLabels {
+        &self.qname
+    }
+
+    pub fn qtype(&self) -> u16 {
+        self.qtype
+    }
+
+    pub fn qclass(&self) -> u16 {
+        self.qclass
+    }
+}
+
 impl ToBytes for DnsQuestion {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    fn write_to(&self, buf: &mut impl BufMut) -> usize {
+        let len = self.qname.write_to(buf);
+        buf.put_u16(self.qtype);
+        buf.put_u16(self.qclass);
+        len + 4
+    }
 
-        bytes.extend(&self.qname.to_bytes());
-        bytes.extend(&self.qtype.to_be_bytes());
-        bytes.extend(&self.qclass.to_be_bytes());
-        bytes
+    fn wire_len(&self) -> usize {
+        self.qname.wire_len() + 4
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// Fixed 12 byte message header
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct DnsHeader {
     // 2 bytes
     id: u16,
@@ -101,8 +454,12 @@ pub struct DnsHeader {
     rd: u8,
     // 1bit
     ra: u8,
-    // 3bits
+    // 1bit, reserved
     z: u8,
+    // 1bit, RFC 4035
+    ad: u8,
+    // 1bit, RFC 4035
+    cd: u8,
     // 4bit
     rcode: u8,
     // 2 bytes
@@ -115,87 +472,830 @@ pub struct DnsHeader {
     arcount: u16,
 }
 
+impl DnsHeader {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.qr == 1
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    pub fn authoritative(&self) -> bool {
+        self.aa == 1
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.tc == 1
+    }
+
+    pub fn recursion_desired(&self) -> bool {
+        self.rd == 1
+    }
+
+    pub fn recursion_available(&self) -> bool {
+        self.ra == 1
+    }
+
+    /// Reserved bit, zero in every message this crate builds
+    pub fn z(&self) -> u8 {
+        self.z
+    }
+
+    /// AD: the answer was validated with DNSSEC (in a query: the client understands AD)
+    pub fn authentic_data(&self) -> bool {
+        self.ad == 1
+    }
+
+    /// CD: the client asks for answers without DNSSEC validation
+    pub fn checking_disabled(&self) -> bool {
+        self.cd == 1
+    }
+
+    pub fn rcode(&self) -> u8 {
+        self.rcode
+    }
+
+    pub fn qdcount(&self) -> u16 {
+        self.qdcount
+    }
+
+    pub fn ancount(&self) -> u16 {
+        self.ancount
+    }
+
+    pub fn nscount(&self) -> u16 {
+        self.nscount
+    }
+
+    pub fn arcount(&self) -> u16 {
+        self.arcount
+    }
+}
+
 impl ToBytes for DnsHeader {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    fn write_to(&self, buf: &mut impl BufMut) -> usize {
+        buf.put_u16(self.id);
+        buf.put_u8((self.qr << 7) | (self.opcode << 3) | (self.aa << 2) | (self.tc << 1) | self.rd);
+        buf.put_u8((self.ra << 7) | (self.z << 6) | (self.ad << 5) | (self.cd << 4) | self.rcode);
+        buf.put_u16(self.qdcount);
+        buf.put_u16(self.ancount);
+        buf.put_u16(self.nscount);
+        buf.put_u16(self.arcount);
 
-        bytes.extend(&self.id.to_be_bytes());
-        bytes.push((self.qr << 7) | (self.opcode << 3) | (self.aa << 2) | (self.tc << 1) | self.rd);
-        bytes.push((self.ra << 7) | (self.z << 4) | self.rcode);
-        bytes.extend(&self.qdcount.to_be_bytes());
-        bytes.extend(&self.ancount.to_be_bytes());
-        bytes.extend(&self.nscount.to_be_bytes());
-        bytes.extend(&self.arcount.to_be_bytes());
+        12
+    }
 
-        bytes
+    fn wire_len(&self) -> usize {
+        12
     }
 }
 
+/// EDNS(0) parameters, carried by the OPT pseudo-record of the additional section (RFC 6891)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Edns {
+    payload_size: u16,
+    extended_rcode: u8,
+    version: u8,
+    dnssec_ok: bool,
+    options: Vec<(u16, Vec<u8>)>,
+}
+
+impl Edns {
+    /// Version 0 with a UDP payload size of `payload_size` bytes and no options
+    pub fn new(payload_size: u16) -> Self {
+        Self {
+            payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Largest UDP payload the sender can receive
+    pub fn payload_size(&self) -> u16 {
+        self.payload_size
+    }
+
+    /// Upper 8 bits of the 12 bit response code, whose lower 4 bits are in the header
+    pub fn extended_rcode(&self) -> u8 {
+        self.extended_rcode
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// DO: the sender wants DNSSEC records (RFC 3225)
+    pub fn dnssec_ok(&self) -> bool {
+        self.dnssec_ok
+    }
+
+    /// Option codes and data, in wire order
+    pub fn options(&self) -> slice::Iter<'_, (u16, Vec<u8>)> {
+        self.options.iter()
+    }
+
+    /// Data of the first option with `code`, if there is one
+    pub fn option(&self, code: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == code)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    pub fn with_extended_rcode(mut self, extended_rcode: u8) -> Self {
+        self.extended_rcode = extended_rcode;
+        self
+    }
+
+    pub fn with_dnssec_ok(mut self, dnssec_ok: bool) -> Self {
+        self.dnssec_ok = dnssec_ok;
+        self
+    }
+
+    pub fn with_option(mut self, code: u16, data: Vec<u8>) -> Self {
+        self.options.push((code, data));
+        self
+    }
+
+    /// Reads the parameters out of an OPT record
+    fn from_record(record: &DnsRecordRef<'_>) -> Result<Self, DnsError> {
+        if record.name.0[..] != [0] {
+            return Err(DnsError::Malformed(
+                "OPT record not owned by the root".into(),
+            ));
+        }
+        let mut options = Vec::new();
+        let mut data = &record.data[..];
+        while !data.is_empty() {
+            let header = data.get(..4).ok_or(DnsError::Truncated)?;
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let value = data.get(4..4 + len).ok_or(DnsError::Truncated)?;
+            options.push((code, value.to_vec()));
+            data = &data[4 + len..];
+        }
+        Ok(Self {
+            payload_size: record.class,
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: record.ttl & 0x8000 != 0,
+            options,
+        })
+    }
+
+    /// Writes the OPT record, or only measures it without a `buf`
+    fn encode<B: BufMut>(&self, buf: Option<&mut B>) -> usize {
+        let data_len: usize = self.options.iter().map(|(_, data)| 4 + data.len()).sum();
+        if let Some(buf) = buf {
+            buf.put_u8(0);
+            buf.put_u16(rtype::OPT);
+            buf.put_u16(self.payload_size);
+            buf.put_u8(self.extended_rcode);
+            buf.put_u8(self.version);
+            buf.put_u16(if self.dnssec_ok { 0x8000 } else { 0 });
+            buf.put_u16(data_len as u16);
+            for (code, data) in &self.options {
+                buf.put_u16(*code);
+                buf.put_u16(data.len() as u16);
+                buf.put_slice(data);
+            }
+        }
+        11 + data_len
+    }
+}
+
+/// Complete DNS message
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct DnsMessage {
     header: DnsHeader,
     questions: Vec<DnsQuestion>,
-    answers: Vec<DnsAnswer>,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    /// Additional records other than OPT, which is kept as `edns`
+    additionals: Vec<DnsRecord>,
+    edns: Option<Edns>,
 }
 
-impl ToBytes for DnsMessage {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
+/// Compression pointers hold a 14 bit offset, so only names in the first 16 KiB can be targets
+const MAX_POINTER_TARGET: usize = 0x3FFF;
+
+/// Offsets of the names already written into a message, so that later names can end with a
+/// compression pointer to them (RFC 1035 4.1.4)
+#[derive(Default)]
+struct NameTable<'a> {
+    suffixes: Vec<(&'a [u8], u16)>,
+}
+
+impl<'a> NameTable<'a> {
+    /// Splits `name`, about to be written at `offset`, into the labels to write and the pointer
+    /// ending them, if an earlier name ends the same way. Suffixes match byte for byte so that
+    /// every name keeps its spelling.
+    fn compress(&mut self, name: &'a [u8], offset: usize) -> (&'a [u8], Option<u16>) {
+        let mut at = 0;
+        while name[at] != 0 {
+            let suffix = &name[at..];
+            if let Some(&(_, target)) = self.suffixes.iter().find(|(known, _)| *known == suffix) {
+                return (&name[..at], Some(target));
+            }
+            if offset + at <= MAX_POINTER_TARGET {
+                self.suffixes.push((suffix, (offset + at) as u16));
+            }
+            at += 1 + name[at] as usize;
+        }
+        (name, None)
+    }
 
-        bytes.extend(self.header.to_bytes());
+    /// Writes `name` at `offset`, compressed, returning its length on the wire. Without a
+    /// `buf` the name is only measured (and remembered).
+    fn write<B: BufMut>(
+        &mut self,
+        name: &'a DnsLabels,
+        offset: usize,
+        buf: Option<&mut B>,
+    ) -> usize {
+        let (labels, pointer) = self.compress(&name.0, offset);
+        if let Some(buf) = buf {
+            buf.put_slice(labels);
+            if let Some(target) = pointer {
+                buf.put_u16(0xC000 | target);
+            }
+        }
+        labels.len() + if pointer.is_some() { 2 } else { 0 }
+    }
+}
+
+impl DnsMessage {
+    /// Writes the message with its question and owner names compressed, or only measures it
+    /// without a `buf`
+    fn encode<B: BufMut>(&self, mut buf: Option<&mut B>) -> usize {
+        let mut names = NameTable::default();
+        if let Some(buf) = buf.as_deref_mut() {
+            // counts of what is written, whatever the message was parsed with
+            let header = DnsHeader {
+                qdcount: self.questions.len() as u16,
+                ancount: self.answers.len() as u16,
+                nscount: self.authorities.len() as u16,
+                arcount: (self.additionals.len() + self.edns.is_some() as usize) as u16,
+                ..self.header.clone()
+            };
+            header.write_to(buf);
+        }
+        let mut len = self.header.wire_len();
         for question in &self.questions {
-            bytes.extend(question.to_bytes());
+            len += names.write(&question.qname, len, buf.as_deref_mut());
+            if let Some(buf) = buf.as_deref_mut() {
+                buf.put_u16(question.qtype);
+                buf.put_u16(question.qclass);
+            }
+            len += 4;
         }
-        for answer in &self.answers {
-            bytes.extend(answer.to_bytes());
+        let records = self.answers.iter().chain(&self.authorities);
+        for record in records.chain(&self.additionals) {
+            len += names.write(&record.name, len, buf.as_deref_mut());
+            if let Some(buf) = buf.as_deref_mut() {
+                buf.put_u16(record.record_type);
+                buf.put_u16(record.class);
+                buf.put_u32(record.ttl);
+                buf.put_u16(record.data.len() as u16);
+                buf.put_slice(&record.data);
+            }
+            len += 10 + record.data.len();
         }
+        if let Some(edns) = &self.edns {
+            len += edns.encode(buf);
+        }
+        len
+    }
+}
 
-        bytes
+impl ToBytes for DnsMessage {
+    fn write_to(&self, buf: &mut impl BufMut) -> usize {
+        self.encode(Some(buf))
+    }
+
+    fn wire_len(&self) -> usize {
+        self.encode(None::<&mut Vec<u8>>)
+    }
+}
+
+impl DnsMessage {
+    /// Standard recursive query for a single name
+    pub fn query(id: u16, name: &str, qtype: u16) -> DnsMessage {
+        MessageBuilder::new()
+            .id(id)
+            .recursion_desired(true)
+            .add_question(DnsQuestion::new(DnsLabels::from(name), qtype, 1))
+            .build()
+    }
+
+    /// Parses a message from its wire format, ignoring any trailing bytes
+    pub fn from_bytes(input: &[u8]) -> Result<DnsMessage, DnsError> {
+        Ok(DnsMessageRef::from_bytes(input)?.to_owned())
+    }
+
+    pub fn header(&self) -> &DnsHeader {
+        &self.header
+    }
+
+    pub fn id(&self) -> u16 {
+        self.header.id
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.header.is_response()
+    }
+
+    pub fn rcode(&self) -> u8 {
+        self.header.rcode
+    }
+
+    pub fn questions(&self) -> slice::Iter<'_, DnsQuestion> {
+        self.questions.iter()
+    }
+
+    pub fn answers(&self) -> slice::Iter<'_, DnsRecord> {
+        self.answers.iter()
+    }
+
+    /// Records of the authority section: the NS records of a referral, or the SOA of a
+    /// negative answer
+    pub fn authorities(&self) -> slice::Iter<'_, DnsRecord> {
+        self.authorities.iter()
+    }
+
+    /// Records of the additional section, such as the glue addresses of a referral, without
+    /// the OPT record
+    pub fn additionals(&self) -> slice::Iter<'_, DnsRecord> {
+        self.additionals.iter()
+    }
+
+    /// EDNS parameters, if the message has an OPT record
+    pub fn edns(&self) -> Option<&Edns> {
+        self.edns.as_ref()
+    }
+
+    /// Largest UDP response the sender of this query accepts: its EDNS payload size, kept
+    /// between 512 bytes and [`MAX_UDP_PAYLOAD`]
+    pub fn max_udp_response(&self) -> usize {
+        self.edns.as_ref().map_or(MAX_UDP_RESPONSE, |edns| {
+            (edns.payload_size as usize).clamp(MAX_UDP_RESPONSE, MAX_UDP_PAYLOAD)
+        })
+    }
+
+    /// Records of a single section
+    pub fn section(&self, section: Section) -> slice::Iter<'_, DnsRecord> {
+        match section {
+            Section::Answer => self.answers.iter(),
+            Section::Authority => self.authorities.iter(),
+            Section::Additional => self.additionals.iter(),
+        }
+    }
+
+    /// Every record of the message in wire order, tagged with its section
+    pub fn records(&self) -> impl Iterator<Item = (Section, &DnsRecord)> {
+        [Section::Answer, Section::Authority, Section::Additional]
+            .into_iter()
+            .flat_map(move |section| self.section(section).map(move |record| (section, record)))
+    }
+
+    /// This response adapted to `query`, another query with the same questions: takes over its
+    /// id and the exact spelling of its question names
+    pub fn retarget(mut self, query: &DnsMessage) -> DnsMessage {
+        self.header.id = query.header.id;
+        if self.questions == query.questions {
+            self.questions.clone_from(&query.questions);
+        }
+        self
+    }
+
+    /// This message with an EDNS option added, if it has an OPT record to carry it
+    pub fn with_edns_option(mut self, code: u16, data: Vec<u8>) -> DnsMessage {
+        self.edns = self.edns.map(|edns| edns.with_option(code, data));
+        self
+    }
+
+    /// This message cut down to at most `max_len` bytes on the wire, for UDP: records are
+    /// dropped whole from the end, keeping the OPT record. Additional records go first and
+    /// silently; once answer or authority records go, TC is set so the client retries over TCP
+    /// (RFC 2181 section 9).
+    pub fn truncate(mut self, max_len: usize) -> DnsMessage {
+        while self.wire_len() > max_len && self.additionals.pop().is_some() {}
+        if self.wire_len() > max_len {
+            self.header.tc = 1;
+        }
+        while self.wire_len() > max_len {
+            if self.authorities.pop().is_none() && self.answers.pop().is_none() {
+                break;
+            }
+        }
+        self.header.ancount = self.answers.len() as u16;
+        self.header.nscount = self.authorities.len() as u16;
+        self.header.arcount = (self.additionals.len() + self.edns.is_some() as usize) as u16;
+        self
+    }
+
+    /// Hands every allocation of the message back to `arena`
+    pub(crate) fn recycle(mut self, arena: &mut Arena) {
+        for question in self.questions.drain(..) {
+            arena.put_bytes(question.qname.0);
+        }
+        arena.put_questions(self.questions);
+        for mut records in [self.answers, self.authorities, self.additionals] {
+            for record in records.drain(..) {
+                arena.put_bytes(record.name.0);
+                arena.put_bytes(record.data);
+            }
+            arena.put_records(records);
+        }
+    }
+}
+
+/// View of a name: its wire bytes up to and including the root label.
+///
+/// The bytes are borrowed from the input unless the name was compressed, in which case they
+/// are expanded from the compression pointers into an owned copy.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DnsLabelsRef<'a>(Cow<'a, [u8]>);
+
+impl<'a> DnsLabelsRef<'a> {
+    pub fn labels(&self) -> impl Iterator<Item = &[u8]> {
+        wire_labels(&self.0)
+    }
+
+    pub fn to_owned(&self) -> DnsLabels {
+        DnsLabels(self.0.to_vec())
+    }
+}
+
+/// Borrowed view of a question
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DnsQuestionRef<'a> {
+    qname: DnsLabelsRef<'a>,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl<'a> DnsQuestionRef<'a> {
+    pub fn qname(&self) -> &DnsLabelsRef<'a> {
+        &self.qname
+    }
+
+    pub fn qtype(&self) -> u16 {
+        self.qtype
+    }
+
+    pub fn qclass(&self) -> u16 {
+        self.qclass
+    }
+
+    pub fn to_owned(&self) -> DnsQuestion {
+        DnsQuestion::new(self.qname.to_owned(), self.qtype, self.qclass)
+    }
+}
+
+/// Borrowed view of a resource record.
+///
+/// Record data is borrowed from the input unless it embeds names (NS, CNAME, SOA, PTR, MX,
+/// SRV), in which case those are expanded from any compression pointers into an owned copy.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DnsRecordRef<'a> {
+    name: DnsLabelsRef<'a>,
+    record_type: u16,
+    class: u16,
+    ttl: u32,
+    data: Cow<'a, [u8]>,
+}
+
+impl<'a> DnsRecordRef<'a> {
+    pub fn name(&self) -> &DnsLabelsRef<'a> {
+        &self.name
+    }
+
+    pub fn record_type(&self) -> u16 {
+        self.record_type
+    }
+
+    pub fn class(&self) -> u16 {
+        self.class
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// RDATA with any embedded names uncompressed
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn to_owned(&self) -> DnsRecord {
+        DnsRecord::new(
+            self.name.to_owned(),
+            self.record_type,
+            self.class,
+            self.ttl,
+            self.data.to_vec(),
+        )
+    }
+}
+
+/// Message parsed without copying names or record data out of the input buffer
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DnsMessageRef<'a> {
+    header: DnsHeader,
+    questions: Vec<DnsQuestionRef<'a>>,
+    answers: Vec<DnsRecordRef<'a>>,
+    authorities: Vec<DnsRecordRef<'a>>,
+    /// Additional records other than OPT
+    additionals: Vec<DnsRecordRef<'a>>,
+    edns: Option<Edns>,
+}
+
+impl<'a> DnsMessageRef<'a> {
+    /// Parses a message from its wire format, ignoring any trailing bytes
+    pub fn from_bytes(input: &'a [u8]) -> Result<DnsMessageRef<'a>, DnsError> {
+        let (_, msg) = dns_msg(input)?;
+        Ok(msg)
+    }
+
+    pub fn header(&self) -> &DnsHeader {
+        &self.header
+    }
+
+    pub fn questions(&self) -> slice::Iter<'_, DnsQuestionRef<'a>> {
+        self.questions.iter()
+    }
+
+    pub fn answers(&self) -> slice::Iter<'_, DnsRecordRef<'a>> {
+        self.answers.iter()
+    }
+
+    pub fn authorities(&self) -> slice::Iter<'_, DnsRecordRef<'a>> {
+        self.authorities.iter()
+    }
+
+    pub fn additionals(&self) -> slice::Iter<'_, DnsRecordRef<'a>> {
+        self.additionals.iter()
+    }
+
+    pub fn edns(&self) -> Option<&Edns> {
+        self.edns.as_ref()
+    }
+
+    pub fn to_owned(&self) -> DnsMessage {
+        DnsMessage {
+            header: self.header.clone(),
+            questions: self
+                .questions
+                .iter()
+                .map(DnsQuestionRef::to_owned)
+                .collect(),
+            answers: self.answers.iter().map(DnsRecordRef::to_owned).collect(),
+            authorities: self
+                .authorities
+                .iter()
+                .map(DnsRecordRef::to_owned)
+                .collect(),
+            additionals: self
+                .additionals
+                .iter()
+                .map(DnsRecordRef::to_owned)
+                .collect(),
+            edns: self.edns.clone(),
+        }
+    }
+
+    /// Like [`DnsMessageRef::to_owned`], taking every allocation from `arena`
+    pub fn to_owned_in(&self, arena: &mut Arena) -> DnsMessage {
+        let mut questions = arena.questions();
+        questions.extend(self.questions.iter().map(|question| DnsQuestion {
+            qname: DnsLabels(arena.copy(&question.qname.0)),
+            qtype: question.qtype,
+            qclass: question.qclass,
+        }));
+
+        let mut records_in = |section: &[DnsRecordRef<'_>]| {
+            let mut records = arena.records();
+            records.extend(section.iter().map(|record| DnsRecord {
+                name: DnsLabels(arena.copy(&record.name.0)),
+                record_type: record.record_type,
+                class: record.class,
+                ttl: record.ttl,
+                data: arena.copy(&record.data),
+            }));
+            records
+        };
+
+        DnsMessage {
+            header: self.header.clone(),
+            questions,
+            answers: records_in(&self.answers),
+            authorities: records_in(&self.authorities),
+            additionals: records_in(&self.additionals),
+            edns: self.edns.clone(),
+        }
+    }
+}
+
+/// Builds a [`DnsMessage`], deriving the header section counts from the sections added
+#[derive(Debug, Clone, Default)]
+pub struct MessageBuilder {
+    header: DnsHeader,
+    questions: Vec<DnsQuestion>,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    additionals: Vec<DnsRecord>,
+    edns: Option<Edns>,
+}
+
+impl MessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a response to `query`: copies its id, opcode, RD and CD flags and sets QR. A
+    /// query with EDNS gets an OPT record advertising [`MAX_UDP_PAYLOAD`], its DO bit copied.
+    ///
+    /// AD starts cleared, as nothing has been validated, and the reserved Z bit is never set.
+    pub fn response_to(query: &DnsMessage) -> Self {
+        let builder = Self::new()
+            .id(query.header.id)
+            .opcode(query.header.opcode)
+            .recursion_desired(query.header.rd == 1)
+            .checking_disabled(query.header.cd == 1)
+            .response(true);
+        match &query.edns {
+            Some(edns) => {
+                builder.edns(Edns::new(MAX_UDP_PAYLOAD as u16).with_dnssec_ok(edns.dnssec_ok))
+            }
+            None => builder,
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.header.id = id;
+        self
+    }
+
+    pub fn response(mut self, response: bool) -> Self {
+        self.header.qr = response as u8;
+        self
+    }
+
+    /// Panics if `opcode` does not fit in 4 bits
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        assert!(opcode < 16, "opcode {opcode} does not fit in 4 bits");
+        self.header.opcode = opcode;
+        self
+    }
+
+    pub fn authoritative(mut self, aa: bool) -> Self {
+        self.header.aa = aa as u8;
+        self
+    }
+
+    pub fn truncated(mut self, tc: bool) -> Self {
+        self.header.tc = tc as u8;
+        self
+    }
+
+    pub fn recursion_desired(mut self, rd: bool) -> Self {
+        self.header.rd = rd as u8;
+        self
+    }
+
+    pub fn recursion_available(mut self, ra: bool) -> Self {
+        self.header.ra = ra as u8;
+        self
+    }
+
+    /// Only set AD on data that was actually validated
+    pub fn authentic_data(mut self, ad: bool) -> Self {
+        self.header.ad = ad as u8;
+        self
+    }
+
+    pub fn checking_disabled(mut self, cd: bool) -> Self {
+        self.header.cd = cd as u8;
+        self
+    }
+
+    /// Panics if `rcode` does not fit in 4 bits
+    pub fn rcode(mut self, rcode: u8) -> Self {
+        assert!(rcode < 16, "rcode {rcode} does not fit in 4 bits");
+        self.header.rcode = rcode;
+        self
+    }
+
+    pub fn add_question(mut self, question: DnsQuestion) -> Self {
+        self.questions.push(question);
+        self
+    }
+
+    pub fn add_answer(mut self, answer: DnsRecord) -> Self {
+        self.answers.push(answer);
+        self
+    }
+
+    pub fn add_authority(mut self, authority: DnsRecord) -> Self {
+        self.authorities.push(authority);
+        self
+    }
+
+    /// Adds an additional record; EDNS goes through [`MessageBuilder::edns`] instead
+    pub fn add_additional(mut self, additional: DnsRecord) -> Self {
+        self.additionals.push(additional);
+        self
+    }
+
+    /// Adds an OPT record with `edns`, replacing any earlier one
+    pub fn edns(mut self, edns: Edns) -> Self {
+        self.edns = Some(edns);
+        self
+    }
+
+    pub fn build(self) -> DnsMessage {
+        let mut header = self.header;
+        header.qdcount = self.questions.len() as u16;
+        header.ancount = self.answers.len() as u16;
+        header.nscount = self.authorities.len() as u16;
+        header.arcount = (self.additionals.len() + self.edns.is_some() as usize) as u16;
+        DnsMessage {
+            header,
+            questions: self.questions,
+            answers: self.answers,
+            authorities: self.authorities,
+            additionals: self.additionals,
+            edns: self.edns,
+        }
     }
 }
 
+/// Builds the server's response to `req`: its questions echoed unchanged, each A question of a
+/// standard query answered with 8.8.8.8.
+///
+/// Every other opcode gets NOTIMP and no answers: IQUERY (RFC 3425), STATUS, and NOTIFY and
+/// UPDATE, whose RFCs (1996 and 2136) ask servers that do not implement them for NOTIMP.
 pub fn response(req: &DnsMessage) -> DnsMessage {
-    DnsMessage {
-        header: DnsHeader {
-            id: req.header.id,
-            qr: 1,
-            opcode: req.header.opcode,
-            aa: 0,
-            tc: 0,
-            rd: req.header.rd,
-            ra: 0,
-            z: 0,
-            rcode: if req.header.opcode == 0 { 0 } else { 4 },
-            qdcount: 1,
-            ancount: 1,
-            nscount: 0,
-            arcount: 0,
-        },
-        questions: vec![DnsQuestion {
-            qname: DnsLabels(vec!["codecrafters".to_string(), "io".to_string()]),
-            qtype: 1,
-            qclass: 1,
-        }],
-        answers: vec![DnsAnswer {
-            name: DnsLabels(vec!["codecrafters".to_string(), "io".to_string()]),
-            answer_type: 1,
-            class: 1,
-            ttl: 60,
-            data: vec![8, 8, 8, 8],
-        }],
-    }
-}
-
-/// Parse
-pub fn dns_header(input: &[u8]) -> IResult<&[u8], DnsHeader> {
+    let mut builder = MessageBuilder::response_to(req);
+    for question in req.questions() {
+        builder = builder.add_question(question.clone());
+    }
+    if req.header.opcode != opcode::QUERY {
+        return builder.rcode(rcode::NOTIMP).build();
+    }
+    for question in req.questions() {
+        if question.qtype == rtype::A && question.qclass == class::IN {
+            let answer =
+                DnsRecord::with_rdata(question.qname.clone(), 60, Ipv4Addr::new(8, 8, 8, 8));
+            builder = builder.add_answer(answer);
+        }
+    }
+    builder.build()
+}
+
+/// Response with `rcode` and no records to a parsed query, its questions echoed
+pub fn error_response(query: &DnsMessage, rcode: u8) -> DnsMessage {
+    query
+        .questions()
+        .fold(MessageBuilder::response_to(query), |builder, question| {
+            builder.add_question(question.clone())
+        })
+        .rcode(rcode)
+        .build()
+}
+
+/// Header-only response with `rcode` to a query whose body could not be used.
+///
+/// Only the 12 header bytes of `query` are read; `None` if those are missing or it is a response.
+pub fn header_response(query: &[u8], rcode: u8) -> Option<DnsMessage> {
+    let (_, header) = dns_header(query).ok()?;
+    if header.is_response() {
+        return None;
+    }
+    let response = MessageBuilder::new()
+        .id(header.id)
+        .response(true)
+        .opcode(header.opcode)
+        .recursion_desired(header.recursion_desired())
+        .rcode(rcode)
+        .build();
+    Some(response)
+}
+
+/// Parse the message header
+fn dns_header(input: &[u8]) -> ParseResult<'_, DnsHeader> {
     let (input, id) = be_u16(input)?;
 
-    let (input, (qr, opcode, aa, tc, rd, ra, z, rcode)) = match dns_header_bits(input) {
-        Ok((input, vals)) => Ok((input.0, vals)),
-        Err(NomErr::Error(e)) => Err(NomErr::Error(Error::new(e.input.0, e.code))),
-        Err(NomErr::Failure(e)) => Err(NomErr::Failure(Error::new(e.input.0, e.code))),
-        Err(NomErr::Incomplete(n)) => Err(NomErr::Incomplete(n)),
-    }?;
+    let (input, (qr, opcode, aa, tc, rd, ra, z, ad, cd, rcode)) =
+        dns_header_bits(input).map(|(input, vals)| (input.0, vals))?;
     let (input, (qdcount, ancount, nscount, arcount)) =
         tuple((be_u16, be_u16, be_u16, be_u16))(input)?;
     let header = DnsHeader {
@@ -207,6 +1307,8 @@ pub fn dns_header(input: &[u8]) -> IResult<&[u8], DnsHeader> {
         rd,
         ra,
         z,
+        ad,
+        cd,
         rcode,
         qdcount,
         ancount,
@@ -217,56 +1319,115 @@ pub fn dns_header(input: &[u8]) -> IResult<&[u8], DnsHeader> {
     Ok((input, header))
 }
 
-fn dns_header_bits(input: &[u8]) -> IResult<(&[u8], usize), (u8, u8, u8, u8, u8, u8, u8, u8)> {
+type HeaderBits = (u8, u8, u8, u8, u8, u8, u8, u8, u8, u8);
+
+fn dns_header_bits(input: &[u8]) -> IResult<(&[u8], usize), HeaderBits, DnsError> {
     let (input, qr) = take_bits(1usize)((input, 0))?;
     let (input, opcode) = take_bits(4usize)(input)?;
     let (input, aa) = take_bits(1usize)(input)?;
     let (input, tc) = take_bits(1usize)(input)?;
     let (input, rd) = take_bits(1usize)(input)?;
     let (input, ra) = take_bits(1usize)(input)?;
-    let (input, z) = take_bits(3usize)(input)?;
+    let (input, z) = take_bits(1usize)(input)?;
+    let (input, ad) = take_bits(1usize)(input)?;
+    let (input, cd) = take_bits(1usize)(input)?;
     let (input, rcode) = take_bits(4usize)(input)?;
-    Ok((input, (qr, opcode, aa, tc, rd, ra, z, rcode)))
+    Ok((input, (qr, opcode, aa, tc, rd, ra, z, ad, cd, rcode)))
 }
 
-pub fn dns_msg(input: &[u8]) -> IResult<&[u8], DnsMessage> {
-    let (input, header) = dns_header(input)?;
-    let (input, questions) = count(dns_question, header.qdcount as usize)(input)?;
-    let (input, answers) = count(dns_answer, header.ancount as usize)(input)?;
+/// Smallest possible question: root name, type and class
+const MIN_QUESTION_LEN: usize = 1 + 4;
+/// Smallest possible record: root name, type, class, TTL and an empty rdata
+const MIN_RECORD_LEN: usize = 1 + 10;
+
+/// Parse a complete message
+fn dns_msg(message: &[u8]) -> ParseResult<'_, DnsMessageRef<'_>> {
+    let (input, header) = dns_header(message)?;
+    check_counts(&header, input.len())?;
+    let question = |input| dns_question(message, input);
+    let (input, questions) = count(question, header.qdcount as usize)(input)?;
+    let record = |input| dns_record(message, input);
+    let (input, answers) = count(record, header.ancount as usize)(input)?;
+    let (input, authorities) = count(record, header.nscount as usize)(input)?;
+    let (input, mut additionals) = count(record, header.arcount as usize)(input)?;
+
+    let mut opt = additionals
+        .iter()
+        .filter(|record| record.record_type == rtype::OPT);
+    let edns = match (opt.next(), opt.next()) {
+        (None, _) => None,
+        (Some(record), None) => Some(Edns::from_record(record).map_err(NomErr::Failure)?),
+        (Some(_), Some(_)) => {
+            let err = DnsError::Malformed("more than one OPT record".into());
+            return Err(NomErr::Failure(err));
+        }
+    };
+    additionals.retain(|record| record.record_type != rtype::OPT);
 
     Ok((
         input,
-        DnsMessage {
+        DnsMessageRef {
             header,
             questions,
             answers,
+            authorities,
+            additionals,
+            edns,
         },
     ))
 }
 
-fn dns_answer(input: &[u8]) -> IResult<&[u8], DnsAnswer> {
-    let (input, name) = dns_labels(input)?;
-    let (input, (answer_type, class, ttl)) = tuple((be_u16, be_u16, be_u32))(input)?;
+/// Rejects section counts that could not fit in the `remaining` bytes, before anything is
+/// allocated for them
+fn check_counts(header: &DnsHeader, remaining: usize) -> Result<(), NomErr<DnsError>> {
+    let sections = [
+        ("question", header.qdcount, MIN_QUESTION_LEN),
+        ("answer", header.ancount, MIN_RECORD_LEN),
+        ("authority", header.nscount, MIN_RECORD_LEN),
+        ("additional", header.arcount, MIN_RECORD_LEN),
+    ];
+    let mut needed = 0;
+    for (section, count, min_len) in sections {
+        needed += count as usize * min_len;
+        if needed > remaining {
+            return Err(NomErr::Failure(DnsError::BadCount {
+                section,
+                count,
+                remaining,
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Parses the record at the start of `input`, a suffix of `message`
+fn dns_record<'a>(message: &'a [u8], input: &'a [u8]) -> ParseResult<'a, DnsRecordRef<'a>> {
+    let (input, name) = dns_labels(message, input)?;
+    let (input, (record_type, class, ttl)) = tuple((be_u16, be_u16, be_u32))(input)?;
     let (input, length) = be_u16(input)?;
-    let (input, data) = take_bytes(length as usize)(input)?;
+    let start = message.len() - input.len();
+    let (input, _) = take_bytes(length as usize)(input)?;
+    let data = rdata::expand_names(record_type, message, start..start + length as usize)
+        .map_err(NomErr::Failure)?;
     Ok((
         input,
-        DnsAnswer {
+        DnsRecordRef {
             name,
-            answer_type,
+            record_type,
             class,
             ttl,
-            data: data.to_vec(),
+            data,
         },
     ))
 }
 
-fn dns_question(input: &[u8]) -> IResult<&[u8], DnsQuestion> {
-    let (input, qname) = dns_labels(input)?;
+/// Parses the question at the start of `input`, a suffix of `message`
+fn dns_question<'a>(message: &'a [u8], input: &'a [u8]) -> ParseResult<'a, DnsQuestionRef<'a>> {
+    let (input, qname) = dns_labels(message, input)?;
     let (input, (qtype, qclass)) = tuple((be_u16, be_u16))(input)?;
     Ok((
         input,
-        DnsQuestion {
+        DnsQuestionRef {
             qname,
             qtype,
             qclass,
@@ -274,32 +1435,87 @@ fn dns_question(input: &[u8]) -> IResult<&[u8], DnsQuestion> {
     ))
 }
 
-fn dns_labels(input: &[u8]) -> IResult<&[u8], DnsLabels> {
-    let mut qname = Vec::new();
+/// Parses the name at the start of `input`, a suffix of `message` that compression pointers
+/// are resolved against. Uncompressed names are borrowed from the input.
+pub(crate) fn dns_labels<'a>(
+    message: &'a [u8],
+    input: &'a [u8],
+) -> ParseResult<'a, DnsLabelsRef<'a>> {
     let mut remaining_input = input;
     loop {
-        let (input, label) = parse_domain_label(remaining_input)?;
-        match label {
-            Some(label) => {
-                qname.push(label);
-                remaining_input = input;
+        if remaining_input
+            .first()
+            .is_some_and(|length| length & 0xC0 == 0xC0)
+        {
+            let start = message.len() - input.len();
+            let mut name = Vec::new();
+            let end = read_name(message, start, &mut name).map_err(NomErr::Failure)?;
+            return Ok((&message[end..], DnsLabelsRef(Cow::Owned(name))));
+        }
+        let (rest, label) = parse_domain_label(remaining_input)?;
+        remaining_input = rest;
+        let len = input.len() - remaining_input.len();
+        if len > MAX_NAME_LEN {
+            return Err(NomErr::Failure(DnsError::NameTooLong(len)));
+        }
+        if label.is_none() {
+            return Ok((remaining_input, DnsLabelsRef(Cow::Borrowed(&input[..len]))));
+        }
+    }
+}
+
+/// Appends the uncompressed wire form of the name at `at` in `message` to `out`, following
+/// compression pointers, and returns the offset just past the name where it started.
+///
+/// Pointers must point strictly backwards, which rules out loops.
+pub(crate) fn read_name(
+    message: &[u8],
+    mut at: usize,
+    out: &mut Vec<u8>,
+) -> Result<usize, DnsError> {
+    let start_len = out.len();
+    let mut end = None;
+    loop {
+        let &length = message.get(at).ok_or(DnsError::Truncated)?;
+        match length {
+            0 => {
+                out.push(0);
+                return Ok(end.unwrap_or(at + 1));
             }
-            None => {
-                return Ok((input, DnsLabels(qname)));
+            0xC0.. => {
+                let &low = message.get(at + 1).ok_or(DnsError::Truncated)?;
+                let target = usize::from(length & 0x3F) << 8 | usize::from(low);
+                if target >= at {
+                    return Err(DnsError::BadPointer(target));
+                }
+                end.get_or_insert(at + 2);
+                at = target;
             }
+            1..=63 => {
+                let label = message
+                    .get(at..at + 1 + length as usize)
+                    .ok_or(DnsError::Truncated)?;
+                out.extend_from_slice(label);
+                at += label.len();
+            }
+            _ => return Err(DnsError::BadLabelLength(length.into())),
+        }
+        if out.len() - start_len + 1 > MAX_NAME_LEN {
+            return Err(DnsError::NameTooLong(out.len() - start_len + 1));
         }
     }
 }
 
-fn parse_domain_label(input: &[u8]) -> IResult<&[u8], Option<String>> {
+fn parse_domain_label(input: &[u8]) -> ParseResult<'_, Option<&[u8]>> {
     let (input, length) = be_u8(input)?;
     if length == 0 {
         // Reached the end of domain name
         return Ok((input, None));
     }
-    let (input, label) = map_res(take_bytes(length as usize), |bytes: &[u8]| {
-        String::from_utf8(bytes.to_vec())
-    })(input)?;
+    if length as usize > MAX_LABEL_LEN {
+        return Err(NomErr::Failure(DnsError::BadLabelLength(length.into())));
+    }
+    let (input, label) = take_bytes(length as usize)(input)?;
     Ok((input, Some(label)))
 }
 
@@ -309,10 +1525,35 @@ mod test {
 
     #[test]
     fn test_encode_labels() {
-        let l = DnsLabels(vec!["google".to_string(), "com".to_string()]);
+        let l = DnsLabels::new(["google", "com"]).unwrap();
         println!("{:?}", l.to_bytes())
     }
 
+    #[test]
+    fn test_non_utf8_labels() {
+        let name = DnsLabels::new([&b"caf\xe9"[..], b"example"]).unwrap();
+        let query = MessageBuilder::new()
+            .add_question(DnsQuestion::new(name.clone(), 1, 1))
+            .build();
+
+        let parsed = DnsMessage::from_bytes(&query.to_bytes()).unwrap();
+        let qname = parsed.questions().next().unwrap().qname();
+        assert_eq!(qname, &name);
+        assert_eq!(qname.to_string(), "caf\u{fffd}.example");
+    }
+
+    #[test]
+    fn test_labels_ignore_case() {
+        use std::collections::HashMap;
+
+        let mut zone = HashMap::new();
+        zone.insert(DnsLabels::from("www.example.com"), 1);
+
+        assert_eq!(zone.get(&DnsLabels::from("WWW.Example.com")), Some(&1));
+        assert_eq!(zone.get(&DnsLabels::from("www.example.org")), None);
+        assert_ne!(DnsLabels::from("a.b"), DnsLabels::from("ab"));
+    }
+
     #[test]
     fn test_round_trip() {
         let original = DnsMessage {
@@ -325,28 +1566,399 @@ mod test {
                 rd: 0,
                 ra: 0,
                 z: 0,
+                ad: 0,
+                cd: 0,
                 rcode: 0,
                 qdcount: 1,
                 ancount: 1,
-                nscount: 0,
+                nscount: 1,
                 arcount: 0,
             },
             questions: vec![DnsQuestion {
-                qname: DnsLabels(vec!["google".to_string(), "com".to_string()]),
+                qname: DnsLabels::new(["google", "com"]).unwrap(),
                 qtype: 1,
                 qclass: 1,
             }],
-            answers: vec![DnsAnswer {
-                name: DnsLabels(vec!["google".to_string(), "com".to_string()]),
-                answer_type: 0,
+            answers: vec![DnsRecord {
+                name: DnsLabels::new(["google", "com"]).unwrap(),
+                record_type: 0,
                 class: 0,
                 ttl: 0,
                 data: vec![],
             }],
+            additionals: vec![],
+            authorities: vec![DnsRecord {
+                name: DnsLabels::new(["com"]).unwrap(),
+                record_type: rtype::NS,
+                class: class::IN,
+                ttl: 172800,
+                data: DnsLabels::new(["a", "gtld-servers", "net"]).unwrap().0,
+            }],
+            edns: None,
         };
 
         let binding = original.to_bytes();
-        let results = dns_msg(binding.as_slice());
-        assert_eq!(results, Ok((vec![].as_slice(), original)));
+        let (rest, results) = dns_msg(binding.as_slice()).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(results.to_owned(), original);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let query = DnsMessage::query(1, "google.com", 1).to_bytes();
+        assert!(matches!(
+            DnsMessage::from_bytes(&query[..20]),
+            Err(DnsError::Truncated)
+        ));
+
+        let mut bad_count = query.clone();
+        bad_count[4..6].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(matches!(
+            DnsMessage::from_bytes(&bad_count),
+            Err(DnsError::BadCount {
+                section: "question",
+                count: u16::MAX,
+                ..
+            })
+        ));
+
+        let mut bad_label = query.clone();
+        bad_label[12] = 64;
+        assert!(matches!(
+            DnsMessage::from_bytes(&bad_label),
+            Err(DnsError::BadLabelLength(64))
+        ));
+    }
+
+    #[test]
+    fn test_name_limits() {
+        let long_label = "a".repeat(64);
+        assert!(matches!(
+            long_label.parse::<DnsLabels>(),
+            Err(DnsError::BadLabelLength(64))
+        ));
+        assert!(DnsLabels::new([&long_label[..63]]).is_ok());
+
+        // 4 labels of 63 bytes plus length octets and the root label make 257 bytes
+        let long_name = [&long_label[..63]; 4].join(".");
+        assert!(matches!(
+            long_name.parse::<DnsLabels>(),
+            Err(DnsError::NameTooLong(257))
+        ));
+
+        let mut query = MessageBuilder::new().build().to_bytes();
+        for _ in 0..4 {
+            query.push(63);
+            query.extend_from_slice(&long_label.as_bytes()[..63]);
+        }
+        query.extend_from_slice(&[0, 0, 1, 0, 1]);
+        query[5] = 1;
+        assert!(matches!(
+            DnsMessage::from_bytes(&query),
+            Err(DnsError::NameTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_subdomain() {
+        let zone = DnsLabels::from("corp.example");
+        assert!(DnsLabels::from("WWW.Corp.example").is_subdomain_of(&zone));
+        assert!(zone.is_subdomain_of(&zone));
+        assert!(zone.is_subdomain_of(&DnsLabels::from(".")));
+        assert!(!DnsLabels::from("notcorp.example").is_subdomain_of(&zone));
+        assert!(!DnsLabels::from("example").is_subdomain_of(&zone));
+    }
+
+    #[test]
+    fn test_accessors() {
+        let query = DnsMessage::query(42, "www.example.com.", 28).to_bytes();
+        let parsed = DnsMessage::from_bytes(&query).unwrap();
+
+        assert_eq!(parsed.header().id(), 42);
+        assert!(parsed.header().recursion_desired());
+        let question = parsed.questions().next().unwrap();
+        assert_eq!(question.qname().to_string(), "www.example.com");
+        assert_eq!(question.qtype(), 28);
+        assert_eq!(parsed.answers().len(), 0);
+    }
+
+    #[test]
+    fn test_builder_counts() {
+        let query = DnsMessage::query(7, "google.com", 1);
+        let answer = DnsRecord::new(DnsLabels::from("google.com"), 1, 1, 60, vec![1, 2, 3, 4]);
+        let response = MessageBuilder::response_to(&query)
+            .add_question(query.questions[0].clone())
+            .add_answer(answer.clone())
+            .add_answer(answer)
+            .rcode(0)
+            .build();
+
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.qr, 1);
+        assert_eq!(response.header.rd, 1);
+        assert_eq!(response.header.qdcount, 1);
+        assert_eq!(response.header.ancount, 2);
+    }
+
+    #[test]
+    fn test_borrowed_view() {
+        let bytes = DnsMessage::query(3, "mail.example.org", 15).to_bytes();
+        let view = DnsMessageRef::from_bytes(&bytes).unwrap();
+
+        let question = view.questions().next().unwrap();
+        let labels: Vec<&[u8]> = question.qname().labels().collect();
+        assert_eq!(labels, [&b"mail"[..], b"example", b"org"]);
+        assert_eq!(view.to_owned(), DnsMessage::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_compressed_names() {
+        // answer whose owner is a pointer to the question name, then one whose owner is
+        // `www` followed by a pointer to it
+        let mut bytes = DnsMessage::query(3, "example.com", rtype::A).to_bytes();
+        bytes[7] = 2;
+        bytes.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        bytes.extend_from_slice(&[3, b'w', b'w', b'w', 0xC0, 12]);
+        bytes.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 2]);
+
+        let msg = DnsMessage::from_bytes(&bytes).unwrap();
+        let names: Vec<String> = msg.answers().map(|a| a.name().to_string()).collect();
+        assert_eq!(names, ["example.com", "www.example.com"]);
+        assert_eq!(msg.answers().nth(1).unwrap().data(), [192, 0, 2, 2]);
+
+        // a pointer to itself
+        let mut looped = DnsMessage::query(3, "example.com", rtype::A).to_bytes();
+        looped[7] = 1;
+        looped.extend_from_slice(&[0xC0, 29, 0, 1, 0, 1, 0, 0, 0, 60, 0, 0]);
+        assert!(matches!(
+            DnsMessage::from_bytes(&looped),
+            Err(DnsError::BadPointer(29))
+        ));
+    }
+
+    #[test]
+    fn test_emit_compression() {
+        let query = DnsMessage::query(4, "www.example.com", rtype::A);
+        let response = MessageBuilder::response_to(&query)
+            .add_question(query.questions().next().unwrap().clone())
+            .add_answer(DnsRecord::with_rdata(
+                "www.example.com".into(),
+                60,
+                Ipv4Addr::new(192, 0, 2, 1),
+            ))
+            .add_answer(DnsRecord::with_rdata(
+                "mail.example.com".into(),
+                60,
+                Ipv4Addr::new(192, 0, 2, 2),
+            ))
+            .build();
+        let bytes = response.to_bytes();
+
+        // question name at 12, then a pointer to it, then `mail` and a pointer to `example`
+        let answers = 12 + 17 + 4;
+        assert_eq!(bytes[answers..answers + 2], [0xC0, 12]);
+        let second = answers + 2 + 10 + 4;
+        assert_eq!(
+            bytes[second..second + 7],
+            [4, b'm', b'a', b'i', b'l', 0xC0, 16]
+        );
+        assert_eq!(bytes.len(), response.wire_len());
+        assert_eq!(DnsMessage::from_bytes(&bytes).unwrap(), response);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let query = DnsMessage::query(5, "example.com", rtype::A);
+        let response = (0..40).fold(
+            MessageBuilder::response_to(&query).add_question(query.questions[0].clone()),
+            |response, i| {
+                let address = Ipv4Addr::new(192, 0, 2, i);
+                response.add_answer(DnsRecord::with_rdata("example.com".into(), 60, address))
+            },
+        );
+        let response = response.build();
+        assert!(response.wire_len() > MAX_UDP_RESPONSE);
+
+        let truncated = response.clone().truncate(MAX_UDP_RESPONSE);
+        assert!(truncated.header().truncated());
+        assert!(truncated.wire_len() <= MAX_UDP_RESPONSE);
+        assert_eq!(
+            truncated.answers[..],
+            response.answers[..truncated.answers.len()]
+        );
+        let parsed = DnsMessage::from_bytes(&truncated.to_bytes()).unwrap();
+        assert_eq!(parsed, truncated);
+
+        let untouched = response.clone().truncate(MAX_UDP_PAYLOAD);
+        assert_eq!(untouched, response);
+
+        // additional records are dropped first, without TC
+        let glue = (0..40).fold(
+            MessageBuilder::response_to(&query).add_question(query.questions[0].clone()),
+            |response, i| {
+                let address = Ipv4Addr::new(192, 0, 2, i);
+                response.add_additional(DnsRecord::with_rdata("ns.example.com".into(), 60, address))
+            },
+        );
+        let trimmed = glue.build().truncate(MAX_UDP_RESPONSE);
+        assert!(!trimmed.header().truncated());
+        assert!(trimmed.additionals().len() < 40);
+        let parsed = DnsMessage::from_bytes(&trimmed.to_bytes()).unwrap();
+        assert_eq!(parsed, trimmed);
+    }
+
+    #[test]
+    fn test_edns() {
+        let edns = Edns::new(1232)
+            .with_dnssec_ok(true)
+            .with_option(10, vec![0xA1; 8]);
+        let query = MessageBuilder::new()
+            .id(6)
+            .add_question(DnsQuestion::new("example.com".into(), rtype::A, class::IN))
+            .edns(edns.clone())
+            .build();
+        let bytes = query.to_bytes();
+        assert_eq!(
+            bytes[bytes.len() - 23..bytes.len() - 12],
+            [0, 0, 41, 4, 0xD0, 0, 0, 0x80, 0, 0, 12]
+        );
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.header().arcount(), 1);
+        assert_eq!(parsed.edns(), Some(&edns));
+        assert_eq!(parsed.max_udp_response(), 1232);
+
+        let response = response(&parsed);
+        let opt = response.edns().unwrap();
+        assert_eq!(opt.payload_size() as usize, MAX_UDP_PAYLOAD);
+        assert!(opt.dnssec_ok());
+        assert_eq!(opt.options().len(), 0);
+
+        // a second OPT record is a format error
+        let mut twice = bytes.clone();
+        twice[11] = 2;
+        twice.extend_from_slice(&bytes[bytes.len() - 23..]);
+        assert!(DnsMessage::from_bytes(&twice).is_err());
+    }
+
+    #[test]
+    fn test_write_to_slice() {
+        let query = DnsMessage::query(9, "example.com", 1);
+        let mut buf = [0u8; 512];
+        let len = query.write_to(&mut buf.as_mut_slice());
+
+        assert_eq!(&buf[..len], query.to_bytes().as_slice());
+    }
+
+    #[test]
+    fn test_records() {
+        let query = DnsMessage::query(9, "example.com", 1);
+        let response = response(&query);
+
+        let records: Vec<_> = response.records().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, Section::Answer);
+        assert_eq!(records[0].1.record_type(), 1);
+        assert_eq!(response.section(Section::Authority).len(), 0);
+    }
+
+    #[test]
+    fn test_response_echoes_questions() {
+        let query = MessageBuilder::new()
+            .id(5)
+            .add_question(DnsQuestion::new(
+                "WwW.Example.com".into(),
+                rtype::A,
+                class::IN,
+            ))
+            .add_question(DnsQuestion::new(
+                "example.org".into(),
+                rtype::AAAA,
+                class::IN,
+            ))
+            .build();
+        let response = DnsMessage::from_bytes(&response(&query).to_bytes()).unwrap();
+
+        assert_eq!(response.header().qdcount(), 2);
+        assert!(response.questions().eq(query.questions()));
+        assert_eq!(
+            response.questions().next().unwrap().qname().to_string(),
+            "WwW.Example.com"
+        );
+        // only the A question is answered
+        let answers: Vec<_> = response.answers().collect();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].name().to_string(), "WwW.Example.com");
+    }
+
+    #[test]
+    fn test_response_answers_every_question() {
+        let query = ["a.example.com", "b.example.com", "c.example.com"]
+            .into_iter()
+            .fold(MessageBuilder::new().id(8), |builder, name| {
+                builder.add_question(DnsQuestion::new(name.into(), rtype::A, class::IN))
+            })
+            .build();
+        let response = DnsMessage::from_bytes(&response(&query).to_bytes()).unwrap();
+
+        assert_eq!(response.header().qdcount(), 3);
+        assert_eq!(response.header().ancount(), 3);
+        let names: Vec<String> = response.answers().map(|a| a.name().to_string()).collect();
+        assert_eq!(names, ["a.example.com", "b.example.com", "c.example.com"]);
+    }
+
+    #[test]
+    fn test_opcodes() {
+        let query = DnsMessage::query(6, "example.com", rtype::A);
+        let with_opcode = |opcode| {
+            let mut bytes = query.to_bytes();
+            bytes[2] |= opcode << 3;
+            response(&DnsMessage::from_bytes(&bytes).unwrap())
+        };
+
+        for (op, expected) in [
+            (opcode::IQUERY, rcode::NOTIMP),
+            (opcode::STATUS, rcode::NOTIMP),
+            (3, rcode::NOTIMP),
+            (opcode::NOTIFY, rcode::NOTIMP),
+            (opcode::UPDATE, rcode::NOTIMP),
+        ] {
+            let response = with_opcode(op);
+            assert_eq!(response.rcode(), expected, "opcode {op}");
+            assert_eq!(response.header().opcode(), op);
+            assert!(response.is_response());
+            assert!(response.header().recursion_desired());
+            assert!(!response.header().authoritative());
+            assert_eq!(response.questions().len(), 1);
+            assert_eq!(response.answers().len(), 0);
+        }
+        assert_eq!(with_opcode(opcode::QUERY).answers().len(), 1);
+    }
+
+    #[test]
+    fn test_ad_cd_bits() {
+        let mut bytes = DnsMessage::query(8, "example.com", rtype::A).to_bytes();
+        // Z, AD and CD
+        bytes[3] |= 0x70;
+        let query = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(query.header().z(), 1);
+        assert!(query.header().authentic_data());
+        assert!(query.header().checking_disabled());
+        assert_eq!(query.to_bytes(), bytes);
+
+        // CD is echoed, AD and Z are not
+        let response = response(&query);
+        assert!(response.header().checking_disabled());
+        assert!(!response.header().authentic_data());
+        assert_eq!(response.header().z(), 0);
+        assert_eq!(response.to_bytes()[3] & 0x70, 0x10);
+    }
+
+    #[test]
+    fn test_wire_len() {
+        let query = DnsMessage::query(9, "example.com", 1);
+        let response = response(&query);
+
+        assert_eq!(query.wire_len(), query.to_bytes().len());
+        assert_eq!(response.wire_len(), response.to_bytes().len());
     }
 }
@@ -0,0 +1,542 @@
+//! dnstap output: the messages the server exchanges, for DNS observability tools
+//!
+//! [`Dnstap`] encodes events as `dnstap.Dnstap` protobuf messages and sends them in Frame
+//! Streams, to a file (`dnstap -r`) or to the unix socket of a collector such as `dnstap`,
+//! `fstrm_capture` or vector, with the bidirectional READY/ACCEPT/START handshake. As a
+//! [`Layer`] it records the CLIENT_QUERY and CLIENT_RESPONSE of every query; given to a
+//! [`Forwarder`](crate::forward::Forwarder) or [`Recursor`](crate::recursive::Recursor) it
+//! records the FORWARDER_* or RESOLVER_* messages exchanged with upstreams.
+//!
+//! Events are written by a background task, dropped when it falls behind, and the socket is
+//! reconnected when the collector goes away. Client addresses go through an [`Anonymizer`]
+//! first; a hashed client is left out of the event, as dnstap has no field for it.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::dns::{DnsMessage, ToBytes};
+use crate::handler::{RequestCtx, Transport};
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::privacy::{Anonymizer, Client};
+use crate::warn;
+
+/// Frame Streams content type of dnstap
+pub const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Events waiting for the writer before new ones are dropped
+const QUEUE: usize = 4096;
+
+/// Frame Streams control frame types
+mod control {
+    pub const ACCEPT: u32 = 1;
+    pub const START: u32 = 2;
+    pub const STOP: u32 = 3;
+    pub const READY: u32 = 4;
+    /// The only control field
+    pub const CONTENT_TYPE: u32 = 1;
+}
+
+/// `dnstap.Message.Type` of the events the server records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    ResolverQuery = 3,
+    ResolverResponse = 4,
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+impl MessageType {
+    fn is_query(self) -> bool {
+        matches!(
+            self,
+            MessageType::ResolverQuery | MessageType::ClientQuery | MessageType::ForwarderQuery
+        )
+    }
+
+    /// Whether the peer is a client that sent the query, rather than an upstream asked
+    fn with_client(self) -> bool {
+        matches!(self, MessageType::ClientQuery | MessageType::ClientResponse)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Sink {
+    File(PathBuf),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Records messages in a dnstap output
+#[derive(Debug, Clone)]
+pub struct Dnstap {
+    tx: Sender<Vec<u8>>,
+    identity: Option<Vec<u8>>,
+    anonymizer: Anonymizer,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Dnstap {
+    /// Writes a Frame Streams file at `path`, replacing it. Must be called inside a tokio
+    /// runtime.
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self::start(Sink::File(path.into()))
+    }
+
+    /// Sends to the collector listening on the unix socket at `path`. Must be called inside a
+    /// tokio runtime.
+    #[cfg(unix)]
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self::start(Sink::Unix(path.into()))
+    }
+
+    fn start(sink: Sink) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        tokio::spawn(write(sink, rx));
+        Self {
+            tx,
+            identity: None,
+            anonymizer: Anonymizer::off(),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Name of the server in its events, such as its hostname
+    pub fn identity(mut self, identity: impl Into<Vec<u8>>) -> Self {
+        self.identity = Some(identity.into());
+        self
+    }
+
+    /// How clients are recorded, as they are by default; upstreams are always recorded as
+    /// they are
+    pub fn anonymize(mut self, anonymizer: Anonymizer) -> Self {
+        self.anonymizer = anonymizer;
+        self
+    }
+
+    /// Events dropped since the start because the writer fell behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Records `message` of type `kind`, exchanged with `peer` over `transport`; responses
+    /// carry the time of their query too
+    pub fn record(
+        &self,
+        kind: MessageType,
+        peer: SocketAddr,
+        transport: Transport,
+        query_time: SystemTime,
+        response_time: Option<SystemTime>,
+        message: &DnsMessage,
+    ) {
+        let event = kind.encode(
+            self.recorded_peer(kind, peer),
+            transport,
+            query_time,
+            response_time,
+            &message.to_bytes(),
+        );
+        let mut frame = Vec::with_capacity(event.len() + 64);
+        if let Some(identity) = &self.identity {
+            bytes_field(&mut frame, 1, identity);
+        }
+        let version = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+        bytes_field(&mut frame, 2, version.as_bytes());
+        bytes_field(&mut frame, 14, &event);
+        // Dnstap.Type MESSAGE
+        varint_field(&mut frame, 15, 1);
+        if self.tx.try_send(frame).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// `peer` as the event records it, `None` to leave it out
+    fn recorded_peer(&self, kind: MessageType, peer: SocketAddr) -> Option<SocketAddr> {
+        if !kind.with_client() {
+            return Some(peer);
+        }
+        match self.anonymizer.client(peer.ip()) {
+            Client::Addr(ip) => Some(SocketAddr::new(ip, peer.port())),
+            Client::Hashed(_) => None,
+        }
+    }
+}
+
+impl MessageType {
+    /// `dnstap.Message` of this type
+    fn encode(
+        self,
+        peer: Option<SocketAddr>,
+        transport: Transport,
+        query_time: SystemTime,
+        response_time: Option<SystemTime>,
+        wire: &[u8],
+    ) -> Vec<u8> {
+        let mut message = Vec::with_capacity(wire.len() + 48);
+        varint_field(&mut message, 1, self as u64);
+        let protocol = match transport {
+            Transport::Udp => 1,
+            Transport::Tcp => 2,
+            Transport::Https => 4,
+        };
+        varint_field(&mut message, 3, protocol);
+        if let Some(peer) = peer {
+            let (family, address) = match peer.ip() {
+                IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+            };
+            varint_field(&mut message, 2, family);
+            // query_address/port for the client that sent a query, response_* for an upstream
+            let (address_field, port_field) = if self.with_client() { (4, 6) } else { (5, 7) };
+            bytes_field(&mut message, address_field, &address);
+            varint_field(&mut message, port_field, peer.port().into());
+        }
+        time_fields(&mut message, 8, query_time);
+        if self.is_query() {
+            bytes_field(&mut message, 10, wire);
+        } else {
+            time_fields(
+                &mut message,
+                12,
+                response_time.unwrap_or_else(SystemTime::now),
+            );
+            bytes_field(&mut message, 14, wire);
+        }
+        message
+    }
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    varint(buf, field << 3);
+    varint(buf, value);
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Seconds in `field` (uint64) and nanoseconds in the next one (fixed32)
+fn time_fields(buf: &mut Vec<u8>, field: u64, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    varint_field(buf, field, since_epoch.as_secs());
+    varint(buf, (field + 1) << 3 | 5);
+    buf.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+}
+
+fn control_frame(kind: u32) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(16 + CONTENT_TYPE.len());
+    // a zero length escapes a control frame
+    frame.extend_from_slice(&0u32.to_be_bytes());
+    let fields = if kind == control::STOP {
+        0
+    } else {
+        8 + CONTENT_TYPE.len()
+    };
+    frame.extend_from_slice(&(4 + fields as u32).to_be_bytes());
+    frame.extend_from_slice(&kind.to_be_bytes());
+    if kind != control::STOP {
+        frame.extend_from_slice(&control::CONTENT_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        frame.extend_from_slice(CONTENT_TYPE);
+    }
+    frame
+}
+
+type Output = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Opens `sink` and starts a stream in it
+async fn connect(sink: &Sink) -> io::Result<Output> {
+    let mut output: Output = match sink {
+        Sink::File(path) => Box::pin(tokio::fs::File::create(path).await?),
+        #[cfg(unix)]
+        Sink::Unix(path) => {
+            let mut stream = tokio::net::UnixStream::connect(path).await?;
+            stream.write_all(&control_frame(control::READY)).await?;
+            let mut header = [0u8; 8];
+            stream.read_exact(&mut header).await?;
+            let len = u32::from_be_bytes(header[4..].try_into().unwrap());
+            if header[..4] != [0; 4] || !(4..=512).contains(&len) {
+                return Err(io::Error::other("expected a control frame"));
+            }
+            let mut frame = vec![0u8; len as usize];
+            stream.read_exact(&mut frame).await?;
+            let accepted = frame[..4] == control::ACCEPT.to_be_bytes()
+                && frame
+                    .windows(CONTENT_TYPE.len())
+                    .any(|window| window == CONTENT_TYPE);
+            if !accepted {
+                return Err(io::Error::other("collector did not accept dnstap"));
+            }
+            Box::pin(stream)
+        }
+    };
+    output.write_all(&control_frame(control::START)).await?;
+    Ok(output)
+}
+
+/// Writes events as they come until every [`Dnstap`] is gone, then stops the stream
+async fn write(sink: Sink, mut rx: Receiver<Vec<u8>>) {
+    let mut output = None;
+    let mut failing = false;
+    let mut events = Vec::new();
+    while rx.recv_many(&mut events, QUEUE).await > 0 {
+        if output.is_none() {
+            match connect(&sink).await {
+                Ok(connected) => {
+                    output = Some(connected);
+                    failing = false;
+                }
+                Err(err) => {
+                    if !failing {
+                        warn!("failed to open dnstap output {sink:?} with {err}");
+                    }
+                    failing = true;
+                    events.clear();
+                    continue;
+                }
+            }
+        }
+        let mut frames = Vec::new();
+        for event in events.drain(..) {
+            frames.extend_from_slice(&(event.len() as u32).to_be_bytes());
+            frames.extend_from_slice(&event);
+        }
+        let out = output.as_mut().unwrap();
+        if let Err(err) = async {
+            out.write_all(&frames).await?;
+            out.flush().await
+        }
+        .await
+        {
+            warn!("failed to write to dnstap output {sink:?} with {err}");
+            output = None;
+        }
+    }
+    if let Some(mut out) = output {
+        let _ = out.write_all(&control_frame(control::STOP)).await;
+        let _ = out.flush().await;
+    }
+}
+
+impl Layer for Dnstap {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let (client, transport) = (ctx.client(), ctx.transport());
+            let query_time = SystemTime::now();
+            self.record(
+                MessageType::ClientQuery,
+                client,
+                transport,
+                query_time,
+                None,
+                &query,
+            );
+            let response = next.run(query, ctx).await;
+            self.record(
+                MessageType::ClientResponse,
+                client,
+                transport,
+                query_time,
+                Some(SystemTime::now()),
+                &response,
+            );
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::dns::rtype;
+    use crate::handler::{DefaultHandler, RequestHandler};
+    use crate::pipeline::Pipeline;
+
+    /// Fields of a protobuf message: number, and value or contents
+    fn fields(mut buf: &[u8]) -> Vec<(u64, u64, Vec<u8>)> {
+        let read_varint = |buf: &mut &[u8]| {
+            let (mut value, mut shift) = (0u64, 0);
+            loop {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                value |= u64::from(byte & 0x7f) << shift;
+                shift += 7;
+                if byte < 0x80 {
+                    return value;
+                }
+            }
+        };
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf);
+            match key & 7 {
+                0 => fields.push((key >> 3, read_varint(&mut buf), Vec::new())),
+                2 => {
+                    let len = read_varint(&mut buf) as usize;
+                    fields.push((key >> 3, 0, buf[..len].to_vec()));
+                    buf = &buf[len..];
+                }
+                5 => {
+                    fields.push((key >> 3, 0, buf[..4].to_vec()));
+                    buf = &buf[4..];
+                }
+                wire => panic!("unexpected wire type {wire}"),
+            }
+        }
+        fields
+    }
+
+    /// Data frames of a Frame Streams file, after checking its control frames
+    fn data_frames(mut stream: &[u8]) -> Vec<Vec<u8>> {
+        assert!(stream.starts_with(&control_frame(control::START)));
+        stream = &stream[control_frame(control::START).len()..];
+        let mut frames = Vec::new();
+        while stream != control_frame(control::STOP) {
+            let len = u32::from_be_bytes(stream[..4].try_into().unwrap()) as usize;
+            frames.push(stream[4..4 + len].to_vec());
+            stream = &stream[4 + len..];
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_file() {
+        let path = std::env::temp_dir().join(format!("dnstap-test-{}.fstrm", std::process::id()));
+        let tap = Dnstap::file(&path).identity("ns1");
+        let pipeline = Pipeline::new(DefaultHandler).layer(tap);
+        let client = SocketAddr::from(([192, 0, 2, 1], 5353));
+        let query = DnsMessage::query(9, "codecrafters.io", rtype::A);
+        pipeline
+            .handle(query.clone(), RequestCtx::new(client, Transport::Udp))
+            .await;
+        drop(pipeline);
+
+        let mut stream = Vec::new();
+        for _ in 0..100 {
+            stream = std::fs::read(&path).unwrap_or_default();
+            if stream.ends_with(&control_frame(control::STOP)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let frames = data_frames(&stream);
+        assert_eq!(frames.len(), 2);
+        let dnstap = fields(&frames[0]);
+        assert_eq!(dnstap[0], (1, 0, b"ns1".to_vec()));
+        assert_eq!(dnstap.last().unwrap(), &(15, 1, Vec::new()));
+        let message = fields(&dnstap.iter().find(|field| field.0 == 14).unwrap().2);
+        assert_eq!(message[0], (1, MessageType::ClientQuery as u64, Vec::new()));
+        assert!(message.contains(&(4, 0, vec![192, 0, 2, 1])));
+        assert!(message.contains(&(6, 5353, Vec::new())));
+        assert!(message.contains(&(10, 0, query.to_bytes())));
+
+        let message = fields(&fields(&frames[1])[2].2);
+        assert_eq!(message[0].1, MessageType::ClientResponse as u64);
+        assert!(message.iter().any(|field| field.0 == 14));
+    }
+
+    #[test]
+    fn test_anonymized_clients() {
+        let tap = |anonymizer| Dnstap {
+            tx: mpsc::channel(1).0,
+            identity: None,
+            anonymizer,
+            dropped: Arc::default(),
+        };
+        let client = SocketAddr::from(([192, 0, 2, 77], 5353));
+        let upstream = SocketAddr::from(([9, 9, 9, 9], 53));
+
+        let truncate = tap(Anonymizer::truncate(24, 56));
+        assert_eq!(
+            truncate.recorded_peer(MessageType::ClientQuery, client),
+            Some(SocketAddr::from(([192, 0, 2, 0], 5353)))
+        );
+        assert_eq!(
+            truncate.recorded_peer(MessageType::ForwarderQuery, upstream),
+            Some(upstream)
+        );
+        let hash = tap(Anonymizer::hash());
+        assert_eq!(
+            hash.recorded_peer(MessageType::ClientResponse, client),
+            None
+        );
+
+        let message =
+            MessageType::ClientQuery.encode(None, Transport::Udp, SystemTime::now(), None, &[]);
+        assert!(!fields(&message)
+            .iter()
+            .any(|field| matches!(field.0, 2 | 4 | 6)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_handshake() {
+        let path = std::env::temp_dir().join(format!("dnstap-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let tap = Dnstap::unix(&path);
+        let upstream = SocketAddr::from(([9, 9, 9, 9], 53));
+        let query = DnsMessage::query(1, "codecrafters.io", rtype::A);
+        let now = SystemTime::now();
+        tap.record(
+            MessageType::ForwarderQuery,
+            upstream,
+            Transport::Udp,
+            now,
+            None,
+            &query,
+        );
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let ready = control_frame(control::READY);
+        let mut buf = vec![0u8; ready.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, ready);
+        stream
+            .write_all(&control_frame(control::ACCEPT))
+            .await
+            .unwrap();
+        let start = control_frame(control::START);
+        let mut buf = vec![0u8; start.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, start);
+        let len = stream.read_u32().await.unwrap();
+        let mut frame = vec![0u8; len as usize];
+        stream.read_exact(&mut frame).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let message = fields(&fields(&frame)[1].2);
+        assert_eq!(message[0].1, MessageType::ForwarderQuery as u64);
+        assert!(message.contains(&(5, 0, vec![9, 9, 9, 9])));
+        assert!(message.contains(&(7, 53, Vec::new())));
+    }
+}
@@ -0,0 +1,283 @@
+//! DNS-over-HTTPS listener (RFC 8484)
+//!
+//! Queries come in at [`PATH`], either as `GET /dns-query?dns=<base64url message>` or as a
+//! `POST` with an `application/dns-message` body, and the answer goes back as an
+//! `application/dns-message` body.
+//!
+//! The listener speaks plain HTTP/1.1 through [`http`](crate::http): browsers and OS stubs
+//! reach it through a reverse proxy terminating TLS and HTTP/2 (nginx, haproxy, Caddy), which
+//! is then the client the pipeline sees. Like the TCP listener it limits the connections open
+//! at once and the time requests and answers take, see [`DohOptions`]. Answers carry a
+//! `Cache-Control: max-age` of their smallest TTL, so HTTP caches keep them no longer than DNS
+//! caches would.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+use crate::base64;
+use crate::dns::{error_response, rcode, DnsMessage, ToBytes};
+use crate::handler::{RequestCtx, RequestHandler, Transport};
+use crate::http::{self, Request};
+use crate::rdata::negative_ttl;
+use crate::{debug, error, warn};
+
+/// Where queries are accepted
+pub const PATH: &str = "/dns-query";
+
+const CONTENT_TYPE: &str = "application/dns-message";
+
+/// Tunables of the DoH listener
+#[derive(Debug, Clone)]
+pub struct DohOptions {
+    max_connections: usize,
+    read_timeout: Duration,
+    query_timeout: Duration,
+}
+
+impl Default for DohOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 1_000,
+            read_timeout: Duration::from_secs(10),
+            query_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl DohOptions {
+    /// Maximum number of connections open at once
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Time a client gets to send its whole request before the connection is closed
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Time a handler gets to answer before the client gets SERVFAIL instead
+    pub fn query_timeout(mut self, query_timeout: Duration) -> Self {
+        self.query_timeout = query_timeout;
+        self
+    }
+}
+
+/// Response to one request
+struct Reply {
+    status: u16,
+    body: Vec<u8>,
+    /// Seconds the answer may be cached, for `Cache-Control`
+    max_age: Option<u32>,
+}
+
+impl Reply {
+    fn status(status: u16) -> Self {
+        Self {
+            status,
+            body: Vec::new(),
+            max_age: None,
+        }
+    }
+}
+
+/// Serves DoH requests accepted on `listener` with `handler` and default [`DohOptions`]
+pub async fn run<H: RequestHandler>(listener: TcpListener, handler: H) {
+    run_with_options(listener, handler, DohOptions::default()).await
+}
+
+/// Serves DoH requests accepted on `listener` with `handler` until the task is dropped, each
+/// connection on its own task
+pub async fn run_with_options<H: RequestHandler>(
+    listener: TcpListener,
+    handler: H,
+    options: DohOptions,
+) {
+    let handler = Arc::new(handler);
+    let connections = Arc::new(Semaphore::new(options.max_connections));
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("failed to accept DoH connection with {err}");
+                continue;
+            }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            warn!("refused DoH connection from {addr}, too many connections");
+            continue;
+        };
+        let handler = handler.clone();
+        let options = options.clone();
+        tokio::spawn(async move {
+            let read = tokio::time::timeout(options.read_timeout, http::read_request(&mut stream));
+            let reply = match read.await {
+                Ok(Ok(request)) => answer(&request, addr, handler.as_ref(), &options).await,
+                Ok(Err(err)) => {
+                    debug!("invalid DoH request from {addr}: {err}");
+                    Reply::status(400)
+                }
+                Err(_) => {
+                    debug!("DoH request from {addr} not completed in time");
+                    return;
+                }
+            };
+            let content_type = if reply.status == 200 {
+                CONTENT_TYPE
+            } else {
+                "text/plain"
+            };
+            let cache_control = reply.max_age.map(|max_age| format!("max-age={max_age}"));
+            let headers: Vec<(&str, &str)> = cache_control
+                .iter()
+                .map(|value| ("Cache-Control", value.as_str()))
+                .collect();
+            let write = http::write_response(
+                &mut stream,
+                reply.status,
+                content_type,
+                &headers,
+                &reply.body,
+            );
+            let _ = tokio::time::timeout(options.read_timeout, write).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Response to `request`
+async fn answer<H: RequestHandler>(
+    request: &Request,
+    addr: SocketAddr,
+    handler: &H,
+    options: &DohOptions,
+) -> Reply {
+    if request.path() != PATH {
+        return Reply::status(404);
+    }
+    let wire = match request.method() {
+        "GET" => {
+            let dns = request
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="));
+            match dns.and_then(base64::decode_url) {
+                Some(wire) => wire,
+                None => return Reply::status(400),
+            }
+        }
+        "POST" => {
+            let content_type = request.header("content-type").unwrap_or_default();
+            if !content_type.eq_ignore_ascii_case(CONTENT_TYPE) {
+                return Reply::status(415);
+            }
+            request.body().to_vec()
+        }
+        _ => return Reply::status(405),
+    };
+    let query = match DnsMessage::from_bytes(&wire) {
+        Ok(query) if !query.is_response() => query,
+        _ => return Reply::status(400),
+    };
+
+    let handled = handler.handle(query.clone(), RequestCtx::new(addr, Transport::Https));
+    let response = match tokio::time::timeout(options.query_timeout, handled).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("DoH query from {addr} timed out");
+            error_response(&query, rcode::SERVFAIL)
+        }
+    };
+    let response = response.truncate(u16::MAX as usize);
+    Reply {
+        status: 200,
+        body: response.to_bytes(),
+        max_age: max_age(&response),
+    }
+}
+
+/// Freshness of `response` for HTTP caches: the smallest TTL of its records, the SOA of a
+/// negative answer counting for its negative TTL (RFC 8484 section 5.1)
+fn max_age(response: &DnsMessage) -> Option<u32> {
+    let ttls = response.records().map(|(_, record)| record.ttl());
+    let negative = response.authorities().filter_map(negative_ttl);
+    ttls.chain(negative).min()
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::dns::rtype;
+    use crate::handler::DefaultHandler;
+
+    async fn ask(raw: Vec<u8>) -> (u16, Vec<u8>) {
+        let request = http::read_request(raw.as_slice()).await.unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 443));
+        let reply = answer(&request, addr, &DefaultHandler, &DohOptions::default()).await;
+        if reply.status == 200 {
+            // DefaultHandler answers with TTL 60
+            assert_eq!(reply.max_age, Some(60));
+        }
+        (reply.status, reply.body)
+    }
+
+    #[tokio::test]
+    async fn test_get_and_post() {
+        let query = DnsMessage::query(0, "codecrafters.io", rtype::A).to_bytes();
+
+        let get = format!(
+            "GET {PATH}?ct&dns={} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            base64::encode_url(&query)
+        );
+        let (status, body) = ask(get.into_bytes()).await;
+        assert_eq!(status, 200);
+        assert_eq!(DnsMessage::from_bytes(&body).unwrap().answers().len(), 1);
+
+        let mut post = format!(
+            "POST {PATH} HTTP/1.1\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\n\r\n",
+            query.len()
+        )
+        .into_bytes();
+        post.extend_from_slice(&query);
+        let (status, body) = ask(post).await;
+        assert_eq!(status, 200);
+        assert_eq!(DnsMessage::from_bytes(&body).unwrap().answers().len(), 1);
+
+        let invalid = format!("GET {PATH}?dns=!! HTTP/1.1\r\n\r\n");
+        assert_eq!(ask(invalid.into_bytes()).await.0, 400);
+        let elsewhere = "GET /resolve?name=codecrafters.io HTTP/1.1\r\n\r\n";
+        assert_eq!(ask(elsewhere.into()).await.0, 404);
+        let text = format!("POST {PATH} HTTP/1.1\r\nContent-Type: text/plain\r\n\r\n");
+        assert_eq!(ask(text.into_bytes()).await.0, 415);
+    }
+
+    #[tokio::test]
+    async fn test_slow_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = DohOptions::default()
+            .max_connections(1)
+            .read_timeout(Duration::from_millis(100));
+        tokio::spawn(run_with_options(listener, DefaultHandler, options));
+
+        // half a request holds the only connection until the read timeout
+        let mut slow = TcpStream::connect(addr).await.unwrap();
+        slow.write_all(b"GET /dns-query").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(refused.read_to_end(&mut buf).await.unwrap_or(0), 0);
+
+        let closed = tokio::time::timeout(Duration::from_secs(1), slow.read_to_end(&mut buf));
+        assert_eq!(closed.await.unwrap().unwrap_or(0), 0);
+    }
+}
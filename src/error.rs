@@ -0,0 +1,67 @@
+//! Error type shared by the codec and the server
+
+use std::fmt::Display;
+use std::io;
+
+use nom::error::{ErrorKind, FromExternalError, ParseError};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("message truncated")]
+    Truncated,
+    #[error("invalid label length {0}")]
+    BadLabelLength(usize),
+    #[error("name of {0} bytes exceeds 255")]
+    NameTooLong(usize),
+    #[error("invalid compression pointer to offset {0}")]
+    BadPointer(usize),
+    #[error("{count} {section} entries cannot fit in the {remaining} bytes after the header")]
+    BadCount {
+        section: &'static str,
+        count: u16,
+        remaining: usize,
+    },
+    #[error("unsupported record type {0}")]
+    UnsupportedType(u16),
+    #[error("malformed message: {0}")]
+    Malformed(String),
+    #[error("config file: {0}")]
+    Config(String),
+    #[error("usage: {0}")]
+    Usage(String),
+    #[error("zone file: {0}")]
+    Zone(String),
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("timed out")]
+    Timeout,
+}
+
+impl<I> ParseError<I> for DnsError {
+    fn from_error_kind(_input: I, kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Eof => DnsError::Truncated,
+            kind => DnsError::Malformed(kind.description().to_string()),
+        }
+    }
+
+    fn append(_input: I, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<I, E: Display> FromExternalError<I, E> for DnsError {
+    fn from_external_error(_input: I, _kind: ErrorKind, e: E) -> Self {
+        DnsError::Malformed(e.to_string())
+    }
+}
+
+impl From<nom::Err<DnsError>> for DnsError {
+    fn from(err: nom::Err<DnsError>) -> Self {
+        match err {
+            nom::Err::Error(err) | nom::Err::Failure(err) => err,
+            nom::Err::Incomplete(_) => DnsError::Truncated,
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! C interface to the message codec, declared in `include/dns.h`
+//!
+//! Cargo.toml only builds an rlib, so the shared library is produced with
+//! `cargo rustc --release --lib --crate-type cdylib`.
+
+use std::ffi::{c_char, CString};
+use std::fmt::Write;
+use std::{ptr, slice};
+
+use crate::dns::{DnsLabels, DnsMessage, DnsRecord};
+use crate::json::quote;
+use crate::rdata::RData;
+
+/// Parses `len` bytes at `buf`, returning null if the message is malformed
+///
+/// # Safety
+///
+/// `buf` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn dns_parse(buf: *const u8, len: usize) -> *mut DnsMessage {
+    if buf.is_null() {
+        return ptr::null_mut();
+    }
+    let bytes = slice::from_raw_parts(buf, len);
+    match DnsMessage::from_bytes(bytes) {
+        Ok(msg) => Box::into_raw(Box::new(msg)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// JSON rendering of `msg`, to be released with [`dns_string_free`]
+///
+/// # Safety
+///
+/// `msg` must be null or a pointer returned by [`dns_parse`] that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn dns_to_json(msg: *const DnsMessage) -> *mut c_char {
+    let Some(msg) = msg.as_ref() else {
+        return ptr::null_mut();
+    };
+    match CString::new(to_json(msg)) {
+        Ok(json) => json.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `msg` must be null or a pointer returned by [`dns_parse`] that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn dns_free(msg: *mut DnsMessage) {
+    if !msg.is_null() {
+        drop(Box::from_raw(msg));
+    }
+}
+
+/// # Safety
+///
+/// `json` must be null or a pointer returned by [`dns_to_json`] that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn dns_string_free(json: *mut c_char) {
+    if !json.is_null() {
+        drop(CString::from_raw(json));
+    }
+}
+
+fn to_json(msg: &DnsMessage) -> String {
+    let header = msg.header();
+    let mut json = String::new();
+    let _ = write!(
+        json,
+        "{{\"id\":{},\"qr\":{},\"opcode\":{},\"aa\":{},\"tc\":{},\"rd\":{},\"ra\":{},\
+         \"ad\":{},\"cd\":{},\"rcode\":{}",
+        header.id(),
+        header.is_response(),
+        header.opcode(),
+        header.authoritative(),
+        header.truncated(),
+        header.recursion_desired(),
+        header.recursion_available(),
+        header.authentic_data(),
+        header.checking_disabled(),
+        header.rcode(),
+    );
+
+    json.push_str(",\"questions\":[");
+    for (i, question) in msg.questions().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            "{{\"name\":{},\"type\":{},\"class\":{}}}",
+            json_name(question.qname()),
+            question.qtype(),
+            question.qclass()
+        );
+    }
+
+    json.push_str("],\"answers\":[");
+    for (i, record) in msg.answers().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json_record(&mut json, record);
+    }
+    json.push_str("]}");
+    json
+}
+
+fn json_record(json: &mut String, record: &DnsRecord) {
+    let _ = write!(
+        json,
+        "{{\"name\":{},\"type\":{},\"class\":{},\"ttl\":{},\"rdata\":",
+        json_name(record.name()),
+        record.record_type(),
+        record.class(),
+        record.ttl()
+    );
+    match record.rdata() {
+        Ok(RData::A(ip)) => quote(json, &ip.to_string()),
+        Ok(RData::Aaaa(ip)) => quote(json, &ip.to_string()),
+        Ok(RData::Cname(name) | RData::Ns(name) | RData::Ptr(name)) => {
+            json.push_str(&json_name(&name));
+        }
+        Ok(RData::Mx {
+            preference,
+            exchange,
+        }) => {
+            let _ = write!(
+                json,
+                "{{\"preference\":{preference},\"exchange\":{}}}",
+                json_name(&exchange)
+            );
+        }
+        Ok(RData::Txt(strings)) => {
+            json.push('[');
+            for (i, string) in strings.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                quote(json, &String::from_utf8_lossy(string));
+            }
+            json.push(']');
+        }
+        Ok(RData::Soa {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        }) => {
+            let _ = write!(
+                json,
+                "{{\"mname\":{},\"rname\":{},\"serial\":{serial},\"refresh\":{refresh},\
+                 \"retry\":{retry},\"expire\":{expire},\"minimum\":{minimum}}}",
+                json_name(&mname),
+                json_name(&rname)
+            );
+        }
+        Ok(RData::Srv {
+            priority,
+            weight,
+            port,
+            target,
+        }) => {
+            let _ = write!(
+                json,
+                "{{\"priority\":{priority},\"weight\":{weight},\"port\":{port},\"target\":{}}}",
+                json_name(&target)
+            );
+        }
+        Ok(RData::Caa { flags, tag, value }) => {
+            let _ = write!(json, "{{\"flags\":{flags},\"tag\":");
+            quote(json, &tag);
+            json.push_str(",\"value\":");
+            quote(json, &String::from_utf8_lossy(&value));
+            json.push('}');
+        }
+        Ok(RData::Unknown(..)) | Err(_) => {
+            let hex: String = record.data().iter().map(|b| format!("{b:02x}")).collect();
+            quote(json, &hex);
+        }
+    }
+    json.push('}');
+}
+
+fn json_name(name: &DnsLabels) -> String {
+    let mut json = String::new();
+    quote(&mut json, &name.to_string());
+    json
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CStr;
+
+    use super::*;
+    use crate::dns::{response, ToBytes};
+
+    #[test]
+    fn test_parse_to_json() {
+        let bytes = response(&DnsMessage::query(2, "codecrafters.io", 1)).to_bytes();
+
+        unsafe {
+            let msg = dns_parse(bytes.as_ptr(), bytes.len());
+            assert!(!msg.is_null());
+            let json = dns_to_json(msg);
+            assert_eq!(
+                CStr::from_ptr(json).to_str().unwrap(),
+                "{\"id\":2,\"qr\":true,\"opcode\":0,\"aa\":false,\"tc\":false,\"rd\":true,\
+                 \"ra\":false,\"ad\":false,\"cd\":false,\"rcode\":0,\
+                 \"questions\":[{\"name\":\"codecrafters.io\",\"type\":1,\"class\":1}],\
+                 \"answers\":[{\"name\":\"codecrafters.io\",\"type\":1,\"class\":1,\
+                 \"ttl\":60,\"rdata\":\"8.8.8.8\"}]}"
+            );
+            dns_string_free(json);
+            dns_free(msg);
+
+            assert!(dns_parse(bytes.as_ptr(), 5).is_null());
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! Forwarding queries to an upstream resolver over UDP, retrying over TCP when the answer
+//! does not fit in a datagram
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::dns::{
+    error_response, opcode, rcode, rtype, DnsMessage, DnsQuestion, Edns, MessageBuilder, ToBytes,
+    MAX_UDP_PAYLOAD,
+};
+use crate::dnstap::{Dnstap, MessageType};
+use crate::error::DnsError;
+use crate::handler::{RequestCtx, RequestHandler, Transport};
+use crate::warn;
+
+/// Sends `query` to `upstream` under a fresh random id and waits up to `timeout` for the
+/// matching response, which is returned carrying the id of `query`.
+///
+/// Datagrams from other addresses, with another id or for other questions are ignored, so a
+/// spoofed reply has to guess the id and the ephemeral port. A truncated (TC) reply is followed
+/// by the same exchange over TCP, see [`exchange_tcp`].
+pub async fn exchange(
+    upstream: SocketAddr,
+    query: &DnsMessage,
+    timeout: Duration,
+) -> Result<DnsMessage, DnsError> {
+    let response = exchange_udp(upstream, query, timeout).await?;
+    if !response.header().truncated() {
+        return Ok(response);
+    }
+    exchange_tcp(upstream, query, timeout).await
+}
+
+async fn exchange_udp(
+    upstream: SocketAddr,
+    query: &DnsMessage,
+    timeout: Duration,
+) -> Result<DnsMessage, DnsError> {
+    let local: SocketAddr = match upstream {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let sock = UdpSocket::bind(local).await?;
+    let id: u16 = rand::random();
+    let mut bytes = query.to_bytes();
+    bytes[..2].copy_from_slice(&id.to_be_bytes());
+    sock.send_to(&bytes, upstream).await?;
+
+    let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+    let receive = async {
+        loop {
+            let (len, from) = sock.recv_from(&mut buf).await?;
+            if from != upstream {
+                continue;
+            }
+            let Ok(response) = DnsMessage::from_bytes(&buf[..len]) else {
+                continue;
+            };
+            let matches = response.is_response()
+                && response.id() == id
+                && response.questions().eq(query.questions());
+            if matches {
+                return Ok(response.retarget(query));
+            }
+        }
+    };
+    tokio::time::timeout(timeout, receive)
+        .await
+        .map_err(|_| DnsError::Timeout)?
+}
+
+/// Sends `query` to `upstream` over a new TCP connection, for answers too large for UDP, and
+/// waits up to `timeout` for the response
+pub async fn exchange_tcp(
+    upstream: SocketAddr,
+    query: &DnsMessage,
+    timeout: Duration,
+) -> Result<DnsMessage, DnsError> {
+    let id: u16 = rand::random();
+    let mut bytes = query.to_bytes();
+    bytes[..2].copy_from_slice(&id.to_be_bytes());
+    let framed = [&(bytes.len() as u16).to_be_bytes()[..], &bytes].concat();
+    let transfer = async {
+        let mut stream = TcpStream::connect(upstream).await?;
+        stream.write_all(&framed).await?;
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).await?;
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).await?;
+        let response = DnsMessage::from_bytes(&buf)?;
+        let matches = response.is_response()
+            && response.id() == id
+            && response.questions().eq(query.questions());
+        if !matches {
+            return Err(DnsError::Malformed(format!(
+                "{upstream} answered another query over TCP"
+            )));
+        }
+        Ok(response.retarget(query))
+    };
+    tokio::time::timeout(timeout, transfer)
+        .await
+        .map_err(|_| DnsError::Timeout)?
+}
+
+/// Handler relaying every query to an upstream resolver.
+///
+/// Queries with several questions are split into one query per question, since resolvers
+/// generally answer only the first, and the answers merged back into one response. Any failed
+/// exchange turns the whole response into SERVFAIL.
+#[derive(Debug, Clone)]
+pub struct Forwarder {
+    upstreams: Vec<SocketAddr>,
+    timeout: Duration,
+    dnstap: Option<Dnstap>,
+}
+
+impl Forwarder {
+    pub fn new(upstream: SocketAddr) -> Self {
+        Self {
+            upstreams: vec![upstream],
+            timeout: Duration::from_secs(2),
+            dnstap: None,
+        }
+    }
+
+    /// Upstream asked when the ones before it fail or time out
+    pub fn fallback(mut self, upstream: SocketAddr) -> Self {
+        self.upstreams.push(upstream);
+        self
+    }
+
+    /// Time the upstream gets to answer each question, 2s by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Records the queries sent to upstreams and their responses in `dnstap`
+    pub fn dnstap(mut self, dnstap: Dnstap) -> Self {
+        self.dnstap = Some(dnstap);
+        self
+    }
+
+    /// Response of the first upstream that answers, in order
+    async fn exchange(&self, query: &DnsMessage) -> Result<DnsMessage, DnsError> {
+        let mut last_err = DnsError::Timeout;
+        for &upstream in &self.upstreams {
+            let sent = SystemTime::now();
+            if let Some(dnstap) = &self.dnstap {
+                let kind = MessageType::ForwarderQuery;
+                dnstap.record(kind, upstream, Transport::Udp, sent, None, query);
+            }
+            match exchange(upstream, query, self.timeout).await {
+                Ok(response) => {
+                    if let Some(dnstap) = &self.dnstap {
+                        let (kind, now) = (MessageType::ForwarderResponse, SystemTime::now());
+                        dnstap.record(kind, upstream, Transport::Udp, sent, Some(now), &response);
+                    }
+                    return Ok(response);
+                }
+                Err(err) => {
+                    warn!("forwarding to {upstream} failed with {err}");
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn forward(&self, query: &DnsMessage) -> Result<DnsMessage, DnsError> {
+        if query.questions().len() <= 1 {
+            return self
+                .exchange(&upstream_query(query, query.questions()))
+                .await;
+        }
+
+        let mut merged = MessageBuilder::response_to(query);
+        let mut rcode = rcode::NOERROR;
+        for question in query.questions() {
+            let single = upstream_query(query, [question]);
+            let response = self.exchange(&single).await?;
+            merged = merged
+                .add_question(question.clone())
+                .recursion_available(response.header().recursion_available());
+            for answer in response.answers() {
+                merged = merged.add_answer(answer.clone());
+            }
+            if rcode == rcode::NOERROR {
+                rcode = response.rcode();
+            }
+        }
+        Ok(merged.rcode(rcode).build())
+    }
+}
+
+/// The query sent upstream for `questions` of the client's `query`: its flags and additional
+/// records, but not its TSIG record, whose signature is for this server only, and with this
+/// server's own EDNS parameters in place of the client's
+fn upstream_query<'a>(
+    query: &DnsMessage,
+    questions: impl IntoIterator<Item = &'a DnsQuestion>,
+) -> DnsMessage {
+    let header = query.header();
+    let builder = MessageBuilder::new()
+        .id(query.id())
+        .opcode(header.opcode())
+        .recursion_desired(header.recursion_desired())
+        .checking_disabled(header.checking_disabled());
+    let builder = questions.into_iter().fold(builder, |builder, question| {
+        builder.add_question(question.clone())
+    });
+    let builder = query
+        .additionals()
+        .filter(|record| record.record_type() != rtype::TSIG)
+        .fold(builder, |builder, record| {
+            builder.add_additional(record.clone())
+        });
+    match query.edns() {
+        Some(edns) => builder
+            .edns(Edns::new(MAX_UDP_PAYLOAD as u16).with_dnssec_ok(edns.dnssec_ok()))
+            .build(),
+        None => builder.build(),
+    }
+}
+
+impl RequestHandler for Forwarder {
+    async fn handle(&self, query: DnsMessage, _ctx: RequestCtx) -> DnsMessage {
+        if query.header().opcode() != opcode::QUERY {
+            return error_response(&query, rcode::NOTIMP);
+        }
+        match self.forward(&query).await {
+            Ok(response) => response,
+            Err(_) => error_response(&query, rcode::SERVFAIL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::dns::{class, response, rtype, DnsQuestion};
+    use crate::handler::Transport;
+    use crate::tsig::{Algorithm, Key};
+
+    #[tokio::test]
+    async fn test_exchange() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let query = DnsMessage::from_bytes(&buf[..len]).unwrap();
+            // a reply with the wrong id first, then the real one
+            let mut wrong = response(&query).to_bytes();
+            wrong[0] ^= 0xFF;
+            upstream.send_to(&wrong, from).await.unwrap();
+            upstream
+                .send_to(&response(&query).to_bytes(), from)
+                .await
+                .unwrap();
+        });
+
+        let query = DnsMessage::query(0x1234, "Example.COM", rtype::A);
+        let resp = exchange(upstream_addr, &query, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(resp.id(), 0x1234);
+        assert_eq!(resp.answers().count(), 1);
+
+        // nobody listening anymore
+        let err = exchange(upstream_addr, &query, Duration::from_millis(50)).await;
+        assert!(matches!(err, Err(DnsError::Timeout | DnsError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_strips_client_tsig_and_edns() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let (seen, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let query = DnsMessage::from_bytes(&buf[..len]).unwrap();
+            upstream
+                .send_to(&response(&query).to_bytes(), from)
+                .await
+                .unwrap();
+            seen.send(query).unwrap();
+        });
+
+        let key = Key::new("xfr.example".into(), Algorithm::HmacSha256, *b"secret");
+        let query = MessageBuilder::new()
+            .id(5)
+            .recursion_desired(true)
+            .add_question(DnsQuestion::new("example.com".into(), rtype::A, class::IN))
+            .edns(Edns::new(1232).with_option(8, vec![0, 1, 24, 0, 192, 0, 2]))
+            .build();
+        let mut wire = query.to_bytes();
+        key.sign(&mut wire);
+        let query = DnsMessage::from_bytes(&wire).unwrap();
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+        let resp = Forwarder::new(upstream_addr).handle(query, ctx).await;
+        assert_eq!(resp.rcode(), rcode::NOERROR);
+
+        let forwarded = received.recv().await.unwrap();
+        assert_eq!(forwarded.additionals().len(), 0);
+        let edns = forwarded.edns().unwrap();
+        assert_eq!(edns.payload_size(), MAX_UDP_PAYLOAD as u16);
+        assert_eq!(edns.options().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_retries_over_tcp() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let listener = tokio::net::TcpListener::bind(upstream_addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let query = DnsMessage::from_bytes(&buf[..len]).unwrap();
+            let question = query.questions().next().unwrap().clone();
+            let truncated = MessageBuilder::response_to(&query)
+                .add_question(question)
+                .truncated(true)
+                .build();
+            upstream.send_to(&truncated.to_bytes(), from).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            let answer = response(&DnsMessage::from_bytes(&buf).unwrap()).to_bytes();
+            stream
+                .write_all(&(answer.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&answer).await.unwrap();
+        });
+
+        let query = DnsMessage::query(7, "example.com", rtype::A);
+        let resp = exchange(upstream_addr, &query, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(!resp.header().truncated());
+        assert_eq!((resp.id(), resp.answers().len()), (7, 1));
+    }
+
+    #[tokio::test]
+    async fn test_forwarder_splits_questions() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+            loop {
+                let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+                let query = DnsMessage::from_bytes(&buf[..len]).unwrap();
+                assert_eq!(query.questions().len(), 1);
+                upstream
+                    .send_to(&response(&query).to_bytes(), from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let query = ["a.example.com", "b.example.com"]
+            .into_iter()
+            .fold(MessageBuilder::new().id(42), |builder, name| {
+                builder.add_question(DnsQuestion::new(name.into(), rtype::A, class::IN))
+            })
+            .build();
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+        let forwarder = Forwarder::new(upstream_addr);
+        let resp = forwarder.handle(query, ctx.clone()).await;
+        assert_eq!(resp.id(), 42);
+        assert_eq!(resp.questions().len(), 2);
+        let names: Vec<String> = resp.answers().map(|a| a.name().to_string()).collect();
+        assert_eq!(names, ["a.example.com", "b.example.com"]);
+
+        let unreachable = Forwarder::new(SocketAddr::from(([127, 0, 0, 1], 9)))
+            .timeout(Duration::from_millis(50));
+        let query = DnsMessage::query(43, "example.com", rtype::A);
+        assert_eq!(
+            unreachable.clone().handle(query, ctx.clone()).await.rcode(),
+            rcode::SERVFAIL
+        );
+
+        // the next upstream answers when the first does not
+        let query = DnsMessage::query(44, "example.com", rtype::A);
+        let resp = unreachable.fallback(upstream_addr).handle(query, ctx).await;
+        assert_eq!(resp.rcode(), rcode::NOERROR);
+    }
+}
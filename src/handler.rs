@@ -0,0 +1,96 @@
+//! Resolution logic plugged into the listeners
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::dns::{response, DnsLabels, DnsMessage};
+
+/// Transport a query arrived on
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    /// DNS over HTTPS, see [`doh`](crate::doh)
+    Https,
+}
+
+/// Per-query information about where a query came from
+#[derive(Debug, Clone)]
+pub struct RequestCtx {
+    client: SocketAddr,
+    transport: Transport,
+    received: Instant,
+    tsig_key: Option<DnsLabels>,
+}
+
+impl RequestCtx {
+    pub fn new(client: SocketAddr, transport: Transport) -> Self {
+        Self {
+            client,
+            transport,
+            received: Instant::now(),
+            tsig_key: None,
+        }
+    }
+
+    /// Marks the query as signed with the TSIG key `key`, its signature verified
+    pub fn signed_with(mut self, key: DnsLabels) -> Self {
+        self.tsig_key = Some(key);
+        self
+    }
+
+    /// Overrides when the query was received, e.g. to replay a capture on its own clock
+    pub fn received_at(mut self, received: Instant) -> Self {
+        self.received = received;
+        self
+    }
+
+    pub fn client(&self) -> SocketAddr {
+        self.client
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// When the query was received. Time-based policies go by this rather than the wall clock.
+    pub fn received(&self) -> Instant {
+        self.received
+    }
+
+    /// Name of the TSIG key the query was signed with, see [`crate::tsig`]
+    pub fn tsig_key(&self) -> Option<&DnsLabels> {
+        self.tsig_key.as_ref()
+    }
+}
+
+/// Turns a parsed query into the response sent back to the client.
+///
+/// Implementations can be written as `async fn handle(..)` as long as the future is `Send`.
+pub trait RequestHandler: Send + Sync + 'static {
+    fn handle(&self, query: DnsMessage, ctx: RequestCtx)
+        -> impl Future<Output = DnsMessage> + Send;
+}
+
+/// Shares one handler between listeners, e.g. UDP and TCP on the same port
+impl<H: RequestHandler> RequestHandler for Arc<H> {
+    fn handle(
+        &self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+    ) -> impl Future<Output = DnsMessage> + Send {
+        self.as_ref().handle(query, ctx)
+    }
+}
+
+/// Answers with [`response`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultHandler;
+
+impl RequestHandler for DefaultHandler {
+    async fn handle(&self, query: DnsMessage, _ctx: RequestCtx) -> DnsMessage {
+        response(&query)
+    }
+}
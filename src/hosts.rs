@@ -0,0 +1,448 @@
+//! Local answers from hosts files (`/etc/hosts` format)
+//!
+//! [`HostsLayer`] answers A, AAAA and PTR questions for the names listed in its files and passes
+//! everything else down the pipeline, the way dnsmasq serves LAN overrides. Files are re-read
+//! when their modification time changes, see [`HostsLayer::watch`]. DHCP leases can be served
+//! alongside, see [`HostsLayer::leases`].
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use crate::dns::{class, rtype, DnsLabels, DnsMessage, DnsRecord, MessageBuilder, ToBytes};
+use crate::handler::RequestCtx;
+use crate::info;
+use crate::leases::{self, LeaseFormat};
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::warn;
+
+pub const SYSTEM_HOSTS: &str = "/etc/hosts";
+
+/// Addresses by name and names by address, as listed in hosts files
+#[derive(Debug, Default, Clone)]
+pub struct Hosts {
+    by_name: HashMap<DnsLabels, Vec<IpAddr>>,
+    by_addr: HashMap<IpAddr, DnsLabels>,
+    expires: Option<SystemTime>,
+}
+
+impl Hosts {
+    /// Parses `address name [aliases...]` lines, skipping comments and invalid entries
+    pub fn parse(text: &str) -> Hosts {
+        let mut hosts = Hosts::default();
+        hosts.extend(text);
+        hosts
+    }
+
+    /// Adds the entries of another file; the first name listed for an address wins for PTR
+    pub fn extend(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(Ok(addr)) = fields.next().map(str::parse::<IpAddr>) else {
+                continue;
+            };
+            for name in fields {
+                if let Ok(name) = name.parse() {
+                    self.insert(name, addr);
+                }
+            }
+        }
+    }
+
+    /// Adds the active leases in `text` as `<hostname>.<domain>`
+    pub fn extend_leases(&mut self, text: &str, format: LeaseFormat, domain: &DnsLabels) {
+        let now = SystemTime::now();
+        for lease in leases::parse(text, format) {
+            if !lease.is_active(now) {
+                continue;
+            }
+            let Ok(name) = format!("{}.{domain}", lease.hostname()).parse() else {
+                continue;
+            };
+            self.insert(name, lease.addr());
+            if let Some(expires) = lease.expires() {
+                self.expires = Some(self.expires.map_or(expires, |e| e.min(expires)));
+            }
+        }
+    }
+
+    pub fn insert(&mut self, name: DnsLabels, addr: IpAddr) {
+        self.by_addr.entry(addr).or_insert_with(|| name.clone());
+        let addrs = self.by_name.entry(name).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    pub fn lookup(&self, name: &DnsLabels) -> Option<&[IpAddr]> {
+        self.by_name.get(name).map(Vec::as_slice)
+    }
+
+    pub fn reverse(&self, addr: IpAddr) -> Option<&DnsLabels> {
+        self.by_addr.get(&addr)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// When the first lease in the table runs out
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+    }
+}
+
+/// `in-addr.arpa`/`ip6.arpa` name of `addr`, as asked in PTR queries
+pub fn reverse_name(addr: IpAddr) -> DnsLabels {
+    let labels: Vec<String> = match addr {
+        IpAddr::V4(addr) => addr
+            .octets()
+            .iter()
+            .rev()
+            .map(u8::to_string)
+            .chain(["in-addr".into(), "arpa".into()])
+            .collect(),
+        IpAddr::V6(addr) => addr
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| [byte & 0xF, byte >> 4])
+            .map(|nibble| format!("{nibble:x}"))
+            .chain(["ip6".into(), "arpa".into()])
+            .collect(),
+    };
+    DnsLabels::new(labels).expect("reverse names are short")
+}
+
+/// Address a full `in-addr.arpa`/`ip6.arpa` name stands for
+pub fn parse_reverse_name(name: &DnsLabels) -> Option<IpAddr> {
+    let labels: Vec<&[u8]> = name.labels().collect();
+    let (suffix, digits) = match labels.as_slice() {
+        [digits @ .., suffix, arpa] if arpa.eq_ignore_ascii_case(b"arpa") => (*suffix, digits),
+        _ => return None,
+    };
+    let digits: Vec<&str> = digits
+        .iter()
+        .map(|label| std::str::from_utf8(label).ok())
+        .collect::<Option<_>>()?;
+
+    if suffix.eq_ignore_ascii_case(b"in-addr") && digits.len() == 4 {
+        let mut octets = [0u8; 4];
+        for (octet, digit) in octets.iter_mut().rev().zip(&digits) {
+            *octet = digit.parse().ok()?;
+        }
+        Some(Ipv4Addr::from(octets).into())
+    } else if suffix.eq_ignore_ascii_case(b"ip6") && digits.len() == 32 {
+        let mut octets = [0u8; 16];
+        for (i, digit) in digits.iter().rev().enumerate() {
+            if digit.len() != 1 {
+                return None;
+            }
+            let nibble = u8::from_str_radix(digit, 16).ok()?;
+            octets[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+        }
+        Some(Ipv6Addr::from(octets).into())
+    } else {
+        None
+    }
+}
+
+/// A file the table is built from
+#[derive(Debug, Clone)]
+enum Source {
+    Hosts(PathBuf),
+    Leases {
+        path: PathBuf,
+        format: LeaseFormat,
+        domain: DnsLabels,
+    },
+}
+
+impl Source {
+    fn path(&self) -> &PathBuf {
+        match self {
+            Source::Hosts(path) | Source::Leases { path, .. } => path,
+        }
+    }
+}
+
+/// Answers questions about names in hosts files locally
+pub struct HostsLayer {
+    sources: Vec<Source>,
+    ttl: u32,
+    table: Arc<RwLock<Arc<Hosts>>>,
+}
+
+impl HostsLayer {
+    /// Layer serving the entries of `paths`, read now; unreadable files are logged and skipped
+    pub fn new(paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        let sources: Vec<Source> = paths
+            .into_iter()
+            .map(|path| Source::Hosts(path.into()))
+            .collect();
+        let hosts = load(&sources);
+        Self {
+            sources,
+            ttl: 0,
+            table: Arc::new(RwLock::new(Arc::new(hosts))),
+        }
+    }
+
+    /// Also serves the active DHCP leases in `path` as `<hostname>.<domain>`, with PTR records
+    /// for their addresses. Expired leases drop out on the next reload.
+    pub fn leases(
+        mut self,
+        path: impl Into<PathBuf>,
+        format: LeaseFormat,
+        domain: DnsLabels,
+    ) -> Self {
+        self.sources.push(Source::Leases {
+            path: path.into(),
+            format,
+            domain,
+        });
+        *self.table.write().unwrap() = Arc::new(load(&self.sources));
+        self
+    }
+
+    /// TTL of the records served, 0 (do not cache) by default so edits show up at once
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Re-reads the files whenever one of their modification times changes or a lease expires,
+    /// checking every `interval` on a background task that ends with the layer. Must be called
+    /// inside a tokio runtime.
+    pub fn watch(self, interval: Duration) -> Self {
+        let table = Arc::downgrade(&self.table);
+        let sources = self.sources.clone();
+        tokio::spawn(watch(sources, table, interval));
+        self
+    }
+
+    pub fn hosts(&self) -> Arc<Hosts> {
+        self.table.read().unwrap().clone()
+    }
+
+    /// Answer to `query`, if it is a single A, AAAA or PTR question this layer knows about
+    fn answer(&self, query: &DnsMessage) -> Option<DnsMessage> {
+        let mut questions = query.questions();
+        let question = questions.next()?;
+        if questions.next().is_some() || question.qclass() != class::IN {
+            return None;
+        }
+        let hosts = self.hosts();
+        let name = question.qname();
+
+        let answers: Vec<DnsRecord> = match question.qtype() {
+            rtype::A | rtype::AAAA => {
+                let want_v4 = question.qtype() == rtype::A;
+                hosts
+                    .lookup(name)?
+                    .iter()
+                    .filter(|addr| addr.is_ipv4() == want_v4)
+                    .map(|addr| DnsRecord::with_rdata(name.clone(), self.ttl, *addr))
+                    .collect()
+            }
+            rtype::PTR => {
+                let target = hosts.reverse(parse_reverse_name(name)?)?;
+                let ptr = DnsRecord::new(
+                    name.clone(),
+                    rtype::PTR,
+                    class::IN,
+                    self.ttl,
+                    target.to_bytes(),
+                );
+                vec![ptr]
+            }
+            _ => return None,
+        };
+
+        // a listed name without an address of the asked family is NODATA, not forwarded
+        let response = answers.into_iter().fold(
+            MessageBuilder::response_to(query)
+                .authoritative(true)
+                .add_question(question.clone()),
+            MessageBuilder::add_answer,
+        );
+        Some(response.build())
+    }
+}
+
+impl Layer for HostsLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            match self.answer(&query) {
+                Some(response) => response,
+                None => next.run(query, ctx).await,
+            }
+        })
+    }
+}
+
+fn load(sources: &[Source]) -> Hosts {
+    let mut hosts = Hosts::default();
+    for source in sources {
+        let text = match fs::read_to_string(source.path()) {
+            Ok(text) => text,
+            Err(err) => {
+                warn!("failed to read {} with {err}", source.path().display());
+                continue;
+            }
+        };
+        match source {
+            Source::Hosts(_) => hosts.extend(&text),
+            Source::Leases { format, domain, .. } => hosts.extend_leases(&text, *format, domain),
+        }
+    }
+    hosts
+}
+
+fn modified(sources: &[Source]) -> Vec<io::Result<SystemTime>> {
+    sources
+        .iter()
+        .map(|source| fs::metadata(source.path())?.modified())
+        .collect()
+}
+
+async fn watch(sources: Vec<Source>, table: Weak<RwLock<Arc<Hosts>>>, interval: Duration) {
+    let mut seen = modified(&sources);
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(table) = table.upgrade() else {
+            return;
+        };
+        let current = modified(&sources);
+        let changed = current
+            .iter()
+            .zip(&seen)
+            .any(|(now, before)| now.as_ref().ok() != before.as_ref().ok());
+        let expired = table
+            .read()
+            .unwrap()
+            .expires()
+            .is_some_and(|expires| expires <= SystemTime::now());
+        if changed || expired {
+            info!("reloading hosts files");
+            *table.write().unwrap() = Arc::new(load(&sources));
+            seen = current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    const HOSTS: &str = "\
+127.0.0.1   localhost
+# a comment line
+192.168.1.10 nas.lan nas   # trailing comment
+fd00::10     nas.lan
+not-an-ip    ignored.lan
+";
+
+    #[test]
+    fn test_parse() {
+        let hosts = Hosts::parse(HOSTS);
+        let nas = DnsLabels::from("NAS.lan");
+        assert_eq!(
+            hosts.lookup(&nas).unwrap(),
+            [
+                "192.168.1.10".parse::<IpAddr>().unwrap(),
+                "fd00::10".parse().unwrap()
+            ]
+        );
+        assert!(hosts.lookup(&"nas".into()).is_some());
+        assert!(hosts.lookup(&"ignored.lan".into()).is_none());
+        assert_eq!(
+            hosts.reverse("192.168.1.10".parse().unwrap()),
+            Some(&"nas.lan".into())
+        );
+    }
+
+    #[test]
+    fn test_reverse_names() {
+        for addr in ["192.168.1.10", "2001:db8::567:89ab"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(parse_reverse_name(&reverse_name(addr)), Some(addr));
+        }
+        assert_eq!(
+            reverse_name("192.168.1.10".parse().unwrap()).to_string(),
+            "10.1.168.192.in-addr.arpa"
+        );
+        assert_eq!(parse_reverse_name(&"1.168.192.in-addr.arpa".into()), None);
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let path = std::env::temp_dir().join(format!("hosts-test-{}", std::process::id()));
+        fs::write(&path, HOSTS).unwrap();
+        let pipeline = Pipeline::new(DefaultHandler).layer(HostsLayer::new([&path]).ttl(30));
+        fs::remove_file(&path).unwrap();
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+
+        let response = pipeline
+            .handle(DnsMessage::query(1, "nas.lan", rtype::AAAA), ctx.clone())
+            .await;
+        assert!(response.header().authoritative());
+        let answers: Vec<_> = response.answers().collect();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].ttl(), 30);
+        assert_eq!(
+            IpAddr::try_from(answers[0]).unwrap(),
+            "fd00::10".parse::<IpAddr>().unwrap()
+        );
+
+        let reverse = reverse_name("192.168.1.10".parse().unwrap()).to_string();
+        let response = pipeline
+            .handle(DnsMessage::query(2, &reverse, rtype::PTR), ctx.clone())
+            .await;
+        let ptr = response.answers().next().unwrap();
+        assert_eq!(ptr.data(), DnsLabels::from("nas.lan").to_bytes());
+
+        // unknown names go down the pipeline
+        let response = pipeline
+            .handle(DnsMessage::query(3, "codecrafters.io", rtype::A), ctx)
+            .await;
+        assert!(!response.header().authoritative());
+    }
+
+    #[test]
+    fn test_leases() {
+        let path = std::env::temp_dir().join(format!("leases-test-{}", std::process::id()));
+        fs::write(
+            &path,
+            "0 aa:bb:cc:dd:ee:ff 192.168.1.50 laptop *\n\
+             1 11:22:33:44:55:66 192.168.1.51 stale *\n",
+        )
+        .unwrap();
+        let layer = HostsLayer::new(Vec::<PathBuf>::new()).leases(
+            &path,
+            LeaseFormat::Dnsmasq,
+            "lan".into(),
+        );
+        fs::remove_file(&path).unwrap();
+
+        let hosts = layer.hosts();
+        let addr: IpAddr = "192.168.1.50".parse().unwrap();
+        assert_eq!(hosts.lookup(&"laptop.lan".into()).unwrap(), [addr]);
+        assert_eq!(hosts.reverse(addr), Some(&"laptop.lan".into()));
+        assert!(hosts.lookup(&"stale.lan".into()).is_none());
+    }
+}
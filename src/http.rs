@@ -0,0 +1,236 @@
+//! Plain HTTP/1.1 for the local APIs of service-discovery backends and the server's own APIs
+//!
+//! One request per connection (`Connection: close`). As a client, the body is read to the end
+//! and `Transfer-Encoding: chunked` undone; as a server, request bodies need a `Content-Length`.
+//! No TLS: these APIs are expected to stay on the same host or network.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Responses larger than this are refused
+const MAX_RESPONSE: u64 = 16 << 20;
+
+/// Request heads and bodies larger than this are refused
+const MAX_REQUEST: usize = 64 << 10;
+
+/// Time a `get` gets to connect and read the whole response
+const GET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of `GET http://<host><path>`, failing unless the status is 200
+pub async fn get(host: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(host)
+            .await
+            .with_context(|| format!("connecting to {host}"))?;
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nAccept: application/json\r\n\
+             Connection: close\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE).read_to_end(&mut response).await?;
+        anyhow::Ok(response)
+    };
+    let response = tokio::time::timeout(GET_TIMEOUT, exchange)
+        .await
+        .with_context(|| format!("{host} did not answer within {GET_TIMEOUT:?}"))??;
+    parse_response(&response)
+}
+
+fn parse_response(response: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let head_len = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("response head not terminated")?;
+    let head = std::str::from_utf8(&response[..head_len]).context("response head not UTF-8")?;
+    let body = &response[head_len + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("unexpected status {status:?}");
+    }
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    if chunked {
+        dechunk(body)
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+fn dechunk(mut body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .context("chunk size not terminated")?;
+        let size = std::str::from_utf8(&body[..line_end])?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).context("bad chunk size")?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size + 2 {
+            bail!("chunk cut short");
+        }
+        out.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+/// A request received by one of the server's APIs
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Request {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Path of the target, without the query string
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// Query string of the target, without the `?`
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// Value of the first header `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+/// Reads one request from `stream`
+pub async fn read_request(stream: impl AsyncRead + Unpin) -> anyhow::Result<Request> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST as u64);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        bail!("invalid request line {line:?}");
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("request head not terminated");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').context("invalid header line")?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method,
+        target,
+        headers,
+        body: Vec::new(),
+    };
+    if let Some(len) = request.header("content-length") {
+        let len: usize = len.parse().context("invalid content length")?;
+        if len > MAX_REQUEST {
+            bail!("body of {len} bytes too large");
+        }
+        request.body = vec![0; len];
+        reader.read_exact(&mut request.body).await?;
+    }
+    Ok(request)
+}
+
+/// Writes a complete response, with `headers` besides the content headers, and shuts the
+/// connection down
+pub async fn write_response(
+    mut stream: impl AsyncWrite + Unpin,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        _ => "Error",
+    };
+    let mut head = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let raw: &[u8] = b"POST /update?x=1 HTTP/1.1\r\nHost: localhost\r\n\
+                           X-Api-User: alice\r\nContent-Length: 4\r\n\r\n{}\r\nextra";
+        let request = read_request(raw).await.unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.path(), "/update");
+        assert_eq!(request.query(), Some("x=1"));
+        assert_eq!(request.header("x-api-user"), Some("alice"));
+        assert_eq!(request.body(), b"{}\r\n");
+
+        assert!(read_request(&b"GET /\r\n\r\n"[..]).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let plain = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(plain).unwrap(), b"{}");
+
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        3\r\n[1,\r\n2;ext=1\r\n2]\r\n0\r\n\r\n";
+        assert_eq!(parse_response(chunked).unwrap(), b"[1,2]");
+
+        assert!(parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! DHCP lease files, so every device on the LAN resolves by its hostname
+//!
+//! Leases are served through [`crate::hosts::HostsLayer::leases`] under a local domain, e.g.
+//! `laptop.lan` for a client that sent the hostname `laptop`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Lease file layout
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LeaseFormat {
+    /// `dnsmasq.leases`: `<expiry> <mac> <address> <hostname> <client-id>` per line
+    Dnsmasq,
+    /// ISC Kea memfile CSV (`kea-leases4.csv`/`kea-leases6.csv`) with its header line
+    KeaCsv,
+}
+
+/// An address handed to a named client
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Lease {
+    addr: IpAddr,
+    hostname: String,
+    expires: Option<SystemTime>,
+}
+
+impl Lease {
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Single label hostname the client asked for
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// `None` for infinite leases
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        match self.expires {
+            Some(expires) => expires > now,
+            None => true,
+        }
+    }
+}
+
+/// Leases with a hostname listed in `text`, active or not; malformed lines are skipped
+pub fn parse(text: &str, format: LeaseFormat) -> Vec<Lease> {
+    match format {
+        LeaseFormat::Dnsmasq => text.lines().filter_map(parse_dnsmasq).collect(),
+        LeaseFormat::KeaCsv => parse_kea(text),
+    }
+}
+
+fn parse_dnsmasq(line: &str) -> Option<Lease> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [expiry, _mac, addr, hostname, ..] = fields.as_slice() else {
+        return None;
+    };
+    lease(addr, hostname, expiry.parse().ok()?)
+}
+
+fn parse_kea(text: &str) -> Vec<Lease> {
+    let mut lines = text.lines();
+    let Some(header) = lines.next() else {
+        return Vec::new();
+    };
+    let columns: HashMap<&str, usize> = header
+        .split(',')
+        .enumerate()
+        .map(|(i, column)| (column.trim(), i))
+        .collect();
+    let (Some(&addr), Some(&expire), Some(&hostname)) = (
+        columns.get("address"),
+        columns.get("expire"),
+        columns.get("hostname"),
+    ) else {
+        return Vec::new();
+    };
+    let state = columns.get("state").copied();
+
+    lines
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            // 0 is the default state; declined and expired-reclaimed leases are not served
+            if state.is_some_and(|state| fields.get(state).is_some_and(|s| *s != "0")) {
+                return None;
+            }
+            lease(
+                fields.get(addr)?,
+                fields.get(hostname)?,
+                fields.get(expire)?.parse().ok()?,
+            )
+        })
+        .collect()
+}
+
+/// `expiry` is seconds since the epoch, 0 meaning never
+fn lease(addr: &str, hostname: &str, expiry: u64) -> Option<Lease> {
+    // FQDNs (Kea) are reduced to their first label, anonymous clients (`*`) skipped
+    let hostname = hostname.split('.').next()?;
+    if hostname.is_empty() || hostname == "*" {
+        return None;
+    }
+    Some(Lease {
+        addr: addr.parse().ok()?,
+        hostname: hostname.to_string(),
+        expires: (expiry != 0).then(|| UNIX_EPOCH + Duration::from_secs(expiry)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dnsmasq() {
+        let text = "\
+1700000000 aa:bb:cc:dd:ee:ff 192.168.1.50 laptop 01:aa:bb:cc:dd:ee:ff
+0 11:22:33:44:55:66 192.168.1.51 printer *
+1700000000 11:22:33:44:55:77 192.168.1.52 * *
+garbage
+";
+        let leases = parse(text, LeaseFormat::Dnsmasq);
+        assert_eq!(leases.len(), 2);
+        assert_eq!(leases[0].hostname(), "laptop");
+        assert_eq!(leases[0].addr(), "192.168.1.50".parse::<IpAddr>().unwrap());
+        assert!(!leases[0].is_active(UNIX_EPOCH + Duration::from_secs(1_800_000_000)));
+        assert!(leases[1].is_active(SystemTime::now()));
+    }
+
+    #[test]
+    fn test_kea() {
+        let text = "\
+address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context
+192.168.1.60,aa:bb:cc:dd:ee:01,,3600,1700003600,1,0,0,tv.example.org.,0,
+192.168.1.61,aa:bb:cc:dd:ee:02,,3600,1700003600,1,0,0,declined,1,
+192.168.1.62,aa:bb:cc:dd:ee:03,,3600,1700003600,1,0,0,,0,
+";
+        let leases = parse(text, LeaseFormat::KeaCsv);
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].hostname(), "tv");
+        assert_eq!(
+            leases[0].expires(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_003_600))
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! A small DNS server and the wire codec it is built on.
+//!
+//! - [`dns`] holds the message types, the parser ([`dns::DnsMessage::from_bytes`]) and the
+//!   serializer ([`dns::ToBytes`]).
+//! - [`rdata`] decodes record data into [`rdata::RData`] and converts it to `std::net` types.
+//! - [`canonical`] implements RFC 4034 canonical name ordering and record form.
+//! - [`codec`] frames messages for stream transports such as TCP.
+//! - [`error`] defines [`DnsError`], returned by every fallible public API.
+//! - [`config`] reads the server settings from a TOML file.
+//! - [`cli`] parses the command line, whose options override the [`config`] file.
+//! - [`handler`] defines [`handler::RequestHandler`], the resolution logic behind the listeners.
+//! - [`pipeline`] composes [`pipeline::Layer`] stages (logging, filtering, ...) around a handler.
+//! - [`tsig`] verifies and signs messages with shared-secret transaction signatures.
+//! - [`acl`] refuses queries, recursion or zone transfers to clients outside allowed networks.
+//! - [`log`] writes leveled log lines, as text or JSON, with the fields of the query answered.
+//! - [`privacy`] anonymizes client addresses before logs and stats record them.
+//! - [`stats`] counts queries per client.
+//! - [`dnstap`] streams the messages exchanged with clients and upstreams to dnstap tools.
+//! - [`query_log`] writes a line per query to a log file rotated by size and age.
+//! - [`retention`] purges old client data and forgets clients on request.
+//! - [`pool`] recycles packet buffers across queries.
+//! - [`arena`] recycles the names and record data of parsed messages per thread.
+//! - [`hosts`] answers A, AAAA and PTR questions from `/etc/hosts`-style files.
+//! - [`records`] serves locally configured records of any type, SRV among them, and reverse
+//!   zones.
+//! - [`zone`] parses RFC 1035 master files into [`records`] to serve.
+//! - [`zone_store`] answers authoritatively from the closest zone enclosing a name.
+//! - [`forward`] relays queries to an upstream resolver.
+//! - [`recursive`] resolves queries itself, iteratively from the root servers.
+//! - [`stamp`] decodes DNS stamps (`sdns://`) describing upstreams, using [`base64`].
+//! - [`split`] routes domains to their own upstreams (split DNS for VPNs).
+//! - [`acme`] publishes ACME DNS-01 challenge records set through an HTTP API.
+//! - [`consul`] serves the instances of Consul services, read over [`http`] as [`json`].
+//! - [`mdns`] resolves `.local` names over multicast DNS for unicast clients.
+//! - [`leases`] reads dnsmasq and ISC Kea DHCP lease files for [`hosts`] to serve.
+//! - [`chaos`] answers CHAOS-class `version.bind`, `hostname.bind` and `id.server` queries.
+//! - [`blocklist`] answers NXDOMAIN or a sinkhole address for ad and malware domains.
+//! - [`dga`] scores names for randomness to catch malware domain generation algorithms.
+//! - [`cname`] follows CNAMEs that answers stop at and appends the records of the target.
+//! - [`cache`] answers repeated questions from earlier answers until their TTLs run out.
+//! - [`coalesce`] answers identical concurrent queries with a single resolution.
+//! - [`response_cache`] replays serialized responses for repeated questions.
+//! - [`server`] runs the UDP listener on top of the codec and a handler, counting per socket
+//!   and worker.
+//! - [`ratelimit`] limits the queries per second of each client with token buckets.
+//! - [`rrl`] limits identical responses per client network, against amplification attacks.
+//! - [`tcp`] runs the DNS-over-TCP listener (RFC 7766) with the same handler.
+//! - [`doh`] serves DNS over HTTPS (RFC 8484) requests with the same handler.
+//! - [`batch`] receives and sends UDP datagrams in batches (`recvmmsg`/`sendmmsg` on Linux).
+//! - [`blocking`] runs the same handler on blocking `std::net` sockets, without tokio.
+//! - [`ffi`] exposes the codec to C (`include/dns.h`).
+//! - [`replay`] feeds queries from a [`pcap`] capture through a handler and diffs the responses.
+//! - [`self_test`] fires queries at a running server to check it is functional.
+
+pub mod acl;
+pub mod acme;
+pub mod arena;
+pub mod base64;
+pub mod batch;
+pub mod blocking;
+pub mod blocklist;
+pub mod cache;
+pub mod canonical;
+pub mod chaos;
+pub mod cli;
+pub mod cname;
+pub mod coalesce;
+pub mod codec;
+pub mod config;
+pub mod consul;
+pub mod dga;
+pub mod dns;
+pub mod dnstap;
+pub mod doh;
+pub mod error;
+pub mod ffi;
+pub mod forward;
+pub mod handler;
+pub mod hosts;
+pub mod http;
+pub mod json;
+pub mod leases;
+pub mod log;
+pub mod mdns;
+pub mod pcap;
+pub mod pipeline;
+pub mod pool;
+pub mod privacy;
+pub mod query_log;
+pub mod ratelimit;
+pub mod rdata;
+pub mod records;
+pub mod recursive;
+pub mod replay;
+pub mod response_cache;
+pub mod retention;
+pub mod rrl;
+pub mod self_test;
+pub mod server;
+pub mod split;
+pub mod stamp;
+pub mod stats;
+pub mod tcp;
+pub mod tsig;
+pub mod zone;
+pub mod zone_store;
+
+pub use error::DnsError;
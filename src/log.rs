@@ -0,0 +1,202 @@
+//! Leveled logging, with the query being answered attached
+//!
+//! [`error!`](crate::error!), [`warn!`](crate::warn!), [`info!`](crate::info!) and
+//! [`debug!`](crate::debug!) write a line per event, errors to stderr and the rest to stdout,
+//! when [`init`] enabled their level. Events logged while a query goes through the pipeline
+//! carry the [`Span`] that [`LoggingLayer`](crate::pipeline::LoggingLayer) opened for it: the
+//! client, query id, qname and qtype. With [`LogFormat::Json`] every line is a JSON object.
+
+use std::fmt::{self, Write};
+use std::future::Future;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{LogFormat, LogLevel};
+use crate::dns::{rtype, DnsMessage};
+use crate::json::quote;
+use crate::privacy::Client;
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+static FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Text as u8);
+
+tokio::task_local! {
+    static SPAN: Span;
+}
+
+/// Logs the events of `level` and the levels before it, written in `format`
+pub fn init(level: LogLevel, format: LogFormat) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+/// Whether events of `level` are logged
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// The query events are logged for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    client: Client,
+    id: u16,
+    /// First question, if any
+    question: Option<(String, u16)>,
+}
+
+impl Span {
+    pub fn new(client: Client, query: &DnsMessage) -> Self {
+        Self {
+            client,
+            id: query.id(),
+            question: query
+                .questions()
+                .next()
+                .map(|question| (question.qname().to_string(), question.qtype())),
+        }
+    }
+
+    /// Runs `future` with the span attached to the events it logs
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        SPAN.scope(self, future).await
+    }
+
+    /// The span of the query being answered, if any
+    pub fn current() -> Option<Span> {
+        SPAN.try_with(Span::clone).ok()
+    }
+}
+
+/// Writes an event, what the macros expand to
+#[doc(hidden)]
+pub fn event(level: LogLevel, message: fmt::Arguments<'_>) {
+    if !enabled(level) {
+        return;
+    }
+    let json = FORMAT.load(Ordering::Relaxed) == LogFormat::Json as u8;
+    let line = format(level, message, Span::current().as_ref(), json);
+    match level {
+        LogLevel::Error => eprintln!("{line}"),
+        _ => println!("{line}"),
+    }
+}
+
+fn format(level: LogLevel, message: fmt::Arguments<'_>, span: Option<&Span>, json: bool) -> String {
+    let mut line = String::new();
+    if !json {
+        let _ = write!(
+            line,
+            "{}: {message}",
+            level.to_string().to_ascii_uppercase()
+        );
+        if let Some(span) = span {
+            let _ = write!(line, " client={} id={}", span.client, span.id);
+            if let Some((qname, qtype)) = &span.question {
+                let _ = write!(line, " qname={qname} qtype={}", type_name(*qtype));
+            }
+        }
+        return line;
+    }
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let _ = write!(line, "{{\"time\":{:.3},\"level\":", time.as_secs_f64());
+    quote(&mut line, &level.to_string());
+    line.push_str(",\"message\":");
+    quote(&mut line, &message.to_string());
+    if let Some(span) = span {
+        line.push_str(",\"client\":");
+        quote(&mut line, &span.client.to_string());
+        let _ = write!(line, ",\"id\":{}", span.id);
+        if let Some((qname, qtype)) = &span.question {
+            line.push_str(",\"qname\":");
+            quote(&mut line, qname);
+            line.push_str(",\"qtype\":");
+            quote(&mut line, &type_name(*qtype));
+        }
+    }
+    line.push('}');
+    line
+}
+
+/// `AAAA`, or `TYPE65` for the types without a mnemonic (RFC 3597)
+pub(crate) fn type_name(code: u16) -> String {
+    match rtype::name(code) {
+        Some(name) => name.to_string(),
+        None => format!("TYPE{code}"),
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::event($crate::config::LogLevel::Error, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::event($crate::config::LogLevel::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::event($crate::config::LogLevel::Info, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::event($crate::config::LogLevel::Debug, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::json::Json;
+
+    #[tokio::test]
+    async fn test_format() {
+        let query = DnsMessage::query(7, "codecrafters.io", rtype::AAAA);
+        let span = Span::new(Client::Addr([192, 0, 2, 1].into()), &query);
+        assert_eq!(Span::current(), None);
+        let current = span.clone().scope(async { Span::current() }).await;
+        assert_eq!(current.as_ref(), Some(&span));
+
+        let text = format(
+            LogLevel::Warn,
+            format_args!("upstream failed"),
+            Some(&span),
+            false,
+        );
+        assert_eq!(
+            text,
+            "WARN: upstream failed client=192.0.2.1 id=7 qname=codecrafters.io qtype=AAAA"
+        );
+        assert_eq!(
+            format(LogLevel::Info, format_args!("started"), None, false),
+            "INFO: started"
+        );
+
+        let json = format(
+            LogLevel::Debug,
+            format_args!("a \"quote\""),
+            Some(&span),
+            true,
+        );
+        let json = Json::parse(&json).unwrap();
+        assert_eq!(json.get("level").and_then(Json::as_str), Some("debug"));
+        assert_eq!(
+            json.get("message").and_then(Json::as_str),
+            Some("a \"quote\"")
+        );
+        assert_eq!(json.get("client").and_then(Json::as_str), Some("192.0.2.1"));
+        assert_eq!(json.get("id").and_then(Json::as_f64), Some(7.0));
+        assert_eq!(json.get("qtype").and_then(Json::as_str), Some("AAAA"));
+    }
+}
@@ -1,72 +1,443 @@
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
-use nom::AsBytes;
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::Receiver;
+use tokio::net::{TcpListener, UdpSocket};
 
-use dns::{dns_msg, response, Writeable};
+use dns_starter_rust::acl::AclLayer;
+use dns_starter_rust::acme::{AcmeLayer, Challenges};
+use dns_starter_rust::blocklist::BlocklistLayer;
+use dns_starter_rust::cache::CacheLayer;
+use dns_starter_rust::chaos::ChaosLayer;
+use dns_starter_rust::cli::{Cli, Command, USAGE};
+use dns_starter_rust::cname::CnameLayer;
+use dns_starter_rust::coalesce::CoalesceLayer;
+use dns_starter_rust::config::Config;
+use dns_starter_rust::consul::ConsulLayer;
+use dns_starter_rust::dga::DgaLayer;
+use dns_starter_rust::dnstap::Dnstap;
+use dns_starter_rust::forward::Forwarder;
+use dns_starter_rust::handler::DefaultHandler;
+use dns_starter_rust::hosts::HostsLayer;
+use dns_starter_rust::mdns::MdnsLayer;
+use dns_starter_rust::pipeline::{LoggingLayer, Pipeline};
+use dns_starter_rust::query_log::{QueryLog, QueryLogLayer};
+use dns_starter_rust::ratelimit::RateLimiter;
+use dns_starter_rust::recursive::Recursor;
+use dns_starter_rust::replay::Replay;
+use dns_starter_rust::response_cache::ResponseCache;
+use dns_starter_rust::retention::Retention;
+use dns_starter_rust::rrl::Rrl;
+use dns_starter_rust::self_test::SelfTest;
+use dns_starter_rust::server::{ServerOptions, StatsRegistry};
+use dns_starter_rust::split::SplitLayer;
+use dns_starter_rust::stats::{ClientStats, ClientStatsLayer};
+use dns_starter_rust::tcp::TcpOptions;
+use dns_starter_rust::tsig::{Keyring, TsigLayer};
+use dns_starter_rust::zone_store::ZoneLayer;
+use dns_starter_rust::{acme, doh, info, log, pcap, server, tcp};
 
-mod dns;
+/// Clients the stats keep counts for at once
+const MAX_CLIENTS: usize = 10_000;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let addr = "127.0.0.1:2053";
-    let sock = UdpSocket::bind(addr).await?;
-
-    println!("INFO: listening on {addr}");
-
-    let receiver = Arc::new(sock);
-    let sender = receiver.clone();
-    let (tx, mut rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(1_000);
+/// Where answers not in the hosts file come from
+#[derive(Debug, Clone)]
+enum Mode {
+    /// [`DefaultHandler`]
+    Static,
+    /// `--resolver` or `upstream.resolvers`, in order of preference
+    Forward(Vec<SocketAddr>),
+    /// `--recursive` or `upstream.recursive`
+    Recursive,
+}
 
-    tokio::spawn(async move {
-        response_handler(sender, rx).await;
-    });
+async fn handler(
+    mode: &Mode,
+    config: &Config,
+    zones: &ZoneLayer,
+    query_log: Option<&QueryLogLayer>,
+    dnstap: Option<&Dnstap>,
+    client_stats: Option<&Arc<ClientStats>>,
+    challenges: Option<&Arc<Challenges>>,
+) -> (Pipeline, SelfTest) {
+    let self_test = SelfTest::new()
+        .forwarding(matches!(mode, Mode::Forward(_)))
+        .zones(zones.clone());
+    let hosts = config.leases().iter().fold(
+        HostsLayer::new(config.hosts_files()),
+        |hosts, (file, format)| hosts.leases(file, *format, config.lease_domain().clone()),
+    );
+    let hosts = hosts.ttl(config.hosts_ttl()).watch(Duration::from_secs(5));
+    let pipeline = match mode {
+        Mode::Static => Pipeline::new(DefaultHandler),
+        Mode::Forward(resolvers) => {
+            let forwarder = resolvers[1..]
+                .iter()
+                .fold(Forwarder::new(resolvers[0]), |forwarder, resolver| {
+                    forwarder.fallback(*resolver)
+                });
+            match dnstap {
+                Some(dnstap) => Pipeline::new(forwarder.dnstap(dnstap.clone())),
+                None => Pipeline::new(forwarder),
+            }
+        }
+        Mode::Recursive => match dnstap {
+            Some(dnstap) => Pipeline::new(Recursor::new().dnstap(dnstap.clone())),
+            None => Pipeline::new(Recursor::new()),
+        },
+    };
+    let pipeline = pipeline.layer(LoggingLayer::default().anonymize(config.log_anonymizer()));
+    let pipeline = match client_stats {
+        Some(stats) => pipeline
+            .layer(ClientStatsLayer::new(stats.clone()).anonymize(config.stats_anonymizer())),
+        None => pipeline,
+    };
+    let pipeline = match config.acl_query() {
+        Some(acl) => pipeline.layer(AclLayer::queries(acl.clone())),
+        None => pipeline,
+    };
+    let pipeline = match config.acl_transfer() {
+        Some(acl) => pipeline.layer(AclLayer::transfers(acl.clone())),
+        None => pipeline,
+    };
+    let pipeline = match config.tsig_keys() {
+        [] => pipeline,
+        _ => pipeline.layer(TsigLayer),
+    };
+    let pipeline = match query_log {
+        Some(query_log) => pipeline.layer(query_log.clone()),
+        None => pipeline,
+    };
+    let pipeline = match dnstap {
+        Some(dnstap) => pipeline.layer(dnstap.clone()),
+        None => pipeline,
+    };
+    let chaos = ChaosLayer::new();
+    let chaos = match config.chaos_version() {
+        Some(version) => chaos.version(version),
+        None => chaos,
+    };
+    let chaos = match config.chaos_hostname() {
+        Some(hostname) => chaos.hostname(hostname),
+        None => chaos,
+    };
+    let chaos = match config.chaos_id() {
+        Some(id) => chaos.id(id),
+        None => chaos,
+    };
+    let pipeline = pipeline.layer(chaos).layer(hosts);
+    let (pipeline, self_test) = match config.blocklists() {
+        [] => (pipeline, self_test),
+        blocklists => {
+            let blocklist = BlocklistLayer::new(blocklists)
+                .sinkhole(config.sinkhole().iter().copied())
+                .watch(Duration::from_secs(60));
+            (
+                pipeline.layer(blocklist.clone()),
+                self_test.blocklist(blocklist),
+            )
+        }
+    };
+    let pipeline = if config.mdns() {
+        pipeline.layer(MdnsLayer::new())
+    } else {
+        pipeline
+    };
+    let pipeline = match config.dga_action() {
+        Some(action) => pipeline.layer(DgaLayer::new(action).threshold(config.dga_threshold())),
+        None => pipeline,
+    };
+    // zones answer above the cache, so that reloads take effect straight away
+    let pipeline = pipeline.layer(CnameLayer::new());
+    let pipeline = match challenges {
+        Some(challenges) => pipeline.layer(AcmeLayer::new(challenges.clone())),
+        None => pipeline,
+    };
+    let pipeline = match config.consul_agent() {
+        Some(agent) => pipeline.layer(
+            ConsulLayer::new(agent, config.consul_suffix().clone()).watch(Duration::from_secs(10)),
+        ),
+        None => pipeline,
+    };
+    let pipeline = pipeline.layer(zones.clone());
+    let split = match (config.split_routes(), config.split_command()) {
+        (Some(routes), _) => Some(SplitLayer::from_file(routes).await),
+        (None, Some(command)) => Some(SplitLayer::from_command(command).await),
+        (None, None) => None,
+    };
+    // recursion is refused above the cache, or denied clients would get the cached answers
+    let forwards = split.is_some() || !matches!(mode, Mode::Static);
+    let pipeline = match config.acl_recursion() {
+        Some(acl) if forwards => pipeline.layer(AclLayer::recursion(acl.clone())),
+        _ => pipeline,
+    };
+    let pipeline = match split {
+        Some(split) => pipeline.layer(split.watch(Duration::from_secs(5))),
+        None => pipeline,
+    };
+    match mode {
+        Mode::Static => (pipeline, self_test),
+        Mode::Forward(_) | Mode::Recursive => {
+            let cache = Arc::new(CacheLayer::new(config.cache_size()));
+            let pipeline = pipeline.layer(cache.clone()).layer(CoalesceLayer::new());
+            (pipeline, self_test.cache(cache))
+        }
+    }
+}
 
-    // listening for new requests
-    let mut buf = [0u8; 1024];
+/// Logs the counters of every listener and the `top` busiest clients every `interval`
+async fn report_stats(
+    registry: Arc<StatsRegistry>,
+    stats: Arc<ClientStats>,
+    top: usize,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
     loop {
-        let (len, addr) = match receiver.recv_from(&mut buf).await {
-            Ok(values) => values,
-            Err(err) => {
-                println!("ERROR: failed to read from socket with {err}");
-                continue;
-            }
-        };
-        println!("{:?} bytes received from {:?}", len, addr);
-        if let Err(err) = tx.send((buf[..len].to_vec(), addr)).await {
-            println!("ERROR: failed to send to channel with {err}");
+        ticks.tick().await;
+        for line in registry.report().lines() {
+            info!("{line}");
+        }
+        let clients: Vec<String> = stats
+            .top(top)
+            .into_iter()
+            .map(|(client, count)| format!("{client}={count}"))
+            .collect();
+        if !clients.is_empty() {
+            info!("top clients: {}", clients.join(" "));
         }
     }
 }
 
-async fn response_handler(sender: Arc<UdpSocket>, mut rx: Receiver<(Vec<u8>, SocketAddr)>) {
-    while let Some((bytes, addr)) = rx.recv().await {
-        let req = match dns_msg(bytes.as_slice()) {
-            Ok((_, a)) => {
-                println!("DEBUG: got header {a:?}");
-                a
-            }
-            Err(err) => {
-                eprintln!("ERROR: failed to parse - '{err}'");
-                continue;
-            }
-        };
+/// `replay <capture.pcap> [server address]`: replays the queries the capture holds for the
+/// server, listening on the first configured address unless given, and prints every response
+/// that differs. In forwarding mode the upstream answers come from the capture too.
+async fn replay(
+    capture: &Path,
+    server: Option<SocketAddr>,
+    mode: Mode,
+    config: &Config,
+    zones: &ZoneLayer,
+) -> anyhow::Result<bool> {
+    let server = server.unwrap_or(config.listen()[0]);
+    let datagrams = pcap::read_udp(&std::fs::read(capture)?)?;
+    let replay = Replay::new(&datagrams, server);
+    let upstream = match mode {
+        Mode::Forward(_) => Some(replay.mock_upstream().await?),
+        Mode::Static | Mode::Recursive => None,
+    };
+    let mode = match &upstream {
+        Some(upstream) => Mode::Forward(vec![upstream.addr()]),
+        None => mode,
+    };
+    let (handler, _) = handler(&mode, config, zones, None, None, None, None).await;
+    let mismatches = replay.run(&handler).await;
+    for mismatch in &mismatches {
+        println!("{mismatch}");
+    }
+    info!(
+        "replayed {} queries, {} responses differ",
+        replay.exchanges().len(),
+        mismatches.len()
+    );
+    Ok(mismatches.is_empty())
+}
 
-        let response = response(&req);
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = match Cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("ERROR: {err}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let self_test = match cli.command() {
+        Command::Help => {
+            print!("{USAGE}");
+            return Ok(());
+        }
+        Command::Forget {
+            client,
+            log_dir,
+            log_prefix,
+        } => {
+            let lines = Retention::new()
+                .logs(log_dir, log_prefix.as_str())
+                .forget(client)?;
+            info!("removed {lines} log lines about {client}");
+            return Ok(());
+        }
+        Command::Serve { self_test } => *self_test,
+        Command::Replay { .. } => false,
+    };
+
+    let config = cli.config()?;
+    log::init(config.log_level(), config.log_format());
+    let zones = ZoneLayer::load(config.zone_files().to_vec())?
+        .watch(Duration::from_secs(5))
+        .reload_on_sighup();
+    let mode = if !config.resolvers().is_empty() {
+        Mode::Forward(config.resolvers().to_vec())
+    } else if config.recursive() {
+        Mode::Recursive
+    } else {
+        Mode::Static
+    };
+    if let Command::Replay { capture, server } = cli.command() {
+        let same = replay(capture, *server, mode, &config, &zones).await?;
+        std::process::exit(if same { 0 } else { 1 });
+    }
 
-        let mut buff: Vec<u8> = Vec::new();
-        if response.write(&mut buff).is_ok() {
-            match sender.send_to(buff.as_bytes(), &addr).await {
-                Ok(len) => {
-                    println!("INFO response with {:?} bytes", len);
-                }
-                Err(err) => {
-                    println!("ERROR: failed to write to socket with {err}");
-                }
+    let mut socks = Vec::new();
+    let mut listeners = Vec::new();
+    for addr in config.listen() {
+        socks.push(UdpSocket::bind(addr).await?);
+        listeners.push(TcpListener::bind(addr).await?);
+        info!("listening on {addr}");
+    }
+    let mut doh_listeners = Vec::new();
+    for addr in config.doh_listen() {
+        doh_listeners.push(TcpListener::bind(addr).await?);
+        info!("serving DNS over HTTPS on http://{addr}{}", doh::PATH);
+    }
+    match &mode {
+        Mode::Static => {}
+        Mode::Forward(resolvers) => info!("forwarding to {resolvers:?}"),
+        Mode::Recursive => info!("resolving from the root servers"),
+    }
+
+    let query_log = match config.query_log() {
+        Some(dir) => {
+            let query_log = QueryLog::new(dir)
+                .max_bytes(config.query_log_max_bytes())
+                .max_age(config.query_log_rotate())
+                .anonymize(config.query_log_anonymizer())
+                .start()
+                .await?;
+            if let Some(keep) = config.query_log_keep() {
+                Retention::new()
+                    .logs(dir, "queries.log")
+                    .max_age(keep)
+                    .schedule(Duration::from_secs(60 * 60));
             }
-        };
+            info!("logging queries to {}", dir.display());
+            Some(query_log)
+        }
+        None => None,
+    };
+    let dnstap = match (config.dnstap_socket(), config.dnstap_file()) {
+        #[cfg(unix)]
+        (Some(socket), _) => Some(Dnstap::unix(socket)),
+        #[cfg(not(unix))]
+        (Some(_), _) => anyhow::bail!("dnstap.socket needs unix sockets, use dnstap.file"),
+        (None, Some(file)) => Some(Dnstap::file(file)),
+        (None, None) => None,
+    };
+    let dnstap = match (dnstap, config.dnstap_identity()) {
+        (Some(dnstap), Some(identity)) => Some(dnstap.identity(identity)),
+        (dnstap, _) => dnstap,
+    };
+    let dnstap = dnstap.map(|dnstap| dnstap.anonymize(config.dnstap_anonymizer()));
+    let registry = Arc::new(match config.identity() {
+        Some(identity) => StatsRegistry::for_node(identity),
+        None => StatsRegistry::new(),
+    });
+    let client_stats = config.stats_report().map(|interval| {
+        let stats = Arc::new(ClientStats::with_capacity(MAX_CLIENTS));
+        tokio::spawn(report_stats(
+            registry.clone(),
+            stats.clone(),
+            config.stats_top_clients(),
+            interval,
+        ));
+        stats
+    });
+    let challenges = match (
+        config.acme_listen(),
+        config.acme_zone(),
+        config.acme_token(),
+    ) {
+        (Some(addr), Some(zone), Some(token)) => {
+            let challenges = Arc::new(Challenges::new(zone.clone(), token));
+            let listener = TcpListener::bind(addr).await?;
+            tokio::spawn(acme::serve_api(listener, challenges.clone()));
+            info!("serving the ACME challenge API on http://{addr}");
+            Some(challenges)
+        }
+        _ => None,
+    };
+    let (handler, checks) = handler(
+        &mode,
+        &config,
+        &zones,
+        query_log.as_ref(),
+        dnstap.as_ref(),
+        client_stats.as_ref(),
+        challenges.as_ref(),
+    )
+    .await;
+    let handler = Arc::new(handler);
+    let keyring = match config.tsig_keys() {
+        [] => None,
+        keys => Some(Arc::new(keys.iter().cloned().collect::<Keyring>())),
+    };
+    let mut tcp_options = TcpOptions::default();
+    if let Some(keyring) = &keyring {
+        tcp_options = tcp_options.tsig(keyring.clone());
+    }
+    for listener in listeners {
+        tokio::spawn(tcp::run_with_options(
+            listener,
+            handler.clone(),
+            tcp_options.clone(),
+        ));
+    }
+    for listener in doh_listeners {
+        tokio::spawn(doh::run(listener, handler.clone()));
+    }
+    let mut options = ServerOptions::default().registry(registry);
+    if let Some(qps) = config.rate_limit_qps() {
+        let limiter = RateLimiter::new(qps).burst(config.rate_limit_burst().unwrap_or(qps));
+        options = options.rate_limit(Arc::new(limiter), config.rate_limit_policy());
+        info!("limiting clients to {qps} queries per second");
+    }
+    if let Some(rate) = config.rrl_rate() {
+        let rrl = Rrl::new(rate)
+            .window(config.rrl_window())
+            .slip(config.rrl_slip());
+        options = options.rrl(Arc::new(rrl));
+        info!("limiting identical responses to {rate} per second");
+    }
+    if let Some(size) = config.response_cache_size() {
+        let cache = ResponseCache::new(size, config.response_cache_max_age());
+        options = options.response_cache(cache);
+    }
+    if let Some(keyring) = keyring {
+        info!(
+            "verifying signed queries with {} TSIG keys",
+            keyring.keys().len()
+        );
+        options = options.tsig(keyring);
+    }
+    let first = socks[0].local_addr()?;
+    let servers: Vec<_> = socks
+        .into_iter()
+        .map(|sock| {
+            let server = server::run_with_options(sock, handler.clone(), options.clone());
+            tokio::spawn(server)
+        })
+        .collect();
+
+    if self_test {
+        let passed = checks.run(first).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    for server in servers {
+        server.await?;
     }
+    Ok(())
 }
@@ -0,0 +1,208 @@
+//! Unicast bridge to multicast DNS for `.local` names
+//!
+//! [`MdnsLayer`] asks questions about `.local` names on the LAN (RFC 6762) and relays the first
+//! answer, so clients that only speak unicast DNS, such as containers, still find printers and
+//! other mDNS-only devices. Queries are sent from an ephemeral port, which makes responders
+//! reply straight to it with the query id echoed ("legacy unicast", RFC 6762 section 6.7).
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::dns::{
+    class, rtype, DnsMessage, DnsQuestion, DnsRecord, MessageBuilder, ToBytes, MAX_UDP_PAYLOAD,
+};
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::warn;
+
+/// IPv4 mDNS group and port
+pub const MDNS_GROUP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+/// Top bit of the class in mDNS answers, telling caches to replace older records
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Resolves single `.local` questions over mDNS; other queries, and `.local` questions nobody
+/// on the LAN answers, go down the pipeline
+#[derive(Debug, Clone)]
+pub struct MdnsLayer {
+    group: SocketAddr,
+    timeout: Duration,
+}
+
+impl Default for MdnsLayer {
+    fn default() -> Self {
+        Self {
+            group: MDNS_GROUP,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl MdnsLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where questions are sent, [`MDNS_GROUP`] by default
+    pub fn group(mut self, group: SocketAddr) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// How long to wait for a responder, 1s by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Answers to `question` from the first responder, or none if nobody answered in time
+    async fn resolve(&self, question: &DnsQuestion) -> io::Result<Option<Vec<DnsRecord>>> {
+        let local: SocketAddr = match self.group {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let sock = UdpSocket::bind(local).await?;
+        let id = rand::random();
+        let query = MessageBuilder::new()
+            .id(id)
+            .add_question(question.clone())
+            .build();
+        sock.send_to(&query.to_bytes(), self.group).await?;
+
+        let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+        let receive = async {
+            loop {
+                let (len, _) = sock.recv_from(&mut buf).await?;
+                let Ok(response) = DnsMessage::from_bytes(&buf[..len]) else {
+                    continue;
+                };
+                if !response.is_response() || response.id() != id {
+                    continue;
+                }
+                let answers: Vec<DnsRecord> = response
+                    .answers()
+                    .filter(|answer| answer.name() == question.qname())
+                    .map(|answer| {
+                        DnsRecord::new(
+                            answer.name().clone(),
+                            answer.record_type(),
+                            answer.class() & !CACHE_FLUSH,
+                            answer.ttl(),
+                            answer.data().to_vec(),
+                        )
+                    })
+                    .collect();
+                if !answers.is_empty() {
+                    return Ok(answers);
+                }
+            }
+        };
+        match tokio::time::timeout(self.timeout, receive).await {
+            Ok(answers) => answers.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Single IN question about a name under `local.`
+fn local_question(query: &DnsMessage) -> Option<&DnsQuestion> {
+    let mut questions = query.questions();
+    let question = questions.next()?;
+    let last = question.qname().labels().next_back()?;
+    let is_local = questions.next().is_none()
+        && question.qclass() == class::IN
+        && question.qtype() != rtype::SOA
+        && last.eq_ignore_ascii_case(b"local");
+    is_local.then_some(question)
+}
+
+impl Layer for MdnsLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let Some(question) = local_question(&query) else {
+                return next.run(query, ctx).await;
+            };
+            match self.resolve(question).await {
+                Ok(Some(answers)) => answers
+                    .into_iter()
+                    .fold(
+                        MessageBuilder::response_to(&query).add_question(question.clone()),
+                        MessageBuilder::add_answer,
+                    )
+                    .build(),
+                Ok(None) => next.run(query, ctx).await,
+                Err(err) => {
+                    warn!("mDNS query for {} failed with {err}", question.qname());
+                    next.run(query, ctx).await
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    #[tokio::test]
+    async fn test_relay() {
+        // a responder on loopback standing in for the multicast group
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let group = responder.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+            let (len, from) = responder.recv_from(&mut buf).await.unwrap();
+            let query = DnsMessage::from_bytes(&buf[..len]).unwrap();
+            let question = query.questions().next().unwrap().clone();
+            let answer = DnsRecord::new(
+                question.qname().clone(),
+                rtype::A,
+                class::IN | CACHE_FLUSH,
+                120,
+                vec![192, 168, 1, 20],
+            );
+            let response = MessageBuilder::response_to(&query)
+                .authoritative(true)
+                .add_question(question)
+                .add_answer(answer)
+                .build();
+            responder.send_to(&response.to_bytes(), from).await.unwrap();
+        });
+
+        let layer = MdnsLayer::new()
+            .group(group)
+            .timeout(Duration::from_millis(200));
+        let pipeline = Pipeline::new(DefaultHandler).layer(layer);
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+
+        let response = pipeline
+            .handle(DnsMessage::query(7, "printer.local", rtype::A), ctx.clone())
+            .await;
+        assert_eq!(response.id(), 7);
+        let answer = response.answers().next().unwrap();
+        assert_eq!(answer.class(), class::IN);
+        assert_eq!(
+            IpAddr::try_from(answer).unwrap(),
+            IpAddr::from([192, 168, 1, 20])
+        );
+
+        // nobody answers anymore: the rest of the pipeline does
+        let response = pipeline
+            .handle(DnsMessage::query(8, "printer.local", rtype::A), ctx)
+            .await;
+        assert_eq!(
+            IpAddr::try_from(response.answers().next().unwrap()).unwrap(),
+            IpAddr::from([8, 8, 8, 8])
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Reader for the UDP datagrams in libpcap capture files, as written by `tcpdump -w`
+//!
+//! Only the classic format is read (not pcapng), with Ethernet (optionally VLAN tagged), Linux
+//! cooked, BSD loopback and raw IP link types. Fragmented and non-UDP packets are skipped.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use crate::error::DnsError;
+
+const MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+const MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_UDP: u8 = 17;
+
+/// UDP datagram read from a capture
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Datagram {
+    time: Duration,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: Vec<u8>,
+}
+
+impl Datagram {
+    pub fn new(time: Duration, src: SocketAddr, dst: SocketAddr, payload: Vec<u8>) -> Self {
+        Self {
+            time,
+            src,
+            dst,
+            payload,
+        }
+    }
+
+    /// Capture time, since the Unix epoch
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    pub fn src(&self) -> SocketAddr {
+        self.src
+    }
+
+    pub fn dst(&self) -> SocketAddr {
+        self.dst
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+fn malformed(what: &str) -> DnsError {
+    DnsError::Malformed(format!("pcap: {what}"))
+}
+
+/// Reads every UDP datagram in the capture `bytes`, in capture order
+pub fn read_udp(bytes: &[u8]) -> Result<Vec<Datagram>, DnsError> {
+    let header = bytes.get(..24).ok_or(DnsError::Truncated)?;
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let (little_endian, nanos) = match (magic, magic.swap_bytes()) {
+        (MAGIC_MICROS, _) => (true, false),
+        (MAGIC_NANOS, _) => (true, true),
+        (_, MAGIC_MICROS) => (false, false),
+        (_, MAGIC_NANOS) => (false, true),
+        _ => return Err(malformed("not a libpcap file")),
+    };
+    let u32_at = |bytes: &[u8], at: usize| {
+        let word = bytes[at..at + 4].try_into().unwrap();
+        if little_endian {
+            u32::from_le_bytes(word)
+        } else {
+            u32::from_be_bytes(word)
+        }
+    };
+    let linktype = u32_at(header, 20) & 0x0FFF_FFFF;
+
+    let mut datagrams = Vec::new();
+    let mut rest = &bytes[24..];
+    while !rest.is_empty() {
+        let record = rest.get(..16).ok_or(DnsError::Truncated)?;
+        let secs = u32_at(record, 0) as u64;
+        let frac = u32_at(record, 4);
+        let captured = u32_at(record, 8) as usize;
+        let frame = rest.get(16..16 + captured).ok_or(DnsError::Truncated)?;
+        rest = &rest[16 + captured..];
+
+        let time = Duration::from_secs(secs)
+            + if nanos {
+                Duration::from_nanos(frac as u64)
+            } else {
+                Duration::from_micros(frac as u64)
+            };
+        if let Some((src, dst, payload)) = ip_packet(linktype, frame).and_then(udp) {
+            datagrams.push(Datagram::new(time, src, dst, payload.to_vec()));
+        }
+    }
+    Ok(datagrams)
+}
+
+/// The IP packet in a link layer `frame`
+fn ip_packet(linktype: u32, frame: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+            let mut at = 14;
+            while ethertype == ETHERTYPE_VLAN {
+                ethertype = u16::from_be_bytes(frame.get(at + 2..at + 4)?.try_into().ok()?);
+                at += 4;
+            }
+            matches!(ethertype, ETHERTYPE_IPV4 | ETHERTYPE_IPV6).then(|| &frame[at..])
+        }
+        LINKTYPE_LINUX_SLL => frame.get(16..),
+        // host-endian address family, which the IP version nibble makes redundant
+        LINKTYPE_NULL => frame.get(4..),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(frame),
+        _ => None,
+    }
+}
+
+/// Source, destination and payload of `packet` if it is an unfragmented UDP datagram
+fn udp(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (src, dst, segment) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = (packet[0] & 0x0F) as usize * 4;
+            let total_len = u16::from_be_bytes(packet.get(2..4)?.try_into().ok()?) as usize;
+            let fragmented = u16::from_be_bytes(packet.get(6..8)?.try_into().ok()?) & 0x3FFF != 0;
+            if packet[9] != IPPROTO_UDP || fragmented {
+                return None;
+            }
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            let segment = packet.get(header_len..total_len.min(packet.len()))?;
+            (
+                Ipv4Addr::from(src).into(),
+                Ipv4Addr::from(dst).into(),
+                segment,
+            )
+        }
+        6 => {
+            // extension headers are not followed
+            if *packet.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            let payload_len = u16::from_be_bytes(packet.get(4..6)?.try_into().ok()?) as usize;
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            let segment = packet.get(40..(40 + payload_len).min(packet.len()))?;
+            (
+                IpAddr::from(Ipv6Addr::from(src)),
+                IpAddr::from(Ipv6Addr::from(dst)),
+                segment,
+            )
+        }
+        _ => return None,
+    };
+    let src_port = u16::from_be_bytes(segment.get(0..2)?.try_into().ok()?);
+    let dst_port = u16::from_be_bytes(segment.get(2..4)?.try_into().ok()?);
+    let udp_len = u16::from_be_bytes(segment.get(4..6)?.try_into().ok()?) as usize;
+    let payload = segment.get(8..udp_len.max(8).min(segment.len()))?;
+    Some((
+        SocketAddr::new(src, src_port),
+        SocketAddr::new(dst, dst_port),
+        payload,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Little-endian microsecond capture of Ethernet frames holding the given IPv4 datagrams
+    fn capture(datagrams: &[(u32, SocketAddr, SocketAddr, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(MAGIC_MICROS.to_le_bytes());
+        out.extend([2, 0, 4, 0]);
+        out.extend([0; 8]);
+        out.extend(65535u32.to_le_bytes());
+        out.extend(LINKTYPE_ETHERNET.to_le_bytes());
+        for &(secs, src, dst, payload) in datagrams {
+            let (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) = (src.ip(), dst.ip()) else {
+                panic!("IPv4 only");
+            };
+            let mut frame = vec![0; 12];
+            frame.extend(ETHERTYPE_IPV4.to_be_bytes());
+            frame.extend([0x45, 0]);
+            frame.extend((28 + payload.len() as u16).to_be_bytes());
+            frame.extend([0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]);
+            frame.extend(src_ip.octets());
+            frame.extend(dst_ip.octets());
+            frame.extend(src.port().to_be_bytes());
+            frame.extend(dst.port().to_be_bytes());
+            frame.extend((8 + payload.len() as u16).to_be_bytes());
+            frame.extend([0, 0]);
+            frame.extend(payload);
+
+            out.extend(secs.to_le_bytes());
+            out.extend(0u32.to_le_bytes());
+            out.extend((frame.len() as u32).to_le_bytes());
+            out.extend((frame.len() as u32).to_le_bytes());
+            out.extend(frame);
+        }
+        out
+    }
+
+    #[test]
+    fn test_read_udp() {
+        let client = SocketAddr::from(([192, 0, 2, 1], 40000));
+        let server = SocketAddr::from(([192, 0, 2, 53], 53));
+        let bytes = capture(&[(7, client, server, b"query"), (8, server, client, b"reply")]);
+        let datagrams = read_udp(&bytes).unwrap();
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].time(), Duration::from_secs(7));
+        assert_eq!(datagrams[0].src(), client);
+        assert_eq!(datagrams[0].dst(), server);
+        assert_eq!(datagrams[1].payload(), b"reply");
+
+        assert!(read_udp(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_udp(&[0; 24]).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! Layered query processing: stages wrapped around a terminal [`RequestHandler`]
+//!
+//! Each [`Layer`] sees the query on its way in and the response on its way out, and decides
+//! whether to call the rest of the pipeline through [`Next`]. Layers are trait objects so the
+//! stack can be assembled at startup from configuration.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::debug;
+use crate::dns::DnsMessage;
+use crate::handler::{RequestCtx, RequestHandler};
+use crate::log::Span;
+use crate::privacy::Anonymizer;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A processing stage of the pipeline
+pub trait Layer: Send + Sync + 'static {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage>;
+}
+
+/// Shared layers, whose state stays reachable once they are in a pipeline
+impl<L: Layer> Layer for Arc<L> {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        (**self).call(query, ctx, next)
+    }
+}
+
+/// Object safe form of [`RequestHandler`]
+trait DynHandler: Send + Sync {
+    fn handle_boxed(&self, query: DnsMessage, ctx: RequestCtx) -> BoxFuture<'_, DnsMessage>;
+}
+
+impl<H: RequestHandler> DynHandler for H {
+    fn handle_boxed(&self, query: DnsMessage, ctx: RequestCtx) -> BoxFuture<'_, DnsMessage> {
+        Box::pin(self.handle(query, ctx))
+    }
+}
+
+/// The remainder of the pipeline after the current layer, which a layer may run more than once
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Layer>],
+    handler: &'a dyn DynHandler,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, query: DnsMessage, ctx: RequestCtx) -> BoxFuture<'a, DnsMessage> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => {
+                let next = Next {
+                    layers,
+                    handler: self.handler,
+                };
+                layer.call(query, ctx, next)
+            }
+            None => self.handler.handle_boxed(query, ctx),
+        }
+    }
+}
+
+/// Layers run in the order they were added, the first one being the outermost
+pub struct Pipeline {
+    layers: Vec<Arc<dyn Layer>>,
+    handler: Arc<dyn DynHandler>,
+}
+
+impl Pipeline {
+    pub fn new(handler: impl RequestHandler) -> Self {
+        Self {
+            layers: Vec::new(),
+            handler: Arc::new(handler),
+        }
+    }
+
+    pub fn layer(mut self, layer: impl Layer) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+}
+
+impl RequestHandler for Pipeline {
+    async fn handle(&self, query: DnsMessage, ctx: RequestCtx) -> DnsMessage {
+        let next = Next {
+            layers: &self.layers,
+            handler: self.handler.as_ref(),
+        };
+        next.run(query, ctx).await
+    }
+}
+
+/// Logs every query and its response, and opens the [`Span`] of the query for the layers after
+/// it to log in
+#[derive(Debug, Default, Clone)]
+pub struct LoggingLayer {
+    anonymizer: Anonymizer,
+}
+
+impl LoggingLayer {
+    /// How clients are written to the log, as they are by default
+    pub fn anonymize(mut self, anonymizer: Anonymizer) -> Self {
+        self.anonymizer = anonymizer;
+        self
+    }
+}
+
+impl Layer for LoggingLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        let span = Span::new(self.anonymizer.client(ctx.client().ip()), &query);
+        Box::pin(span.scope(async move {
+            debug!("query {query:?}");
+            let started = Instant::now();
+            let response = next.run(query, ctx).await;
+            debug!(
+                "response rcode {} with {} answers in {:?}",
+                response.rcode(),
+                response.answers().len(),
+                started.elapsed()
+            );
+            response
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::dns::MessageBuilder;
+    use crate::handler::{DefaultHandler, Transport};
+
+    /// Records the order it ran in, optionally answering without calling the rest
+    struct Trace {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        short_circuit: bool,
+    }
+
+    impl Layer for Trace {
+        fn call<'a>(
+            &'a self,
+            query: DnsMessage,
+            ctx: RequestCtx,
+            next: Next<'a>,
+        ) -> BoxFuture<'a, DnsMessage> {
+            Box::pin(async move {
+                self.calls.lock().unwrap().push(self.name);
+                if self.short_circuit {
+                    return MessageBuilder::response_to(&query).rcode(5).build();
+                }
+                next.run(query, ctx).await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layer_order_and_short_circuit() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let trace = |name, short_circuit| Trace {
+            name,
+            calls: calls.clone(),
+            short_circuit,
+        };
+        let pipeline = Pipeline::new(DefaultHandler)
+            .layer(trace("outer", false))
+            .layer(trace("filter", true))
+            .layer(trace("inner", false));
+
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+        let response = pipeline
+            .handle(DnsMessage::query(1, "example.com", 1), ctx)
+            .await;
+
+        assert_eq!(response.rcode(), 5);
+        assert_eq!(*calls.lock().unwrap(), ["outer", "filter"]);
+    }
+}
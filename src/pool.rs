@@ -0,0 +1,100 @@
+//! Recycled packet buffers
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Free list of byte buffers handed out as [`PooledBuf`]s and returned to the pool on drop
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    buf_capacity: usize,
+    max_free: usize,
+}
+
+impl BufferPool {
+    /// Pool of buffers preallocated to `buf_capacity`, keeping at most `max_free` idle ones
+    pub fn new(buf_capacity: usize, max_free: usize) -> Arc<Self> {
+        Arc::new(Self {
+            free: Mutex::new(Vec::new()),
+            buf_capacity,
+            max_free,
+        })
+    }
+
+    /// An empty buffer, reused from the pool when one is idle
+    pub fn get(self: &Arc<Self>) -> PooledBuf {
+        let buf = self.free.lock().unwrap().pop();
+        let buf = buf.unwrap_or_else(|| Vec::with_capacity(self.buf_capacity));
+        PooledBuf {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_free {
+            buf.clear();
+            free.push(buf);
+        }
+    }
+}
+
+/// Buffer borrowed from a [`BufferPool`]
+#[derive(Debug)]
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl AsMut<[u8]> for PooledBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_recycled() {
+        let pool = BufferPool::new(1024, 1);
+
+        let mut first = pool.get();
+        first.extend_from_slice(b"query");
+        let ptr = first.as_ptr();
+        drop(first);
+
+        let second = pool.get();
+        assert_eq!(second.as_ptr(), ptr);
+        assert!(second.is_empty());
+        assert!(second.capacity() >= 1024);
+
+        // only one idle buffer is kept
+        let third = pool.get();
+        drop(second);
+        drop(third);
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,107 @@
+//! Anonymization of client addresses before they are logged or counted
+//!
+//! Every output that records clients (query logs, per-client stats, ...) takes its own
+//! [`Anonymizer`], so a deployment can e.g. keep full addresses in short-lived stats while
+//! writing only /24 networks to long-lived logs.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A client as an output records it
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Client {
+    /// The address, possibly with its host bits cleared
+    Addr(IpAddr),
+    /// Keyed hash of the address
+    Hashed(u64),
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::Addr(addr) => addr.fmt(f),
+            Client::Hashed(hash) => write!(f, "#{hash:016x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+enum Mode {
+    #[default]
+    Off,
+    Truncate {
+        v4_prefix: u8,
+        v6_prefix: u8,
+    },
+    Hash(RandomState),
+}
+
+/// Turns client addresses into what an output may record
+#[derive(Debug, Clone, Default)]
+pub struct Anonymizer(Mode);
+
+impl Anonymizer {
+    /// Records addresses as they are
+    pub fn off() -> Self {
+        Self(Mode::Off)
+    }
+
+    /// Keeps only the network part of addresses, e.g. `truncate(24, 56)` records
+    /// `192.0.2.77` as `192.0.2.0`
+    pub fn truncate(v4_prefix: u8, v6_prefix: u8) -> Self {
+        Self(Mode::Truncate {
+            v4_prefix: v4_prefix.min(32),
+            v6_prefix: v6_prefix.min(128),
+        })
+    }
+
+    /// Records a hash of addresses under a key drawn at startup: the same client keeps the
+    /// same hash while the process runs, and hashes cannot be reversed by trying every address
+    pub fn hash() -> Self {
+        Self(Mode::Hash(RandomState::new()))
+    }
+
+    pub fn client(&self, addr: IpAddr) -> Client {
+        match &self.0 {
+            Mode::Off => Client::Addr(addr),
+            Mode::Truncate {
+                v4_prefix,
+                v6_prefix,
+            } => Client::Addr(match addr {
+                IpAddr::V4(v4) => {
+                    let mask = u32::MAX.checked_shl(32 - *v4_prefix as u32).unwrap_or(0);
+                    Ipv4Addr::from(u32::from(v4) & mask).into()
+                }
+                IpAddr::V6(v6) => {
+                    let mask = u128::MAX.checked_shl(128 - *v6_prefix as u32).unwrap_or(0);
+                    Ipv6Addr::from(u128::from(v6) & mask).into()
+                }
+            }),
+            Mode::Hash(key) => Client::Hashed(key.hash_one(addr)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_anonymize() {
+        let v4: IpAddr = "192.0.2.77".parse().unwrap();
+        let v6: IpAddr = "2001:db8:1234:5678::1".parse().unwrap();
+
+        assert_eq!(Anonymizer::off().client(v4).to_string(), "192.0.2.77");
+        let truncate = Anonymizer::truncate(24, 56);
+        assert_eq!(truncate.client(v4).to_string(), "192.0.2.0");
+        assert_eq!(truncate.client(v6).to_string(), "2001:db8:1234:5600::");
+        assert_eq!(Anonymizer::truncate(0, 0).client(v4).to_string(), "0.0.0.0");
+
+        let hash = Anonymizer::hash();
+        assert_eq!(hash.client(v4), hash.client(v4));
+        assert_ne!(hash.client(v4), hash.client(v6));
+        assert!(hash.client(v4).to_string().starts_with('#'));
+    }
+}
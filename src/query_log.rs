@@ -0,0 +1,282 @@
+//! Query log: a line per request, for auditing what clients ask
+//!
+//! [`QueryLogLayer`] records every query that goes through the pipeline as
+//!
+//! ```text
+//! 1792056544.315 192.0.2.1 codecrafters.io A NOERROR 1 0.042
+//! ```
+//!
+//! that is the time it arrived (seconds since the epoch), the client as the [`Anonymizer`]
+//! writes it, qname, qtype, rcode, number of answers and the milliseconds spent answering.
+//! Lines are handed to a background task that writes them, so a slow disk never delays
+//! responses; when it falls too far behind lines are dropped and counted instead.
+//!
+//! The task writes `queries.log` in the configured directory and rotates it to `queries.log.1`
+//! (the older files moving up to `.2`, `.3`, ...) once it grows past a size or age. Rotated
+//! files are left to a [`Retention`](crate::retention::Retention) policy to purge, which also
+//! removes a client's lines on request.
+
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::dns::{rcode, DnsMessage};
+use crate::handler::RequestCtx;
+use crate::log::type_name;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::privacy::Anonymizer;
+use crate::warn;
+
+/// Lines waiting for the writer before new ones are dropped
+const QUEUE: usize = 4096;
+
+/// Where and how the query log is written
+#[derive(Debug, Clone)]
+pub struct QueryLog {
+    dir: PathBuf,
+    prefix: String,
+    max_bytes: u64,
+    max_age: Duration,
+    anonymizer: Anonymizer,
+}
+
+impl QueryLog {
+    /// Log written to `queries.log` in `dir`, rotated daily or at 100 MB
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            prefix: "queries.log".to_string(),
+            max_bytes: 100_000_000,
+            max_age: Duration::from_secs(24 * 60 * 60),
+            anonymizer: Anonymizer::default(),
+        }
+    }
+
+    /// Name of the file written, and start of the names of the rotated ones
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// The file is rotated once it holds more than `max_bytes`
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// The file is rotated once it has been written to for `max_age`
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// How clients are written to the log, as they are by default
+    pub fn anonymize(mut self, anonymizer: Anonymizer) -> Self {
+        self.anonymizer = anonymizer;
+        self
+    }
+
+    /// Opens the log and starts its writer. Must be called inside a tokio runtime.
+    pub async fn start(self) -> io::Result<QueryLogLayer> {
+        fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(&self.prefix);
+        let file = Output::open(&path).await?;
+        let (tx, rx) = mpsc::channel(QUEUE);
+        let anonymizer = self.anonymizer.clone();
+        tokio::spawn(write(self, path, file, rx));
+        Ok(QueryLogLayer {
+            tx,
+            anonymizer,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+}
+
+/// Records every query and its response in a [`QueryLog`]
+#[derive(Debug, Clone)]
+pub struct QueryLogLayer {
+    tx: Sender<String>,
+    anonymizer: Anonymizer,
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueryLogLayer {
+    /// Lines dropped since the start because the writer fell behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Layer for QueryLogLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let client = self.anonymizer.client(ctx.client().ip());
+            let question = query
+                .questions()
+                .next()
+                .map(|question| (question.qname().to_string(), question.qtype()));
+            let started = Instant::now();
+            let response = next.run(query, ctx).await;
+            let elapsed = started.elapsed();
+
+            let mut line = format!("{:.3} {client} ", time.as_secs_f64());
+            match question {
+                Some((qname, qtype)) => {
+                    let _ = write!(line, "{qname} {}", type_name(qtype));
+                }
+                None => line.push_str("- -"),
+            }
+            let code = response.rcode();
+            let _ = match rcode::name(code) {
+                Some(name) => write!(line, " {name}"),
+                None => write!(line, " RCODE{code}"),
+            };
+            let _ = writeln!(
+                line,
+                " {} {:.3}",
+                response.answers().len(),
+                elapsed.as_secs_f64() * 1000.0
+            );
+            if self.tx.try_send(line).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            response
+        })
+    }
+}
+
+/// The file being written
+struct Output {
+    writer: BufWriter<File>,
+    len: u64,
+    opened: Instant,
+}
+
+impl Output {
+    async fn open(path: &Path) -> io::Result<Output> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let len = file.metadata().await?.len();
+        Ok(Output {
+            writer: BufWriter::new(file),
+            len,
+            opened: Instant::now(),
+        })
+    }
+}
+
+/// Writes lines as they come until every [`QueryLogLayer`] is gone
+async fn write(log: QueryLog, path: PathBuf, mut output: Output, mut rx: Receiver<String>) {
+    let mut lines = Vec::new();
+    while rx.recv_many(&mut lines, QUEUE).await > 0 {
+        let full = output.len >= log.max_bytes || output.opened.elapsed() >= log.max_age;
+        if full && output.len > 0 {
+            match rotate(&log, &path, &mut output).await {
+                Ok(rotated) => output = rotated,
+                Err(err) => warn!("failed to rotate {} with {err}", path.display()),
+            }
+        }
+        for line in lines.drain(..) {
+            if let Err(err) = output.writer.write_all(line.as_bytes()).await {
+                warn!("failed to write to {} with {err}", path.display());
+                break;
+            }
+            output.len += line.len() as u64;
+        }
+        if let Err(err) = output.writer.flush().await {
+            warn!("failed to write to {} with {err}", path.display());
+        }
+    }
+}
+
+/// Moves `queries.log.N` to `queries.log.N+1`, from the oldest, then the current file to
+/// `queries.log.1`, and opens a new one
+async fn rotate(log: &QueryLog, path: &Path, output: &mut Output) -> io::Result<Output> {
+    output.writer.flush().await?;
+    let rotated = |n: usize| log.dir.join(format!("{}.{n}", log.prefix));
+    let mut last = 0;
+    while fs::try_exists(rotated(last + 1)).await? {
+        last += 1;
+    }
+    for n in (1..=last).rev() {
+        fs::rename(rotated(n), rotated(n + 1)).await?;
+    }
+    fs::rename(path, rotated(1)).await?;
+    Output::open(path).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::dns::rtype;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+    use crate::privacy::Client;
+    use crate::retention::Retention;
+
+    fn lines(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .map(|text| text.lines().count())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("query-log-test-{}", std::process::id()));
+        let layer = QueryLog::new(&dir)
+            .max_bytes(1)
+            .anonymize(Anonymizer::truncate(24, 56))
+            .start()
+            .await
+            .unwrap();
+        let pipeline = Pipeline::new(DefaultHandler).layer(layer.clone());
+        let ctx = RequestCtx::new(SocketAddr::from(([192, 0, 2, 77], 5353)), Transport::Udp);
+        for id in 0..3 {
+            let query = DnsMessage::query(id, "codecrafters.io", rtype::A);
+            pipeline.handle(query, ctx.clone()).await;
+            // written before the next query, so that each rotates the file
+            for _ in 0..100 {
+                if lines(&dir) > usize::from(id) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        let oldest = std::fs::read_to_string(dir.join("queries.log.2")).unwrap();
+        let fields: Vec<&str> = oldest.split_whitespace().collect();
+        assert_eq!(
+            fields[1..6],
+            ["192.0.2.0", "codecrafters.io", "A", "NOERROR", "1"]
+        );
+        assert!(dir.join("queries.log.1").exists());
+        assert_eq!(layer.dropped(), 0);
+
+        let client = Client::Addr([192, 0, 2, 0].into());
+        let removed = Retention::new().logs(&dir, "queries.log").forget(&client);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(removed.unwrap(), 3);
+    }
+}
@@ -0,0 +1,131 @@
+//! Per-client rate limiting
+//!
+//! [`RateLimiter`] keeps a token bucket per client address: each query takes a token, tokens
+//! come back at the configured rate up to a burst, and a client without tokens left is over
+//! the limit. The UDP server checks it before handling a query (see
+//! [`ServerOptions::rate_limit`](crate::server::ServerOptions::rate_limit)) and drops or
+//! refuses the queries over the limit, before they cost any work.
+//!
+//! Buckets that have filled up again are forgotten, so the table only holds the clients seen
+//! in the last few seconds.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often full buckets are swept out of the table
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What to do with a query over its client's limit
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RateLimitPolicy {
+    /// Discard the query
+    Drop,
+    /// Answer with just a REFUSED header
+    Refused,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Table {
+    buckets: HashMap<IpAddr, Bucket>,
+    swept: Instant,
+}
+
+/// Token buckets of the clients, shared by every listener
+#[derive(Debug)]
+pub struct RateLimiter {
+    qps: f64,
+    burst: f64,
+    table: Mutex<Table>,
+}
+
+impl RateLimiter {
+    /// Allows each client `qps` queries per second, in bursts of as many
+    pub fn new(qps: u32) -> Self {
+        Self {
+            qps: qps.max(1).into(),
+            burst: qps.max(1).into(),
+            table: Mutex::new(Table {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Queries a client may send at once after being quiet, `qps` by default
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1).into();
+        self
+    }
+
+    /// Takes a token for a query from `client`, `false` if it is over the limit
+    pub fn check(&self, client: IpAddr) -> bool {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> bool {
+        let mut table = self.table.lock().unwrap();
+        if now.saturating_duration_since(table.swept) >= SWEEP_INTERVAL {
+            let refill = Duration::from_secs_f64(self.burst / self.qps);
+            table
+                .buckets
+                .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < refill);
+            table.swept = now;
+        }
+
+        let bucket = table.buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.qps).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Number of clients with a bucket
+    pub fn len(&self) -> usize {
+        self.table.lock().unwrap().buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        let limiter = RateLimiter::new(10).burst(3);
+        let (client, other) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+        let start = Instant::now();
+
+        let allowed: Vec<bool> = (0..4).map(|_| limiter.check_at(client, start)).collect();
+        assert_eq!(allowed, [true, true, true, false]);
+        assert!(limiter.check_at(other, start));
+
+        // a token every 100ms
+        let later = start + Duration::from_millis(150);
+        assert!(limiter.check_at(client, later));
+        assert!(!limiter.check_at(client, later));
+
+        // full buckets are swept out
+        assert_eq!(limiter.len(), 2);
+        assert!(limiter.check_at(client, start + SWEEP_INTERVAL));
+        assert_eq!(limiter.len(), 1);
+    }
+}
@@ -0,0 +1,474 @@
+//! Typed record data and conversions to and from `std::net` types
+
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+
+use bytes::BufMut;
+
+use crate::dns::{dns_labels, read_name, rtype, DnsLabels, DnsRecord, ToBytes};
+use crate::error::DnsError;
+
+/// RDATA of a resource record, decoded according to its type
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(DnsLabels),
+    Ns(DnsLabels),
+    Ptr(DnsLabels),
+    Mx {
+        preference: u16,
+        exchange: DnsLabels,
+    },
+    /// Character-strings; longer than 255 bytes ones are split when written
+    Txt(Vec<Vec<u8>>),
+    Soa {
+        mname: DnsLabels,
+        rname: DnsLabels,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DnsLabels,
+    },
+    /// Which certificate authorities may issue for the name (RFC 8659); `tag` is `issue`,
+    /// `issuewild` or `iodef` in practice
+    Caa {
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+    },
+    /// Any type without a dedicated variant, kept as raw bytes
+    Unknown(u16, Vec<u8>),
+}
+
+impl RData {
+    /// Decodes the RDATA bytes of a record of type `record_type`
+    pub fn from_wire(record_type: u16, data: &[u8]) -> Result<RData, DnsError> {
+        let rdata = match record_type {
+            rtype::A => RData::A(Ipv4Addr::from(fixed::<4>(data)?)),
+            rtype::AAAA => RData::Aaaa(Ipv6Addr::from(fixed::<16>(data)?)),
+            rtype::CNAME => RData::Cname(only_name(data)?),
+            rtype::NS => RData::Ns(only_name(data)?),
+            rtype::PTR => RData::Ptr(only_name(data)?),
+            rtype::MX => {
+                if data.len() < 3 {
+                    return Err(DnsError::Truncated);
+                }
+                RData::Mx {
+                    preference: u16::from_be_bytes([data[0], data[1]]),
+                    exchange: only_name(&data[2..])?,
+                }
+            }
+            rtype::TXT => {
+                let mut strings = Vec::new();
+                let mut rest = data;
+                while let Some((&len, tail)) = rest.split_first() {
+                    let string = tail.get(..len as usize).ok_or(DnsError::Truncated)?;
+                    strings.push(string.to_vec());
+                    rest = &tail[len as usize..];
+                }
+                RData::Txt(strings)
+            }
+            rtype::SOA => {
+                let (rest, mname) = dns_labels(data, data)?;
+                let (rest, rname) = dns_labels(data, rest)?;
+                let fields = fixed::<20>(rest)?;
+                let field = |at: usize| u32::from_be_bytes(fields[at..at + 4].try_into().unwrap());
+                RData::Soa {
+                    mname: mname.to_owned(),
+                    rname: rname.to_owned(),
+                    serial: field(0),
+                    refresh: field(4),
+                    retry: field(8),
+                    expire: field(12),
+                    minimum: field(16),
+                }
+            }
+            rtype::SRV => {
+                if data.len() < 7 {
+                    return Err(DnsError::Truncated);
+                }
+                let field = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+                // RFC 2782: the target is never compressed
+                let (_, target) = dns_labels(&data[6..], &data[6..])?;
+                RData::Srv {
+                    priority: field(0),
+                    weight: field(2),
+                    port: field(4),
+                    target: target.to_owned(),
+                }
+            }
+            rtype::CAA => {
+                let (&flags, rest) = data.split_first().ok_or(DnsError::Truncated)?;
+                let (&len, rest) = rest.split_first().ok_or(DnsError::Truncated)?;
+                let tag = rest.get(..len as usize).ok_or(DnsError::Truncated)?;
+                if tag.is_empty() || !tag.iter().all(u8::is_ascii_alphanumeric) {
+                    return Err(DnsError::Malformed(format!("invalid CAA tag {tag:02x?}")));
+                }
+                RData::Caa {
+                    flags,
+                    tag: String::from_utf8_lossy(tag).into_owned(),
+                    value: rest[len as usize..].to_vec(),
+                }
+            }
+            other => RData::Unknown(other, data.to_vec()),
+        };
+        Ok(rdata)
+    }
+
+    /// TXT data holding `text` as one string, such as an SPF policy or a verification token
+    pub fn txt(text: impl AsRef<[u8]>) -> RData {
+        RData::Txt(vec![text.as_ref().to_vec()])
+    }
+
+    pub fn record_type(&self) -> u16 {
+        match self {
+            RData::A(_) => rtype::A,
+            RData::Aaaa(_) => rtype::AAAA,
+            RData::Cname(_) => rtype::CNAME,
+            RData::Ns(_) => rtype::NS,
+            RData::Ptr(_) => rtype::PTR,
+            RData::Mx { .. } => rtype::MX,
+            RData::Txt(_) => rtype::TXT,
+            RData::Soa { .. } => rtype::SOA,
+            RData::Srv { .. } => rtype::SRV,
+            RData::Caa { .. } => rtype::CAA,
+            RData::Unknown(record_type, _) => *record_type,
+        }
+    }
+
+    /// Address of an SRV target once the target name has been resolved to `ip`
+    pub fn srv_socket_addr(&self, ip: IpAddr) -> Option<SocketAddr> {
+        match self {
+            RData::Srv { port, .. } => Some(SocketAddr::new(ip, *port)),
+            _ => None,
+        }
+    }
+}
+
+/// RDATA of the record occupying `range` of `message`, with the names embedded in NS, CNAME,
+/// SOA, PTR, MX and SRV data expanded from compression pointers.
+///
+/// Compressed names make RDLENGTH describe the bytes on the wire, not the data itself, so the
+/// expanded copy is what gets stored and written back, with RDLENGTH recomputed from it.
+pub(crate) fn expand_names(
+    record_type: u16,
+    message: &[u8],
+    range: Range<usize>,
+) -> Result<Cow<'_, [u8]>, DnsError> {
+    // fixed bytes before the names, number of names, fixed bytes after them
+    let (prefix, names, suffix) = match record_type {
+        rtype::NS | rtype::CNAME | rtype::PTR => (0, 1, 0),
+        rtype::MX => (2, 1, 0),
+        rtype::SRV => (6, 1, 0),
+        // MNAME, RNAME, then SERIAL, REFRESH, RETRY, EXPIRE and MINIMUM
+        rtype::SOA => (0, 2, 20),
+        _ => return Ok(Cow::Borrowed(&message[range])),
+    };
+
+    let data = &message[range.clone()];
+    if data.len() < prefix + names + suffix {
+        return Err(DnsError::Truncated);
+    }
+    let mut expanded = data[..prefix].to_vec();
+    let mut at = range.start + prefix;
+    for _ in 0..names {
+        at = read_name(&message[..range.end], at, &mut expanded)?;
+    }
+    if range.end - at != suffix {
+        return Err(DnsError::Malformed(format!(
+            "{} bytes of rdata left after the names of a type {record_type} record",
+            range.end - at
+        )));
+    }
+    expanded.extend_from_slice(&message[at..range.end]);
+    Ok(Cow::Owned(expanded))
+}
+
+/// The single name that makes up `data`, as in CNAME, NS and PTR records
+fn only_name(data: &[u8]) -> Result<DnsLabels, DnsError> {
+    let (rest, name) = dns_labels(data, data)?;
+    if !rest.is_empty() {
+        return Err(DnsError::Malformed(format!(
+            "{} bytes of rdata left after the name",
+            rest.len()
+        )));
+    }
+    Ok(name.to_owned())
+}
+
+fn fixed<const N: usize>(data: &[u8]) -> Result<[u8; N], DnsError> {
+    data.try_into().map_err(|_| {
+        DnsError::Malformed(format!("expected {N} bytes of rdata, got {}", data.len()))
+    })
+}
+
+impl ToBytes for RData {
+    fn write_to(&self, buf: &mut impl BufMut) -> usize {
+        match self {
+            RData::A(ip) => buf.put_slice(&ip.octets()),
+            RData::Aaaa(ip) => buf.put_slice(&ip.octets()),
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => {
+                name.write_to(buf);
+            }
+            RData::Mx {
+                preference,
+                exchange,
+            } => {
+                buf.put_u16(*preference);
+                exchange.write_to(buf);
+            }
+            RData::Txt(strings) => {
+                for string in strings {
+                    if string.is_empty() {
+                        buf.put_u8(0);
+                    }
+                    for chunk in string.chunks(255) {
+                        buf.put_u8(chunk.len() as u8);
+                        buf.put_slice(chunk);
+                    }
+                }
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                mname.write_to(buf);
+                rname.write_to(buf);
+                for field in [serial, refresh, retry, expire, minimum] {
+                    buf.put_u32(*field);
+                }
+            }
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                buf.put_u16(*priority);
+                buf.put_u16(*weight);
+                buf.put_u16(*port);
+                target.write_to(buf);
+            }
+            RData::Caa { flags, tag, value } => {
+                buf.put_u8(*flags);
+                buf.put_u8(tag.len() as u8);
+                buf.put_slice(tag.as_bytes());
+                buf.put_slice(value);
+            }
+            RData::Unknown(_, data) => buf.put_slice(data),
+        }
+        self.wire_len()
+    }
+
+    fn wire_len(&self) -> usize {
+        match self {
+            RData::A(_) => 4,
+            RData::Aaaa(_) => 16,
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => name.wire_len(),
+            RData::Mx { exchange, .. } => 2 + exchange.wire_len(),
+            RData::Txt(strings) => strings
+                .iter()
+                .map(|string| string.len() + string.len().div_ceil(255).max(1))
+                .sum(),
+            RData::Soa { mname, rname, .. } => mname.wire_len() + rname.wire_len() + 20,
+            RData::Srv { target, .. } => 6 + target.wire_len(),
+            RData::Caa { tag, value, .. } => 2 + tag.len() + value.len(),
+            RData::Unknown(_, data) => data.len(),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for RData {
+    fn from(ip: Ipv4Addr) -> Self {
+        RData::A(ip)
+    }
+}
+
+impl From<Ipv6Addr> for RData {
+    fn from(ip: Ipv6Addr) -> Self {
+        RData::Aaaa(ip)
+    }
+}
+
+impl From<IpAddr> for RData {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => ip.into(),
+            IpAddr::V6(ip) => ip.into(),
+        }
+    }
+}
+
+impl TryFrom<&RData> for IpAddr {
+    type Error = DnsError;
+
+    fn try_from(rdata: &RData) -> Result<Self, Self::Error> {
+        match rdata {
+            RData::A(ip) => Ok(IpAddr::V4(*ip)),
+            RData::Aaaa(ip) => Ok(IpAddr::V6(*ip)),
+            other => Err(DnsError::UnsupportedType(other.record_type())),
+        }
+    }
+}
+
+impl TryFrom<&DnsRecord> for IpAddr {
+    type Error = DnsError;
+
+    /// Address carried by an A or AAAA record
+    fn try_from(record: &DnsRecord) -> Result<Self, Self::Error> {
+        IpAddr::try_from(&record.rdata()?)
+    }
+}
+
+/// How long a negative answer carrying the SOA `record` may be cached: the smaller of the
+/// record's TTL and its MINIMUM field (RFC 2308 section 5). `None` for any other record.
+pub fn negative_ttl(record: &DnsRecord) -> Option<u32> {
+    match record.rdata() {
+        Ok(RData::Soa { minimum, .. }) => Some(minimum.min(record.ttl())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns::{DnsLabels, DnsMessage};
+
+    #[test]
+    fn test_expand_compressed_rdata() {
+        // answer to example.com MX whose exchange is mail + a pointer to the question name
+        let mut msg = DnsMessage::query(1, "example.com", rtype::MX).to_bytes();
+        msg[7] = 1;
+        msg.extend_from_slice(&DnsLabels::from("example.com").to_bytes());
+        msg.extend_from_slice(&[0, 15, 0, 1, 0, 0, 0, 60, 0, 9]);
+        msg.extend_from_slice(&[0, 10, 4, b'm', b'a', b'i', b'l', 0xC0, 12]);
+
+        let parsed = DnsMessage::from_bytes(&msg).unwrap();
+        let mx = parsed.answers().next().unwrap();
+        let mut expected = vec![0, 10];
+        expected.extend_from_slice(&DnsLabels::from("mail.example.com").to_bytes());
+        assert_eq!(mx.data(), expected);
+
+        // RDLENGTH is recomputed from the expanded data
+        let written = parsed.to_bytes();
+        let rdlength = &written[written.len() - expected.len() - 2..][..2];
+        assert_eq!(rdlength, [0, expected.len() as u8]);
+
+        // a pointer to itself would loop
+        let at = msg.len() - 2;
+        msg[at + 1] = at as u8;
+        assert!(matches!(
+            DnsMessage::from_bytes(&msg),
+            Err(DnsError::BadPointer(_))
+        ));
+    }
+
+    #[test]
+    fn test_ip_round_trip() {
+        let v4 = DnsRecord::with_rdata("a.example".into(), 60, Ipv4Addr::new(10, 0, 0, 1));
+        let v6 = DnsRecord::with_rdata("b.example".into(), 60, Ipv6Addr::LOCALHOST);
+
+        assert_eq!(v4.record_type(), rtype::A);
+        assert_eq!(v4.data(), [10, 0, 0, 1]);
+        assert_eq!(IpAddr::try_from(&v4).unwrap(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(IpAddr::try_from(&v6).unwrap(), Ipv6Addr::LOCALHOST);
+    }
+
+    #[test]
+    fn test_typed_round_trip() {
+        let rdatas = [
+            RData::Cname("www.example.com".into()),
+            RData::Ns("ns1.example.com".into()),
+            RData::Ptr("host.example.com".into()),
+            RData::Mx {
+                preference: 10,
+                exchange: "mail.example.com".into(),
+            },
+            RData::Txt(vec![b"v=spf1 -all".to_vec(), Vec::new()]),
+            RData::Soa {
+                mname: "ns1.example.com".into(),
+                rname: "hostmaster.example.com".into(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+            RData::Caa {
+                flags: 0,
+                tag: "issue".to_string(),
+                value: b"letsencrypt.org".to_vec(),
+            },
+        ];
+        for rdata in rdatas {
+            let bytes = rdata.to_bytes();
+            assert_eq!(bytes.len(), rdata.wire_len());
+            assert_eq!(
+                RData::from_wire(rdata.record_type(), &bytes).unwrap(),
+                rdata
+            );
+        }
+
+        // long strings are split into 255 byte character-strings
+        let long = RData::Txt(vec![vec![b'x'; 300]]);
+        let decoded = RData::from_wire(rtype::TXT, &long.to_bytes()).unwrap();
+        assert_eq!(decoded, RData::Txt(vec![vec![b'x'; 255], vec![b'x'; 45]]));
+
+        assert!(RData::from_wire(rtype::CNAME, &[3, b'w', b'w', b'w', 0, 0]).is_err());
+        assert!(RData::from_wire(rtype::SOA, &[0, 0, 1, 2]).is_err());
+        assert!(RData::from_wire(rtype::CAA, &[0, 0]).is_err());
+        assert!(RData::from_wire(rtype::CAA, &[0, 5, b'i', b's']).is_err());
+    }
+
+    #[test]
+    fn test_negative_ttl() {
+        let soa = |ttl, minimum| {
+            let rdata = RData::Soa {
+                mname: "ns1.example.com".into(),
+                rname: "hostmaster.example.com".into(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum,
+            };
+            DnsRecord::with_rdata("example.com".into(), ttl, rdata)
+        };
+        assert_eq!(negative_ttl(&soa(3600, 300)), Some(300));
+        assert_eq!(negative_ttl(&soa(60, 300)), Some(60));
+        let a = DnsRecord::with_rdata("example.com".into(), 60, Ipv4Addr::LOCALHOST);
+        assert_eq!(negative_ttl(&a), None);
+    }
+
+    #[test]
+    fn test_srv() {
+        let srv = RData::Srv {
+            priority: 10,
+            weight: 5,
+            port: 5060,
+            target: "sip.example.com".into(),
+        };
+        let decoded = RData::from_wire(rtype::SRV, &srv.to_bytes()).unwrap();
+
+        assert_eq!(decoded, srv);
+        assert!(IpAddr::try_from(&decoded).is_err());
+        assert_eq!(
+            decoded.srv_socket_addr(Ipv4Addr::LOCALHOST.into()),
+            Some(SocketAddr::from(([127, 0, 0, 1], 5060)))
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Records configured locally, of any type
+//!
+//! [`Records`] keeps resource records by owner name, whatever their type: addresses, SRV
+//! entries for service discovery, TXT, MX, CAA, ... [`RecordsLayer`] answers questions about
+//! the names it holds authoritatively and passes every other name down the pipeline. SRV
+//! answers carry the addresses the store knows for their targets in the additional section
+//! (RFC 2782), so clients reach the service without a second lookup.
+//!
+//! Reverse mappings are PTR records at the `in-addr.arpa`/`ip6.arpa` name of an address, see
+//! [`Records::insert_ptr`]. With [`RecordsLayer::reverse_zone`] the layer also answers NXDOMAIN
+//! for the other addresses of a local network instead of asking upstreams about them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::dns::{rcode, rtype, DnsLabels, DnsMessage, DnsRecord, MessageBuilder};
+use crate::handler::RequestCtx;
+use crate::hosts::reverse_name;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::rdata::RData;
+
+/// Records by owner name
+#[derive(Debug, Default, Clone)]
+pub struct Records {
+    by_name: HashMap<DnsLabels, Vec<DnsRecord>>,
+}
+
+impl Records {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `record`, unless the same record is already there
+    pub fn insert(&mut self, record: DnsRecord) {
+        let records = self.by_name.entry(record.name().clone()).or_default();
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+
+    /// Adds the reverse mapping of `addr` to `name`, a PTR record at the reverse name of `addr`
+    pub fn insert_ptr(&mut self, addr: IpAddr, name: DnsLabels, ttl: u32) {
+        self.insert(DnsRecord::with_rdata(
+            reverse_name(addr),
+            ttl,
+            RData::Ptr(name),
+        ));
+    }
+
+    /// Every record owned by `name`, `None` if the name is not in the store
+    pub fn get(&self, name: &DnsLabels) -> Option<&[DnsRecord]> {
+        self.by_name.get(name).map(Vec::as_slice)
+    }
+
+    /// Records of type `record_type` and class `class` owned by `name`
+    pub fn lookup<'a>(
+        &'a self,
+        name: &DnsLabels,
+        record_type: u16,
+        class: u16,
+    ) -> impl Iterator<Item = &'a DnsRecord> {
+        self.get(name)
+            .unwrap_or_default()
+            .iter()
+            .filter(move |record| record.record_type() == record_type && record.class() == class)
+    }
+
+    /// Every record in the store, name by name
+    pub fn iter(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.by_name.values().flatten()
+    }
+
+    /// Number of names in the store
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+impl Extend<DnsRecord> for Records {
+    fn extend<I: IntoIterator<Item = DnsRecord>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|record| self.insert(record));
+    }
+}
+
+impl FromIterator<DnsRecord> for Records {
+    fn from_iter<I: IntoIterator<Item = DnsRecord>>(iter: I) -> Self {
+        let mut records = Records::new();
+        records.extend(iter);
+        records
+    }
+}
+
+/// Answers questions about the names in a [`Records`] store
+#[derive(Debug, Clone)]
+pub struct RecordsLayer {
+    records: Records,
+    reverse_zones: Vec<DnsLabels>,
+}
+
+impl RecordsLayer {
+    pub fn new(records: Records) -> Self {
+        Self {
+            records,
+            reverse_zones: Vec::new(),
+        }
+    }
+
+    /// Answers NXDOMAIN for the names under `zone` (e.g. `168.192.in-addr.arpa`) that have no
+    /// PTR record in the store, so lookups of local addresses do not leak to upstreams
+    pub fn reverse_zone(mut self, zone: DnsLabels) -> Self {
+        self.reverse_zones.push(zone);
+        self
+    }
+
+    pub fn records(&self) -> &Records {
+        &self.records
+    }
+
+    /// Answer to `query`, if it is a single question about a name in the store
+    fn answer(&self, query: &DnsMessage) -> Option<DnsMessage> {
+        let mut questions = query.questions();
+        let question = questions.next()?;
+        if questions.next().is_some() {
+            return None;
+        }
+        if self.records.get(question.qname()).is_none() {
+            let name = question.qname();
+            if !self
+                .reverse_zones
+                .iter()
+                .any(|zone| name.is_subdomain_of(zone))
+            {
+                return None;
+            }
+            let response = MessageBuilder::response_to(query)
+                .authoritative(true)
+                .add_question(question.clone())
+                .rcode(rcode::NXDOMAIN);
+            return Some(response.build());
+        }
+
+        let (name, class) = (question.qname(), question.qclass());
+        let mut answers: Vec<&DnsRecord> =
+            self.records.lookup(name, question.qtype(), class).collect();
+        if answers.is_empty() {
+            // an alias stands for every type; CnameLayer follows it
+            answers = self.records.lookup(name, rtype::CNAME, class).collect();
+        }
+        let additionals: Vec<&DnsRecord> = answers
+            .iter()
+            .filter_map(|record| match record.rdata() {
+                Ok(RData::Srv { target, .. }) => Some(target),
+                _ => None,
+            })
+            .flat_map(|target| {
+                let v4: Vec<&DnsRecord> = self.records.lookup(&target, rtype::A, class).collect();
+                v4.into_iter()
+                    .chain(self.records.lookup(&target, rtype::AAAA, class))
+            })
+            .collect();
+
+        // a name in the store without records of the asked type is NODATA, not forwarded
+        let response = MessageBuilder::response_to(query)
+            .authoritative(true)
+            .add_question(question.clone());
+        let response = answers
+            .into_iter()
+            .cloned()
+            .fold(response, MessageBuilder::add_answer);
+        let response = additionals
+            .into_iter()
+            .cloned()
+            .fold(response, MessageBuilder::add_additional);
+        Some(response.build())
+    }
+}
+
+impl Layer for RecordsLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            match self.answer(&query) {
+                Some(response) => response,
+                None => next.run(query, ctx).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    #[tokio::test]
+    async fn test_srv() {
+        let srv = RData::Srv {
+            priority: 10,
+            weight: 5,
+            port: 8080,
+            target: "web1.lab.internal".into(),
+        };
+        let records: Records = [
+            DnsRecord::with_rdata("_http._tcp.lab.internal".into(), 300, srv.clone()),
+            DnsRecord::with_rdata("web1.lab.internal".into(), 300, Ipv4Addr::new(10, 0, 0, 5)),
+        ]
+        .into_iter()
+        .collect();
+        let pipeline = Pipeline::new(DefaultHandler).layer(RecordsLayer::new(records));
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+
+        let query = DnsMessage::query(1, "_HTTP._tcp.lab.internal", rtype::SRV);
+        let resp = pipeline.handle(query, ctx.clone()).await;
+        assert!(resp.header().authoritative());
+        let answers: Vec<RData> = resp.answers().map(|r| r.rdata().unwrap()).collect();
+        assert_eq!(answers, [srv]);
+        let glue: Vec<RData> = resp.additionals().map(|r| r.rdata().unwrap()).collect();
+        assert_eq!(glue, [RData::A(Ipv4Addr::new(10, 0, 0, 5))]);
+
+        // the name exists, without AAAA records
+        let query = DnsMessage::query(2, "web1.lab.internal", rtype::AAAA);
+        let resp = pipeline.handle(query, ctx.clone()).await;
+        assert_eq!((resp.rcode(), resp.answers().len()), (rcode::NOERROR, 0));
+        assert!(resp.header().authoritative());
+
+        // other names are answered by the rest of the pipeline
+        let query = DnsMessage::query(3, "example.com", rtype::A);
+        let resp = pipeline.handle(query, ctx).await;
+        assert!(!resp.header().authoritative());
+        assert_eq!(resp.answers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reverse_zone() {
+        let mut records = Records::new();
+        let addr = IpAddr::from([192, 168, 1, 10]);
+        records.insert_ptr(addr, "nas.lab.internal".into(), 300);
+        let layer = RecordsLayer::new(records).reverse_zone("168.192.in-addr.arpa".into());
+        let pipeline = Pipeline::new(DefaultHandler).layer(layer);
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+        let ask = |name: DnsLabels| {
+            let query = DnsMessage::query(1, &name.to_string(), rtype::PTR);
+            pipeline.handle(query, ctx.clone())
+        };
+
+        let resp = ask(reverse_name(addr)).await;
+        let answer = resp.answers().next().unwrap();
+        assert_eq!(answer.name().to_string(), "10.1.168.192.in-addr.arpa");
+        assert_eq!(
+            answer.rdata().unwrap(),
+            RData::Ptr("nas.lab.internal".into())
+        );
+
+        let resp = ask(reverse_name(IpAddr::from([192, 168, 1, 11]))).await;
+        assert_eq!(resp.rcode(), rcode::NXDOMAIN);
+        assert!(resp.header().authoritative());
+
+        let resp = ask(reverse_name(IpAddr::from([198, 51, 100, 1]))).await;
+        assert!(!resp.header().authoritative());
+    }
+}
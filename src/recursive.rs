@@ -0,0 +1,445 @@
+//! Iterative resolution from the root servers, for running without an upstream resolver
+//!
+//! [`Recursor`] asks a root server, follows the NS referrals down the tree (using glue
+//! addresses when the referral carries them and resolving the name server names otherwise),
+//! restarts from the root for every CNAME whose target the answer does not include, and
+//! assembles the CNAME chain and final records into one answer.
+//!
+//! Loops are cut off three ways: every referral must be for a zone closer to the name than the
+//! last, at most [`MAX_REFERRALS`] referrals and [`MAX_CNAMES`] CNAMEs are followed, and name
+//! server names are resolved at most [`MAX_DEPTH`] lookups deep.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use crate::debug;
+use crate::dns::{
+    class, dns_labels, error_response, opcode, rcode, rtype, DnsLabels, DnsMessage, DnsQuestion,
+    DnsRecord, MessageBuilder,
+};
+use crate::dnstap::{Dnstap, MessageType};
+use crate::error::DnsError;
+use crate::forward::exchange;
+use crate::handler::{RequestCtx, RequestHandler, Transport};
+use crate::pipeline::BoxFuture;
+use crate::warn;
+
+/// Addresses of the 13 root servers, a to m.root-servers.net
+pub const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Referrals followed for one name before giving up
+pub const MAX_REFERRALS: usize = 16;
+/// CNAMEs followed for one question before giving up
+pub const MAX_CNAMES: usize = 8;
+/// Nesting of lookups for the addresses of name servers without glue
+pub const MAX_DEPTH: usize = 4;
+
+/// Outcome of resolving one question
+struct Answer {
+    rcode: u8,
+    records: Vec<DnsRecord>,
+    /// SOA of the zone saying the name or type is missing, for caching the negative answer
+    authorities: Vec<DnsRecord>,
+}
+
+/// Handler resolving every query itself, starting from the root servers
+#[derive(Debug, Clone)]
+pub struct Recursor {
+    roots: Vec<IpAddr>,
+    port: u16,
+    timeout: Duration,
+    dnstap: Option<Dnstap>,
+}
+
+impl Default for Recursor {
+    fn default() -> Self {
+        Self {
+            roots: ROOT_HINTS.iter().map(|&ip| ip.into()).collect(),
+            port: 53,
+            timeout: Duration::from_millis(1500),
+            dnstap: None,
+        }
+    }
+}
+
+impl Recursor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from these servers instead of [`ROOT_HINTS`]
+    pub fn roots(mut self, roots: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.roots = roots.into_iter().collect();
+        self
+    }
+
+    /// Port every name server is asked on, 53 unless testing
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Time each name server gets to answer before the next one is tried, 1.5s by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Records the queries sent to name servers and their responses in `dnstap`
+    pub fn dnstap(mut self, dnstap: Dnstap) -> Self {
+        self.dnstap = Some(dnstap);
+        self
+    }
+
+    /// Asks `servers` in turn until one answers
+    async fn ask(
+        &self,
+        servers: &[IpAddr],
+        question: &DnsQuestion,
+    ) -> Result<DnsMessage, DnsError> {
+        let query = MessageBuilder::new()
+            .id(rand::random())
+            .add_question(question.clone())
+            .build();
+        let mut last_err = DnsError::Timeout;
+        for &server in servers {
+            let upstream = SocketAddr::new(server, self.port);
+            let sent = SystemTime::now();
+            if let Some(dnstap) = &self.dnstap {
+                let kind = MessageType::ResolverQuery;
+                dnstap.record(kind, upstream, Transport::Udp, sent, None, &query);
+            }
+            match exchange(upstream, &query, self.timeout).await {
+                Ok(response) => {
+                    if let Some(dnstap) = &self.dnstap {
+                        let (kind, now) = (MessageType::ResolverResponse, SystemTime::now());
+                        dnstap.record(kind, upstream, Transport::Udp, sent, Some(now), &response);
+                    }
+                    return Ok(response);
+                }
+                Err(err) => {
+                    debug!("{upstream} failed to answer with {err}");
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Addresses of the name servers in a referral to `zone`, from the glue or else looked up
+    fn name_servers<'a>(
+        &'a self,
+        zone: &'a DnsLabels,
+        authority: &'a [DnsRecord],
+        additional: &'a [DnsRecord],
+        depth: usize,
+    ) -> BoxFuture<'a, Vec<IpAddr>> {
+        Box::pin(async move {
+            let names: Vec<DnsLabels> = authority
+                .iter()
+                .filter(|record| record.record_type() == rtype::NS && record.name() == zone)
+                .filter_map(|record| name_in(record).ok())
+                .collect();
+            let glue: Vec<IpAddr> = additional
+                .iter()
+                .filter(|record| names.contains(record.name()))
+                .filter_map(|record| IpAddr::try_from(record).ok())
+                .collect();
+            if !glue.is_empty() || depth >= MAX_DEPTH {
+                return glue;
+            }
+            for name in names {
+                let question = DnsQuestion::new(name, rtype::A, class::IN);
+                if let Ok(answer) = self.lookup(question, depth + 1).await {
+                    let addrs: Vec<IpAddr> = answer
+                        .records
+                        .iter()
+                        .filter_map(|record| IpAddr::try_from(record).ok())
+                        .collect();
+                    if !addrs.is_empty() {
+                        return addrs;
+                    }
+                }
+            }
+            Vec::new()
+        })
+    }
+
+    fn lookup(
+        &self,
+        question: DnsQuestion,
+        depth: usize,
+    ) -> BoxFuture<'_, Result<Answer, DnsError>> {
+        Box::pin(async move {
+            let (qtype, qclass) = (question.qtype(), question.qclass());
+            let mut records = Vec::new();
+            let mut name = question.qname().clone();
+            for _ in 0..=MAX_CNAMES {
+                let question = DnsQuestion::new(name.clone(), qtype, qclass);
+                let mut servers = self.roots.clone();
+                let mut zone_depth = 0;
+                let mut referrals = 0;
+                let target = loop {
+                    let response = self.ask(&servers, &question).await?;
+                    let soa = || {
+                        response
+                            .authorities()
+                            .filter(|record| record.record_type() == rtype::SOA)
+                            .cloned()
+                            .collect()
+                    };
+                    if response.rcode() != rcode::NOERROR {
+                        return Ok(Answer {
+                            rcode: response.rcode(),
+                            records,
+                            authorities: soa(),
+                        });
+                    }
+
+                    // the answer, possibly behind a chain of CNAMEs
+                    let mut current = name.clone();
+                    for _ in 0..=MAX_CNAMES {
+                        let matching = response.answers().filter(|record| {
+                            record.name() == &current && record.record_type() == qtype
+                        });
+                        let before = records.len();
+                        records.extend(matching.cloned());
+                        if records.len() > before {
+                            return Ok(Answer {
+                                rcode: rcode::NOERROR,
+                                records,
+                                authorities: Vec::new(),
+                            });
+                        }
+                        let cname = response.answers().find(|record| {
+                            record.name() == &current && record.record_type() == rtype::CNAME
+                        });
+                        let Some(cname) = cname else {
+                            break;
+                        };
+                        records.push(cname.clone());
+                        current = name_in(cname)?;
+                    }
+                    if current != name {
+                        break current;
+                    }
+
+                    // otherwise a referral closer to the name, or no data
+                    let authority = response.authorities().as_slice();
+                    let additional = response.additionals().as_slice();
+                    let zone = authority
+                        .iter()
+                        .filter(|record| record.record_type() == rtype::NS)
+                        .map(DnsRecord::name)
+                        .find(|zone| name.is_subdomain_of(zone) && zone.label_count() > zone_depth);
+                    let Some(zone) = zone.filter(|_| referrals < MAX_REFERRALS) else {
+                        return Ok(Answer {
+                            rcode: rcode::NOERROR,
+                            records,
+                            authorities: soa(),
+                        });
+                    };
+                    servers = self.name_servers(zone, authority, additional, depth).await;
+                    if servers.is_empty() {
+                        return Err(DnsError::Malformed(format!(
+                            "no reachable name server for {zone}"
+                        )));
+                    }
+                    zone_depth = zone.label_count();
+                    referrals += 1;
+                };
+                name = target;
+            }
+            Err(DnsError::Malformed(format!(
+                "more than {MAX_CNAMES} CNAMEs from {}",
+                question.qname()
+            )))
+        })
+    }
+}
+
+/// Name held in the data of an NS or CNAME record
+fn name_in(record: &DnsRecord) -> Result<DnsLabels, DnsError> {
+    let (_, name) = dns_labels(record.data(), record.data())?;
+    Ok(name.to_owned())
+}
+
+impl RequestHandler for Recursor {
+    async fn handle(&self, query: DnsMessage, _ctx: RequestCtx) -> DnsMessage {
+        if query.header().opcode() != opcode::QUERY {
+            return error_response(&query, rcode::NOTIMP);
+        }
+        let mut response = MessageBuilder::response_to(&query).recursion_available(true);
+        let mut response_rcode = rcode::NOERROR;
+        for question in query.questions() {
+            let answer = match self.lookup(question.clone(), 0).await {
+                Ok(answer) => answer,
+                Err(err) => {
+                    warn!("failed to resolve {} with {err}", question.qname());
+                    return error_response(&query, rcode::SERVFAIL);
+                }
+            };
+            response = answer.records.into_iter().fold(
+                response.add_question(question.clone()),
+                |response, record| response.add_answer(record),
+            );
+            response = answer
+                .authorities
+                .into_iter()
+                .fold(response, |response, record| response.add_authority(record));
+            if response_rcode == rcode::NOERROR {
+                response_rcode = answer.rcode;
+            }
+        }
+        response.rcode(response_rcode).build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::dns::{ToBytes, MAX_UDP_PAYLOAD};
+    use crate::handler::Transport;
+    use crate::rdata::RData;
+
+    /// Response to `query` with the given sections
+    fn reply(
+        query: &DnsMessage,
+        answers: &[DnsRecord],
+        authority: &[DnsRecord],
+        additional: &[DnsRecord],
+    ) -> Vec<u8> {
+        let question = query.questions().next().unwrap().clone();
+        let builder = MessageBuilder::response_to(query).add_question(question);
+        let builder = answers.iter().fold(builder, |builder, record| {
+            builder.add_answer(record.clone())
+        });
+        let builder = authority.iter().fold(builder, |builder, record| {
+            builder.add_authority(record.clone())
+        });
+        additional
+            .iter()
+            .fold(builder, |builder, record| {
+                builder.add_additional(record.clone())
+            })
+            .build()
+            .to_bytes()
+    }
+
+    fn ns(zone: &str, server: &str) -> DnsRecord {
+        let data = DnsLabels::from(server).to_bytes();
+        DnsRecord::new(zone.into(), rtype::NS, class::IN, 3600, data)
+    }
+
+    fn cname(name: &str, target: &str) -> DnsRecord {
+        let data = DnsLabels::from(target).to_bytes();
+        DnsRecord::new(name.into(), rtype::CNAME, class::IN, 300, data)
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> DnsRecord {
+        DnsRecord::with_rdata(name.into(), 300, Ipv4Addr::from(ip))
+    }
+
+    fn soa(zone: &str) -> DnsRecord {
+        let rdata = RData::Soa {
+            mname: "ns.com".into(),
+            rname: "hostmaster.com".into(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        };
+        DnsRecord::with_rdata(zone.into(), 3600, rdata)
+    }
+
+    /// Serves `answer` on `addr` until the test ends
+    async fn serve(addr: SocketAddr, answer: fn(&DnsMessage, &str) -> Vec<u8>) {
+        let sock = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+            loop {
+                let (len, from) = sock.recv_from(&mut buf).await.unwrap();
+                let query = DnsMessage::from_bytes(&buf[..len]).unwrap();
+                let name = query.questions().next().unwrap().qname().to_string();
+                sock.send_to(&answer(&query, &name), from).await.unwrap();
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_resolve_from_root() {
+        let root = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = root.local_addr().unwrap().port();
+        drop(root);
+
+        // root: refers .com to ns.com (glue 127.0.0.2) and .org to glueless ns.example.com
+        serve(SocketAddr::from(([127, 0, 0, 1], port)), |query, name| {
+            if name.ends_with(".com") {
+                let glue = a("ns.com", [127, 0, 0, 2]);
+                reply(query, &[], &[ns("com", "ns.com")], &[glue])
+            } else {
+                reply(query, &[], &[ns("org", "ns.example.com")], &[])
+            }
+        })
+        .await;
+        // com and org: www.example.com is a CNAME to example.org, answered elsewhere
+        serve(
+            SocketAddr::from(([127, 0, 0, 2], port)),
+            |query, name| match name {
+                "www.example.com" => reply(query, &[cname(name, "example.org")], &[], &[]),
+                "ns.example.com" => reply(query, &[a(name, [127, 0, 0, 2])], &[], &[]),
+                "example.org" => reply(query, &[a(name, [192, 0, 2, 1])], &[], &[]),
+                "empty.com" => reply(query, &[], &[soa("com")], &[]),
+                _ => error_response(query, rcode::NXDOMAIN).to_bytes(),
+            },
+        )
+        .await;
+
+        let recursor = Recursor::new()
+            .roots([IpAddr::from([127, 0, 0, 1])])
+            .port(port)
+            .timeout(Duration::from_millis(500));
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+        let query = DnsMessage::query(7, "www.example.com", rtype::A);
+        let resp = recursor.handle(query, ctx.clone()).await;
+        assert_eq!(resp.rcode(), rcode::NOERROR);
+        assert!(resp.header().recursion_available());
+        let chain: Vec<(String, u16)> = resp
+            .answers()
+            .map(|record| (record.name().to_string(), record.record_type()))
+            .collect();
+        assert_eq!(
+            chain,
+            [
+                ("www.example.com".to_string(), rtype::CNAME),
+                ("example.org".to_string(), rtype::A)
+            ]
+        );
+
+        // NODATA: the SOA is no referral, and is passed on for negative caching
+        let query = DnsMessage::query(9, "empty.com", rtype::A);
+        let resp = recursor.handle(query, ctx.clone()).await;
+        assert_eq!((resp.rcode(), resp.answers().len()), (rcode::NOERROR, 0));
+        assert_eq!(resp.authorities().next().unwrap().record_type(), rtype::SOA);
+
+        let query = DnsMessage::query(8, "missing.com", rtype::A);
+        assert_eq!(recursor.handle(query, ctx).await.rcode(), rcode::NXDOMAIN);
+    }
+}
@@ -0,0 +1,334 @@
+//! Offline replay of captured traffic, for reproducing bug reports
+//!
+//! A [`Replay`] takes the client queries to one server out of a capture (see [`crate::pcap`]),
+//! feeds them one at a time through a handler and reports every response that differs from
+//! the captured one. Queries carry their capture time as [`RequestCtx::received`], so
+//! time-based policies decide as they did live, and the server's own exchanges with its
+//! upstreams can be played back by a [`MockUpstream`].
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::dns::{DnsMessage, ToBytes, MAX_UDP_PAYLOAD};
+use crate::handler::{RequestCtx, RequestHandler, Transport};
+use crate::pcap::Datagram;
+use crate::warn;
+
+/// Client query to the server and the response captured for it
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    time: Duration,
+    client: SocketAddr,
+    query: DnsMessage,
+    captured: Option<DnsMessage>,
+}
+
+impl Exchange {
+    /// Capture time of the query, since the Unix epoch
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    pub fn client(&self) -> SocketAddr {
+        self.client
+    }
+
+    pub fn query(&self) -> &DnsMessage {
+        &self.query
+    }
+
+    /// `None` if the capture holds no response, e.g. because the server dropped the query
+    pub fn captured(&self) -> Option<&DnsMessage> {
+        self.captured.as_ref()
+    }
+}
+
+/// Response produced by the replay that differs from the captured one
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    exchange: Exchange,
+    produced: DnsMessage,
+}
+
+impl Mismatch {
+    pub fn exchange(&self) -> &Exchange {
+        &self.exchange
+    }
+
+    pub fn produced(&self) -> &DnsMessage {
+        &self.produced
+    }
+}
+
+/// One line per header, question and record, so that messages can be diffed line by line
+fn render(msg: &DnsMessage) -> Vec<String> {
+    let header = msg.header();
+    let flags = [
+        ("qr", header.is_response()),
+        ("aa", header.authoritative()),
+        ("tc", header.truncated()),
+        ("rd", header.recursion_desired()),
+        ("ra", header.recursion_available()),
+        ("ad", header.authentic_data()),
+        ("cd", header.checking_disabled()),
+    ];
+    let flags: Vec<&str> = flags
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| *name)
+        .collect();
+    let mut lines = vec![format!(
+        "opcode {} rcode {} flags [{}]",
+        header.opcode(),
+        header.rcode(),
+        flags.join(" ")
+    )];
+    for question in msg.questions() {
+        lines.push(format!(
+            "question {} class {} type {}",
+            question.qname(),
+            question.qclass(),
+            question.qtype()
+        ));
+    }
+    for (section, record) in msg.records() {
+        let data = match record.rdata() {
+            Ok(rdata) => format!("{rdata:?}"),
+            Err(_) => format!("{:02x?}", record.data()),
+        };
+        lines.push(format!(
+            "{section:?} {} class {} type {} ttl {} {data}",
+            record.name(),
+            record.class(),
+            record.record_type(),
+            record.ttl()
+        ));
+    }
+    lines
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exchange = &self.exchange;
+        write!(f, "query {} from {}", exchange.query.id(), exchange.client)?;
+        if let Some(question) = exchange.query.questions().next() {
+            write!(f, " for {} type {}", question.qname(), question.qtype())?;
+        }
+        writeln!(f, " at {:?}", exchange.time)?;
+        let produced = render(&self.produced);
+        let Some(captured) = &exchange.captured else {
+            writeln!(f, "  (no response captured)")?;
+            return produced.iter().try_for_each(|line| writeln!(f, "+ {line}"));
+        };
+        let captured = render(captured);
+        for line in captured.iter().filter(|line| !produced.contains(line)) {
+            writeln!(f, "- {line}")?;
+        }
+        for line in produced.iter().filter(|line| !captured.contains(line)) {
+            writeln!(f, "+ {line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Captured traffic of a server, ready to be replayed
+#[derive(Debug, Clone, Default)]
+pub struct Replay {
+    exchanges: Vec<Exchange>,
+    upstream: Vec<DnsMessage>,
+}
+
+impl Replay {
+    /// Takes the traffic of the server listening on `server` out of `datagrams`: the queries
+    /// sent to it with the responses it sent back, and the responses it got from upstreams
+    pub fn new(datagrams: &[Datagram], server: SocketAddr) -> Self {
+        let mut replay = Self::default();
+        for datagram in datagrams {
+            let Ok(msg) = DnsMessage::from_bytes(datagram.payload()) else {
+                continue;
+            };
+            if datagram.dst() == server && !msg.is_response() {
+                replay.exchanges.push(Exchange {
+                    time: datagram.time(),
+                    client: datagram.src(),
+                    query: msg,
+                    captured: None,
+                });
+            } else if datagram.src() == server && msg.is_response() {
+                // the first unanswered query with the same client and id
+                let exchange = replay.exchanges.iter_mut().find(|exchange| {
+                    exchange.client == datagram.dst()
+                        && exchange.query.id() == msg.id()
+                        && exchange.captured.is_none()
+                });
+                if let Some(exchange) = exchange {
+                    exchange.captured = Some(msg);
+                }
+            } else if datagram.dst().ip() == server.ip() && msg.is_response() {
+                replay.upstream.push(msg);
+            }
+        }
+        replay
+    }
+
+    pub fn exchanges(&self) -> &[Exchange] {
+        &self.exchanges
+    }
+
+    /// Feeds every query through `handler` in capture order, returning the responses that
+    /// differ from the captured ones
+    pub async fn run<H: RequestHandler>(&self, handler: &H) -> Vec<Mismatch> {
+        let start = Instant::now();
+        let first = self
+            .exchanges
+            .first()
+            .map(Exchange::time)
+            .unwrap_or_default();
+        let mut mismatches = Vec::new();
+        for exchange in &self.exchanges {
+            let received = start + exchange.time.saturating_sub(first);
+            let ctx = RequestCtx::new(exchange.client, Transport::Udp).received_at(received);
+            let produced = handler.handle(exchange.query.clone(), ctx).await;
+            if exchange.captured.as_ref() != Some(&produced) {
+                mismatches.push(Mismatch {
+                    exchange: exchange.clone(),
+                    produced,
+                });
+            }
+        }
+        mismatches
+    }
+
+    /// Starts a [`MockUpstream`] answering with the upstream responses of the capture. Must be
+    /// called inside a tokio runtime.
+    pub async fn mock_upstream(&self) -> io::Result<MockUpstream> {
+        let sock = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = sock.local_addr()?;
+        let responses = self.upstream.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0; MAX_UDP_PAYLOAD];
+            loop {
+                let (len, peer) = match sock.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(err) => {
+                        warn!("mock upstream failed to receive with {err}");
+                        continue;
+                    }
+                };
+                let Ok(query) = DnsMessage::from_bytes(&buf[..len]) else {
+                    continue;
+                };
+                let response = responses
+                    .iter()
+                    .find(|response| response.questions().eq(query.questions()));
+                let Some(response) = response else {
+                    warn!("no captured upstream response for query {query:?}");
+                    continue;
+                };
+                let response = response.clone().retarget(&query);
+                if let Err(err) = sock.send_to(&response.to_bytes(), peer).await {
+                    warn!("mock upstream failed to send with {err}");
+                }
+            }
+        });
+        Ok(MockUpstream { addr, task })
+    }
+}
+
+/// Upstream resolver answering from a capture, stopped when dropped. Questions the capture
+/// holds no response for go unanswered, as if the upstream timed out.
+pub struct MockUpstream {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    /// Address to forward to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns::{rcode, rtype, MessageBuilder};
+    use crate::forward;
+    use crate::handler::DefaultHandler;
+
+    fn datagram(secs: u64, src: SocketAddr, dst: SocketAddr, msg: &DnsMessage) -> Datagram {
+        Datagram::new(Duration::from_secs(secs), src, dst, msg.to_bytes())
+    }
+
+    #[tokio::test]
+    async fn test_replay_diff() {
+        let server = SocketAddr::from(([192, 0, 2, 53], 53));
+        let client = SocketAddr::from(([192, 0, 2, 1], 40000));
+        let same = DnsMessage::query(1, "codecrafters.io", rtype::A);
+        let changed = DnsMessage::query(2, "example.com", rtype::A);
+        let unanswered = DnsMessage::query(3, "example.org", rtype::A);
+        let stale = MessageBuilder::response_to(&changed)
+            .rcode(rcode::NXDOMAIN)
+            .build();
+        let fresh = DefaultHandler
+            .handle(same.clone(), RequestCtx::new(client, Transport::Udp))
+            .await;
+        let datagrams = [
+            datagram(1, client, server, &same),
+            datagram(1, server, client, &fresh),
+            datagram(2, client, server, &changed),
+            datagram(2, server, client, &stale),
+            datagram(3, client, server, &unanswered),
+        ];
+
+        let replay = Replay::new(&datagrams, server);
+        assert_eq!(replay.exchanges().len(), 3);
+        let mismatches = replay.run(&DefaultHandler).await;
+        let ids: Vec<u16> = mismatches
+            .iter()
+            .map(|m| m.exchange().query().id())
+            .collect();
+        assert_eq!(ids, [2, 3]);
+        let diff = mismatches[0].to_string();
+        assert!(diff.contains("- opcode 0 rcode 3"), "{diff}");
+        assert!(diff.contains("+ opcode 0 rcode 0"), "{diff}");
+        assert!(diff.contains("+ Answer example.com"), "{diff}");
+    }
+
+    #[tokio::test]
+    async fn test_mock_upstream() {
+        let server = SocketAddr::from(([192, 0, 2, 53], 53));
+        let upstream = SocketAddr::from(([198, 51, 100, 1], 53));
+        let outgoing = SocketAddr::from(([192, 0, 2, 53], 33000));
+        let query = DnsMessage::query(7, "example.com", rtype::A);
+        let answer = DefaultHandler
+            .handle(query.clone(), RequestCtx::new(outgoing, Transport::Udp))
+            .await;
+        let datagrams = [
+            datagram(1, outgoing, upstream, &query),
+            datagram(1, upstream, outgoing, &answer),
+        ];
+
+        let mock = Replay::new(&datagrams, server)
+            .mock_upstream()
+            .await
+            .unwrap();
+        let asked = DnsMessage::query(99, "example.com", rtype::A);
+        let response = forward::exchange(mock.addr(), &asked, Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(response.id(), 99);
+        assert_eq!(response.answers().len(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+//! Cache of fully serialized responses keyed by the raw question
+//!
+//! A hit copies the stored bytes and patches the 2 byte id, skipping parsing, the handler and
+//! record encoding. Only single-question queries are cached, with no additional record or an
+//! OPT record without options, keyed on the exact question bytes (so clients randomizing the
+//! case of names get their own spelling back), the query flags that change the answer and the
+//! EDNS version, DO bit and payload size. TTLs inside a stored response are not decremented, so
+//! entries live for at most `max_age`.
+//!
+//! The handler is bypassed on hits: only enable this in front of handlers whose answers do not
+//! depend on the client.
+//!
+//! Every query task reads the cache, so entries are spread over [`SHARDS`] independently locked
+//! maps picked by the hash of the key. Lookups take a shared lock and never wait for each other;
+//! a store only blocks lookups of the one shard it writes to, for the duration of a map insert.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::dns::{DnsMessage, MAX_UDP_PAYLOAD};
+
+struct Entry {
+    bytes: Vec<u8>,
+    expires: Instant,
+}
+
+/// Number of independently locked maps the entries are spread over
+pub const SHARDS: usize = 16;
+
+pub struct ResponseCache {
+    shards: Vec<RwLock<HashMap<Vec<u8>, Entry>>>,
+    hasher: RandomState,
+    shard_capacity: usize,
+    max_age: Duration,
+}
+
+impl ResponseCache {
+    /// Cache of at most `capacity` responses, each kept for at most `max_age`
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+            shard_capacity: capacity.div_ceil(SHARDS),
+            max_age,
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<HashMap<Vec<u8>, Entry>> {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % SHARDS]
+    }
+
+    /// Writes the cached response to `query` into `out`, returning whether there was one
+    pub fn lookup(&self, query: &[u8], out: &mut Vec<u8>) -> bool {
+        let Some(key) = cache_key(query) else {
+            return false;
+        };
+        let entries = self.shard(&key).read().unwrap();
+        match entries.get(&key) {
+            Some(entry) if entry.expires > Instant::now() => {
+                out.clear();
+                out.extend_from_slice(&entry.bytes);
+                out[..2].copy_from_slice(&query[..2]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remembers `bytes`, the serialized `response` to `query`, if it is cacheable
+    pub fn store(&self, query: &[u8], response: &DnsMessage, bytes: &[u8]) {
+        let Some(key) = cache_key(query) else {
+            return;
+        };
+        let header = response.header();
+        if header.truncated() || !matches!(header.rcode(), 0 | 3) {
+            return;
+        }
+        let Some(ttl) = response.records().map(|(_, record)| record.ttl()).min() else {
+            return;
+        };
+        let age = self.max_age.min(Duration::from_secs(ttl.into()));
+        if age.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.shard(&key).write().unwrap();
+        if entries.len() >= self.shard_capacity {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() < self.shard_capacity {
+            let entry = Entry {
+                bytes: bytes.to_vec(),
+                expires: now + age,
+            };
+            entries.insert(key, entry);
+        }
+    }
+}
+
+/// Opcode, RD, AD and CD bits followed by the question and, with EDNS, the version, DO bit and
+/// payload size; for single-question queries only
+fn cache_key(query: &[u8]) -> Option<Vec<u8>> {
+    if query.len() < 12 || query[2] & 0x80 != 0 {
+        return None;
+    }
+    let edns = match query[4..12] {
+        [0, 1, 0, 0, 0, 0, 0, 0] => false,
+        [0, 1, 0, 0, 0, 0, 0, 1] => true,
+        _ => return None,
+    };
+
+    let mut at = 12;
+    loop {
+        let len = *query.get(at)? as usize;
+        if len > 63 {
+            return None;
+        }
+        at += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let question = query.get(12..at + 4)?;
+
+    let mut key = Vec::with_capacity(2 + question.len() + 4);
+    key.push(query[2] & 0x79);
+    key.push(query[3] & 0x30);
+    key.extend_from_slice(question);
+    if edns {
+        // root name, OPT, payload, extended rcode, version, flags and no options
+        let [0, 0, 41, high, low, _, version, flags, _, 0, 0] = *query.get(at + 4..)? else {
+            return None;
+        };
+        let payload = u16::from_be_bytes([high, low]).min(MAX_UDP_PAYLOAD as u16);
+        key.extend_from_slice(&[version, flags & 0x80]);
+        key.extend_from_slice(&payload.to_be_bytes());
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns::{response, DnsQuestion, Edns, MessageBuilder, ToBytes};
+
+    #[test]
+    fn test_hit_patches_id() {
+        let cache = ResponseCache::new(16, Duration::from_secs(30));
+        let first = DnsMessage::query(1, "codecrafters.io", 1);
+        let resp = response(&first);
+        cache.store(&first.to_bytes(), &resp, &resp.to_bytes());
+
+        let mut out = Vec::new();
+        let second = DnsMessage::query(2, "codecrafters.io", 1).to_bytes();
+        assert!(cache.lookup(&second, &mut out));
+        let cached = DnsMessage::from_bytes(&out).unwrap();
+        assert_eq!(cached.id(), 2);
+        assert_eq!(cached.answers().len(), 1);
+
+        // a different spelling of the name is a different entry
+        let upper = DnsMessage::query(3, "CODECRAFTERS.io", 1).to_bytes();
+        assert!(!cache.lookup(&upper, &mut out));
+    }
+
+    #[test]
+    fn test_edns() {
+        let cache = ResponseCache::new(16, Duration::from_secs(30));
+        let query = |id, edns: Option<Edns>| {
+            let question = DnsQuestion::new("codecrafters.io".into(), 1, 1);
+            let query = MessageBuilder::new().id(id).add_question(question);
+            match edns {
+                Some(edns) => query.edns(edns).build(),
+                None => query.build(),
+            }
+        };
+        let first = query(1, Some(Edns::new(1232)));
+        let resp = response(&first);
+        cache.store(&first.to_bytes(), &resp, &resp.to_bytes());
+
+        let mut out = Vec::new();
+        let hit = |query: DnsMessage, out: &mut Vec<u8>| cache.lookup(&query.to_bytes(), out);
+        assert!(hit(query(2, Some(Edns::new(1232))), &mut out));
+        assert!(DnsMessage::from_bytes(&out).unwrap().edns().is_some());
+
+        // the answer may differ without EDNS, with DO or for another payload size
+        assert!(!hit(query(3, None), &mut out));
+        assert!(!hit(
+            query(4, Some(Edns::new(1232).with_dnssec_ok(true))),
+            &mut out
+        ));
+        assert!(!hit(query(5, Some(Edns::new(4096))), &mut out));
+
+        // options such as cookies and client subnets are not cached
+        let cookie = Edns::new(1232).with_option(10, vec![0; 8]);
+        let with_cookie = query(6, Some(cookie.clone()));
+        let resp = response(&with_cookie);
+        cache.store(&with_cookie.to_bytes(), &resp, &resp.to_bytes());
+        assert!(!hit(query(7, Some(cookie)), &mut out));
+    }
+
+    #[test]
+    fn test_concurrent_lookups() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(30));
+        let names: Vec<String> = (0..32).map(|i| format!("host{i}.example")).collect();
+        for name in &names {
+            let query = DnsMessage::query(0, name, 1);
+            let resp = response(&query);
+            cache.store(&query.to_bytes(), &resp, &resp.to_bytes());
+        }
+
+        std::thread::scope(|scope| {
+            for id in 0..4 {
+                let (cache, names) = (&cache, &names);
+                scope.spawn(move || {
+                    let mut out = Vec::new();
+                    for name in names {
+                        let query = DnsMessage::query(id, name, 1).to_bytes();
+                        assert!(cache.lookup(&query, &mut out));
+                        assert_eq!(DnsMessage::from_bytes(&out).unwrap().id(), id);
+                    }
+                });
+            }
+        });
+    }
+}
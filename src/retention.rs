@@ -0,0 +1,201 @@
+//! Retention of recorded client data: log files and per-client stats
+//!
+//! A [`Retention`] policy bounds how long and how much is kept, purging on a schedule (see
+//! [`Retention::schedule`]), and removes everything about one client on demand with
+//! [`Retention::forget`], for deployments bound by data-retention rules.
+//!
+//! Log files are the files in a directory whose name starts with a prefix, e.g. `queries.log`
+//! and its rotated `queries.log.1`, ...; they are expected to hold one record per line with the
+//! client as a whitespace-separated field.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::task::JoinHandle;
+
+use crate::info;
+use crate::privacy::Client;
+use crate::stats::ClientStats;
+use crate::warn;
+
+/// How long and how much client data is kept
+#[derive(Debug, Default, Clone)]
+pub struct Retention {
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+    logs: Option<(PathBuf, String)>,
+    stats: Option<Arc<ClientStats>>,
+}
+
+impl Retention {
+    /// Policy keeping everything, until limits are set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Log files last written, and clients last seen, longer than `max_age` ago are purged
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// The oldest log files are purged while the files take more than `max_bytes` together
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Applies the policy to the files in `dir` whose name starts with `prefix`
+    pub fn logs(mut self, dir: impl Into<PathBuf>, prefix: impl Into<String>) -> Self {
+        self.logs = Some((dir.into(), prefix.into()));
+        self
+    }
+
+    /// Applies the policy to `stats`
+    pub fn stats(mut self, stats: Arc<ClientStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Log files covered by the policy with their modification time and size, oldest first
+    fn log_files(&self) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+        let Some((dir, prefix)) = &self.logs else {
+            return Ok(Vec::new());
+        };
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let matches = entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(prefix.as_str());
+            if matches && metadata.is_file() {
+                files.push((entry.path(), metadata.modified()?, metadata.len()));
+            }
+        }
+        files.sort_by_key(|(_, modified, _)| *modified);
+        Ok(files)
+    }
+
+    /// Purges what the policy no longer allows, returning the number of log files deleted
+    pub fn purge(&self) -> io::Result<usize> {
+        if let (Some(stats), Some(max_age)) = (&self.stats, self.max_age) {
+            stats.purge(max_age);
+        }
+
+        let now = SystemTime::now();
+        let files = self.log_files()?;
+        let mut total: u64 = files.iter().map(|(_, _, len)| len).sum();
+        let mut deleted = 0;
+        // never the newest file, which is the one being written
+        let purgeable = files.len().saturating_sub(1);
+        for (path, modified, len) in files.into_iter().take(purgeable) {
+            let expired = self
+                .max_age
+                .is_some_and(|max_age| now.duration_since(modified).unwrap_or_default() > max_age);
+            let over = self.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+            if expired || over {
+                fs::remove_file(&path)?;
+                total -= len;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Removes `client` from the stats and every line mentioning it from the log files,
+    /// returning the number of lines removed
+    pub fn forget(&self, client: &Client) -> io::Result<usize> {
+        if let Some(stats) = &self.stats {
+            stats.forget(client);
+        }
+        let client = client.to_string();
+        let mut removed = 0;
+        for (path, _, _) in self.log_files()? {
+            let text = fs::read_to_string(&path)?;
+            let mut kept = String::with_capacity(text.len());
+            for line in text.lines() {
+                if line.split_whitespace().any(|field| field == client) {
+                    removed += 1;
+                } else {
+                    kept.push_str(line);
+                    kept.push('\n');
+                }
+            }
+            if kept.len() != text.len() {
+                // written aside and renamed, so a crash cannot leave a half-scrubbed file
+                let scrubbed = path.with_extension("scrub");
+                fs::write(&scrubbed, kept)?;
+                fs::rename(&scrubbed, &path)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Purges every `interval` on a background task. Must be called inside a tokio runtime.
+    pub fn schedule(self, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match self.purge() {
+                    Ok(0) => {}
+                    Ok(deleted) => info!("retention purged {deleted} log files"),
+                    Err(err) => warn!("retention purge failed with {err}"),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_purge_and_forget() {
+        let dir = std::env::temp_dir().join(format!("retention-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let old = dir.join("queries.log.1");
+        let current = dir.join("queries.log");
+        fs::write(&old, "t1 10.0.0.1 example.com A\n").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        fs::write(
+            &current,
+            "t2 10.0.0.1 example.com A\nt3 10.0.0.10 example.org AAAA\n",
+        )
+        .unwrap();
+        fs::write(dir.join("other.txt"), "10.0.0.1").unwrap();
+
+        let stats = Arc::new(ClientStats::default());
+        let client = Client::Addr([10, 0, 0, 1].into());
+        stats.record(client);
+        let retention = Retention::new()
+            .max_age(Duration::from_secs(60))
+            .logs(&dir, "queries.log")
+            .stats(stats.clone());
+
+        assert_eq!(retention.purge().unwrap(), 1);
+        assert!(!old.exists());
+        assert_eq!(retention.forget(&client).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(&current).unwrap(),
+            "t3 10.0.0.10 example.org AAAA\n"
+        );
+        assert!(stats.top(1).is_empty());
+        assert_eq!(
+            fs::read_to_string(dir.join("other.txt")).unwrap(),
+            "10.0.0.1"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+//! Response rate limiting (RRL), against reflection and amplification attacks
+//!
+//! As in BIND, responses are accounted per (client network, qname, rcode): clients share the
+//! account of their /24 (IPv4) or /56 (IPv6), since spoofed sources spread over a network. An
+//! account earns `responses_per_second` credits a second, up to that many, and spends one per
+//! response; it can go into debt down to `window` seconds' worth, so a flood keeps being limited
+//! for a while after it stops. Responses from an account in debt are dropped, except every
+//! `slip`th, which is replaced by an empty truncated (TC=1) response: a legitimate client whose
+//! address is being spoofed retries over TCP and gets its answer, while the victim of the
+//! reflection only receives small packets.
+//!
+//! Only UDP responses are limited, see
+//! [`ServerOptions::rrl`](crate::server::ServerOptions::rrl).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::privacy::{Anonymizer, Client};
+
+/// How often settled accounts are swept out of the table
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What to do with a response
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RrlAction {
+    Send,
+    Drop,
+    /// Send an empty response with TC=1 instead
+    Slip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    network: Client,
+    /// Lowercase wire form
+    qname: Vec<u8>,
+    rcode: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Account {
+    balance: f64,
+    updated: Instant,
+    /// Limited responses until the next slip
+    slip_in: u32,
+}
+
+#[derive(Debug)]
+struct Table {
+    accounts: HashMap<Key, Account>,
+    swept: Instant,
+}
+
+/// Response accounts, shared by every UDP listener
+#[derive(Debug)]
+pub struct Rrl {
+    rate: f64,
+    window: Duration,
+    slip: u32,
+    networks: Anonymizer,
+    table: Mutex<Table>,
+}
+
+impl Rrl {
+    /// Limits identical responses to a client network to `responses_per_second`
+    pub fn new(responses_per_second: u32) -> Self {
+        Self {
+            rate: responses_per_second.max(1).into(),
+            window: Duration::from_secs(15),
+            slip: 2,
+            networks: Anonymizer::truncate(24, 56),
+            table: Mutex::new(Table {
+                accounts: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Seconds of excess an account remembers, 15 by default
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Every `slip`th limited response is slipped, 2 by default; 0 drops them all, 1 slips
+    /// them all
+    pub fn slip(mut self, slip: u32) -> Self {
+        self.slip = slip;
+        self
+    }
+
+    /// Prefix lengths of the client networks that share an account, /24 and /56 by default
+    pub fn prefixes(mut self, v4_prefix: u8, v6_prefix: u8) -> Self {
+        self.networks = Anonymizer::truncate(v4_prefix, v6_prefix);
+        self
+    }
+
+    /// Accounts `response` to `client`, both in wire form with `query` the query it answers
+    pub fn check(&self, client: IpAddr, query: &[u8], response: &[u8]) -> RrlAction {
+        let (Some(qname), Some(&flags)) = (question_name(query), response.get(3)) else {
+            return RrlAction::Send;
+        };
+        let key = Key {
+            network: self.networks.client(client),
+            qname,
+            rcode: flags & 0x0f,
+        };
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: Key, now: Instant) -> RrlAction {
+        let mut table = self.table.lock().unwrap();
+        if now.saturating_duration_since(table.swept) >= SWEEP_INTERVAL {
+            let settled = self.window + Duration::from_secs(1);
+            table
+                .accounts
+                .retain(|_, account| now.saturating_duration_since(account.updated) < settled);
+            table.swept = now;
+        }
+
+        let account = table.accounts.entry(key).or_insert(Account {
+            balance: self.rate,
+            updated: now,
+            slip_in: self.slip,
+        });
+        let elapsed = now.saturating_duration_since(account.updated).as_secs_f64();
+        let debt = self.rate * self.window.as_secs_f64();
+        account.balance = ((account.balance + elapsed * self.rate).min(self.rate) - 1.0).max(-debt);
+        account.updated = now;
+        if account.balance >= 0.0 {
+            return RrlAction::Send;
+        }
+        if self.slip == 0 {
+            return RrlAction::Drop;
+        }
+        account.slip_in = account.slip_in.saturating_sub(1);
+        if account.slip_in > 0 {
+            return RrlAction::Drop;
+        }
+        account.slip_in = self.slip;
+        RrlAction::Slip
+    }
+
+    /// Number of accounts
+    pub fn len(&self) -> usize {
+        self.table.lock().unwrap().accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Lowercase wire form of the first question's name, which starts right after the header
+fn question_name(query: &[u8]) -> Option<Vec<u8>> {
+    let mut name = Vec::new();
+    let mut at = 12;
+    loop {
+        let len = usize::from(*query.get(at)?);
+        // a query's first name cannot be compressed
+        if len > 63 {
+            return None;
+        }
+        name.extend(query.get(at..at + 1 + len)?.to_ascii_lowercase());
+        at += 1 + len;
+        if len == 0 {
+            return Some(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns::{response, rtype, DnsMessage, ToBytes};
+
+    #[test]
+    fn test_accounts() {
+        use RrlAction::{Drop, Send, Slip};
+
+        let rrl = Rrl::new(2).window(Duration::from_secs(1));
+        let query = DnsMessage::query(1, "Example.COM", rtype::A).to_bytes();
+        let answer = response(&DnsMessage::query(1, "example.com", rtype::A)).to_bytes();
+        let start = Instant::now();
+        let check = |client: [u8; 4], query: &[u8], at: Instant| {
+            let key = Key {
+                network: rrl.networks.client(client.into()),
+                qname: question_name(query).unwrap(),
+                rcode: answer[3] & 0x0f,
+            };
+            rrl.check_at(key, at)
+        };
+
+        let actions: Vec<RrlAction> = (1..=6)
+            .map(|host| check([198, 51, 100, host], &query, start))
+            .collect();
+        assert_eq!(actions, [Send, Send, Drop, Slip, Drop, Slip]);
+
+        // other networks and names have their own accounts
+        assert_eq!(check([203, 0, 113, 1], &query, start), Send);
+        let other = DnsMessage::query(2, "example.org", rtype::A).to_bytes();
+        assert_eq!(check([198, 51, 100, 1], &other, start), Send);
+
+        // the debt is at most a window's worth: 2 responses, repaid in a second
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(check([198, 51, 100, 1], &query, later), Send);
+        assert_eq!(rrl.len(), 3);
+
+        assert_eq!(
+            question_name(&query).unwrap(),
+            b"\x07example\x03com\x00".to_vec()
+        );
+        assert_eq!(rrl.check([10, 0, 0, 1].into(), &[0; 5], &answer), Send);
+    }
+}
@@ -0,0 +1,363 @@
+//! Startup self-test: query a running server and check the answers
+//!
+//! Besides a plain lookup, every subsystem the server was started with gets a check of its
+//! own: a name of a loaded zone is answered authoritatively with the zone data, a repeated
+//! question is served from the [`CacheLayer`], a name outside the local data is forwarded,
+//! and a blocklisted name gets the blocklist response.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::blocklist::BlocklistLayer;
+use crate::cache::CacheLayer;
+use crate::dns::{class, rcode, rtype, DnsMessage, DnsQuestion, MessageBuilder, ToBytes};
+use crate::error;
+use crate::error::DnsError;
+use crate::info;
+use crate::rdata::RData;
+use crate::zone_store::ZoneLayer;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Name the plain, forwarded and cached lookups ask about
+const LOOKUP: &str = "codecrafters.io";
+
+/// Verdict on a response
+type Expect = Box<dyn Fn(&DnsMessage) -> anyhow::Result<()> + Send + Sync>;
+
+/// A single query fired at the running server, and what its response must look like
+struct Check {
+    name: String,
+    query: DnsMessage,
+    expect: Expect,
+}
+
+impl Check {
+    fn new(
+        name: impl Into<String>,
+        query: DnsMessage,
+        expect: impl Fn(&DnsMessage) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            query,
+            expect: Box::new(expect),
+        }
+    }
+}
+
+/// Checks for the subsystems a server was started with; those left out are skipped
+#[derive(Default, Clone)]
+pub struct SelfTest {
+    forwarding: bool,
+    cache: Option<Arc<CacheLayer>>,
+    zones: Option<ZoneLayer>,
+    blocklist: Option<BlocklistLayer>,
+}
+
+impl SelfTest {
+    /// Only the plain lookup, which any server answers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expects names outside the local data to be forwarded to resolvers
+    pub fn forwarding(mut self, forwarding: bool) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+
+    /// Expects the lookup asked again to be served by `cache`
+    pub fn cache(mut self, cache: Arc<CacheLayer>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Expects the apex SOA of the first zone of `zones` to be answered authoritatively
+    pub fn zones(mut self, zones: ZoneLayer) -> Self {
+        self.zones = Some(zones);
+        self
+    }
+
+    /// Expects a domain of `blocklist` to get its NXDOMAIN or sinkhole response
+    pub fn blocklist(mut self, blocklist: BlocklistLayer) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    /// Queries the server listening on `server` and reports whether every check passed
+    pub async fn run(&self, server: SocketAddr) -> bool {
+        let sock = match UdpSocket::bind((server.ip(), 0)).await {
+            Ok(sock) => sock,
+            Err(err) => {
+                error!("self-test failed to bind with {err}");
+                return false;
+            }
+        };
+
+        let mut passed = true;
+        for check in self.checks() {
+            match run_check(&sock, server, &check).await {
+                Ok(()) => info!("self-test '{}' passed", check.name),
+                Err(err) => {
+                    error!("self-test '{}' failed with {err}", check.name);
+                    passed = false;
+                }
+            }
+        }
+        passed
+    }
+
+    /// The checks in the order they run; the cache check repeats the lookup before it
+    fn checks(&self) -> Vec<Check> {
+        let mut checks = vec![self.lookup()];
+        checks.extend(self.cache_check());
+        checks.extend(self.zone_check());
+        checks.extend(self.blocklist_check());
+        checks
+    }
+
+    fn lookup(&self) -> Check {
+        let query = DnsMessage::query(0x5e1f, LOOKUP, rtype::A);
+        if !self.forwarding {
+            return Check::new(format!("answer for {LOOKUP}"), query, |resp| {
+                if resp.rcode() != rcode::NOERROR {
+                    bail!("unexpected rcode {}", resp.rcode());
+                }
+                if resp.answers().len() == 0 {
+                    bail!("no answers");
+                }
+                Ok(())
+            });
+        }
+        Check::new(format!("forwarded answer for {LOOKUP}"), query, |resp| {
+            if resp.rcode() != rcode::NOERROR {
+                bail!("unexpected rcode {}", resp.rcode());
+            }
+            if resp.answers().len() == 0 {
+                bail!("no answers");
+            }
+            if resp.header().authoritative() {
+                bail!("answered authoritatively, not by a resolver");
+            }
+            Ok(())
+        })
+    }
+
+    fn cache_check(&self) -> Option<Check> {
+        let cache = self.cache.clone()?;
+        // counted when the checks are made, before the lookup stores the answer
+        let hits = cache.hits();
+        // spelled differently, so the response cache, which keys on the exact bytes, passes
+        // it on to the pipeline
+        let name = LOOKUP.to_ascii_uppercase();
+        let query = DnsMessage::query(0x5e20, &name, rtype::A);
+        let check = Check::new(format!("cached answer for {name}"), query, move |resp| {
+            if resp.rcode() != rcode::NOERROR || resp.answers().len() == 0 {
+                bail!("no answers, rcode {}", resp.rcode());
+            }
+            if cache.hits() <= hits {
+                bail!("not served from the cache");
+            }
+            Ok(())
+        });
+        Some(check)
+    }
+
+    fn zone_check(&self) -> Option<Check> {
+        let store = self.zones.as_ref()?.store();
+        let soa = store.zones().next()?.soa().clone();
+        let query = MessageBuilder::new()
+            .id(0x5e21)
+            .recursion_desired(true)
+            .add_question(DnsQuestion::new(soa.name().clone(), rtype::SOA, class::IN))
+            .build();
+        let name = format!("zone data for {}", soa.name());
+        let check = Check::new(name, query, move |resp| {
+            if resp.rcode() != rcode::NOERROR {
+                bail!("unexpected rcode {}", resp.rcode());
+            }
+            if !resp.header().authoritative() {
+                bail!("answer is not authoritative");
+            }
+            match resp.answers().next() {
+                Some(answer)
+                    if answer.record_type() == rtype::SOA && answer.data() == soa.data() =>
+                {
+                    Ok(())
+                }
+                _ => bail!("answer differs from the zone's SOA record"),
+            }
+        });
+        Some(check)
+    }
+
+    fn blocklist_check(&self) -> Option<Check> {
+        let blocklist = self.blocklist.clone()?;
+        let domain = blocklist.blocklist().domains().next()?.clone();
+        let query = MessageBuilder::new()
+            .id(0x5e22)
+            .recursion_desired(true)
+            .add_question(DnsQuestion::new(domain.clone(), rtype::A, class::IN))
+            .build();
+        let check = Check::new(format!("blocked {domain}"), query, move |resp| {
+            if blocklist.sinkholes().is_empty() {
+                if resp.rcode() != rcode::NXDOMAIN {
+                    bail!("expected NXDOMAIN, got rcode {}", resp.rcode());
+                }
+                return Ok(());
+            }
+            if resp.rcode() != rcode::NOERROR {
+                bail!("unexpected rcode {}", resp.rcode());
+            }
+            let answered: Vec<IpAddr> = resp
+                .answers()
+                .filter_map(|answer| match answer.rdata() {
+                    Ok(RData::A(addr)) => Some(addr.into()),
+                    _ => None,
+                })
+                .collect();
+            let sinkholes = blocklist.sinkholes().iter().filter(|addr| addr.is_ipv4());
+            if !answered.iter().eq(sinkholes) {
+                bail!("answers {answered:?} are not the sinkhole addresses");
+            }
+            Ok(())
+        });
+        Some(check)
+    }
+}
+
+async fn run_check(sock: &UdpSocket, server: SocketAddr, check: &Check) -> anyhow::Result<()> {
+    sock.send_to(&check.query.to_bytes(), server).await?;
+
+    let mut buf = [0u8; 1024];
+    let (len, _) = timeout(CHECK_TIMEOUT, sock.recv_from(&mut buf))
+        .await
+        .map_err(|_| DnsError::Timeout)??;
+    let resp = DnsMessage::from_bytes(&buf[..len])?;
+
+    if !resp.is_response() {
+        bail!("response is missing the QR flag");
+    }
+    if resp.id() != check.query.id() {
+        bail!("id mismatch {} != {}", resp.id(), check.query.id());
+    }
+    (check.expect)(&resp)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::dns::DnsLabels;
+    use crate::handler::DefaultHandler;
+    use crate::pipeline::Pipeline;
+    use crate::server;
+    use crate::zone::Zone;
+    use crate::zone_store::ZoneStore;
+
+    const ZONE: &str = "
+$ORIGIN codecrafters.io.
+$TTL 600
+@           SOA ns1 hostmaster 1 7200 3600 1209600 60
+@           A   10.0.0.1
+";
+
+    async fn serve(pipeline: Pipeline) -> SocketAddr {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(server::run(sock, pipeline));
+        addr
+    }
+
+    fn zones() -> ZoneLayer {
+        let mut store = ZoneStore::new();
+        store.insert(Zone::parse(ZONE, DnsLabels::from(".")).unwrap());
+        ZoneLayer::new(store)
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let resolver = serve(Pipeline::new(DefaultHandler)).await;
+        let authority = serve(Pipeline::new(DefaultHandler).layer(zones())).await;
+
+        assert!(SelfTest::new().run(resolver).await);
+        assert!(SelfTest::new().run(authority).await);
+        assert!(SelfTest::new().forwarding(true).run(resolver).await);
+        assert!(!SelfTest::new().forwarding(true).run(authority).await);
+    }
+
+    #[tokio::test]
+    async fn test_cache_check() {
+        let cache = Arc::new(CacheLayer::new(16));
+        let cached = serve(Pipeline::new(DefaultHandler).layer(cache.clone())).await;
+        let uncached = serve(Pipeline::new(DefaultHandler)).await;
+
+        assert!(SelfTest::new().cache(cache.clone()).run(cached).await);
+        assert!(cache.hits() > 0);
+        let unused = Arc::new(CacheLayer::new(16));
+        assert!(!SelfTest::new().cache(unused).run(uncached).await);
+    }
+
+    #[tokio::test]
+    async fn test_zone_check() {
+        let zones = zones();
+        let authority = serve(Pipeline::new(DefaultHandler).layer(zones.clone())).await;
+        let resolver = serve(Pipeline::new(DefaultHandler)).await;
+
+        assert_eq!(SelfTest::new().zones(zones.clone()).checks().len(), 2);
+        assert!(SelfTest::new().zones(zones.clone()).run(authority).await);
+        assert!(!SelfTest::new().zones(zones).run(resolver).await);
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_check() {
+        let path = std::env::temp_dir().join(format!("self-test-{}", std::process::id()));
+        fs::write(&path, "ads.example.com\n").unwrap();
+        let nxdomain = BlocklistLayer::new([&path]);
+        let sinkhole = BlocklistLayer::new([&path]).sinkhole(["0.0.0.0".parse().unwrap()]);
+        fs::remove_file(&path).unwrap();
+
+        let blocking = serve(Pipeline::new(DefaultHandler).layer(nxdomain.clone())).await;
+        let sinkholing = serve(Pipeline::new(DefaultHandler).layer(sinkhole.clone())).await;
+        let open = serve(Pipeline::new(DefaultHandler)).await;
+
+        assert!(
+            SelfTest::new()
+                .blocklist(nxdomain.clone())
+                .run(blocking)
+                .await
+        );
+        assert!(
+            SelfTest::new()
+                .blocklist(sinkhole.clone())
+                .run(sinkholing)
+                .await
+        );
+        // the sinkhole answer is not the NXDOMAIN expected of the other list, and back
+        assert!(
+            !SelfTest::new()
+                .blocklist(nxdomain.clone())
+                .run(sinkholing)
+                .await
+        );
+        assert!(!SelfTest::new().blocklist(sinkhole).run(blocking).await);
+        assert!(!SelfTest::new().blocklist(nxdomain).run(open).await);
+    }
+
+    #[test]
+    fn test_unconfigured_checks_are_skipped() {
+        let names: Vec<_> = SelfTest::new()
+            .checks()
+            .into_iter()
+            .map(|check| check.name)
+            .collect();
+        assert_eq!(names, ["answer for codecrafters.io"]);
+    }
+}
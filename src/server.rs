@@ -0,0 +1,884 @@
+//! UDP listener
+
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::arena;
+use crate::batch::{recv_batch, send_batch};
+use crate::debug;
+use crate::dns::{
+    error_response, header_response, rcode, DnsMessage, DnsMessageRef, ToBytes, MAX_UDP_PAYLOAD,
+};
+use crate::error;
+use crate::handler::{RequestCtx, RequestHandler, Transport};
+use crate::pool::{BufferPool, PooledBuf};
+use crate::ratelimit::{RateLimitPolicy, RateLimiter};
+use crate::response_cache::ResponseCache;
+use crate::rrl::{Rrl, RrlAction};
+use crate::tsig::{Keyring, Signed};
+use crate::warn;
+
+/// One byte over the largest accepted payload, so clipped datagrams can be told apart
+const RECV_BUF_SIZE: usize = MAX_UDP_PAYLOAD + 1;
+const MAX_IDLE_BUFFERS: usize = 1_000;
+/// Datagrams received or sent per syscall
+const BATCH_SIZE: usize = 32;
+
+/// What to do with a query that arrives while `max_in_flight` queries are being handled
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OverloadPolicy {
+    /// Discard the query; the client retries after its own timeout
+    Drop,
+    /// Answer straight away with SERVFAIL
+    ServFail,
+}
+
+/// Tunables of the UDP server
+#[derive(Clone)]
+pub struct ServerOptions {
+    max_in_flight: usize,
+    overload: OverloadPolicy,
+    stats: Arc<ServerStats>,
+    response_cache: Option<Arc<ResponseCache>>,
+    query_timeout: Duration,
+    registry: Option<Arc<StatsRegistry>>,
+    worker: usize,
+    rate_limit: Option<(Arc<RateLimiter>, RateLimitPolicy)>,
+    rrl: Option<Arc<Rrl>>,
+    tsig: Option<Arc<Keyring>>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 1_000,
+            overload: OverloadPolicy::Drop,
+            stats: Arc::default(),
+            response_cache: None,
+            query_timeout: Duration::from_secs(5),
+            registry: None,
+            worker: 0,
+            rate_limit: None,
+            rrl: None,
+            tsig: None,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Maximum number of queries handled concurrently
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    pub fn overload(mut self, overload: OverloadPolicy) -> Self {
+        self.overload = overload;
+        self
+    }
+
+    /// Time a handler gets to answer before the client gets SERVFAIL instead
+    pub fn query_timeout(mut self, timeout: Duration) -> Self {
+        self.query_timeout = timeout;
+        self
+    }
+
+    /// Answers repeated questions from serialized responses, see [`ResponseCache`]
+    pub fn response_cache(mut self, cache: ResponseCache) -> Self {
+        self.response_cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Counters updated by the server, shared with the caller. With a [`StatsRegistry`], the
+    /// server counts in the registry instead.
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.stats.clone()
+    }
+
+    /// Counts in `registry`, segmented by the socket served and the worker index
+    pub fn registry(mut self, registry: Arc<StatsRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Queries from clients over their limit in `limiter`, which may be shared with other
+    /// listeners, are dropped or refused before they are parsed
+    pub fn rate_limit(mut self, limiter: Arc<RateLimiter>, policy: RateLimitPolicy) -> Self {
+        self.rate_limit = Some((limiter, policy));
+        self
+    }
+
+    /// Limits the responses sent, see [`Rrl`]
+    pub fn rrl(mut self, rrl: Arc<Rrl>) -> Self {
+        self.rrl = Some(rrl);
+        self
+    }
+
+    /// Verifies the signatures of signed queries against `keyring`, answering those that fail
+    /// with NOTAUTH, and signs their responses; see [`crate::tsig`]
+    pub fn tsig(mut self, keyring: Arc<Keyring>) -> Self {
+        self.tsig = Some(keyring);
+        self
+    }
+
+    /// Index of the worker the options are for, when several serve the same socket
+    pub fn worker(mut self, worker: usize) -> Self {
+        self.worker = worker;
+        self
+    }
+}
+
+/// Server counters
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    received: AtomicU64,
+    overload_dropped: AtomicU64,
+    overload_servfail: AtomicU64,
+    oversized: AtomicU64,
+    timed_out: AtomicU64,
+    rate_limited: AtomicU64,
+    rrl_dropped: AtomicU64,
+    rrl_slipped: AtomicU64,
+    cached: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn overload_dropped(&self) -> u64 {
+        self.overload_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn overload_servfail(&self) -> u64 {
+        self.overload_servfail.load(Ordering::Relaxed)
+    }
+
+    /// Queries the handler did not answer within the query timeout
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    /// Queries from clients over their rate limit, dropped or refused
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Responses dropped by response rate limiting
+    pub fn rrl_dropped(&self) -> u64 {
+        self.rrl_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Responses replaced by a truncated one by response rate limiting
+    pub fn rrl_slipped(&self) -> u64 {
+        self.rrl_slipped.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams larger than [`MAX_UDP_PAYLOAD`], answered with FORMERR
+    pub fn oversized(&self) -> u64 {
+        self.oversized.load(Ordering::Relaxed)
+    }
+
+    /// Queries answered from the [`ResponseCache`], without reaching the handler
+    pub fn cached(&self) -> u64 {
+        self.cached.load(Ordering::Relaxed)
+    }
+}
+
+/// Listener a [`StatsRegistry`] segment belongs to
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instance {
+    socket: SocketAddr,
+    worker: usize,
+}
+
+impl Instance {
+    pub fn socket(&self) -> SocketAddr {
+        self.socket
+    }
+
+    pub fn worker(&self) -> usize {
+        self.worker
+    }
+}
+
+/// Counters of every listener and worker of this node, kept apart so anycast operators can see
+/// how traffic spreads over instances and spot the odd one out
+#[derive(Debug, Default)]
+pub struct StatsRegistry {
+    node_id: Option<String>,
+    instances: Mutex<Vec<(Instance, Arc<ServerStats>)>>,
+}
+
+impl StatsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry of the node identified by `node_id`, the `server.identity` setting, which is
+    /// reported with every segment
+    pub fn for_node(node_id: impl Into<String>) -> Self {
+        Self {
+            node_id: Some(node_id.into()),
+            instances: Mutex::default(),
+        }
+    }
+
+    pub fn node_id(&self) -> Option<&str> {
+        self.node_id.as_deref()
+    }
+
+    /// Counters of `worker` on `socket`, created on first use
+    pub fn register(&self, socket: SocketAddr, worker: usize) -> Arc<ServerStats> {
+        let instance = Instance { socket, worker };
+        let mut instances = self.instances.lock().unwrap();
+        if let Some((_, stats)) = instances.iter().find(|(i, _)| *i == instance) {
+            return stats.clone();
+        }
+        let stats = Arc::new(ServerStats::default());
+        instances.push((instance, stats.clone()));
+        instances.sort_by_key(|(instance, _)| *instance);
+        stats
+    }
+
+    /// Every segment, ordered by socket then worker
+    pub fn instances(&self) -> Vec<(Instance, Arc<ServerStats>)> {
+        self.instances.lock().unwrap().clone()
+    }
+
+    /// One line per segment, e.g.
+    /// `node=ams1 socket=0.0.0.0:53 worker=0 received=10 dropped=0 servfail=0 oversized=0
+    /// timed_out=0 rate_limited=0 rrl_dropped=0 rrl_slipped=0 cached=0` on one line
+    pub fn report(&self) -> String {
+        let node = self.node_id.as_deref().unwrap_or("-");
+        let mut report = String::new();
+        for (instance, stats) in self.instances() {
+            let _ = writeln!(
+                report,
+                "node={node} socket={} worker={} received={} dropped={} servfail={} oversized={} \
+                 timed_out={} rate_limited={} rrl_dropped={} rrl_slipped={} cached={}",
+                instance.socket,
+                instance.worker,
+                stats.received(),
+                stats.overload_dropped(),
+                stats.overload_servfail(),
+                stats.oversized(),
+                stats.timed_out(),
+                stats.rate_limited(),
+                stats.rrl_dropped(),
+                stats.rrl_slipped(),
+                stats.cached(),
+            );
+        }
+        report
+    }
+}
+
+/// Serves queries arriving on `sock` with `handler` and default [`ServerOptions`]
+pub async fn run<H: RequestHandler>(sock: UdpSocket, handler: H) {
+    run_with_options(sock, handler, ServerOptions::default()).await
+}
+
+/// Serves queries arriving on `sock` with `handler` until the task is dropped.
+///
+/// Datagrams are read and responses written in batches (see [`crate::batch`]). Every query is
+/// handled on its own task, so a slow query does not hold up the others. At most `max_in_flight`
+/// tasks run at once; queries beyond that follow the [`OverloadPolicy`]. A handler still busy
+/// after `query_timeout` is cancelled and the client answered with SERVFAIL.
+pub async fn run_with_options<H: RequestHandler>(
+    sock: UdpSocket,
+    handler: H,
+    mut options: ServerOptions,
+) {
+    if let Some(registry) = &options.registry {
+        match sock.local_addr() {
+            Ok(local) => options.stats = registry.register(local, options.worker),
+            Err(err) => warn!("failed to register stats with {err}"),
+        }
+    }
+    let sock = Arc::new(sock);
+    let handler = Arc::new(handler);
+    let pool = BufferPool::new(RECV_BUF_SIZE, MAX_IDLE_BUFFERS);
+    let in_flight = Arc::new(Semaphore::new(options.max_in_flight));
+    let stats = options.stats.clone();
+
+    let (tx, rx) = mpsc::channel(options.max_in_flight.max(1));
+    tokio::spawn(response_sender(sock.clone(), rx));
+
+    // listening for new requests, a batch at a time
+    let mut bufs: Vec<PooledBuf> = Vec::with_capacity(BATCH_SIZE);
+    let mut meta = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        while bufs.len() < BATCH_SIZE {
+            let mut buf = pool.get();
+            buf.resize(RECV_BUF_SIZE, 0);
+            bufs.push(buf);
+        }
+
+        if let Err(err) = sock.readable().await {
+            error!("failed to read from socket with {err}");
+            continue;
+        }
+        let received = match recv_batch(&sock, &mut bufs, &mut meta) {
+            Ok(received) => received,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => {
+                error!("failed to read from socket with {err}");
+                continue;
+            }
+        };
+
+        for (mut buf, &(len, addr)) in bufs.drain(..received).zip(&meta) {
+            debug!("{len} bytes received from {addr}");
+            buf.truncate(len);
+            stats.received.fetch_add(1, Ordering::Relaxed);
+
+            if len > MAX_UDP_PAYLOAD {
+                stats.oversized.fetch_add(1, Ordering::Relaxed);
+                warn!("datagram from {addr} exceeds {MAX_UDP_PAYLOAD} bytes");
+                reply_header_only(&sock, &buf, rcode::FORMERR, addr).await;
+                continue;
+            }
+
+            if let Some((limiter, policy)) = &options.rate_limit {
+                if !limiter.check(addr.ip()) {
+                    stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+                    debug!("query from {addr} over its rate limit");
+                    if *policy == RateLimitPolicy::Refused {
+                        reply_header_only(&sock, &buf, rcode::REFUSED, addr).await;
+                    }
+                    continue;
+                }
+            }
+
+            let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+                overloaded(&sock, &stats, options.overload, &buf, addr).await;
+                continue;
+            };
+            let query = Query {
+                bytes: buf,
+                addr,
+                _permit: permit,
+            };
+            tokio::spawn(handle_query(
+                handler.clone(),
+                options.clone(),
+                pool.clone(),
+                tx.clone(),
+                query,
+            ));
+        }
+    }
+}
+
+/// Sends queued responses, as many per syscall as are ready
+async fn response_sender(sock: Arc<UdpSocket>, mut rx: Receiver<(PooledBuf, SocketAddr)>) {
+    let mut queued = Vec::with_capacity(BATCH_SIZE);
+    while rx.recv_many(&mut queued, BATCH_SIZE).await > 0 {
+        let packets: Vec<(&[u8], SocketAddr)> = queued
+            .iter()
+            .map(|(buf, addr)| (buf.as_slice(), *addr))
+            .collect();
+
+        let mut sent = 0;
+        while sent < packets.len() {
+            if let Err(err) = sock.writable().await {
+                error!("failed to write to socket with {err}");
+                break;
+            }
+            match send_batch(&sock, &packets[sent..]) {
+                Ok(n) => {
+                    for (buf, addr) in &packets[sent..sent + n] {
+                        debug!("sent {} bytes to {addr}", buf.len());
+                    }
+                    sent += n;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => {
+                    // skip the datagram the kernel refused so the rest still go out
+                    error!("failed to write to socket with {err}");
+                    sent += 1;
+                }
+            }
+        }
+
+        drop(packets);
+        queued.clear();
+    }
+}
+
+/// Answers with just a header, for queries whose body cannot be trusted
+async fn reply_header_only(sock: &UdpSocket, query: &[u8], rcode: u8, addr: SocketAddr) {
+    let Some(response) = header_response(query, rcode) else {
+        return;
+    };
+    if let Err(err) = sock.send_to(&response.to_bytes(), addr).await {
+        error!("failed to write to socket with {err}");
+    }
+}
+
+async fn overloaded(
+    sock: &UdpSocket,
+    stats: &ServerStats,
+    policy: OverloadPolicy,
+    bytes: &[u8],
+    addr: SocketAddr,
+) {
+    match policy {
+        OverloadPolicy::Drop => {
+            stats.overload_dropped.fetch_add(1, Ordering::Relaxed);
+            warn!("dropped query from {addr}, too many queries in flight");
+        }
+        OverloadPolicy::ServFail => {
+            stats.overload_servfail.fetch_add(1, Ordering::Relaxed);
+            let Some(response) = servfail(bytes) else {
+                return;
+            };
+            if let Err(err) = sock.send_to(&response.to_bytes(), addr).await {
+                error!("failed to write to socket with {err}");
+            }
+        }
+    }
+}
+
+/// SERVFAIL echoing the questions of the query in `bytes`
+fn servfail(bytes: &[u8]) -> Option<DnsMessage> {
+    let req = DnsMessage::from_bytes(bytes).ok()?;
+    Some(error_response(&req, rcode::SERVFAIL))
+}
+
+/// Applies response rate limiting to the response in `buf` to `query`, replacing it when it
+/// slips; `false` if it is dropped
+fn rate_limit_response(
+    options: &ServerOptions,
+    addr: SocketAddr,
+    query: &[u8],
+    buf: &mut PooledBuf,
+) -> bool {
+    let Some(rrl) = &options.rrl else {
+        return true;
+    };
+    match rrl.check(addr.ip(), query, buf) {
+        RrlAction::Send => true,
+        RrlAction::Drop => {
+            options.stats.rrl_dropped.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+        RrlAction::Slip => {
+            options.stats.rrl_slipped.fetch_add(1, Ordering::Relaxed);
+            let Ok(response) = DnsMessage::from_bytes(buf) else {
+                return false;
+            };
+            // keeps the header and question, with TC set
+            let slipped = response.truncate(0);
+            buf.clear();
+            slipped.write_to(&mut **buf);
+            true
+        }
+    }
+}
+
+/// Received datagram, holding its in-flight slot until it has been answered
+struct Query {
+    bytes: PooledBuf,
+    addr: SocketAddr,
+    _permit: OwnedSemaphorePermit,
+}
+
+async fn handle_query<H: RequestHandler>(
+    handler: Arc<H>,
+    options: ServerOptions,
+    pool: Arc<BufferPool>,
+    tx: Sender<(PooledBuf, SocketAddr)>,
+    query: Query,
+) {
+    let Query {
+        bytes,
+        addr,
+        _permit,
+    } = query;
+    let cache = &options.response_cache;
+    let mut buf = pool.get();
+    let signed = match options.tsig.as_ref().map(|keyring| keyring.verify(&bytes)) {
+        Some(Ok(signed)) => signed,
+        Some(Err(err)) => {
+            warn!("rejected query from {addr}: {err}");
+            let Some(response) = err.response(&bytes) else {
+                return;
+            };
+            buf.extend_from_slice(&response);
+            if tx.send((buf, addr)).await.is_err() {
+                error!("response sender stopped");
+            }
+            return;
+        }
+        None => None,
+    };
+    // signed responses are made for their request
+    let cache = cache.as_ref().filter(|_| signed.is_none());
+    if cache.is_some_and(|cache| cache.lookup(&bytes, &mut buf)) {
+        options.stats.cached.fetch_add(1, Ordering::Relaxed);
+        if !rate_limit_response(&options, addr, &bytes, &mut buf) {
+            return;
+        }
+        if tx.send((buf, addr)).await.is_err() {
+            error!("response sender stopped");
+        }
+        return;
+    }
+
+    let req = match DnsMessageRef::from_bytes(&bytes) {
+        Ok(req) => arena::with_local(|arena| req.to_owned_in(arena)),
+        Err(err) => {
+            error!("failed to parse - '{err}'");
+            let Some(response) = header_response(&bytes, rcode::FORMERR) else {
+                return;
+            };
+            response.write_to(&mut *buf);
+            if tx.send((buf, addr)).await.is_err() {
+                error!("response sender stopped");
+            }
+            return;
+        }
+    };
+
+    let max_len = req
+        .max_udp_response()
+        .saturating_sub(signed.as_ref().map_or(0, Signed::record_len));
+    let ctx = RequestCtx::new(addr, Transport::Udp);
+    let ctx = match &signed {
+        Some(signed) => ctx.signed_with(signed.key().name().clone()),
+        None => ctx,
+    };
+    let handled = handler.handle(req, ctx);
+    let response = match tokio::time::timeout(options.query_timeout, handled).await {
+        Ok(response) => response,
+        Err(_) => {
+            options.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+            warn!("query from {addr} timed out");
+            let Some(response) = servfail(&bytes) else {
+                return;
+            };
+            response
+        }
+    };
+
+    let response = response.truncate(max_len);
+    buf.reserve(response.wire_len());
+    response.write_to(&mut *buf);
+    if let Some(cache) = cache {
+        cache.store(&bytes, &response, &buf);
+    }
+    arena::with_local(|arena| arena.reclaim(response));
+    if !rate_limit_response(&options, addr, &bytes, &mut buf) {
+        return;
+    }
+    if let Some(signed) = &signed {
+        signed.sign(&mut buf);
+    }
+    drop(bytes);
+    if tx.send((buf, addr)).await.is_err() {
+        error!("response sender stopped");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns::MessageBuilder;
+    use crate::handler::DefaultHandler;
+    use crate::tsig::{Algorithm, Key};
+
+    struct Refuse;
+
+    impl RequestHandler for Refuse {
+        async fn handle(&self, query: DnsMessage, _ctx: RequestCtx) -> DnsMessage {
+            MessageBuilder::response_to(&query).rcode(5).build()
+        }
+    }
+
+    /// Answers slow.example only after a long delay
+    struct Slow;
+
+    impl RequestHandler for Slow {
+        async fn handle(&self, query: DnsMessage, _ctx: RequestCtx) -> DnsMessage {
+            let qname = query.questions().next().unwrap().qname().to_string();
+            if qname == "slow.example" {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+            }
+            MessageBuilder::response_to(&query).build()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_query_does_not_block_others() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(run(sock, Slow));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let slow = DnsMessage::query(1, "slow.example", 1);
+        let fast = DnsMessage::query(2, "fast.example", 1);
+        client.send_to(&slow.to_bytes(), addr).await.unwrap();
+        client.send_to(&fast.to_bytes(), addr).await.unwrap();
+
+        let mut buf = [0u8; 512];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .expect("fast query was blocked by the slow one")
+            .unwrap();
+        assert_eq!(DnsMessage::from_bytes(&buf[..len]).unwrap().id(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_overload_servfail() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let options = ServerOptions::default()
+            .max_in_flight(1)
+            .overload(OverloadPolicy::ServFail);
+        let stats = options.stats();
+        tokio::spawn(run_with_options(sock, Slow, options));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let slow = DnsMessage::query(1, "slow.example", 1);
+        let fast = DnsMessage::query(2, "fast.example", 1);
+        client.send_to(&slow.to_bytes(), addr).await.unwrap();
+        client.send_to(&fast.to_bytes(), addr).await.unwrap();
+
+        let mut buf = [0u8; 512];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        let resp = DnsMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(resp.id(), 2);
+        assert_eq!(resp.rcode(), 2);
+        assert_eq!(stats.overload_servfail(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let limiter = Arc::new(RateLimiter::new(1));
+        let options = ServerOptions::default().rate_limit(limiter, RateLimitPolicy::Refused);
+        let stats = options.stats();
+        tokio::spawn(run_with_options(sock, DefaultHandler, options));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for id in 1..=2 {
+            let query = DnsMessage::query(id, "codecrafters.io", 1);
+            client.send_to(&query.to_bytes(), addr).await.unwrap();
+        }
+        let mut rcodes = Vec::new();
+        for _ in 0..2 {
+            let mut buf = [0u8; 512];
+            let (len, _) = client.recv_from(&mut buf).await.unwrap();
+            let resp = DnsMessage::from_bytes(&buf[..len]).unwrap();
+            rcodes.push((resp.id(), resp.rcode()));
+        }
+        rcodes.sort();
+        assert_eq!(rcodes, [(1, rcode::NOERROR), (2, rcode::REFUSED)]);
+        assert_eq!(stats.rate_limited(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rrl_slip() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let options = ServerOptions::default().rrl(Arc::new(Rrl::new(1).slip(1)));
+        let stats = options.stats();
+        tokio::spawn(run_with_options(sock, DefaultHandler, options));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut responses = Vec::new();
+        for id in 1..=2 {
+            let query = DnsMessage::query(id, "codecrafters.io", 1);
+            client.send_to(&query.to_bytes(), addr).await.unwrap();
+            let mut buf = [0u8; 512];
+            let (len, _) = client.recv_from(&mut buf).await.unwrap();
+            responses.push(DnsMessage::from_bytes(&buf[..len]).unwrap());
+        }
+        assert!(!responses[0].header().truncated());
+        assert_eq!(responses[0].answers().len(), 1);
+        assert!(responses[1].header().truncated());
+        assert_eq!(responses[1].answers().len(), 0);
+        assert_eq!(responses[1].questions().len(), 1);
+        assert_eq!(stats.rrl_slipped(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tsig() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let key = Key::new("xfr.example".into(), Algorithm::HmacSha256, *b"secret");
+        let options = ServerOptions::default().tsig(Arc::new(Keyring::new().key(key.clone())));
+        tokio::spawn(run_with_options(sock, DefaultHandler, options));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut query = DnsMessage::query(3, "codecrafters.io", 1).to_bytes();
+        let mac = key.sign(&mut query);
+        client.send_to(&query, addr).await.unwrap();
+        let mut buf = [0u8; 512];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert!(key.verify_response(&buf[..len], &mac));
+        assert_eq!(
+            DnsMessage::from_bytes(&buf[..len]).unwrap().answers().len(),
+            1
+        );
+
+        let stranger = Key::new("other.example".into(), Algorithm::HmacSha256, *b"secret");
+        let mut query = DnsMessage::query(4, "codecrafters.io", 1).to_bytes();
+        stranger.sign(&mut query);
+        client.send_to(&query, addr).await.unwrap();
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        let resp = DnsMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(resp.rcode(), rcode::NOTAUTH);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_datagram() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let options = ServerOptions::default();
+        let stats = options.stats();
+        tokio::spawn(run_with_options(sock, Refuse, options));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut query = DnsMessage::query(9, "example.com", 1).to_bytes();
+        query.resize(MAX_UDP_PAYLOAD + 100, 0);
+        client.send_to(&query, addr).await.unwrap();
+
+        let mut buf = [0u8; 512];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        let resp = DnsMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(resp.id(), 9);
+        assert_eq!(resp.rcode(), rcode::FORMERR);
+        assert_eq!(stats.oversized(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_counts_formerr() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(run(sock, Refuse));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut query = DnsMessage::query(4, "example.com", 1).to_bytes();
+        query[4..6].copy_from_slice(&u16::MAX.to_be_bytes());
+        client.send_to(&query, addr).await.unwrap();
+
+        let mut buf = [0u8; 512];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        let resp = DnsMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(resp.id(), 4);
+        assert_eq!(resp.rcode(), rcode::FORMERR);
+        assert_eq!(resp.questions().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let options = ServerOptions::default().query_timeout(Duration::from_millis(50));
+        let stats = options.stats();
+        tokio::spawn(run_with_options(sock, Slow, options));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let slow = DnsMessage::query(3, "slow.example", 1);
+        client.send_to(&slow.to_bytes(), addr).await.unwrap();
+
+        let mut buf = [0u8; 512];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .expect("timed out query was not answered")
+            .unwrap();
+        let resp = DnsMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(resp.id(), 3);
+        assert_eq!(resp.rcode(), rcode::SERVFAIL);
+        assert_eq!(resp.questions().len(), 1);
+        assert_eq!(stats.timed_out(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stats_per_instance() {
+        let registry = Arc::new(StatsRegistry::for_node("ams1"));
+        let mut addrs = Vec::new();
+        for worker in 0..2 {
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(sock.local_addr().unwrap());
+            let options = ServerOptions::default()
+                .registry(registry.clone())
+                .worker(worker);
+            tokio::spawn(run_with_options(sock, Refuse, options));
+        }
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for addr in [addrs[0], addrs[1], addrs[1]] {
+            let query = DnsMessage::query(1, "example.com", 1);
+            client.send_to(&query.to_bytes(), addr).await.unwrap();
+            client.recv_from(&mut buf).await.unwrap();
+        }
+
+        let received: Vec<(SocketAddr, usize, u64)> = registry
+            .instances()
+            .iter()
+            .map(|(i, stats)| (i.socket(), i.worker(), stats.received()))
+            .collect();
+        let mut expected = vec![(addrs[0], 0, 1), (addrs[1], 1, 2)];
+        expected.sort();
+        assert_eq!(received, expected);
+        assert!(registry.report().contains(&format!(
+            "node=ams1 socket={} worker=1 received=2",
+            addrs[1]
+        )));
+        assert!(registry
+            .report()
+            .contains("rate_limited=0 rrl_dropped=0 rrl_slipped=0 cached=0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_response_cache_hits_counted() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let options = ServerOptions::default()
+            .response_cache(ResponseCache::new(16, Duration::from_secs(30)));
+        let stats = options.stats();
+        tokio::spawn(run_with_options(sock, DefaultHandler, options));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for _ in 0..3 {
+            let query = DnsMessage::query(1, "example.com", 1);
+            client.send_to(&query.to_bytes(), addr).await.unwrap();
+            client.recv_from(&mut buf).await.unwrap();
+        }
+        assert_eq!(stats.received(), 3);
+        assert_eq!(stats.cached(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_custom_handler() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(run(sock, Refuse));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = DnsMessage::query(77, "example.com", 1);
+        client.send_to(&query.to_bytes(), addr).await.unwrap();
+
+        let mut buf = [0u8; 512];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        let resp = DnsMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(resp.id(), 77);
+        assert_eq!(resp.rcode(), 5);
+    }
+}
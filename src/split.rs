@@ -0,0 +1,277 @@
+//! Split DNS: forwarding some domains to their own resolvers
+//!
+//! [`SplitLayer`] sends queries under a routed domain to that domain's upstream, e.g. a corporate
+//! domain to the resolver behind a VPN, and lets everything else take the default path. Routes
+//! are read from a file or from the output of a command, one `domain upstream` pair per line, so
+//! VPN up/down scripts can rewrite them at any time:
+//!
+//! ```text
+//! # tailnet
+//! ts.net            100.100.100.100
+//! corp.example.com  10.8.0.1:5353
+//! ```
+//!
+//! The most specific domain wins; the upstream port defaults to 53.
+
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use crate::dns::{error_response, rcode, DnsLabels, DnsMessage};
+use crate::forward;
+use crate::handler::RequestCtx;
+use crate::info;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::warn;
+
+/// Upstreams by domain, most specific domain first
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Routes(Vec<(DnsLabels, SocketAddr)>);
+
+impl Routes {
+    /// Parses `domain upstream` lines, skipping comments and invalid lines
+    pub fn parse(text: &str) -> Routes {
+        let mut routes: Vec<(DnsLabels, SocketAddr)> = text
+            .lines()
+            .filter_map(|line| {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut fields = line.split_whitespace();
+                let domain = fields.next()?.parse().ok()?;
+                let upstream = fields.next()?;
+                let upstream = upstream
+                    .parse()
+                    .or_else(|_| upstream.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .ok()?;
+                fields.next().is_none().then_some((domain, upstream))
+            })
+            .collect();
+        // stable, so the first line listing a domain wins
+        routes.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.label_count()));
+        Routes(routes)
+    }
+
+    /// Upstream for `name`, if it is under a routed domain
+    pub fn route(&self, name: &DnsLabels) -> Option<SocketAddr> {
+        self.0
+            .iter()
+            .find(|(domain, _)| name.is_subdomain_of(domain))
+            .map(|(_, upstream)| *upstream)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Where routes are read from
+#[derive(Debug, Clone)]
+enum Source {
+    File(PathBuf),
+    /// Run with `sh -c`, its standard output being the routes
+    Command(String),
+}
+
+impl Source {
+    async fn read(&self) -> Option<String> {
+        let result = match self {
+            Source::File(path) => fs::read_to_string(path),
+            Source::Command(command) => tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .output()
+                .await
+                .and_then(|output| {
+                    if output.status.success() {
+                        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+                    } else {
+                        Err(std::io::Error::other(format!(
+                            "exited with {}",
+                            output.status
+                        )))
+                    }
+                }),
+        };
+        result
+            .map_err(|err| warn!("failed to read routes from {self:?} with {err}"))
+            .ok()
+    }
+
+    /// Modification time for files; commands have to be run to see a change
+    fn modified(&self) -> Option<SystemTime> {
+        match self {
+            Source::File(path) => fs::metadata(path).and_then(|m| m.modified()).ok(),
+            Source::Command(_) => None,
+        }
+    }
+}
+
+/// Forwards queries under routed domains to their upstream
+pub struct SplitLayer {
+    source: Source,
+    timeout: Duration,
+    routes: Arc<RwLock<Arc<Routes>>>,
+}
+
+impl SplitLayer {
+    /// Routes listed in the file at `path`, read now
+    pub async fn from_file(path: impl Into<PathBuf>) -> Self {
+        Self::with_source(Source::File(path.into())).await
+    }
+
+    /// Routes printed by `command`, run now with `sh -c`
+    pub async fn from_command(command: impl Into<String>) -> Self {
+        Self::with_source(Source::Command(command.into())).await
+    }
+
+    async fn with_source(source: Source) -> Self {
+        let routes = source.read().await.map(|text| Routes::parse(&text));
+        Self {
+            source,
+            timeout: Duration::from_secs(2),
+            routes: Arc::new(RwLock::new(Arc::new(routes.unwrap_or_default()))),
+        }
+    }
+
+    /// How long to wait for an upstream before answering SERVFAIL, 2s by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Re-reads the routes every `interval` on a background task that ends with the layer: a
+    /// file when its modification time changes, a command every time. Routes that fail to load
+    /// keep the previous ones. Must be called inside a tokio runtime.
+    pub fn watch(self, interval: Duration) -> Self {
+        let routes = Arc::downgrade(&self.routes);
+        tokio::spawn(watch(self.source.clone(), routes, interval));
+        self
+    }
+
+    pub fn routes(&self) -> Arc<Routes> {
+        self.routes.read().unwrap().clone()
+    }
+}
+
+impl Layer for SplitLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let routes = self.routes();
+            let upstream = match query.questions().next() {
+                Some(question) => routes.route(question.qname()),
+                None => None,
+            };
+            let Some(upstream) = upstream else {
+                return next.run(query, ctx).await;
+            };
+            match forward::exchange(upstream, &query, self.timeout).await {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!("forwarding to {upstream} failed with {err}");
+                    error_response(&query, rcode::SERVFAIL)
+                }
+            }
+        })
+    }
+}
+
+async fn watch(source: Source, routes: Weak<RwLock<Arc<Routes>>>, interval: Duration) {
+    let mut seen = source.modified();
+    loop {
+        tokio::time::sleep(interval).await;
+        if routes.strong_count() == 0 {
+            return;
+        }
+        let modified = source.modified();
+        if matches!(source, Source::File(_)) && modified == seen {
+            continue;
+        }
+        seen = modified;
+        let Some(text) = source.read().await else {
+            continue;
+        };
+        let Some(routes) = routes.upgrade() else {
+            return;
+        };
+        let new = Routes::parse(&text);
+        if **routes.read().unwrap() != new {
+            info!("reloading split DNS routes");
+            *routes.write().unwrap() = Arc::new(new);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::dns::{rtype, MessageBuilder, ToBytes, MAX_UDP_PAYLOAD};
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    #[test]
+    fn test_routes() {
+        let routes = Routes::parse(
+            "example.com 10.0.0.1\n\
+             # comment\n\
+             corp.example.com 10.0.0.2:5353 # vpn\n\
+             broken\n",
+        );
+        let upstream = |name: &str| routes.route(&name.into());
+        assert_eq!(
+            upstream("a.CORP.example.com"),
+            Some("10.0.0.2:5353".parse().unwrap())
+        );
+        assert_eq!(
+            upstream("www.example.com"),
+            Some("10.0.0.1:53".parse().unwrap())
+        );
+        assert_eq!(upstream("example.org"), None);
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        // an upstream answering everything authoritatively
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+            loop {
+                let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+                let query = DnsMessage::from_bytes(&buf[..len]).unwrap();
+                let response = MessageBuilder::response_to(&query)
+                    .authoritative(true)
+                    .add_question(query.questions().next().unwrap().clone())
+                    .build();
+                upstream.send_to(&response.to_bytes(), from).await.unwrap();
+            }
+        });
+
+        let command = format!("echo 'corp.example {upstream_addr}'");
+        let layer = SplitLayer::from_command(command).await;
+        let pipeline = Pipeline::new(DefaultHandler).layer(layer);
+        let ctx = RequestCtx::new(SocketAddr::from(([127, 0, 0, 1], 53)), Transport::Udp);
+
+        let response = pipeline
+            .handle(
+                DnsMessage::query(9, "git.corp.example", rtype::A),
+                ctx.clone(),
+            )
+            .await;
+        assert_eq!(response.id(), 9);
+        assert!(response.header().authoritative());
+
+        let response = pipeline
+            .handle(DnsMessage::query(10, "example.org", rtype::A), ctx)
+            .await;
+        assert!(!response.header().authoritative());
+        assert_eq!(response.answers().count(), 1);
+    }
+}
@@ -0,0 +1,383 @@
+//! DNS Stamps (`sdns://...`), the compact upstream descriptions of public resolver lists
+//!
+//! A stamp packs the transport, address, hostname, certificate hashes and properties of a
+//! resolver into one string, see <https://dnscrypt.info/stamps-specifications>. [`Stamp`] parses
+//! and prints them so an upstream can be configured by pasting its stamp.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::base64;
+use crate::error::DnsError;
+
+const SCHEME: &str = "sdns://";
+
+/// Transport a stamp describes, with its identifier byte
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Protocol {
+    /// Plain DNS over UDP/TCP (0x00)
+    Plain,
+    /// DNSCrypt (0x01)
+    DnsCrypt,
+    /// DNS over HTTPS (0x02)
+    Doh,
+    /// DNS over TLS (0x03)
+    Dot,
+    /// DNS over QUIC (0x04)
+    Doq,
+    /// Oblivious DoH target (0x05)
+    OdohTarget,
+    /// Anonymized DNSCrypt relay (0x81)
+    DnsCryptRelay,
+    /// Oblivious DoH relay (0x85)
+    OdohRelay,
+}
+
+impl Protocol {
+    fn from_id(id: u8) -> Option<Protocol> {
+        Some(match id {
+            0x00 => Protocol::Plain,
+            0x01 => Protocol::DnsCrypt,
+            0x02 => Protocol::Doh,
+            0x03 => Protocol::Dot,
+            0x04 => Protocol::Doq,
+            0x05 => Protocol::OdohTarget,
+            0x81 => Protocol::DnsCryptRelay,
+            0x85 => Protocol::OdohRelay,
+            _ => return None,
+        })
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Protocol::Plain => 0x00,
+            Protocol::DnsCrypt => 0x01,
+            Protocol::Doh => 0x02,
+            Protocol::Dot => 0x03,
+            Protocol::Doq => 0x04,
+            Protocol::OdohTarget => 0x05,
+            Protocol::DnsCryptRelay => 0x81,
+            Protocol::OdohRelay => 0x85,
+        }
+    }
+
+    /// Port used when the address of a stamp has none
+    pub fn default_port(self) -> u16 {
+        match self {
+            Protocol::Plain => 53,
+            Protocol::Dot | Protocol::Doq => 853,
+            _ => 443,
+        }
+    }
+}
+
+/// Property flags announced by the resolver
+pub mod props {
+    pub const DNSSEC: u64 = 1;
+    pub const NO_LOGS: u64 = 2;
+    pub const NO_FILTER: u64 = 4;
+}
+
+/// A decoded DNS stamp; fields a protocol does not carry are empty
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Stamp {
+    protocol: Protocol,
+    props: u64,
+    addr: String,
+    public_key: Vec<u8>,
+    provider_name: String,
+    hashes: Vec<Vec<u8>>,
+    hostname: String,
+    path: String,
+    bootstrap: Vec<String>,
+}
+
+impl Stamp {
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Flags from [`props`]
+    pub fn props(&self) -> u64 {
+        self.props
+    }
+
+    pub fn dnssec(&self) -> bool {
+        self.props & props::DNSSEC != 0
+    }
+
+    pub fn no_logs(&self) -> bool {
+        self.props & props::NO_LOGS != 0
+    }
+
+    pub fn no_filter(&self) -> bool {
+        self.props & props::NO_FILTER != 0
+    }
+
+    /// Address as written in the stamp, possibly empty for DoH whose hostname is resolved
+    pub fn addr_str(&self) -> &str {
+        &self.addr
+    }
+
+    /// Socket address of the resolver, with the protocol's default port if none is given;
+    /// `None` if the stamp only names the resolver by hostname
+    pub fn addr(&self) -> Option<SocketAddr> {
+        if self.addr.is_empty() {
+            return None;
+        }
+        if let Ok(addr) = self.addr.parse() {
+            return Some(addr);
+        }
+        let ip = self.addr.trim_start_matches('[').trim_end_matches(']');
+        let ip: IpAddr = ip.parse().ok()?;
+        Some(SocketAddr::new(ip, self.protocol.default_port()))
+    }
+
+    /// DNSCrypt provider public key
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// DNSCrypt provider name, e.g. `2.dnscrypt-cert.example.com`
+    pub fn provider_name(&self) -> &str {
+        &self.provider_name
+    }
+
+    /// SHA-256 digests of certificates in the resolver's chain, any of which must match
+    pub fn hashes(&self) -> &[Vec<u8>] {
+        &self.hashes
+    }
+
+    /// Server name for TLS and the HTTP `Host` header
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// HTTP path of DoH and ODoH stamps
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Resolvers to use for looking up the hostname
+    pub fn bootstrap(&self) -> &[String] {
+        &self.bootstrap
+    }
+}
+
+fn malformed(reason: &str) -> DnsError {
+    DnsError::Malformed(format!("DNS stamp {reason}"))
+}
+
+/// Reads the fields of a decoded stamp
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], DnsError> {
+        if self.0.len() < len {
+            return Err(malformed("cut short"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn props(&mut self) -> Result<u64, DnsError> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().expect("8 bytes")))
+    }
+
+    /// Length-prefixed bytes
+    fn lp(&mut self) -> Result<Vec<u8>, DnsError> {
+        let len = self.bytes(1)?[0] as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    fn lp_str(&mut self) -> Result<String, DnsError> {
+        String::from_utf8(self.lp()?).map_err(|_| malformed("has a non UTF-8 string"))
+    }
+
+    /// Variable-length set of length-prefixed bytes, the high bit of a length meaning more
+    /// items follow
+    fn vlp(&mut self) -> Result<Vec<Vec<u8>>, DnsError> {
+        let mut items = Vec::new();
+        loop {
+            let len = self.bytes(1)?[0];
+            items.push(self.bytes((len & 0x7F) as usize)?.to_vec());
+            if len & 0x80 == 0 {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn vlp_str(&mut self) -> Result<Vec<String>, DnsError> {
+        self.vlp()?
+            .into_iter()
+            .map(|item| String::from_utf8(item).map_err(|_| malformed("has a non UTF-8 string")))
+            .collect()
+    }
+
+    /// Optional trailing bootstrap resolvers
+    fn bootstrap(&mut self) -> Result<Vec<String>, DnsError> {
+        if self.0.is_empty() {
+            Ok(Vec::new())
+        } else {
+            self.vlp_str()
+        }
+    }
+}
+
+impl FromStr for Stamp {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| malformed("does not start with sdns://"))?;
+        let bytes = base64::decode_url(encoded).ok_or_else(|| malformed("is not base64url"))?;
+        let mut reader = Reader(&bytes);
+        let id = reader.bytes(1)?[0];
+        let protocol = Protocol::from_id(id).ok_or_else(|| malformed("has an unknown protocol"))?;
+
+        let mut stamp = Stamp {
+            protocol,
+            props: 0,
+            addr: String::new(),
+            public_key: Vec::new(),
+            provider_name: String::new(),
+            hashes: Vec::new(),
+            hostname: String::new(),
+            path: String::new(),
+            bootstrap: Vec::new(),
+        };
+        match protocol {
+            Protocol::Plain => {
+                stamp.props = reader.props()?;
+                stamp.addr = reader.lp_str()?;
+            }
+            Protocol::DnsCrypt => {
+                stamp.props = reader.props()?;
+                stamp.addr = reader.lp_str()?;
+                stamp.public_key = reader.lp()?;
+                stamp.provider_name = reader.lp_str()?;
+            }
+            Protocol::Doh | Protocol::OdohRelay => {
+                stamp.props = reader.props()?;
+                stamp.addr = reader.lp_str()?;
+                stamp.hashes = reader.vlp()?;
+                stamp.hostname = reader.lp_str()?;
+                stamp.path = reader.lp_str()?;
+                stamp.bootstrap = reader.bootstrap()?;
+            }
+            Protocol::Dot | Protocol::Doq => {
+                stamp.props = reader.props()?;
+                stamp.addr = reader.lp_str()?;
+                stamp.hashes = reader.vlp()?;
+                stamp.hostname = reader.lp_str()?;
+                stamp.bootstrap = reader.bootstrap()?;
+            }
+            Protocol::OdohTarget => {
+                stamp.props = reader.props()?;
+                stamp.hostname = reader.lp_str()?;
+                stamp.path = reader.lp_str()?;
+            }
+            Protocol::DnsCryptRelay => stamp.addr = reader.lp_str()?,
+        }
+        if !reader.0.is_empty() {
+            return Err(malformed("has trailing bytes"));
+        }
+        // an empty hash is how stamps without certificate pinning are written
+        stamp.hashes.retain(|hash| !hash.is_empty());
+        Ok(stamp)
+    }
+}
+
+fn push_lp(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(bytes);
+}
+
+fn push_vlp<T: AsRef<[u8]>>(out: &mut Vec<u8>, items: &[T]) {
+    if items.is_empty() {
+        out.push(0);
+    }
+    for (i, item) in items.iter().enumerate() {
+        let more = if i + 1 < items.len() { 0x80 } else { 0 };
+        out.push(item.as_ref().len() as u8 | more);
+        out.extend_from_slice(item.as_ref());
+    }
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = vec![self.protocol.id()];
+        if self.protocol != Protocol::DnsCryptRelay {
+            out.extend_from_slice(&self.props.to_le_bytes());
+        }
+        match self.protocol {
+            Protocol::Plain => push_lp(&mut out, self.addr.as_bytes()),
+            Protocol::DnsCrypt => {
+                push_lp(&mut out, self.addr.as_bytes());
+                push_lp(&mut out, &self.public_key);
+                push_lp(&mut out, self.provider_name.as_bytes());
+            }
+            Protocol::Doh | Protocol::OdohRelay | Protocol::Dot | Protocol::Doq => {
+                push_lp(&mut out, self.addr.as_bytes());
+                push_vlp(&mut out, &self.hashes);
+                push_lp(&mut out, self.hostname.as_bytes());
+                if matches!(self.protocol, Protocol::Doh | Protocol::OdohRelay) {
+                    push_lp(&mut out, self.path.as_bytes());
+                }
+                if !self.bootstrap.is_empty() {
+                    push_vlp(&mut out, &self.bootstrap);
+                }
+            }
+            Protocol::OdohTarget => {
+                push_lp(&mut out, self.hostname.as_bytes());
+                push_lp(&mut out, self.path.as_bytes());
+            }
+            Protocol::DnsCryptRelay => push_lp(&mut out, self.addr.as_bytes()),
+        }
+        write!(f, "{SCHEME}{}", base64::encode_url(&out))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_doh_stamp() {
+        let text = "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5";
+        let stamp: Stamp = text.parse().unwrap();
+        assert_eq!(stamp.protocol(), Protocol::Doh);
+        assert!(stamp.dnssec() && stamp.no_logs() && stamp.no_filter());
+        assert_eq!(stamp.addr(), Some("1.0.0.1:443".parse().unwrap()));
+        assert!(stamp.hashes().is_empty());
+        assert_eq!(stamp.hostname(), "dns.cloudflare.com");
+        assert_eq!(stamp.path(), "/dns-query");
+        assert_eq!(stamp.to_string(), text);
+    }
+
+    #[test]
+    fn test_dnscrypt_stamp() {
+        let text =
+            "sdns://AQcAAAAAAAAADjIxMi40Ny4yMjguMTM2IOgBuE6mBr-wusDOQ0RbsV66ZLAvo8SqMa4QY2oHk\
+                    DJNHzIuZG5zY3J5cHQtY2VydC5mci5kbnNjcnlwdC5vcmc";
+        let stamp: Stamp = text.parse().unwrap();
+        assert_eq!(stamp.protocol(), Protocol::DnsCrypt);
+        assert_eq!(stamp.addr(), Some("212.47.228.136:443".parse().unwrap()));
+        assert_eq!(stamp.public_key().len(), 32);
+        assert_eq!(stamp.provider_name(), "2.dnscrypt-cert.fr.dnscrypt.org");
+        assert_eq!(stamp.to_string(), text);
+
+        for bad in [
+            "https://example.com",
+            "sdns://!!",
+            "sdns://AAcAAAAAAAAA",
+            "sdns://CQ",
+        ] {
+            assert!(bad.parse::<Stamp>().is_err(), "{bad}");
+        }
+    }
+}
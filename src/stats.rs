@@ -0,0 +1,174 @@
+//! Per-client query counts
+//!
+//! [`ClientStatsLayer`] counts the queries of each client, as recorded by its
+//! [`Anonymizer`], for "top clients" reports.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::dns::DnsMessage;
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::privacy::{Anonymizer, Client};
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    count: u64,
+    last_seen: Instant,
+}
+
+/// Query counts by client, shared between the layer and whoever reports them
+#[derive(Debug, Default)]
+pub struct ClientStats {
+    counts: Mutex<HashMap<Client, Entry>>,
+    max_clients: Option<usize>,
+}
+
+impl ClientStats {
+    /// Stats of at most `max_clients` clients, forgetting the least recently seen one to make
+    /// room for a new one
+    pub fn with_capacity(max_clients: usize) -> Self {
+        Self {
+            counts: Mutex::default(),
+            max_clients: Some(max_clients),
+        }
+    }
+
+    pub fn record(&self, client: Client) {
+        let now = Instant::now();
+        let mut counts = self.counts.lock().unwrap();
+        let full = self.max_clients.is_some_and(|max| counts.len() >= max);
+        if full && !counts.contains_key(&client) {
+            let oldest = counts
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(client, _)| *client);
+            if let Some(oldest) = oldest {
+                counts.remove(&oldest);
+            }
+        }
+        let entry = counts.entry(client).or_insert(Entry {
+            count: 0,
+            last_seen: now,
+        });
+        entry.count += 1;
+        entry.last_seen = now;
+    }
+
+    /// The `n` clients with the most queries, busiest first
+    pub fn top(&self, n: usize) -> Vec<(Client, u64)> {
+        let mut counts: Vec<(Client, u64)> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(client, entry)| (*client, entry.count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+
+    /// Drops the clients not seen for `max_age`, returning how many were dropped
+    pub fn purge(&self, max_age: Duration) -> usize {
+        let now = Instant::now();
+        let mut counts = self.counts.lock().unwrap();
+        let before = counts.len();
+        counts.retain(|_, entry| now - entry.last_seen < max_age);
+        before - counts.len()
+    }
+
+    /// Drops everything recorded about `client`, returning whether there was anything
+    pub fn forget(&self, client: &Client) -> bool {
+        self.counts.lock().unwrap().remove(client).is_some()
+    }
+}
+
+/// Counts every query in [`ClientStats`]
+#[derive(Debug, Default)]
+pub struct ClientStatsLayer {
+    stats: Arc<ClientStats>,
+    anonymizer: Anonymizer,
+}
+
+impl ClientStatsLayer {
+    pub fn new(stats: Arc<ClientStats>) -> Self {
+        Self {
+            stats,
+            anonymizer: Anonymizer::off(),
+        }
+    }
+
+    /// How clients are recorded, as they are by default
+    pub fn anonymize(mut self, anonymizer: Anonymizer) -> Self {
+        self.anonymizer = anonymizer;
+        self
+    }
+}
+
+impl Layer for ClientStatsLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        self.stats.record(self.anonymizer.client(ctx.client().ip()));
+        next.run(query, ctx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::dns::rtype;
+    use crate::handler::{DefaultHandler, RequestHandler, Transport};
+    use crate::pipeline::Pipeline;
+
+    #[tokio::test]
+    async fn test_top_clients() {
+        let stats = Arc::new(ClientStats::default());
+        let layer = ClientStatsLayer::new(stats.clone()).anonymize(Anonymizer::truncate(24, 56));
+        let pipeline = Pipeline::new(DefaultHandler).layer(layer);
+
+        for client in [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 1, 1]] {
+            let ctx = RequestCtx::new(SocketAddr::from((client, 4000)), Transport::Udp);
+            pipeline
+                .handle(DnsMessage::query(1, "example.com", rtype::A), ctx)
+                .await;
+        }
+        let top: Vec<(String, u64)> = stats
+            .top(5)
+            .into_iter()
+            .map(|(client, count)| (client.to_string(), count))
+            .collect();
+        assert_eq!(
+            top,
+            [("10.0.0.0".to_string(), 2), ("10.0.1.0".to_string(), 1)]
+        );
+
+        assert!(stats.forget(&Client::Addr([10, 0, 0, 0].into())));
+        assert_eq!(stats.top(5).len(), 1);
+        assert_eq!(stats.purge(Duration::ZERO), 1);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let stats = ClientStats::with_capacity(2);
+        for last in [1, 2, 1, 3] {
+            stats.record(Client::Addr([10, 0, 0, last].into()));
+        }
+        // .2 was the least recently seen when .3 came
+        let clients: Vec<Client> = stats.top(5).into_iter().map(|(c, _)| c).collect();
+        assert_eq!(
+            clients,
+            [
+                Client::Addr([10, 0, 0, 1].into()),
+                Client::Addr([10, 0, 0, 3].into())
+            ]
+        );
+    }
+}
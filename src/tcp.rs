@@ -0,0 +1,229 @@
+//! TCP listener (RFC 7766)
+//!
+//! Every connection carries a sequence of messages, each preceded by its 2 byte length.
+//! Queries on one connection are answered in order; connections beyond `max_connections` are
+//! closed straight away, and idle ones after `idle_timeout`.
+//!
+//! Clients sending the edns-tcp-keepalive option (RFC 7828) are told the idle timeout of their
+//! connection in every response, and get the longer `keepalive_timeout`, since they reuse the
+//! connection rather than open one per query. When fewer than a tenth of the connections are
+//! free, responses advertise a timeout of 0 and the connection is closed once they are sent, to
+//! make room.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+use crate::dns::{edns_option, error_response, header_response, rcode, DnsMessage, ToBytes};
+use crate::error;
+use crate::handler::{RequestCtx, RequestHandler, Transport};
+use crate::tsig::{Keyring, Signed};
+use crate::warn;
+
+/// Tunables of the TCP server
+#[derive(Debug, Clone)]
+pub struct TcpOptions {
+    max_connections: usize,
+    idle_timeout: Duration,
+    keepalive_timeout: Duration,
+    query_timeout: Duration,
+    tsig: Option<Arc<Keyring>>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 1_000,
+            idle_timeout: Duration::from_secs(10),
+            keepalive_timeout: Duration::from_secs(120),
+            query_timeout: Duration::from_secs(5),
+            tsig: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Maximum number of connections open at once
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Time a connection may wait for its next query before it is closed
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Time a connection may wait for its next query once its client sent edns-tcp-keepalive,
+    /// which is advertised to it; 2 minutes by default
+    pub fn keepalive_timeout(mut self, keepalive_timeout: Duration) -> Self {
+        self.keepalive_timeout = keepalive_timeout;
+        self
+    }
+
+    /// Time a handler gets to answer before the client gets SERVFAIL instead
+    pub fn query_timeout(mut self, query_timeout: Duration) -> Self {
+        self.query_timeout = query_timeout;
+        self
+    }
+
+    /// Verifies the signatures of signed queries against `keyring`, answering those that fail
+    /// with NOTAUTH, and signs their responses; see [`crate::tsig`]
+    pub fn tsig(mut self, keyring: Arc<Keyring>) -> Self {
+        self.tsig = Some(keyring);
+        self
+    }
+}
+
+/// Serves connections accepted on `listener` with `handler` and default [`TcpOptions`]
+pub async fn run<H: RequestHandler>(listener: TcpListener, handler: H) {
+    run_with_options(listener, handler, TcpOptions::default()).await
+}
+
+/// Serves connections accepted on `listener` with `handler` until the task is dropped, each on
+/// its own task
+pub async fn run_with_options<H: RequestHandler>(
+    listener: TcpListener,
+    handler: H,
+    options: TcpOptions,
+) {
+    let handler = Arc::new(handler);
+    let connections = Arc::new(Semaphore::new(options.max_connections));
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("failed to accept connection with {err}");
+                continue;
+            }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            warn!("refused connection from {addr}, too many connections");
+            continue;
+        };
+        let handler = handler.clone();
+        let options = options.clone();
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            let served = serve_connection(stream, addr, handler.as_ref(), &options, &connections);
+            if let Err(err) = served.await {
+                warn!("connection from {addr} failed with {err}");
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn serve_connection<H: RequestHandler>(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    handler: &H,
+    options: &TcpOptions,
+    connections: &Semaphore,
+) -> io::Result<()> {
+    let mut out = Vec::with_capacity(512);
+    let mut keepalive = false;
+    let mut idle_timeout = options.idle_timeout;
+    loop {
+        let mut len = [0u8; 2];
+        match tokio::time::timeout(idle_timeout, stream.read_exact(&mut len)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(err)) => return Err(err),
+            // idle, the client reconnects when it has another query
+            Err(_) => return Ok(()),
+        }
+        let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+        tokio::time::timeout(options.idle_timeout, stream.read_exact(&mut msg))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "message not completed"))??;
+
+        let signed = match options.tsig.as_ref().map(|keyring| keyring.verify(&msg)) {
+            Some(Ok(signed)) => signed,
+            Some(Err(err)) => {
+                warn!("rejected query from {addr}: {err}");
+                let Some(response) = err.response(&msg) else {
+                    return Ok(());
+                };
+                out.clear();
+                out.extend_from_slice(&(response.len() as u16).to_be_bytes());
+                out.extend_from_slice(&response);
+                stream.write_all(&out).await?;
+                continue;
+            }
+            None => None,
+        };
+
+        let response = match DnsMessage::from_bytes(&msg) {
+            // clients only ask for keepalive, the timeout is the server's to give
+            Ok(query) if query_keepalive(&query).is_some_and(|data| !data.is_empty()) => {
+                error_response(&query, rcode::FORMERR)
+            }
+            Ok(query) => {
+                keepalive |= query_keepalive(&query).is_some();
+                let ctx = RequestCtx::new(addr, Transport::Tcp);
+                let ctx = match &signed {
+                    Some(signed) => ctx.signed_with(signed.key().name().clone()),
+                    None => ctx,
+                };
+                let handled = handler.handle(query.clone(), ctx);
+                match tokio::time::timeout(options.query_timeout, handled).await {
+                    Ok(response) => response,
+                    Err(_) => {
+                        warn!("query from {addr} timed out");
+                        error_response(&query, rcode::SERVFAIL)
+                    }
+                }
+            }
+            Err(err) => {
+                error!("failed to parse - '{err}'");
+                let Some(response) = header_response(&msg, rcode::FORMERR) else {
+                    return Ok(());
+                };
+                response
+            }
+        };
+        idle_timeout = if connections.available_permits() < options.max_connections / 10 {
+            Duration::ZERO
+        } else if keepalive {
+            options.keepalive_timeout
+        } else {
+            options.idle_timeout
+        };
+        let response = if keepalive {
+            let timeout = (idle_timeout.as_millis() / 100).min(u16::MAX.into()) as u16;
+            response.with_edns_option(edns_option::TCP_KEEPALIVE, timeout.to_be_bytes().to_vec())
+        } else {
+            response
+        };
+        let max_len = u16::MAX as usize - signed.as_ref().map_or(0, Signed::record_len);
+        let response = response.truncate(max_len);
+
+        out.clear();
+        out.extend_from_slice(&[0, 0]);
+        response.write_to(&mut out);
+        if let Some(signed) = &signed {
+            // the length prefix is not part of the signed message
+            let mut wire = out.split_off(2);
+            signed.sign(&mut wire);
+            out.append(&mut wire);
+        }
+        let len = (out.len() - 2) as u16;
+        out[..2].copy_from_slice(&len.to_be_bytes());
+        stream.write_all(&out).await?;
+        if idle_timeout.is_zero() {
+            return Ok(());
+        }
+    }
+}
+
+/// Data of the edns-tcp-keepalive option of `query`, if it has one
+fn query_keepalive(query: &DnsMessage) -> Option<&[u8]> {
+    query.edns()?.option(edns_option::TCP_KEEPALIVE)
+}
@@ -0,0 +1,761 @@
+//! Transaction signatures (TSIG, RFC 8945)
+//!
+//! A TSIG record, last in the additional section, carries an HMAC of the message made with a
+//! secret shared by client and server, and the time it was signed. The listeners check signed
+//! requests against a [`Keyring`] before handling them (see
+//! [`ServerOptions::tsig`](crate::server::ServerOptions::tsig) and
+//! [`TcpOptions::tsig`](crate::tcp::TcpOptions::tsig)): those that fail are answered with
+//! NOTAUTH, and the responses to those that pass are signed, their MAC covering the request's.
+//! The key a request was signed with is in its [`RequestCtx`], for [`TsigLayer`] to refuse
+//! unsigned zone transfers and dynamic updates.
+//!
+//! The MAC covers the message as it is on the wire, without the TSIG record, so signing is the
+//! last thing done to a response, after truncation.
+//!
+//! Keys are written the way `dig -y` takes them, `[algorithm:]name:secret` with the secret in
+//! base64. HMAC-SHA256 is the only algorithm.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::base64;
+use crate::canonical::canonical_name;
+use crate::dns::{
+    class, error_response, header_response, opcode, rcode, rtype, DnsLabels, DnsMessage, ToBytes,
+};
+use crate::error::DnsError;
+use crate::handler::RequestCtx;
+use crate::pipeline::{BoxFuture, Layer, Next};
+
+/// Seconds a signature's time may be off from the server's clock, as RFC 8945 recommends
+const FUDGE: u16 = 300;
+
+/// Errors of the TSIG record, the header carrying NOTAUTH
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+
+/// MAC algorithm of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+}
+
+impl Algorithm {
+    /// Name of the algorithm in TSIG records
+    pub fn name(&self) -> DnsLabels {
+        match self {
+            Algorithm::HmacSha256 => DnsLabels::from("hmac-sha256"),
+        }
+    }
+
+    fn mac(&self, secret: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Algorithm::HmacSha256 => hmac_sha256(secret, parts).to_vec(),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = DnsError;
+
+    fn from_str(text: &str) -> Result<Self, DnsError> {
+        match text.to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Ok(Algorithm::HmacSha256),
+            _ => Err(DnsError::Config(format!(
+                "unsupported TSIG algorithm {text}"
+            ))),
+        }
+    }
+}
+
+/// A shared secret, known by the name TSIG records give
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key {
+    name: DnsLabels,
+    algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for Key {
+    /// Leaves the secret out
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Key {
+    pub fn new(name: DnsLabels, algorithm: Algorithm, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            name,
+            algorithm,
+            secret: secret.into(),
+        }
+    }
+
+    pub fn name(&self) -> &DnsLabels {
+        &self.name
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Signs the request in `wire`, appending its TSIG record, and returns the MAC, which the
+    /// response's covers
+    pub fn sign(&self, wire: &mut Vec<u8>) -> Vec<u8> {
+        let tsig = Tsig::new(self, unix_time(), wire);
+        sign(self, wire, None, tsig)
+    }
+
+    /// Whether the response in `wire` is signed with this key, over `request_mac`
+    pub fn verify_response(&self, wire: &[u8], request_mac: &[u8]) -> bool {
+        let keys = std::slice::from_ref(self);
+        matches!(
+            verify(keys, wire, Some(request_mac), unix_time()),
+            Ok(Some(_))
+        )
+    }
+}
+
+impl FromStr for Key {
+    type Err = DnsError;
+
+    /// `[algorithm:]name:secret`, the secret in base64
+    fn from_str(text: &str) -> Result<Self, DnsError> {
+        let invalid =
+            || DnsError::Config("invalid TSIG key, expected [algorithm:]name:secret".to_string());
+        let mut fields = text.rsplitn(3, ':');
+        let (Some(secret), Some(name)) = (fields.next(), fields.next()) else {
+            return Err(invalid());
+        };
+        let algorithm = match fields.next() {
+            Some(algorithm) => algorithm.parse()?,
+            None => Algorithm::HmacSha256,
+        };
+        let name = name.parse().map_err(|_| invalid())?;
+        let secret = base64::decode(secret).ok_or_else(invalid)?;
+        Ok(Key::new(name, algorithm, secret))
+    }
+}
+
+/// The keys requests may be signed with
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(mut self, key: Key) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// Checks the signature of the request in `wire`: `None` if it is not signed. A request
+    /// that cannot be parsed counts as unsigned, for the listener to reject.
+    pub fn verify(&self, wire: &[u8]) -> Result<Option<Signed>, TsigError> {
+        verify(&self.keys, wire, None, unix_time())
+    }
+}
+
+impl FromIterator<Key> for Keyring {
+    fn from_iter<I: IntoIterator<Item = Key>>(iter: I) -> Self {
+        Self {
+            keys: iter.into_iter().collect(),
+        }
+    }
+}
+
+/// A request whose signature verified, whose response is to be signed
+#[derive(Debug, Clone)]
+pub struct Signed {
+    key: Key,
+    mac: Vec<u8>,
+}
+
+impl Signed {
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Bytes the TSIG record adds to the response
+    pub fn record_len(&self) -> usize {
+        // time, fudge, MAC size, original id, error and other length around the MAC
+        let rdata = self.key.algorithm.name().wire_len() + 16 + self.mac.len();
+        self.key.name.wire_len() + 10 + rdata
+    }
+
+    /// Signs `response`, the answer to the request, appending its TSIG record
+    pub fn sign(&self, response: &mut Vec<u8>) {
+        self.sign_with(response, unix_time(), 0, Vec::new());
+    }
+
+    fn sign_with(&self, response: &mut Vec<u8>, time: u64, error: u16, other: Vec<u8>) {
+        let tsig = Tsig {
+            error,
+            other,
+            ..Tsig::new(&self.key, time, response)
+        };
+        sign(&self.key, response, Some(&self.mac), tsig);
+    }
+}
+
+/// Why a signed request is rejected
+#[derive(Debug, Clone)]
+pub enum TsigError {
+    /// The TSIG record is not alone, not last or cannot be parsed
+    Malformed,
+    /// No key has the name and algorithm of the record
+    BadKey {
+        name: DnsLabels,
+        algorithm: DnsLabels,
+    },
+    /// The MAC does not verify
+    BadSig {
+        name: DnsLabels,
+        algorithm: DnsLabels,
+    },
+    /// Signed at `time`, too far from now
+    BadTime { signed: Signed, time: u64 },
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsigError::Malformed => write!(f, "malformed TSIG record"),
+            TsigError::BadKey { name, algorithm } => {
+                write!(f, "unknown TSIG key {name} ({algorithm})")
+            }
+            TsigError::BadSig { name, .. } => {
+                write!(f, "TSIG signature with {name} does not verify")
+            }
+            TsigError::BadTime { signed, time } => {
+                write!(
+                    f,
+                    "TSIG signature with {} made at {time}, too far from now",
+                    signed.key.name
+                )
+            }
+        }
+    }
+}
+
+impl TsigError {
+    /// Answer to the request in `query`: FORMERR for a malformed record, NOTAUTH with the
+    /// error in an unsigned TSIG record otherwise, or a signed one for a clock out of range
+    pub fn response(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (name, algorithm, error) = match self {
+            TsigError::Malformed => {
+                return header_response(query, rcode::FORMERR).map(|r| r.to_bytes())
+            }
+            TsigError::BadKey { name, algorithm } => (name, algorithm, BADKEY),
+            TsigError::BadSig { name, algorithm } => (name, algorithm, BADSIG),
+            TsigError::BadTime { signed, time } => {
+                let request = DnsMessage::from_bytes(query).ok()?;
+                let mut wire = error_response(&request, rcode::NOTAUTH).to_bytes();
+                // the server's clock, for the client to see how far off it is
+                let now = unix_time().to_be_bytes()[2..].to_vec();
+                signed.sign_with(&mut wire, *time, BADTIME, now);
+                return Some(wire);
+            }
+        };
+        let request = DnsMessage::from_bytes(query).ok()?;
+        let mut wire = error_response(&request, rcode::NOTAUTH).to_bytes();
+        let tsig = Tsig {
+            algorithm: algorithm.clone(),
+            time: unix_time(),
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: request.id(),
+            error,
+            other: Vec::new(),
+        };
+        append(&mut wire, name, &tsig);
+        Some(wire)
+    }
+}
+
+/// Refuses zone transfers and dynamic updates that were not signed with a key of the
+/// listener's [`Keyring`]
+#[derive(Debug, Clone, Default)]
+pub struct TsigLayer;
+
+impl Layer for TsigLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let transfer = query
+                .questions()
+                .any(|question| matches!(question.qtype(), rtype::AXFR | rtype::IXFR));
+            let update = query.header().opcode() == opcode::UPDATE;
+            if (transfer || update) && ctx.tsig_key().is_none() {
+                return error_response(&query, rcode::REFUSED);
+            }
+            next.run(query, ctx).await
+        })
+    }
+}
+
+/// Fields of a TSIG record
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tsig {
+    algorithm: DnsLabels,
+    /// Seconds since the epoch, 48 bits on the wire
+    time: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl Tsig {
+    /// Record to sign `wire` with `key` at `time`
+    fn new(key: &Key, time: u64, wire: &[u8]) -> Tsig {
+        Tsig {
+            algorithm: key.algorithm.name(),
+            time,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([wire[0], wire[1]]),
+            error: 0,
+            other: Vec::new(),
+        }
+    }
+
+    fn parse(data: &[u8]) -> Option<Tsig> {
+        let mut labels = Vec::new();
+        let mut at = 0;
+        loop {
+            let len = usize::from(*data.get(at)?);
+            at += 1;
+            if len == 0 {
+                break;
+            }
+            labels.push(data.get(at..at + len)?);
+            at += len;
+        }
+        let algorithm = DnsLabels::new(labels).ok()?;
+        let mut take = |n: usize| {
+            let field = data.get(at..at + n)?;
+            at += n;
+            Some(field)
+        };
+        let number = |field: &[u8]| field.iter().fold(0u64, |n, &b| n << 8 | u64::from(b));
+        let time = number(take(6)?);
+        let fudge = number(take(2)?) as u16;
+        let mac_len = number(take(2)?) as usize;
+        let mac = take(mac_len)?.to_vec();
+        let original_id = number(take(2)?) as u16;
+        let error = number(take(2)?) as u16;
+        let other_len = number(take(2)?) as usize;
+        let other = take(other_len)?.to_vec();
+        (at == data.len()).then_some(Tsig {
+            algorithm,
+            time,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    fn rdata(&self) -> Vec<u8> {
+        let mut rdata = self.algorithm.to_bytes();
+        rdata.extend_from_slice(&self.time.to_be_bytes()[2..]);
+        rdata.extend_from_slice(&self.fudge.to_be_bytes());
+        rdata.extend_from_slice(&(self.mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&self.mac);
+        rdata.extend_from_slice(&self.original_id.to_be_bytes());
+        rdata.extend_from_slice(&self.error.to_be_bytes());
+        rdata.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&self.other);
+        rdata
+    }
+
+    /// TSIG variables the MAC covers after the message (RFC 8945 section 4.3.3)
+    fn variables(&self, key_name: &DnsLabels) -> Vec<u8> {
+        let mut variables = canonical_name(key_name).to_bytes();
+        variables.extend_from_slice(&class::ANY.to_be_bytes());
+        variables.extend_from_slice(&0u32.to_be_bytes());
+        variables.extend(canonical_name(&self.algorithm).to_bytes());
+        variables.extend_from_slice(&self.time.to_be_bytes()[2..]);
+        variables.extend_from_slice(&self.fudge.to_be_bytes());
+        variables.extend_from_slice(&self.error.to_be_bytes());
+        variables.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        variables.extend_from_slice(&self.other);
+        variables
+    }
+
+    /// MAC of `message`, without its TSIG record, preceded by the request MAC for a response
+    fn mac(
+        &self,
+        key: &Key,
+        key_name: &DnsLabels,
+        prior: Option<&[u8]>,
+        message: &[u8],
+    ) -> Vec<u8> {
+        let variables = self.variables(key_name);
+        match prior {
+            Some(prior) => {
+                let prior_len = (prior.len() as u16).to_be_bytes();
+                key.algorithm
+                    .mac(&key.secret, &[&prior_len, prior, message, &variables])
+            }
+            None => key.algorithm.mac(&key.secret, &[message, &variables]),
+        }
+    }
+}
+
+/// Fills in the MAC of `tsig` and appends it to `wire`; returns the MAC
+fn sign(key: &Key, wire: &mut Vec<u8>, prior: Option<&[u8]>, mut tsig: Tsig) -> Vec<u8> {
+    tsig.mac = tsig.mac(key, &key.name, prior, wire);
+    append(wire, &key.name, &tsig);
+    tsig.mac
+}
+
+/// Appends the TSIG record to `wire`, counting it in the header
+fn append(wire: &mut Vec<u8>, key_name: &DnsLabels, tsig: &Tsig) {
+    let rdata = tsig.rdata();
+    key_name.write_to(wire);
+    wire.extend_from_slice(&rtype::TSIG.to_be_bytes());
+    wire.extend_from_slice(&class::ANY.to_be_bytes());
+    wire.extend_from_slice(&0u32.to_be_bytes());
+    wire.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    wire.extend(rdata);
+    let arcount = u16::from_be_bytes([wire[10], wire[11]]) + 1;
+    wire[10..12].copy_from_slice(&arcount.to_be_bytes());
+}
+
+/// Checks the TSIG record of `wire` against `keys`, with the request MAC `prior` for a response
+fn verify(
+    keys: &[Key],
+    wire: &[u8],
+    prior: Option<&[u8]>,
+    now: u64,
+) -> Result<Option<Signed>, TsigError> {
+    let Ok(message) = DnsMessage::from_bytes(wire) else {
+        return Ok(None);
+    };
+    let mut records = message
+        .additionals()
+        .filter(|record| record.record_type() == rtype::TSIG);
+    let Some(record) = records.next() else {
+        return Ok(None);
+    };
+    let start = last_record(wire).ok_or(TsigError::Malformed)?;
+    let last_type = skip_name(wire, start).and_then(|at| wire.get(at..at + 2));
+    if records.next().is_some() || last_type != Some(&rtype::TSIG.to_be_bytes()[..]) {
+        return Err(TsigError::Malformed);
+    }
+    let tsig = Tsig::parse(record.data()).ok_or(TsigError::Malformed)?;
+
+    let name = record.name();
+    let Some(key) = keys
+        .iter()
+        .find(|key| key.name == *name && key.algorithm.name() == tsig.algorithm)
+    else {
+        return Err(TsigError::BadKey {
+            name: name.clone(),
+            algorithm: tsig.algorithm,
+        });
+    };
+    // the message as it was before the record was added
+    let mut unsigned = wire[..start].to_vec();
+    unsigned[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    let arcount = u16::from_be_bytes([unsigned[10], unsigned[11]]) - 1;
+    unsigned[10..12].copy_from_slice(&arcount.to_be_bytes());
+    let expected = tsig.mac(key, name, prior, &unsigned);
+    if !constant_time_eq(&expected, &tsig.mac) {
+        return Err(TsigError::BadSig {
+            name: name.clone(),
+            algorithm: tsig.algorithm,
+        });
+    }
+
+    let signed = Signed {
+        key: key.clone(),
+        mac: tsig.mac,
+    };
+    if now.abs_diff(tsig.time) > u64::from(tsig.fudge) {
+        return Err(TsigError::BadTime {
+            signed,
+            time: tsig.time,
+        });
+    }
+    Ok(Some(signed))
+}
+
+/// Offset of the last record of the message in `wire`
+fn last_record(wire: &[u8]) -> Option<usize> {
+    let count = |at: usize| {
+        Some(usize::from(u16::from_be_bytes([
+            *wire.get(at)?,
+            *wire.get(at + 1)?,
+        ])))
+    };
+    let mut at = 12;
+    for _ in 0..count(4)? {
+        at = skip_name(wire, at)? + 4;
+    }
+    let mut last = None;
+    for _ in 0..count(6)? + count(8)? + count(10)? {
+        last = Some(at);
+        let end = skip_name(wire, at)?;
+        at = end + 10 + count(end + 8)?;
+    }
+    last.filter(|_| at <= wire.len())
+}
+
+/// Offset right after the name at `at`, which may end with a compression pointer
+fn skip_name(wire: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *wire.get(at)?;
+        match len {
+            0 => return Some(at + 1),
+            1..=63 => at += 1 + usize::from(len),
+            _ if len & 0xC0 == 0xC0 => return Some(at + 2),
+            _ => return None,
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// HMAC (RFC 2104) of the concatenated `parts`
+fn hmac_sha256(secret: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut key = [0u8; 64];
+    if secret.len() > key.len() {
+        key[..32].copy_from_slice(&Sha256::digest(&[secret]));
+    } else {
+        key[..secret.len()].copy_from_slice(secret);
+    }
+    let mut inner = Sha256::new();
+    inner.update(&key.map(|b| b ^ 0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    let inner = inner.finish();
+    Sha256::digest(&[&key.map(|b| b ^ 0x5c), &inner])
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 (FIPS 180-4), fed a piece at a time
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    filled: usize,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            filled: 0,
+            len: 0,
+        }
+    }
+
+    fn digest(parts: &[&[u8]]) -> [u8; 32] {
+        let mut sha = Sha256::new();
+        for part in parts {
+            sha.update(part);
+        }
+        sha.finish()
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let n = data.len().min(64 - self.filled);
+            self.block[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+            if self.filled == 64 {
+                self.compress();
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.len * 8;
+        self.update(&[0x80]);
+        while self.filled != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0u8; 32];
+        for (out, word) in digest.chunks_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(self.block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (k, w) in K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(*k)
+                .wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        assert_eq!(
+            hex(&Sha256::digest(&[b"abc"])),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let long = vec![b'a'; 1000];
+        assert_eq!(
+            hex(&Sha256::digest(&[&long[..500], &long[500..]])),
+            hex(&Sha256::digest(&[&long]))
+        );
+        // RFC 4231 test cases 2 and 6
+        assert_eq!(
+            hex(&hmac_sha256(
+                b"Jefe",
+                &[b"what do ya want ", b"for nothing?"]
+            )),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                &[b"Test Using Larger Than Block-Size Key - Hash Key First"]
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key: Key = "hmac-sha256:xfr.example.:c2VjcmV0LXNoYXJlZC1ieS1ib3Ro"
+            .parse()
+            .unwrap();
+        assert_eq!(key.name(), &DnsLabels::from("xfr.example"));
+        let keyring = Keyring::new().key(key.clone());
+
+        let query = DnsMessage::query(7, "example.com", rtype::AXFR);
+        let mut wire = query.to_bytes();
+        assert!(keyring.verify(&wire).unwrap().is_none());
+        let request_mac = key.sign(&mut wire);
+        let signed = keyring.verify(&wire).unwrap().unwrap();
+        assert_eq!(signed.key(), &key);
+        assert_eq!(
+            DnsMessage::from_bytes(&wire).unwrap().additionals().len(),
+            1
+        );
+
+        let mut response = error_response(&query, rcode::REFUSED).to_bytes();
+        let unsigned_len = response.len();
+        signed.sign(&mut response);
+        assert_eq!(response.len() - unsigned_len, signed.record_len());
+        assert!(key.verify_response(&response, &request_mac));
+        assert!(!key.verify_response(&response, &[0; 32]));
+
+        // a flipped bit, another secret, a stale signature
+        let mut tampered = wire.clone();
+        tampered[14] ^= 0x20;
+        assert!(matches!(
+            keyring.verify(&tampered),
+            Err(TsigError::BadSig { .. })
+        ));
+        let other = Keyring::new().key(Key::new(key.name().clone(), Algorithm::HmacSha256, *b"x"));
+        assert!(matches!(other.verify(&wire), Err(TsigError::BadSig { .. })));
+        let stale = verify(keyring.keys(), &wire, None, unix_time() + 3600);
+        assert!(matches!(stale, Err(TsigError::BadTime { .. })));
+        let rejection = stale.unwrap_err().response(&wire).unwrap();
+        let rejection = DnsMessage::from_bytes(&rejection).unwrap();
+        assert_eq!(rejection.rcode(), rcode::NOTAUTH);
+
+        let unknown = Keyring::new().verify(&wire).unwrap_err();
+        let rejection = DnsMessage::from_bytes(&unknown.response(&wire).unwrap()).unwrap();
+        let tsig = rejection.additionals().next().unwrap();
+        assert_eq!(Tsig::parse(tsig.data()).unwrap().error, BADKEY);
+
+        assert!("xfr.example".parse::<Key>().is_err());
+        assert!("hmac-md5:xfr.example:c2VjcmV0".parse::<Key>().is_err());
+    }
+}
@@ -0,0 +1,576 @@
+//! RFC 1035 master files ("zone files")
+//!
+//! [`Zone::parse`] reads the text format BIND and most authoritative servers load their data
+//! from: one record per entry, `owner TTL class type rdata`, the owner, TTL and class being
+//! optional and carried over from the entries before. `$ORIGIN` and `$TTL` directives, `;`
+//! comments and parentheses continuing an entry over several lines are understood. Names
+//! without a trailing dot are relative to the origin, `@` is the origin itself.
+//!
+//! Types with an [`RData`] variant are written in their usual presentation format; any type
+//! can also be given in the RFC 3597 generic form, e.g. `TYPE65534 \# 3 0a0b0c`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::dns::{class, rtype, DnsLabels, DnsRecord, ToBytes};
+use crate::error::DnsError;
+use crate::rdata::RData;
+use crate::records::Records;
+
+/// The records of a zone, as read from its master file
+#[derive(Debug, Clone)]
+pub struct Zone {
+    origin: DnsLabels,
+    soa: DnsRecord,
+    records: Records,
+    /// Owners of records and the names between them and the origin
+    names: HashSet<DnsLabels>,
+}
+
+impl Zone {
+    /// Parses the master file `text`, with names relative to `origin` until an `$ORIGIN`
+    /// directive says otherwise. The zone is the one named by the owner of its SOA record,
+    /// which must be there, and every record must belong to it.
+    pub fn parse(text: &str, origin: DnsLabels) -> Result<Zone, DnsError> {
+        let mut parser = Parser {
+            origin,
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+            last_class: class::IN,
+        };
+        let mut records = Vec::new();
+        for entry in entries(text)? {
+            let line = entry.line;
+            if let Some(record) = parser
+                .entry(entry)
+                .map_err(|message| error(line, message))?
+            {
+                records.push(record);
+            }
+        }
+
+        let mut soas = records
+            .iter()
+            .filter(|record| record.record_type() == rtype::SOA);
+        let soa = match (soas.next(), soas.next()) {
+            (Some(soa), None) => soa.clone(),
+            (None, _) => return Err(error(0, "no SOA record".to_string())),
+            (Some(_), Some(_)) => return Err(error(0, "more than one SOA record".to_string())),
+        };
+        let origin = soa.name().clone();
+        if let Some(outside) = records.iter().find(|r| !r.name().is_subdomain_of(&origin)) {
+            let message = format!("{} is outside of zone {origin}", outside.name());
+            return Err(error(0, message));
+        }
+
+        let mut names = HashSet::new();
+        for record in &records {
+            let labels: Vec<&[u8]> = record.name().labels().collect();
+            for skip in 0..=labels.len() - origin.label_count() {
+                let name = DnsLabels::new(&labels[skip..]).expect("suffix of a valid name");
+                if !names.insert(name) {
+                    break;
+                }
+            }
+        }
+        Ok(Zone {
+            origin,
+            soa,
+            records: records.into_iter().collect(),
+            names,
+        })
+    }
+
+    /// Reads and parses the master file at `path`, see [`Zone::parse`]
+    pub fn read(path: impl AsRef<Path>, origin: DnsLabels) -> Result<Zone, DnsError> {
+        Zone::parse(&fs::read_to_string(path)?, origin)
+    }
+
+    /// Name of the zone, the owner of its SOA record
+    pub fn origin(&self) -> &DnsLabels {
+        &self.origin
+    }
+
+    pub fn records(&self) -> &Records {
+        &self.records
+    }
+
+    /// The SOA record at the apex of the zone
+    pub fn soa(&self) -> &DnsRecord {
+        &self.soa
+    }
+
+    /// Whether `name` exists in the zone: it owns records, or names below it do (an "empty
+    /// non-terminal")
+    pub fn contains(&self, name: &DnsLabels) -> bool {
+        self.names.contains(name)
+    }
+}
+
+fn error(line: usize, message: String) -> DnsError {
+    DnsError::Zone(if line == 0 {
+        message
+    } else {
+        format!("line {line}: {message}")
+    })
+}
+
+#[derive(Debug)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// Tokens of one entry, which parentheses may spread over several lines
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    /// The entry starts with blank space: the owner is the previous one
+    same_owner: bool,
+    tokens: Vec<Token>,
+}
+
+fn entries(text: &str) -> Result<Vec<Entry>, DnsError> {
+    let mut entries = Vec::new();
+    let mut entry = Entry {
+        line: 1,
+        same_owner: false,
+        tokens: Vec::new(),
+    };
+    let (mut line, mut parens, mut line_start) = (1, 0usize, true);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                line += 1;
+                if parens == 0 {
+                    let next = Entry {
+                        line,
+                        same_owner: false,
+                        tokens: Vec::new(),
+                    };
+                    let done = std::mem::replace(&mut entry, next);
+                    if !done.tokens.is_empty() {
+                        entries.push(done);
+                    }
+                    line_start = true;
+                    continue;
+                }
+            }
+            ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' => parens += 1,
+            ')' => {
+                parens = parens
+                    .checked_sub(1)
+                    .ok_or_else(|| error(line, "unbalanced )".to_string()))?;
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            text.extend(chars.next());
+                        }
+                        Some(c) => {
+                            line += usize::from(c == '\n');
+                            text.push(c);
+                        }
+                        None => return Err(error(line, "unterminated string".to_string())),
+                    }
+                }
+                entry.tokens.push(Token { text, quoted: true });
+            }
+            c if c.is_whitespace() => {
+                entry.same_owner |= line_start && parens == 0 && entry.tokens.is_empty();
+            }
+            c => {
+                let mut text = String::from(c);
+                if c == '\\' {
+                    text.extend(chars.next());
+                }
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !matches!(c, ';' | '(' | ')' | '"'))
+                {
+                    text.push(c);
+                    if c == '\\' {
+                        text.extend(chars.next());
+                    }
+                }
+                entry.tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+        line_start = false;
+    }
+    if parens > 0 {
+        return Err(error(line, "unbalanced (".to_string()));
+    }
+    if !entry.tokens.is_empty() {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Bytes of a character-string, with `\X` and `\DDD` escapes resolved
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [a, b, c, tail @ ..] if [a, b, c].iter().all(|d| d.is_ascii_digit()) => {
+                let value =
+                    u32::from(a - b'0') * 100 + u32::from(b - b'0') * 10 + u32::from(c - b'0');
+                bytes.push(u8::try_from(value).map_err(|_| format!("invalid escape \\{value}"))?);
+                rest = tail;
+            }
+            [escaped, tail @ ..] => {
+                bytes.push(*escaped);
+                rest = tail;
+            }
+            [] => return Err("dangling \\".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Seconds in `text`, either a number or BIND-style units such as `1h30m`
+fn ttl(text: &str) -> Option<u32> {
+    if let Ok(secs) = text.parse() {
+        return Some(secs);
+    }
+    let mut total: u32 = 0;
+    let mut digits = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            'w' => 604_800,
+            _ => return None,
+        };
+        let value: u32 = std::mem::take(&mut digits).parse().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+    }
+    digits.is_empty().then_some(total)
+}
+
+fn class_code(text: &str) -> Option<u16> {
+    match text.to_ascii_uppercase().as_str() {
+        "IN" => Some(class::IN),
+        "CH" => Some(class::CH),
+        "CS" => Some(2),
+        "HS" => Some(4),
+        other => other.strip_prefix("CLASS")?.parse().ok(),
+    }
+}
+
+fn type_code(text: &str) -> Option<u16> {
+    let code = match text.to_ascii_uppercase().as_str() {
+        "A" => rtype::A,
+        "NS" => rtype::NS,
+        "CNAME" => rtype::CNAME,
+        "SOA" => rtype::SOA,
+        "PTR" => rtype::PTR,
+        "MX" => rtype::MX,
+        "TXT" => rtype::TXT,
+        "AAAA" => rtype::AAAA,
+        "SRV" => rtype::SRV,
+        "CAA" => rtype::CAA,
+        other => return other.strip_prefix("TYPE")?.parse().ok(),
+    };
+    Some(code)
+}
+
+struct Parser {
+    origin: DnsLabels,
+    default_ttl: Option<u32>,
+    last_owner: Option<DnsLabels>,
+    last_ttl: Option<u32>,
+    last_class: u16,
+}
+
+impl Parser {
+    /// Absolute form of `text`, a name relative to the origin unless it ends with a dot
+    fn name(&self, text: &str) -> Result<DnsLabels, String> {
+        let invalid = |err: DnsError| format!("invalid name {text}: {err}");
+        if text == "@" {
+            Ok(self.origin.clone())
+        } else if text.ends_with('.') {
+            text.parse().map_err(invalid)
+        } else if text.contains('\\') {
+            Err(format!("escapes in names are not supported: {text}"))
+        } else {
+            format!("{text}.{}", self.origin).parse().map_err(invalid)
+        }
+    }
+
+    /// Record of `entry`, `None` for directives
+    fn entry(&mut self, entry: Entry) -> Result<Option<DnsRecord>, String> {
+        let mut tokens = entry.tokens.iter().map(|token| token.text.as_str());
+        if !entry.same_owner {
+            if let Some(directive) = entry.tokens[0].text.strip_prefix('$') {
+                tokens.next();
+                let value = tokens.next().ok_or(format!("${directive} needs a value"))?;
+                match directive.to_ascii_uppercase().as_str() {
+                    "ORIGIN" => self.origin = self.name(value)?,
+                    "TTL" => self.default_ttl = Some(ttl(value).ok_or("invalid $TTL")?),
+                    _ => return Err(format!("unsupported directive ${directive}")),
+                }
+                return Ok(None);
+            }
+        }
+
+        let owner = if entry.same_owner {
+            self.last_owner.clone().ok_or("no previous owner")?
+        } else {
+            self.name(tokens.next().unwrap_or_default())?
+        };
+        let (mut ttl_field, mut class_field) = (None, None);
+        let record_type = loop {
+            let token = tokens.next().ok_or("missing type")?;
+            if let (None, Some(value)) = (ttl_field, ttl(token)) {
+                ttl_field = Some(value);
+            } else if let (None, Some(value)) = (class_field, class_code(token)) {
+                class_field = Some(value);
+            } else {
+                break type_code(token).ok_or(format!("unknown type {token}"))?;
+            }
+        };
+        let ttl = ttl_field
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or("no TTL and no $TTL before")?;
+        let class = class_field.unwrap_or(self.last_class);
+
+        let rdata_tokens = &entry.tokens[entry.tokens.len() - tokens.count()..];
+        let data = self.rdata(record_type, rdata_tokens)?;
+        self.last_owner = Some(owner.clone());
+        self.last_ttl = Some(ttl);
+        self.last_class = class;
+        Ok(Some(DnsRecord::new(owner, record_type, class, ttl, data)))
+    }
+
+    /// Wire form RDATA of a `record_type` record written as `tokens`
+    fn rdata(&self, record_type: u16, tokens: &[Token]) -> Result<Vec<u8>, String> {
+        if tokens.first().is_some_and(|t| t.text == "\\#" && !t.quoted) {
+            return generic(&tokens[1..]);
+        }
+        let texts: Vec<&str> = tokens.iter().map(|token| token.text.as_str()).collect();
+        let expect = |count: usize| {
+            if texts.len() == count {
+                Ok(())
+            } else {
+                Err(format!(
+                    "expected {count} rdata fields, got {}",
+                    texts.len()
+                ))
+            }
+        };
+        let rdata = match record_type {
+            rtype::A => {
+                expect(1)?;
+                RData::A(number::<Ipv4Addr>(texts[0])?)
+            }
+            rtype::AAAA => {
+                expect(1)?;
+                RData::Aaaa(number::<Ipv6Addr>(texts[0])?)
+            }
+            rtype::NS | rtype::CNAME | rtype::PTR => {
+                expect(1)?;
+                let name = self.name(texts[0])?;
+                match record_type {
+                    rtype::NS => RData::Ns(name),
+                    rtype::CNAME => RData::Cname(name),
+                    _ => RData::Ptr(name),
+                }
+            }
+            rtype::MX => {
+                expect(2)?;
+                RData::Mx {
+                    preference: number(texts[0])?,
+                    exchange: self.name(texts[1])?,
+                }
+            }
+            rtype::TXT => {
+                if texts.is_empty() {
+                    return Err("TXT needs at least one string".to_string());
+                }
+                RData::Txt(
+                    texts
+                        .iter()
+                        .map(|text| unescape(text))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            rtype::SOA => {
+                expect(7)?;
+                let field = |text: &str| ttl(text).ok_or(format!("invalid SOA field {text}"));
+                RData::Soa {
+                    mname: self.name(texts[0])?,
+                    rname: self.name(texts[1])?,
+                    serial: number(texts[2])?,
+                    refresh: field(texts[3])?,
+                    retry: field(texts[4])?,
+                    expire: field(texts[5])?,
+                    minimum: field(texts[6])?,
+                }
+            }
+            rtype::SRV => {
+                expect(4)?;
+                RData::Srv {
+                    priority: number(texts[0])?,
+                    weight: number(texts[1])?,
+                    port: number(texts[2])?,
+                    target: self.name(texts[3])?,
+                }
+            }
+            rtype::CAA => {
+                expect(3)?;
+                RData::Caa {
+                    flags: number(texts[0])?,
+                    tag: texts[1].to_string(),
+                    value: unescape(texts[2])?,
+                }
+            }
+            other => return Err(format!("type {other} needs the \\# generic form")),
+        };
+        Ok(rdata.to_bytes())
+    }
+}
+
+fn number<T: FromStr>(text: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("invalid value {text}"))
+}
+
+/// RDATA in the RFC 3597 form `<length> <hex>...`, after the `\#`
+fn generic(tokens: &[Token]) -> Result<Vec<u8>, String> {
+    let (len, hex) = tokens.split_first().ok_or("\\# needs a length")?;
+    let len: usize = number(&len.text)?;
+    let hex: String = hex.iter().map(|token| token.text.as_str()).collect();
+    let data = hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2);
+            pair.and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex data {hex}"))
+        })
+        .collect::<Result<Vec<u8>, _>>()?;
+    if data.len() != len {
+        return Err(format!("\\# length {len} but {} bytes of data", data.len()));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                2h 1h 2w
+                300 )
+        IN  NS  ns1
+        IN  MX  10 mail
+ns1         A   192.0.2.1
+mail   300  A   192.0.2.2
+            AAAA 2001:db8::2
+www         CNAME @
+@           TXT "v=spf1 mx -all" "say \"hi\"\059"
+_sip._udp   SRV 10 5 5060 sip.example.com.
+@           CAA 0 issue "letsencrypt.org"
+opaque      TYPE65534 \# 3 0a0B0c
+"#;
+
+    #[test]
+    fn test_parse() {
+        let zone = Zone::parse(EXAMPLE, DnsLabels::from(".")).unwrap();
+        assert_eq!(zone.origin().to_string(), "example.com");
+        assert_eq!(zone.records().iter().count(), 11);
+
+        let rdata = |name: &str, record_type| {
+            let record = zone
+                .records()
+                .lookup(&name.into(), record_type, class::IN)
+                .next()
+                .unwrap();
+            (record.ttl(), record.rdata().unwrap())
+        };
+        assert_eq!(
+            rdata("example.com", rtype::SOA),
+            (
+                3600,
+                RData::Soa {
+                    mname: "ns1.example.com".into(),
+                    rname: "hostmaster.example.com".into(),
+                    serial: 2024010101,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum: 300,
+                }
+            )
+        );
+        // the owner carries over to the next entry, an explicit TTL does not
+        assert_eq!(
+            rdata("mail.example.com", rtype::AAAA),
+            (3600, RData::Aaaa("2001:db8::2".parse().unwrap()))
+        );
+        assert_eq!(
+            rdata("www.example.com", rtype::CNAME).1,
+            RData::Cname("example.com".into())
+        );
+        assert_eq!(
+            rdata("example.com", rtype::TXT).1,
+            RData::Txt(vec![b"v=spf1 mx -all".to_vec(), b"say \"hi\";".to_vec()])
+        );
+        assert_eq!(
+            rdata("opaque.example.com", 65534).1,
+            RData::Unknown(65534, vec![10, 11, 12])
+        );
+
+        // _udp.example.com owns nothing but exists, other.example.com does not
+        assert!(zone.contains(&"_udp.example.com".into()));
+        assert!(!zone.contains(&"other.example.com".into()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let soa = "@ 3600 IN SOA ns1 hostmaster 1 2 3 4 5\n";
+        let parse = |text: &str| {
+            Zone::parse(text, "example.com".into())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(parse("").contains("no SOA record"));
+        assert!(parse(&format!("{soa}www A 192.0.2.300\n")).contains("line 2"));
+        assert!(parse(&format!("{soa}www A (192.0.2.1\n")).contains("unbalanced ("));
+        assert!(parse(&format!("{soa}www.example.org. A 192.0.2.1\n")).contains("outside"));
+        assert!(parse("www A 192.0.2.1\n").contains("no TTL"));
+        assert!(parse(&format!("$INCLUDE other.zone\n{soa}")).contains("$INCLUDE"));
+    }
+}
@@ -0,0 +1,410 @@
+//! Authoritative answers from the zones loaded into the server
+//!
+//! [`ZoneStore`] holds any number of [`Zone`]s, keyed on their reversed labels, and answers a
+//! question from the closest zone enclosing its name, as RFC 1034 4.3.2 describes: the records
+//! asked for, a CNAME, NODATA or NXDOMAIN with the SOA of the zone in the authority section,
+//! or, below a delegation, a referral to the child zone's name servers. [`ZoneLayer`] answers
+//! with it in front of forwarding, passing on the names outside every zone. It belongs above
+//! [`CacheLayer`](crate::cache::CacheLayer) too, or answers from before a reload would be
+//! served from the cache until their TTLs run out.
+//!
+//! Zone files are re-read on SIGHUP ([`ZoneLayer::reload_on_sighup`]) or when they change
+//! ([`ZoneLayer::watch`]). The new store replaces the old one only once every file parsed, in
+//! a single swap, so queries in flight finish with the zones they started with and a broken
+//! edit leaves the last good data in service.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use crate::dns::{opcode, rcode, rtype, DnsLabels, DnsMessage, DnsRecord, MessageBuilder};
+use crate::error::DnsError;
+use crate::handler::RequestCtx;
+use crate::info;
+use crate::pipeline::{BoxFuture, Layer, Next};
+use crate::rdata::{negative_ttl, RData};
+use crate::warn;
+use crate::zone::Zone;
+
+/// Lowercase labels of `name`, from the top level domain down
+fn key(name: &DnsLabels) -> Vec<Vec<u8>> {
+    name.labels()
+        .rev()
+        .map(<[u8]>::to_ascii_lowercase)
+        .collect()
+}
+
+/// Zones by name
+#[derive(Debug, Default, Clone)]
+pub struct ZoneStore {
+    zones: BTreeMap<Vec<Vec<u8>>, Zone>,
+}
+
+impl ZoneStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store of the zone files at `paths`, which must all parse and name distinct zones
+    pub fn load(paths: &[PathBuf]) -> Result<ZoneStore, DnsError> {
+        let mut store = ZoneStore::new();
+        for path in paths {
+            let in_file = |message| DnsError::Zone(format!("{}: {message}", path.display()));
+            let zone = Zone::read(path, DnsLabels::from(".")).map_err(|err| match err {
+                DnsError::Zone(message) => in_file(message),
+                other => in_file(other.to_string()),
+            })?;
+            info!(
+                "loaded zone {} ({} records) from {}",
+                zone.origin(),
+                zone.records().iter().count(),
+                path.display()
+            );
+            let origin = zone.origin().clone();
+            if store.insert(zone).is_some() {
+                return Err(in_file(format!("zone {origin} loaded twice")));
+            }
+        }
+        Ok(store)
+    }
+
+    /// Adds `zone`, returning the zone of the same name it replaces
+    pub fn insert(&mut self, zone: Zone) -> Option<Zone> {
+        self.zones.insert(key(zone.origin()), zone)
+    }
+
+    /// The zone `name` belongs to: the one with the longest origin `name` is a subdomain of
+    pub fn find(&self, name: &DnsLabels) -> Option<&Zone> {
+        let key = key(name);
+        (0..=key.len())
+            .rev()
+            .find_map(|len| self.zones.get(&key[..len]))
+    }
+
+    pub fn zones(&self) -> impl Iterator<Item = &Zone> {
+        self.zones.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// Authoritative answer to `query`, if it is a single question about a name in a zone
+    pub fn answer(&self, query: &DnsMessage) -> Option<DnsMessage> {
+        let mut questions = query.questions();
+        let question = questions.next()?;
+        if questions.next().is_some() || query.header().opcode() != opcode::QUERY {
+            return None;
+        }
+        let (name, qtype, class) = (question.qname(), question.qtype(), question.qclass());
+        let zone = self.find(name)?;
+        let records = zone.records();
+        let response = MessageBuilder::response_to(query).add_question(question.clone());
+
+        if let Some(cut) = delegation(zone, name, class) {
+            let ns: Vec<&DnsRecord> = records.lookup(&cut, rtype::NS, class).collect();
+            let glue: Vec<&DnsRecord> = ns
+                .iter()
+                .filter_map(|record| match record.rdata() {
+                    Ok(RData::Ns(host)) if host.is_subdomain_of(&cut) => Some(host),
+                    _ => None,
+                })
+                .flat_map(|host| {
+                    let v4: Vec<&DnsRecord> = records.lookup(&host, rtype::A, class).collect();
+                    v4.into_iter()
+                        .chain(records.lookup(&host, rtype::AAAA, class))
+                })
+                .collect();
+            let response = ns
+                .into_iter()
+                .cloned()
+                .fold(response, MessageBuilder::add_authority);
+            let response = glue
+                .into_iter()
+                .cloned()
+                .fold(response, MessageBuilder::add_additional);
+            return Some(response.build());
+        }
+
+        let response = response.authoritative(true);
+        let mut answers: Vec<&DnsRecord> = records.lookup(name, qtype, class).collect();
+        if answers.is_empty() {
+            // CnameLayer follows the alias
+            answers = records.lookup(name, rtype::CNAME, class).collect();
+        }
+        if !answers.is_empty() {
+            let response = answers
+                .into_iter()
+                .cloned()
+                .fold(response, MessageBuilder::add_answer);
+            return Some(response.build());
+        }
+
+        // NODATA or NXDOMAIN, cacheable for as long as the SOA says (RFC 2308)
+        let soa = zone.soa();
+        let soa = soa.clone().with_ttl(negative_ttl(soa).unwrap_or_default());
+        let response = response.add_authority(soa);
+        let response = if zone.contains(name) {
+            response
+        } else {
+            response.rcode(rcode::NXDOMAIN)
+        };
+        Some(response.build())
+    }
+}
+
+/// Highest name between the origin of `zone` (excluded) and `name` (included) owning NS
+/// records: the zone cut `name` is below, if it is delegated to a child zone
+fn delegation(zone: &Zone, name: &DnsLabels, class: u16) -> Option<DnsLabels> {
+    let labels: Vec<&[u8]> = name.labels().collect();
+    let below_origin = labels.len().checked_sub(zone.origin().label_count() + 1)?;
+    (0..=below_origin).rev().find_map(|skip| {
+        let cut = DnsLabels::new(&labels[skip..]).expect("suffix of a valid name");
+        zone.records()
+            .lookup(&cut, rtype::NS, class)
+            .next()
+            .map(|_| cut)
+    })
+}
+
+/// Zone files and the store read from them
+#[derive(Debug)]
+struct Shared {
+    paths: Vec<PathBuf>,
+    store: RwLock<Arc<ZoneStore>>,
+}
+
+/// Answers questions about names in the zones of a [`ZoneStore`]. Clones share the store.
+#[derive(Debug, Clone)]
+pub struct ZoneLayer {
+    shared: Arc<Shared>,
+}
+
+impl ZoneLayer {
+    /// Layer serving `store` as it is, with nothing to reload it from
+    pub fn new(store: ZoneStore) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                paths: Vec::new(),
+                store: RwLock::new(Arc::new(store)),
+            }),
+        }
+    }
+
+    /// Layer serving the zone files at `paths`, read now; see [`ZoneStore::load`]
+    pub fn load(paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> Result<Self, DnsError> {
+        let paths: Vec<PathBuf> = paths.into_iter().map(Into::into).collect();
+        let store = ZoneStore::load(&paths)?;
+        Ok(Self {
+            shared: Arc::new(Shared {
+                paths,
+                store: RwLock::new(Arc::new(store)),
+            }),
+        })
+    }
+
+    /// The zones served now; queries keep answering from the same store while it is reloaded
+    pub fn store(&self) -> Arc<ZoneStore> {
+        self.shared.store.read().unwrap().clone()
+    }
+
+    /// Re-reads the zone files and swaps the new zones in, or keeps serving the current ones
+    /// if a file fails to parse
+    pub fn reload(&self) -> Result<(), DnsError> {
+        reload(&self.shared)
+    }
+
+    /// Reloads whenever the modification time of a zone file changes, checking every
+    /// `interval` on a background task that ends with the layer. Must be called inside a
+    /// tokio runtime.
+    pub fn watch(self, interval: Duration) -> Self {
+        tokio::spawn(watch(Arc::downgrade(&self.shared), interval));
+        self
+    }
+
+    /// Reloads whenever the process receives SIGHUP, the usual way of telling a name server
+    /// its zones changed. Must be called inside a tokio runtime; does nothing outside Unix.
+    pub fn reload_on_sighup(self) -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            match signal(SignalKind::hangup()) {
+                Ok(mut hangups) => {
+                    let shared = Arc::downgrade(&self.shared);
+                    tokio::spawn(async move {
+                        while hangups.recv().await.is_some() {
+                            let Some(shared) = shared.upgrade() else {
+                                return;
+                            };
+                            info!("SIGHUP received, reloading zones");
+                            let _ = reload(&shared);
+                        }
+                    });
+                }
+                Err(err) => warn!("failed to listen for SIGHUP with {err}"),
+            }
+        }
+        self
+    }
+}
+
+fn reload(shared: &Shared) -> Result<(), DnsError> {
+    match ZoneStore::load(&shared.paths) {
+        Ok(store) => {
+            *shared.store.write().unwrap() = Arc::new(store);
+            Ok(())
+        }
+        Err(err) => {
+            warn!("failed to reload zones, keeping the previous ones, with {err}");
+            Err(err)
+        }
+    }
+}
+
+fn modified(paths: &[PathBuf]) -> Vec<io::Result<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path)?.modified())
+        .collect()
+}
+
+async fn watch(shared: Weak<Shared>, interval: Duration) {
+    let Some(paths) = shared.upgrade().map(|shared| shared.paths.clone()) else {
+        return;
+    };
+    let mut seen = modified(&paths);
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let current = modified(&paths);
+        let changed = current
+            .iter()
+            .zip(&seen)
+            .any(|(now, before)| now.as_ref().ok() != before.as_ref().ok());
+        if changed {
+            info!("zone files changed, reloading");
+            let _ = reload(&shared);
+            seen = current;
+        }
+    }
+}
+
+impl Layer for ZoneLayer {
+    fn call<'a>(
+        &'a self,
+        query: DnsMessage,
+        ctx: RequestCtx,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, DnsMessage> {
+        Box::pin(async move {
+            let answer = self.store().answer(&query);
+            match answer {
+                Some(response) => response,
+                None => next.run(query, ctx).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    const PARENT: &str = "
+$ORIGIN example.com.
+$TTL 3600
+@           SOA ns1 hostmaster 1 7200 3600 1209600 300
+            NS  ns1
+ns1         A   192.0.2.1
+www         A   192.0.2.80
+_sip._udp   SRV 10 5 5060 www
+lab         NS  ns.lab
+ns.lab      A   192.0.2.53
+";
+
+    const CHILD: &str = "
+$ORIGIN corp.example.com.
+$TTL 600
+@           SOA ns1 hostmaster 1 7200 3600 1209600 60
+www         A   10.0.0.80
+";
+
+    fn store() -> ZoneStore {
+        let mut store = ZoneStore::new();
+        for text in [PARENT, CHILD] {
+            store.insert(Zone::parse(text, DnsLabels::from(".")).unwrap());
+        }
+        store
+    }
+
+    #[test]
+    fn test_answer() {
+        let store = store();
+        let ask = |name: &str, qtype| store.answer(&DnsMessage::query(1, name, qtype));
+        let address = |resp: &DnsMessage| IpAddr::try_from(resp.answers().next().unwrap()).ok();
+
+        // the closest enclosing zone answers
+        let resp = ask("WWW.corp.example.com", rtype::A).unwrap();
+        assert!(resp.header().authoritative());
+        assert_eq!(address(&resp), Some(Ipv4Addr::new(10, 0, 0, 80).into()));
+        let resp = ask("www.example.com", rtype::A).unwrap();
+        assert_eq!(address(&resp), Some(Ipv4Addr::new(192, 0, 2, 80).into()));
+
+        // NODATA for an empty non-terminal, NXDOMAIN for a missing name, both with the SOA
+        let resp = ask("_udp.example.com", rtype::SRV).unwrap();
+        assert_eq!((resp.rcode(), resp.answers().len()), (rcode::NOERROR, 0));
+        let resp = ask("_tcp.example.com", rtype::SRV).unwrap();
+        assert_eq!(resp.rcode(), rcode::NXDOMAIN);
+        let soa = resp.authorities().next().unwrap();
+        assert_eq!((soa.record_type(), soa.ttl()), (rtype::SOA, 300));
+
+        // referral to the name servers of a delegated child, with their glue
+        let resp = ask("host.lab.example.com", rtype::A).unwrap();
+        assert!(!resp.header().authoritative());
+        assert_eq!(resp.answers().len(), 0);
+        assert_eq!(resp.authorities().next().unwrap().record_type(), rtype::NS);
+        assert_eq!(
+            resp.additionals().next().unwrap().name().to_string(),
+            "ns.lab.example.com"
+        );
+
+        assert!(ask("example.org", rtype::A).is_none());
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("zone-test-{}", std::process::id()));
+        fs::write(&path, CHILD).unwrap();
+        let layer = ZoneLayer::load([&path]).unwrap();
+        let query = DnsMessage::query(1, "www.corp.example.com", rtype::A);
+        let address = |store: &ZoneStore| {
+            let resp = store.answer(&query).unwrap();
+            IpAddr::try_from(resp.answers().next().unwrap()).unwrap()
+        };
+        let before = layer.store();
+
+        fs::write(&path, CHILD.replace("10.0.0.80", "10.0.0.81")).unwrap();
+        layer.reload().unwrap();
+        assert_eq!(address(&layer.store()), Ipv4Addr::new(10, 0, 0, 81));
+        // a query that started before the reload still sees the old zone
+        assert_eq!(address(&before), Ipv4Addr::new(10, 0, 0, 80));
+
+        // a broken edit keeps the last good zone in service
+        fs::write(&path, format!("{CHILD}broken A\n")).unwrap();
+        assert!(layer.reload().is_err());
+        assert_eq!(address(&layer.store()), Ipv4Addr::new(10, 0, 0, 81));
+        fs::remove_file(&path).unwrap();
+    }
+}
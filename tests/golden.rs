@@ -0,0 +1,159 @@
+//! Golden-vector tests over the packets in `tests/data`
+//!
+//! Every `<name>.hex` (hex bytes, `#` comments) is parsed and rendered to text, which must equal
+//! `<name>.expected`. After an intended change in parsing, rewrite the expectations with
+//! `GOLDEN_BLESS=1 cargo test --test golden` and review the diff.
+
+use std::fmt::Write;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use dns_starter_rust::dns::{DnsMessage, DnsRecord};
+use dns_starter_rust::rdata::RData;
+
+fn decode_hex(text: &str) -> Vec<u8> {
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(str::split_whitespace)
+        .map(|byte| u8::from_str_radix(byte, 16).expect("invalid hex byte"))
+        .collect()
+}
+
+fn render(bytes: &[u8]) -> String {
+    let msg = match DnsMessage::from_bytes(bytes) {
+        Ok(msg) => msg,
+        Err(err) => return format!("error {err}\n"),
+    };
+
+    let header = msg.header();
+    let flags = [
+        ("qr", header.is_response()),
+        ("aa", header.authoritative()),
+        ("tc", header.truncated()),
+        ("rd", header.recursion_desired()),
+        ("ra", header.recursion_available()),
+        ("ad", header.authentic_data()),
+        ("cd", header.checking_disabled()),
+    ];
+    let flags: Vec<_> = flags
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(f, _)| *f)
+        .collect();
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "header id={} opcode={} rcode={} flags={} counts={},{},{},{}",
+        header.id(),
+        header.opcode(),
+        header.rcode(),
+        flags.join(","),
+        header.qdcount(),
+        header.ancount(),
+        header.nscount(),
+        header.arcount(),
+    );
+    for question in msg.questions() {
+        let _ = writeln!(
+            out,
+            "question {} {} {}",
+            question.qname(),
+            question.qtype(),
+            question.qclass()
+        );
+    }
+    for (section, record) in msg.records() {
+        let _ = writeln!(out, "{section:?} {}", render_record(record));
+    }
+    if let Some(edns) = msg.edns() {
+        let options: Vec<_> = edns
+            .options()
+            .map(|(code, data)| {
+                let data: String = data.iter().map(|b| format!("{b:02x}")).collect();
+                format!("{code}:{data}")
+            })
+            .collect();
+        let _ = writeln!(
+            out,
+            "edns version={} payload={} rcode={} do={} options={}",
+            edns.version(),
+            edns.payload_size(),
+            edns.extended_rcode(),
+            edns.dnssec_ok(),
+            options.join(",")
+        );
+    }
+    out
+}
+
+fn render_record(record: &DnsRecord) -> String {
+    let rdata = match record.rdata() {
+        Ok(RData::A(ip)) => IpAddr::from(ip).to_string(),
+        Ok(RData::Aaaa(ip)) => IpAddr::from(ip).to_string(),
+        Ok(RData::Cname(name) | RData::Ns(name) | RData::Ptr(name)) => name.to_string(),
+        Ok(RData::Mx {
+            preference,
+            exchange,
+        }) => format!("{preference} {exchange}"),
+        Ok(RData::Soa {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        }) => format!("{mname} {rname} {serial} {refresh} {retry} {expire} {minimum}"),
+        Ok(RData::Srv {
+            priority,
+            weight,
+            port,
+            target,
+        }) => format!("{priority} {weight} {port} {target}"),
+        Ok(RData::Caa { flags, tag, value }) => {
+            format!("{flags} {tag} {:?}", String::from_utf8_lossy(&value))
+        }
+        _ => record.data().iter().map(|b| format!("{b:02x}")).collect(),
+    };
+    format!(
+        "{} {} {} {} {rdata}",
+        record.name(),
+        record.record_type(),
+        record.class(),
+        record.ttl()
+    )
+}
+
+#[test]
+fn test_golden_vectors() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let bless = std::env::var_os("GOLDEN_BLESS").is_some();
+
+    let mut vectors: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hex"))
+        .collect();
+    vectors.sort();
+    assert!(!vectors.is_empty(), "no vectors in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for path in vectors {
+        let rendered = render(&decode_hex(&fs::read_to_string(&path).unwrap()));
+        let expected_path = path.with_extension("expected");
+        if bless {
+            fs::write(&expected_path, &rendered).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if rendered != expected {
+            failures.push(format!(
+                "{}:\n--- expected\n{expected}--- got\n{rendered}",
+                path.display()
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
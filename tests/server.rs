@@ -0,0 +1,344 @@
+//! End-to-end tests: a server on an ephemeral port, queried by a client written against the
+//! RFC rather than this crate's codec, so a bug shared by encoder and decoder cannot hide.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use dns_starter_rust::dns::DnsLabels;
+use dns_starter_rust::handler::DefaultHandler;
+use dns_starter_rust::pipeline::Pipeline;
+use dns_starter_rust::zone::Zone;
+use dns_starter_rust::zone_store::{ZoneLayer, ZoneStore};
+use dns_starter_rust::{server, tcp};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+const NOERROR: u8 = 0;
+const FORMERR: u8 = 1;
+const NOTIMP: u8 = 4;
+
+const TXT: u16 = 16;
+
+async fn start_server() -> SocketAddr {
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = sock.local_addr().unwrap();
+    tokio::spawn(server::run(sock, DefaultHandler));
+    addr
+}
+
+async fn start_tcp_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(tcp::run(listener, DefaultHandler));
+    addr
+}
+
+/// Handler answering from a zone with more TXT records at `big.example.com` than fit in 512
+/// bytes
+fn big_zone() -> Pipeline {
+    let mut text = String::from(
+        "$ORIGIN example.com.\n$TTL 600\n@ SOA ns1 hostmaster 1 7200 3600 1209600 60\n",
+    );
+    for i in 0..20 {
+        text.push_str(&format!(
+            "big TXT \"record {i:02} padded out to forty bytes or so\"\n"
+        ));
+    }
+    let mut store = ZoneStore::new();
+    store.insert(Zone::parse(&text, DnsLabels::from(".")).unwrap());
+    Pipeline::new(DefaultHandler).layer(ZoneLayer::new(store))
+}
+
+/// Writes `query` with its 2 byte length prefix and reads one length-prefixed reply
+async fn tcp_exchange(stream: &mut TcpStream, query: &[u8]) -> Vec<u8> {
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await.unwrap();
+
+    let mut len = [0u8; 2];
+    timeout(Duration::from_secs(1), stream.read_exact(&mut len))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await.unwrap();
+    buf
+}
+
+/// Sends `query` and waits briefly for a reply
+async fn exchange(server: SocketAddr, query: &[u8]) -> Option<Vec<u8>> {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(query, server).await.unwrap();
+
+    let mut buf = vec![0u8; 65535];
+    let (len, _) = timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+        .await
+        .ok()?
+        .unwrap();
+    buf.truncate(len);
+    Some(buf)
+}
+
+/// Query with RD set and `opcode`, followed by the raw `additional` records
+fn build_query(id: u16, opcode: u8, questions: &[(&str, u16)], additional: &[&[u8]]) -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.push(opcode << 3 | 0x01);
+    msg.push(0);
+    msg.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&[0, 0, 0, 0]);
+    msg.extend_from_slice(&(additional.len() as u16).to_be_bytes());
+    for (name, qtype) in questions {
+        encode_name(&mut msg, name);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+    }
+    for record in additional {
+        msg.extend_from_slice(record);
+    }
+    msg
+}
+
+fn encode_name(msg: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+}
+
+#[derive(Debug)]
+struct Response {
+    id: u16,
+    flags: u16,
+    counts: [u16; 4],
+    questions: Vec<(String, u16, u16)>,
+    /// Name, type, class, TTL and rdata
+    answers: Vec<(String, u16, u16, u32, Vec<u8>)>,
+    additional: Vec<(String, u16, u16, u32, Vec<u8>)>,
+}
+
+impl Response {
+    fn parse(msg: &[u8]) -> Response {
+        let u16_at = |at: usize| u16::from_be_bytes([msg[at], msg[at + 1]]);
+        let counts = [u16_at(4), u16_at(6), u16_at(8), u16_at(10)];
+
+        let mut at = 12;
+        let mut questions = Vec::new();
+        for _ in 0..counts[0] {
+            let (name, next) = decode_name(msg, at);
+            questions.push((name, u16_at(next), u16_at(next + 2)));
+            at = next + 4;
+        }
+        let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+        for (records, &count) in sections.iter_mut().zip(&counts[1..]) {
+            for _ in 0..count {
+                let (name, next) = decode_name(msg, at);
+                let ttl = u32::from_be_bytes(msg[next + 4..next + 8].try_into().unwrap());
+                let rdlength = u16_at(next + 8) as usize;
+                let rdata = msg[next + 10..next + 10 + rdlength].to_vec();
+                records.push((name, u16_at(next), u16_at(next + 2), ttl, rdata));
+                at = next + 10 + rdlength;
+            }
+        }
+        assert_eq!(at, msg.len(), "trailing bytes after the additional section");
+        let [answers, _, additional] = sections;
+
+        Response {
+            id: u16_at(0),
+            flags: u16_at(2),
+            counts,
+            questions,
+            answers,
+            additional,
+        }
+    }
+
+    fn is_response(&self) -> bool {
+        self.flags & 0x8000 != 0
+    }
+
+    fn opcode(&self) -> u8 {
+        (self.flags >> 11 & 0xF) as u8
+    }
+
+    fn rcode(&self) -> u8 {
+        (self.flags & 0xF) as u8
+    }
+}
+
+/// Dotted name at `at`, following compression pointers, and the offset after it
+fn decode_name(msg: &[u8], mut at: usize) -> (String, usize) {
+    let mut labels = Vec::new();
+    let mut end = None;
+    loop {
+        let len = msg[at] as usize;
+        if len & 0xC0 == 0xC0 {
+            end.get_or_insert(at + 2);
+            at = (len & 0x3F) << 8 | msg[at + 1] as usize;
+        } else if len == 0 {
+            return (labels.join("."), end.unwrap_or(at + 1));
+        } else {
+            labels.push(String::from_utf8_lossy(&msg[at + 1..at + 1 + len]).into_owned());
+            at += 1 + len;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_a_query() {
+    let server = start_server().await;
+    let query = build_query(0xBEEF, 0, &[("codecrafters.io", 1)], &[]);
+    let resp = Response::parse(&exchange(server, &query).await.unwrap());
+
+    assert_eq!(resp.id, 0xBEEF);
+    assert!(resp.is_response());
+    assert_eq!(resp.rcode(), NOERROR);
+    assert_eq!(resp.counts, [1, 1, 0, 0]);
+    assert_eq!(resp.questions, [("codecrafters.io".to_string(), 1, 1)]);
+    let (name, rtype, class, _, rdata) = &resp.answers[0];
+    assert_eq!((name.as_str(), *rtype, *class), ("codecrafters.io", 1, 1));
+    assert_eq!(rdata, &[8, 8, 8, 8]);
+}
+
+#[tokio::test]
+async fn test_multiple_questions() {
+    let server = start_server().await;
+    let questions = [("a.example.com", 1), ("B.Example.ORG", 1)];
+    let query = build_query(2, 0, &questions, &[]);
+    let resp = Response::parse(&exchange(server, &query).await.unwrap());
+
+    assert_eq!(resp.rcode(), NOERROR);
+    assert_eq!(resp.counts[0], 2);
+    // names come back exactly as asked
+    let names: Vec<_> = resp.questions.iter().map(|q| q.0.as_str()).collect();
+    assert_eq!(names, ["a.example.com", "B.Example.ORG"]);
+    let answered: Vec<_> = resp.answers.iter().map(|a| a.0.as_str()).collect();
+    assert_eq!(answered, ["a.example.com", "B.Example.ORG"]);
+}
+
+#[tokio::test]
+async fn test_edns_query() {
+    let server = start_server().await;
+    // OPT pseudo-record: root name, type 41, 4096 byte payload, no options
+    let opt: &[u8] = &[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0];
+    let query = build_query(3, 0, &[("example.com", 1)], &[opt]);
+    let resp = Response::parse(&exchange(server, &query).await.unwrap());
+
+    assert_eq!(resp.id, 3);
+    assert_eq!(resp.rcode(), NOERROR);
+    assert_eq!(resp.answers.len(), 1);
+    // OPT in return, advertising the server's own payload size
+    let (name, rtype, payload_size, _, _) = &resp.additional[0];
+    assert_eq!((name.as_str(), *rtype, *payload_size), ("", 41, 4096));
+}
+
+#[tokio::test]
+async fn test_unsupported_opcode() {
+    let server = start_server().await;
+    let query = build_query(4, 2, &[("example.com", 1)], &[]);
+    let resp = Response::parse(&exchange(server, &query).await.unwrap());
+
+    assert_eq!(resp.opcode(), 2);
+    assert_eq!(resp.rcode(), NOTIMP);
+    assert_eq!(resp.counts, [1, 0, 0, 0]);
+}
+
+#[tokio::test]
+async fn test_malformed_input() {
+    let server = start_server().await;
+
+    // question cut short: FORMERR with just the header
+    let query = build_query(5, 0, &[("example.com", 1)], &[]);
+    let resp = Response::parse(&exchange(server, &query[..query.len() - 3]).await.unwrap());
+    assert_eq!(resp.id, 5);
+    assert_eq!(resp.rcode(), FORMERR);
+    assert_eq!(resp.counts, [0, 0, 0, 0]);
+
+    // label length with the reserved 0b10 prefix
+    let mut bad_label = query.clone();
+    bad_label[12] = 0x80;
+    let resp = Response::parse(&exchange(server, &bad_label).await.unwrap());
+    assert_eq!(resp.rcode(), FORMERR);
+
+    // not even a header: nothing to answer
+    assert!(exchange(server, &[1, 2, 3]).await.is_none());
+
+    // the server is still serving
+    assert!(exchange(server, &query).await.is_some());
+}
+
+#[tokio::test]
+async fn test_tcp_queries() {
+    let server = start_tcp_server().await;
+    let mut stream = TcpStream::connect(server).await.unwrap();
+
+    // several queries on one connection, answered in order
+    for id in [10, 11] {
+        let query = build_query(id, 0, &[("codecrafters.io", 1)], &[]);
+        let resp = Response::parse(&tcp_exchange(&mut stream, &query).await);
+        assert_eq!(resp.id, id);
+        assert_eq!(resp.rcode(), NOERROR);
+        assert_eq!(resp.answers[0].4, [8, 8, 8, 8]);
+    }
+
+    let query = build_query(12, 0, &[("example.com", 1)], &[]);
+    let resp = Response::parse(&tcp_exchange(&mut stream, &query[..query.len() - 3]).await);
+    assert_eq!((resp.id, resp.rcode()), (12, FORMERR));
+}
+
+#[tokio::test]
+async fn test_tcp_keepalive() {
+    let server = start_tcp_server().await;
+    let mut stream = TcpStream::connect(server).await.unwrap();
+
+    // OPT with an empty edns-tcp-keepalive option: the idle timeout comes back, in 100ms units
+    let opt: &[u8] = &[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 4, 0, 11, 0, 0];
+    let query = build_query(20, 0, &[("codecrafters.io", 1)], &[opt]);
+    let resp = Response::parse(&tcp_exchange(&mut stream, &query).await);
+    assert_eq!(resp.rcode(), NOERROR);
+    assert_eq!(resp.additional[0].4, [0, 11, 0, 2, 0x04, 0xB0]);
+
+    // a timeout is the server's to give
+    let opt: &[u8] = &[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 6, 0, 11, 0, 2, 0, 100];
+    let query = build_query(21, 0, &[("codecrafters.io", 1)], &[opt]);
+    let resp = Response::parse(&tcp_exchange(&mut stream, &query).await);
+    assert_eq!(resp.rcode(), FORMERR);
+
+    // and never advertised over UDP
+    let server = start_server().await;
+    let opt: &[u8] = &[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 4, 0, 11, 0, 0];
+    let query = build_query(22, 0, &[("codecrafters.io", 1)], &[opt]);
+    let resp = Response::parse(&exchange(server, &query).await.unwrap());
+    assert_eq!(resp.additional[0].4, []);
+}
+
+#[tokio::test]
+async fn test_udp_truncation() {
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let udp = sock.local_addr().unwrap();
+    tokio::spawn(server::run(sock, big_zone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tcp = listener.local_addr().unwrap();
+    tokio::spawn(tcp::run(listener, big_zone()));
+
+    // no OPT record, so the reply is held to 512 bytes
+    let query = build_query(30, 0, &[("big.example.com", TXT)], &[]);
+    let reply = exchange(udp, &query).await.unwrap();
+    assert!(reply.len() <= 512);
+    // parsing to the last byte with the header's counts shows records were dropped whole
+    let resp = Response::parse(&reply);
+    assert_eq!(resp.flags & 0x0200, 0x0200, "TC is not set");
+    assert_eq!(resp.rcode(), NOERROR);
+    assert!(!resp.answers.is_empty() && resp.answers.len() < 20);
+
+    let mut stream = TcpStream::connect(tcp).await.unwrap();
+    let resp = Response::parse(&tcp_exchange(&mut stream, &query).await);
+    assert_eq!(resp.flags & 0x0200, 0);
+    assert_eq!(resp.answers.len(), 20);
+    let mut texts: Vec<_> = resp.answers.iter().map(|answer| answer.4.clone()).collect();
+    texts.sort();
+    texts.dedup();
+    assert_eq!(texts.len(), 20);
+}